@@ -0,0 +1,72 @@
+use axum::extract::State;
+use serde::{Deserialize, Serialize};
+use utoipa::{IntoParams, ToSchema};
+
+use crate::{
+    app_state::AppState,
+    utils::{
+        audit_log::{AuditAction, AuditEntry, AuditQuery, AuditVerification},
+        query::AppQuery,
+        types::ApiResponse,
+    },
+};
+
+const DEFAULT_AUDIT_LOG_LIMIT: usize = 100;
+
+#[derive(Serialize, Deserialize, Clone, Debug, IntoParams)]
+pub struct ListAuditLogQuery {
+    /// Only entries of this principal, or `token:<name>` for admin tokens
+    pub principal: Option<String>,
+    /// Only entries about this post or video
+    pub subject: Option<String>,
+    pub action: Option<AuditAction>,
+    /// Most recent entries to return, 100 by default
+    pub limit: Option<usize>,
+}
+
+#[derive(Serialize, Deserialize, ToSchema)]
+pub struct ListAuditLogResp {
+    pub entries: Vec<AuditEntry>,
+}
+
+/// List the most recent state-changing actions and admin API requests, newest first
+#[utoipa::path(
+    get,
+    path = "/admin/audit-log",
+    params(ListAuditLogQuery),
+    responses(
+        (status = 200, description = "Audit log entries", body = ApiResponse<ListAuditLogResp>),
+        (status = 401, description = "Missing or invalid admin credentials"),
+        (status = 403, description = "Missing the read admin scope")
+    )
+)]
+pub async fn list_audit_log(
+    State(app_state): State<AppState>,
+    AppQuery(query): AppQuery<ListAuditLogQuery>,
+) -> ApiResponse<ListAuditLogResp> {
+    let result = app_state
+        .audit_log
+        .query(&AuditQuery {
+            principal: query.principal,
+            subject: query.subject,
+            action: query.action,
+            limit: query.limit.unwrap_or(DEFAULT_AUDIT_LOG_LIMIT),
+        })
+        .map(|entries| ListAuditLogResp { entries });
+
+    ApiResponse::from(result)
+}
+
+/// Check the hash chain of the audit log from its first entry
+#[utoipa::path(
+    get,
+    path = "/admin/audit-log/verify",
+    responses(
+        (status = 200, description = "Outcome of the check, `valid` is false when an entry was altered or removed", body = ApiResponse<AuditVerification>),
+        (status = 401, description = "Missing or invalid admin credentials"),
+        (status = 403, description = "Missing the read admin scope")
+    )
+)]
+pub async fn verify_audit_log(State(app_state): State<AppState>) -> ApiResponse<AuditVerification> {
+    ApiResponse::from(app_state.audit_log.verify())
+}
@@ -0,0 +1,191 @@
+use axum::{
+    Extension,
+    extract::{Path, State},
+};
+use candid::Principal;
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+use crate::{
+    app_state::AppState,
+    utils::{
+        admin_auth::AdminActor,
+        audit_log::{AuditAction, AuditEvent},
+        blocklist::{BlockedPrincipal, Blocklist},
+        json_body::AppJson,
+        request_id::RequestId,
+        types::{ApiResponse, AppError},
+    },
+};
+
+#[derive(Serialize, Deserialize, Clone, Debug, ToSchema)]
+pub struct BlockPrincipalRequest {
+    #[schema(example = "principal-id-string")]
+    pub principal: String,
+    /// Shown to the user alongside the restriction
+    pub reason: Option<String>,
+    /// Unix timestamp in seconds at which the block lifts, blocks until unblocked when absent
+    pub expires_at: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, ToSchema)]
+pub struct ListBlockedPrincipalsResp {
+    pub blocked_principals: Vec<BlockedPrincipal>,
+}
+
+/// List the principals currently blocked
+#[utoipa::path(
+    get,
+    path = "/admin/blocklist",
+    responses(
+        (status = 200, description = "Blocked principals", body = ApiResponse<ListBlockedPrincipalsResp>),
+        (status = 401, description = "Missing or invalid admin credentials"),
+        (status = 403, description = "Missing the read admin scope")
+    )
+)]
+pub async fn list_blocked_principals(
+    State(app_state): State<AppState>,
+) -> ApiResponse<ListBlockedPrincipalsResp> {
+    let result = app_state
+        .blocklist
+        .list()
+        .map(|blocked_principals| ListBlockedPrincipalsResp { blocked_principals });
+
+    ApiResponse::from(result)
+}
+
+/// Block a principal from uploading, publishing and managing their posts
+#[utoipa::path(
+    post,
+    path = "/admin/blocklist",
+    request_body = BlockPrincipalRequest,
+    responses(
+        (status = 200, description = "Principal blocked", body = ApiResponse<BlockedPrincipal>),
+        (status = 401, description = "Missing or invalid admin credentials"),
+        (status = 403, description = "Missing the moderate admin scope")
+    )
+)]
+pub async fn block_principal(
+    State(app_state): State<AppState>,
+    Extension(AdminActor(actor)): Extension<AdminActor>,
+    request_id: RequestId,
+    AppJson(payload): AppJson<BlockPrincipalRequest>,
+) -> ApiResponse<BlockedPrincipal> {
+    let event = AuditEvent::new(
+        AuditAction::Block,
+        Some(payload.principal.clone()),
+        Some(actor.clone()),
+        Some(request_id.0),
+    )
+    .with_details(serde_json::json!({
+        "reason": payload.reason,
+        "expires_at": payload.expires_at,
+    }));
+    let result = block_principal_impl(&app_state.blocklist, actor, payload);
+
+    app_state
+        .audit_log
+        .record(event.with_error(result.as_ref().err()));
+
+    ApiResponse::from(result)
+}
+
+fn block_principal_impl(
+    blocklist: &Blocklist,
+    actor: String,
+    payload: BlockPrincipalRequest,
+) -> Result<BlockedPrincipal, AppError> {
+    let principal = Principal::from_text(&payload.principal)?;
+
+    blocklist.block(principal, payload.reason, payload.expires_at, actor)
+}
+
+/// Lift the block of a principal
+#[utoipa::path(
+    post,
+    path = "/admin/blocklist/{principal}/unblock",
+    params(
+        ("principal" = String, Path, description = "Blocked principal")
+    ),
+    responses(
+        (status = 200, description = "The block that was lifted", body = ApiResponse<BlockedPrincipal>),
+        (status = 401, description = "Missing or invalid admin credentials"),
+        (status = 403, description = "Missing the moderate admin scope"),
+        (status = 404, description = "Principal is not blocked")
+    )
+)]
+pub async fn unblock_principal(
+    State(app_state): State<AppState>,
+    Extension(AdminActor(actor)): Extension<AdminActor>,
+    request_id: RequestId,
+    Path(principal): Path<String>,
+) -> ApiResponse<BlockedPrincipal> {
+    let result = Principal::from_text(&principal)
+        .map_err(AppError::from)
+        .and_then(|principal| app_state.blocklist.unblock(principal));
+
+    // the lifted block is kept alongside, its entry may be long gone from the log
+    let mut event = AuditEvent::new(
+        AuditAction::Unblock,
+        Some(principal),
+        Some(actor),
+        Some(request_id.0),
+    )
+    .with_error(result.as_ref().err());
+    if let Ok(lifted) = &result {
+        event = event.with_details(serde_json::json!({
+            "reason": lifted.reason,
+            "expires_at": lifted.expires_at,
+            "blocked_by": lifted.blocked_by,
+        }));
+    }
+    app_state.audit_log.record(event);
+
+    ApiResponse::from(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::{store::Store, time::now_unix_secs};
+
+    fn request(principal: &str, expires_at: Option<u64>) -> BlockPrincipalRequest {
+        BlockPrincipalRequest {
+            principal: principal.to_string(),
+            reason: None,
+            expires_at,
+        }
+    }
+
+    #[test]
+    fn test_block_request_validation() {
+        let blocklist = Blocklist::new(&Store::temporary().unwrap()).unwrap();
+        let principal = Principal::self_authenticating(b"creator").to_text();
+
+        assert!(matches!(
+            block_principal_impl(
+                &blocklist,
+                "token:ops".to_string(),
+                request("not a principal", None)
+            ),
+            Err(AppError::InvalidPrincipal(_))
+        ));
+        assert!(matches!(
+            block_principal_impl(
+                &blocklist,
+                "token:ops".to_string(),
+                request(&principal, Some(now_unix_secs() - 1))
+            ),
+            Err(AppError::InvalidRequest(_))
+        ));
+
+        let blocked = block_principal_impl(
+            &blocklist,
+            "token:ops".to_string(),
+            request(&principal, None),
+        )
+        .unwrap();
+        assert_eq!(blocked.blocked_by, "token:ops");
+        assert!(blocklist.ensure_not_blocked(blocked.principal).is_err());
+    }
+}
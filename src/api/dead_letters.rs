@@ -0,0 +1,202 @@
+use axum::{
+    Extension,
+    extract::{Path, State},
+};
+use serde::{Deserialize, Serialize};
+use utoipa::{IntoParams, ToSchema};
+
+use crate::{
+    app_state::AppState,
+    utils::{
+        admin_auth::AdminActor,
+        audit_log::{AuditAction, AuditEvent},
+        dead_letters::{DeadLetter, DeadLetterKind, ReplayOutcome},
+        json_body::AppJson,
+        query::AppQuery,
+        request_id::RequestId,
+        types::{ApiResponse, EmptyResp},
+    },
+};
+
+#[derive(Serialize, Deserialize, Clone, Debug, IntoParams)]
+pub struct ListDeadLettersQuery {
+    /// Only list dead letters of this kind
+    pub kind: Option<DeadLetterKind>,
+}
+
+#[derive(Serialize, Deserialize, ToSchema)]
+pub struct ListDeadLettersResp {
+    pub dead_letters: Vec<DeadLetter>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, ToSchema)]
+pub struct ReplayDeadLettersRequest {
+    /// Dead letters to replay, at most 20, or the next batch of `kind` when empty
+    #[serde(default)]
+    pub ids: Vec<String>,
+    pub kind: Option<DeadLetterKind>,
+}
+
+#[derive(Serialize, Deserialize, ToSchema)]
+pub struct ReplayDeadLettersResp {
+    pub outcomes: Vec<ReplayOutcome>,
+    /// Dead letters of the requested kind left for a later batch
+    pub remaining: usize,
+}
+
+/// List dead letters, oldest first
+#[utoipa::path(
+    get,
+    path = "/admin/dead-letters",
+    params(ListDeadLettersQuery),
+    responses(
+        (status = 200, description = "Dead letters", body = ApiResponse<ListDeadLettersResp>),
+        (status = 401, description = "Missing or invalid admin credentials"),
+        (status = 403, description = "Missing the read admin scope")
+    )
+)]
+pub async fn list_dead_letters(
+    State(app_state): State<AppState>,
+    AppQuery(query): AppQuery<ListDeadLettersQuery>,
+) -> ApiResponse<ListDeadLettersResp> {
+    let result = app_state
+        .dead_letters
+        .list(query.kind)
+        .map(|dead_letters| ListDeadLettersResp { dead_letters });
+
+    ApiResponse::from(result)
+}
+
+/// Get a dead letter with the full context of the failed operation
+#[utoipa::path(
+    get,
+    path = "/admin/dead-letters/{id}",
+    params(
+        ("id" = String, Path, description = "Id of the dead letter")
+    ),
+    responses(
+        (status = 200, description = "Dead letter", body = ApiResponse<DeadLetter>),
+        (status = 401, description = "Missing or invalid admin credentials"),
+        (status = 403, description = "Missing the read admin scope"),
+        (status = 404, description = "Dead letter not found")
+    )
+)]
+pub async fn get_dead_letter(
+    State(app_state): State<AppState>,
+    Path(id): Path<String>,
+) -> ApiResponse<DeadLetter> {
+    ApiResponse::from(app_state.dead_letters.get(&id))
+}
+
+/// Replay a dead letter. It is removed once the replay succeeds.
+#[utoipa::path(
+    post,
+    path = "/admin/dead-letters/{id}/replay",
+    params(
+        ("id" = String, Path, description = "Id of the dead letter")
+    ),
+    responses(
+        (status = 200, description = "Replay succeeded", body = ApiResponse<EmptyResp>),
+        (status = 401, description = "Missing or invalid admin credentials"),
+        (status = 403, description = "Missing the replay admin scope"),
+        (status = 404, description = "Dead letter not found"),
+        (status = 409, description = "The dead letter is already being replayed")
+    )
+)]
+pub async fn replay_dead_letter(
+    State(app_state): State<AppState>,
+    Extension(AdminActor(actor)): Extension<AdminActor>,
+    request_id: RequestId,
+    Path(id): Path<String>,
+) -> ApiResponse<()> {
+    let result = app_state.dead_letters.replay(&app_state, &id).await;
+
+    app_state.audit_log.record(
+        AuditEvent::new(
+            AuditAction::ReplayDeadLetter,
+            Some(id),
+            Some(actor),
+            Some(request_id.0),
+        )
+        .with_error(result.as_ref().err()),
+    );
+
+    ApiResponse::from(result)
+}
+
+/// Replay a batch of dead letters one after the other
+#[utoipa::path(
+    post,
+    path = "/admin/dead-letters/replay",
+    request_body = ReplayDeadLettersRequest,
+    responses(
+        (status = 200, description = "Outcome of every replay", body = ApiResponse<ReplayDeadLettersResp>),
+        (status = 400, description = "Too many dead letters requested"),
+        (status = 401, description = "Missing or invalid admin credentials"),
+        (status = 403, description = "Missing the replay admin scope")
+    )
+)]
+pub async fn replay_dead_letters(
+    State(app_state): State<AppState>,
+    Extension(AdminActor(actor)): Extension<AdminActor>,
+    request_id: RequestId,
+    AppJson(payload): AppJson<ReplayDeadLettersRequest>,
+) -> ApiResponse<ReplayDeadLettersResp> {
+    let result = app_state
+        .dead_letters
+        .replay_many(&app_state, payload.ids, payload.kind)
+        .await
+        .map(|(outcomes, remaining)| ReplayDeadLettersResp {
+            outcomes,
+            remaining,
+        });
+
+    for outcome in result.iter().flat_map(|resp| &resp.outcomes) {
+        app_state.audit_log.record(
+            AuditEvent::new(
+                AuditAction::ReplayDeadLetter,
+                Some(outcome.id.clone()),
+                Some(actor.clone()),
+                Some(request_id.0.clone()),
+            )
+            .with_error(outcome.error.as_ref()),
+        );
+    }
+
+    ApiResponse::from(result)
+}
+
+/// Discard a dead letter without replaying it
+#[utoipa::path(
+    post,
+    path = "/admin/dead-letters/{id}/discard",
+    params(
+        ("id" = String, Path, description = "Id of the dead letter")
+    ),
+    responses(
+        (status = 200, description = "Dead letter discarded", body = ApiResponse<EmptyResp>),
+        (status = 401, description = "Missing or invalid admin credentials"),
+        (status = 403, description = "Missing the delete admin scope"),
+        (status = 404, description = "Dead letter not found")
+    )
+)]
+pub async fn discard_dead_letter(
+    State(app_state): State<AppState>,
+    Extension(AdminActor(actor)): Extension<AdminActor>,
+    request_id: RequestId,
+    Path(id): Path<String>,
+) -> ApiResponse<()> {
+    let result = app_state.dead_letters.discard(&id);
+
+    app_state.audit_log.record(
+        AuditEvent::new(
+            AuditAction::DiscardDeadLetter,
+            Some(id),
+            Some(actor),
+            Some(request_id.0),
+        )
+        .with_error(result.as_ref().err()),
+    );
+
+    ApiResponse::from(result)
+}
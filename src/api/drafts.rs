@@ -0,0 +1,473 @@
+use std::collections::HashMap;
+
+use axum::extract::State;
+use candid::Principal;
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+use uuid::Uuid;
+use yral_canisters_client::{
+    ic::USER_POST_SERVICE_ID,
+    user_post_service::{
+        Post, PostDetailsFromFrontendV1, PostStatus, PostStatusFromFrontend, Result_,
+        UserPostService,
+    },
+};
+
+use crate::{
+    api::{
+        mark_post_as_published::{ensure_post_creator, fetch_post_details, post_status_label},
+        update_video_metadata::{POST_DETAILS_KEY, post_exists, upload_video_canister},
+    },
+    app_state::AppState,
+    utils::{
+        audit_log::{AuditAction, AuditEvent},
+        circuit_breaker::CircuitBreaker,
+        json_body::AppJson,
+        publish_scheduler::PublishScheduler,
+        request_auth::Caller,
+        request_id::RequestId,
+        storj_interface::StorjInterface,
+        types::{ApiResponse, AppError, DelegatedIdentityWire, EmptyResp, RequestPostDetails},
+        upload_saga::UploadSagaState,
+    },
+};
+
+const POSTS_PAGE_SIZE: u64 = 50;
+const MAX_POSTS_PAGES: u64 = 20;
+
+#[derive(Serialize, Deserialize, Clone, Debug, ToSchema)]
+pub struct ListDraftsRequest {
+    /// Identifies the caller of a request that is not signed
+    #[serde(default)]
+    pub delegated_identity_wire: Option<DelegatedIdentityWire>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, ToSchema)]
+pub struct DraftPost {
+    #[schema(example = "post-id-string")]
+    pub post_id: String,
+    pub video_uid: String,
+    pub description: String,
+    pub hashtags: Vec<String>,
+    /// Metadata stored with the Storj object, `None` if it could not be fetched
+    pub storj_metadata: Option<HashMap<String, String>>,
+}
+
+#[derive(Serialize, Deserialize, ToSchema)]
+pub struct ListDraftsResp {
+    pub drafts: Vec<DraftPost>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, ToSchema)]
+pub struct DraftActionRequest {
+    #[schema(example = "post-id-string")]
+    pub post_id: String,
+    /// Identifies the caller of a request that is not signed
+    #[serde(default)]
+    pub delegated_identity_wire: Option<DelegatedIdentityWire>,
+}
+
+#[derive(Serialize, Deserialize, ToSchema)]
+pub struct DuplicateDraftResp {
+    #[schema(example = "post-id-string")]
+    pub post_id: String,
+}
+
+/// List the caller's drafts
+#[utoipa::path(
+    post,
+    path = "/list-drafts",
+    request_body = ListDraftsRequest,
+    responses(
+        (status = 200, description = "Drafts of the caller", body = ApiResponse<ListDraftsResp>)
+    )
+)]
+pub async fn list_drafts(
+    State(app_state): State<AppState>,
+    caller: Caller,
+    AppJson(payload): AppJson<ListDraftsRequest>,
+) -> ApiResponse<ListDraftsResp> {
+    let result = list_drafts_impl(
+        &app_state.ic_admin_agent,
+        &app_state.circuit_breakers.ic_agent,
+        &app_state.storj_client,
+        &caller,
+        payload,
+    )
+    .await;
+
+    ApiResponse::from(result)
+}
+
+/// Delete a draft along with its video
+#[utoipa::path(
+    post,
+    path = "/delete-draft",
+    request_body = DraftActionRequest,
+    responses(
+        (status = 200, description = "Draft deleted", body = ApiResponse<EmptyResp>)
+    )
+)]
+pub async fn delete_draft(
+    State(app_state): State<AppState>,
+    caller: Caller,
+    request_id: RequestId,
+    AppJson(payload): AppJson<DraftActionRequest>,
+) -> ApiResponse<()> {
+    let post_id = payload.post_id.clone();
+    let mut status_before = None;
+    let result = delete_draft_impl(
+        &app_state.ic_admin_agent,
+        &app_state.circuit_breakers.ic_agent,
+        &app_state.storj_client,
+        &app_state.publish_scheduler,
+        &caller,
+        payload,
+        &mut status_before,
+    )
+    .await;
+
+    app_state.audit_log.record(
+        AuditEvent::new(
+            AuditAction::DeleteDraft,
+            Some(post_id),
+            caller.resolved_principal(),
+            Some(request_id.0),
+        )
+        .with_transition_from(status_before, "deleted")
+        .with_error(result.as_ref().err()),
+    );
+
+    ApiResponse::from(result)
+}
+
+/// Duplicate a draft into a new draft with its own copy of the video
+#[utoipa::path(
+    post,
+    path = "/duplicate-draft",
+    request_body = DraftActionRequest,
+    responses(
+        (status = 200, description = "Draft duplicated", body = ApiResponse<DuplicateDraftResp>)
+    )
+)]
+pub async fn duplicate_draft(
+    State(app_state): State<AppState>,
+    caller: Caller,
+    request_id: RequestId,
+    AppJson(payload): AppJson<DraftActionRequest>,
+) -> ApiResponse<DuplicateDraftResp> {
+    let post_id = payload.post_id.clone();
+    let result = duplicate_draft_impl(&app_state, &caller, payload).await;
+
+    app_state.audit_log.record(
+        AuditEvent::new(
+            AuditAction::DuplicateDraft,
+            Some(post_id),
+            caller.resolved_principal(),
+            Some(request_id.0),
+        )
+        .with_error(result.as_ref().err()),
+    );
+
+    ApiResponse::from(result)
+}
+
+async fn list_drafts_impl(
+    ic_admin_agent: &ic_agent::Agent,
+    ic_agent_breaker: &CircuitBreaker,
+    storj_client: &StorjInterface,
+    caller: &Caller,
+    payload: ListDraftsRequest,
+) -> Result<ListDraftsResp, AppError> {
+    let creator_principal = caller.principal(payload.delegated_identity_wire.as_ref())?;
+
+    let draft_posts =
+        fetch_creator_drafts(ic_admin_agent, ic_agent_breaker, creator_principal).await?;
+
+    let mut drafts = Vec::with_capacity(draft_posts.len());
+    for post in draft_posts {
+        let storj_metadata = storj_client
+            .get_metadata(&post.id, &creator_principal.to_text(), false)
+            .await
+            .inspect_err(|e| {
+                log::warn!(
+                    "Failed to fetch Storj metadata for draft {}: {}",
+                    post.id,
+                    e
+                )
+            })
+            .ok();
+
+        drafts.push(DraftPost {
+            post_id: post.id,
+            video_uid: post.video_uid,
+            description: post.description,
+            hashtags: post.hashtags,
+            storj_metadata,
+        });
+    }
+
+    Ok(ListDraftsResp { drafts })
+}
+
+async fn delete_draft_impl(
+    ic_admin_agent: &ic_agent::Agent,
+    ic_agent_breaker: &CircuitBreaker,
+    storj_client: &StorjInterface,
+    publish_scheduler: &PublishScheduler,
+    caller: &Caller,
+    payload: DraftActionRequest,
+    status_before: &mut Option<String>,
+) -> Result<(), AppError> {
+    let post_details =
+        fetch_owned_draft(ic_admin_agent, ic_agent_breaker, caller, &payload).await?;
+    *status_before = Some(post_status_label(&post_details.status));
+
+    let user_post_service = UserPostService(USER_POST_SERVICE_ID, ic_admin_agent);
+
+    let delete_result = ic_agent_breaker
+        .call(async {
+            user_post_service
+                .delete_post(post_details.id.clone())
+                .await
+                .map_err(AppError::from)
+        })
+        .await?;
+
+    if let Result_::Err(user_post_service_error) = delete_result {
+        return Err(AppError::from_user_post_service(
+            user_post_service_error,
+            &post_details.id,
+        ));
+    }
+
+    // the draft is gone once the canister deleted it, a video left behind is only reported
+    discard_video(
+        storj_client,
+        &post_details.id,
+        &post_details.creator_principal.to_text(),
+    )
+    .await;
+
+    publish_scheduler.cancel_superseded(&post_details.id);
+
+    Ok(())
+}
+
+async fn duplicate_draft_impl(
+    app_state: &AppState,
+    caller: &Caller,
+    payload: DraftActionRequest,
+) -> Result<DuplicateDraftResp, AppError> {
+    let storj_client = &app_state.storj_client;
+    let upload_sagas = &app_state.upload_sagas;
+
+    let post_details = fetch_owned_draft(
+        &app_state.ic_admin_agent,
+        &app_state.circuit_breakers.ic_agent,
+        caller,
+        &payload,
+    )
+    .await?;
+    let publisher_user_id = post_details.creator_principal.to_text();
+    let new_post_id = Uuid::new_v4().to_string();
+
+    let new_post_details = PostDetailsFromFrontendV1 {
+        id: new_post_id.clone(),
+        video_uid: new_post_id.clone(),
+        description: post_details.description,
+        hashtags: post_details.hashtags,
+        creator_principal: post_details.creator_principal,
+        status: PostStatusFromFrontend::Draft,
+    };
+
+    // the copy is an upload of its own, compensated like one when the canister write fails and
+    // left to the reconciler when the process dies mid-way
+    app_state
+        .upload_sessions
+        .record(&new_post_id, post_details.creator_principal);
+    upload_sagas.begin(&new_post_id, post_details.creator_principal)?;
+
+    let metadata = match storj_client
+        .get_metadata(&post_details.id, &publisher_user_id, false)
+        .await
+        .map_err(|e| AppError::StorageError(e.to_string()))
+        .and_then(|metadata| duplicate_metadata(metadata, &new_post_details))
+    {
+        Ok(metadata) => metadata,
+        Err(e) => {
+            upload_sagas.advance(&new_post_id, UploadSagaState::Failed, Some(&e));
+            return Err(e);
+        }
+    };
+
+    let copy_result = storj_client
+        .copy_video(
+            &post_details.id,
+            &new_post_id,
+            &publisher_user_id,
+            false,
+            metadata.clone(),
+        )
+        .await
+        .map_err(|e| AppError::StorageError(e.to_string()));
+    if let Err(e) = copy_result {
+        // the copy may have been finalized before a later step failed
+        discard_video(storj_client, &new_post_id, &publisher_user_id).await;
+        upload_sagas.advance(&new_post_id, UploadSagaState::Failed, Some(&e));
+        return Err(e);
+    }
+    upload_sagas.advance(&new_post_id, UploadSagaState::StorjFinalized, None);
+
+    if let Err(e) = upload_video_canister(
+        &app_state.ic_admin_agent,
+        &app_state.circuit_breakers.ic_agent,
+        upload_sagas,
+        new_post_details,
+    )
+    .await
+    {
+        let written = upload_sagas
+            .compensate(
+                storj_client,
+                &new_post_id,
+                &publisher_user_id,
+                metadata,
+                &e,
+                || post_exists(app_state, &new_post_id),
+            )
+            .await;
+
+        if !written {
+            return Err(e);
+        }
+    }
+    upload_sagas.advance(&new_post_id, UploadSagaState::Completed, None);
+
+    Ok(DuplicateDraftResp {
+        post_id: new_post_id,
+    })
+}
+
+/// Metadata of a duplicated draft's video, the original's with the new post's details
+fn duplicate_metadata(
+    mut metadata: HashMap<String, String>,
+    new_post_details: &PostDetailsFromFrontendV1,
+) -> Result<HashMap<String, String>, AppError> {
+    metadata.insert(
+        POST_DETAILS_KEY.to_string(),
+        serde_json::to_string(&RequestPostDetails::from(new_post_details.clone()))?,
+    );
+
+    Ok(metadata)
+}
+
+/// Deletes a video no post refers to. A failure leaves an orphaned object and is reported to
+/// Sentry, the caller's outcome does not depend on it.
+async fn discard_video(storj_client: &StorjInterface, video_id: &str, publisher_user_id: &str) {
+    if let Err(e) = storj_client
+        .delete_video(video_id, publisher_user_id, false)
+        .await
+    {
+        let msg = format!(
+            "Failed to delete video {} of publisher {} from Storj: {}",
+            video_id, publisher_user_id, e
+        );
+        log::error!("{}", msg);
+        sentry::capture_message(&msg, sentry::Level::Error);
+    }
+}
+
+async fn fetch_owned_draft(
+    ic_admin_agent: &ic_agent::Agent,
+    ic_agent_breaker: &CircuitBreaker,
+    caller: &Caller,
+    payload: &DraftActionRequest,
+) -> Result<Post, AppError> {
+    let sender = caller.principal(payload.delegated_identity_wire.as_ref())?;
+
+    let post_details =
+        fetch_post_details(ic_admin_agent, ic_agent_breaker, &payload.post_id).await?;
+
+    ensure_post_creator(sender, &post_details)?;
+
+    if !matches!(post_details.status, PostStatus::Draft) {
+        return Err(AppError::InvalidRequest(format!(
+            "Post {} is not a draft",
+            payload.post_id
+        )));
+    }
+
+    Ok(post_details)
+}
+
+async fn fetch_creator_drafts(
+    ic_admin_agent: &ic_agent::Agent,
+    ic_agent_breaker: &CircuitBreaker,
+    creator_principal: Principal,
+) -> Result<Vec<Post>, AppError> {
+    let user_post_service = UserPostService(USER_POST_SERVICE_ID, ic_admin_agent);
+    let mut drafts = Vec::new();
+
+    for page in 0..MAX_POSTS_PAGES {
+        let posts = ic_agent_breaker
+            .call(async {
+                user_post_service
+                    .get_posts_of_this_user_profile_with_pagination_cursor(
+                        creator_principal,
+                        page * POSTS_PAGE_SIZE,
+                        POSTS_PAGE_SIZE,
+                    )
+                    .await
+                    .map_err(AppError::from)
+            })
+            .await?;
+        let is_last_page = (posts.len() as u64) < POSTS_PAGE_SIZE;
+
+        drafts.extend(
+            posts
+                .into_iter()
+                .filter(|post| matches!(post.status, PostStatus::Draft)),
+        );
+
+        if is_last_page {
+            break;
+        }
+    }
+
+    Ok(drafts)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_duplicate_metadata_points_at_the_new_post() {
+        let creator_principal = Principal::self_authenticating(b"creator");
+        let original = HashMap::from([
+            ("title".to_string(), "title".to_string()),
+            (
+                POST_DETAILS_KEY.to_string(),
+                "{\"id\":\"original\"}".to_string(),
+            ),
+        ]);
+        let new_post_details = PostDetailsFromFrontendV1 {
+            id: "copy".to_string(),
+            video_uid: "copy".to_string(),
+            description: "description".to_string(),
+            hashtags: vec!["tag".to_string()],
+            creator_principal,
+            status: PostStatusFromFrontend::Draft,
+        };
+
+        let metadata = duplicate_metadata(original, &new_post_details).unwrap();
+        let post_details: RequestPostDetails =
+            serde_json::from_str(&metadata[POST_DETAILS_KEY]).unwrap();
+
+        assert_eq!(metadata["title"], "title");
+        assert_eq!(post_details.id, "copy");
+        assert_eq!(post_details.video_uid, "copy");
+        assert_eq!(post_details.creator_principal, creator_principal);
+    }
+}
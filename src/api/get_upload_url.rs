@@ -1,4 +1,4 @@
-use axum::{Json, extract::State};
+use axum::extract::State;
 use candid::Principal;
 use serde::{Deserialize, Serialize};
 use utoipa::{IntoParams, ToSchema};
@@ -11,15 +11,25 @@ use yral_canisters_client::{
 use crate::{
     app_state::AppState,
     utils::{
+        audit_log::{AuditAction, AuditEvent},
+        circuit_breaker::CircuitBreaker,
+        json_body::AppJson,
+        request_auth::Caller,
+        request_id::RequestId,
         storj_interface::StorjInterface,
-        types::{ApiResponse, AppError},
+        types::{ApiResponse, AppError, DelegatedIdentityWire},
+        upload_sessions::UploadSessions,
     },
 };
 
 #[derive(Serialize, Deserialize, ToSchema, IntoParams)]
 pub struct GetUploadUrlReq {
+    /// Must be the principal of the caller
     #[schema(example = "principal-id-string")]
     pub publisher_user_id: String,
+    /// Identifies the caller of a request that is not signed
+    #[serde(default)]
+    pub delegated_identity_wire: Option<DelegatedIdentityWire>,
 }
 
 #[derive(Serialize, Deserialize, ToSchema)]
@@ -36,35 +46,74 @@ pub struct GetUploadUrlResp {
     path = "/get-upload-url",
     request_body = GetUploadUrlReq,
     responses(
-        (status = 200, description = "Upload URL", body = ApiResponse<GetUploadUrlResp>)
+        (status = 200, description = "Upload URL", body = ApiResponse<GetUploadUrlResp>),
+        (status = 401, description = "Unauthenticated"),
+        (status = 403, description = "The caller is not the publisher or is blocked")
     )
 )]
 pub async fn get_upload_url(
     State(app_state): State<AppState>,
-    Json(req): Json<GetUploadUrlReq>,
+    caller: Caller,
+    request_id: RequestId,
+    AppJson(req): AppJson<GetUploadUrlReq>,
 ) -> ApiResponse<GetUploadUrlResp> {
     //TODO: check if the upload url created is for scheduled duration  yes it is scheduled
     //TODO: check if we need to first check if the user is present on our system.
 
-    let get_upload_url_result =
-        get_upload_url_impl(&app_state.ic_admin_agent, &app_state.storj_client, req).await;
+    let get_upload_url_result = get_upload_url_impl(
+        &app_state.ic_admin_agent,
+        &app_state.circuit_breakers.ic_agent,
+        &app_state.storj_client,
+        &app_state.upload_sessions,
+        &caller,
+        req,
+    )
+    .await;
+
+    app_state.audit_log.record(
+        AuditEvent::new(
+            AuditAction::IssueUploadUrl,
+            get_upload_url_result
+                .as_ref()
+                .ok()
+                .map(|resp| resp.video_id.clone()),
+            caller.resolved_principal(),
+            Some(request_id.0),
+        )
+        .with_error(get_upload_url_result.as_ref().err()),
+    );
 
     ApiResponse::from(get_upload_url_result)
 }
 
 async fn get_upload_url_impl(
     ic_admin_agent: &ic_agent::Agent,
+    ic_agent_breaker: &CircuitBreaker,
     storj_client: &StorjInterface,
+    upload_sessions: &UploadSessions,
+    caller: &Caller,
     req_data: GetUploadUrlReq,
 ) -> Result<GetUploadUrlResp, AppError> {
     let new_video_id = Uuid::new_v4();
 
-    let user_principal = Principal::from_text(req_data.publisher_user_id.clone())?;
+    // also rejects a blocked caller
+    let user_principal = caller.principal(req_data.delegated_identity_wire.as_ref())?;
+    if Principal::from_text(&req_data.publisher_user_id)? != user_principal {
+        return Err(AppError::Unauthorized(format!(
+            "The caller is not the publisher. Caller: {:?}, Publisher: {}",
+            user_principal, req_data.publisher_user_id
+        )));
+    }
 
     let user_info_service = UserInfoService(USER_INFO_SERVICE_ID, ic_admin_agent);
 
-    let profile_details_res = user_info_service
-        .get_user_profile_details_v_6(user_principal)
+    let profile_details_res = ic_agent_breaker
+        .call(async {
+            user_info_service
+                .get_user_profile_details_v_6(user_principal)
+                .await
+                .map_err(AppError::from)
+        })
         .await?;
 
     let _profile_details = match profile_details_res {
@@ -81,6 +130,8 @@ async fn get_upload_url_impl(
         false,
     );
 
+    upload_sessions.record(&new_video_id.to_string(), user_principal);
+
     Ok(GetUploadUrlResp {
         upload_url: result,
         video_id: new_video_id.to_string(),
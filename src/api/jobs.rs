@@ -0,0 +1,58 @@
+use axum::extract::{Path, State};
+
+use crate::{
+    app_state::AppState,
+    utils::{
+        finalize_jobs::JobProgress,
+        request_auth::Caller,
+        types::{ApiResponse, AppError},
+    },
+};
+
+/// Get the progress of an asynchronous finalize job. The request must be signed by the creator
+/// of the post.
+#[utoipa::path(
+    get,
+    path = "/jobs/{job_id}",
+    params(
+        ("job_id" = String, Path, description = "Id returned by an asynchronous update-video-metadata")
+    ),
+    responses(
+        (status = 200, description = "Progress of the job", body = ApiResponse<JobProgress>),
+        (status = 401, description = "Request not signed"),
+        (status = 403, description = "Caller is not the creator of the post"),
+        (status = 404, description = "Job not found")
+    )
+)]
+pub async fn get_job(
+    State(app_state): State<AppState>,
+    caller: Caller,
+    Path(job_id): Path<String>,
+) -> ApiResponse<JobProgress> {
+    let result = get_job_impl(&app_state, &caller, job_id);
+
+    ApiResponse::from(result)
+}
+
+fn get_job_impl(
+    app_state: &AppState,
+    caller: &Caller,
+    job_id: String,
+) -> Result<JobProgress, AppError> {
+    // the path carries no delegated identity, so only signed requests get through
+    let sender = caller.principal(None)?;
+
+    let job = app_state
+        .finalize_jobs
+        .get(&job_id)?
+        .ok_or_else(|| AppError::JobNotFound(job_id.clone()))?;
+
+    if job.creator_principal != sender {
+        return Err(AppError::Unauthorized(format!(
+            "The caller is not the creator of the post of job {}. Caller: {:?}, Post Creator: {:?}",
+            job_id, sender, job.creator_principal
+        )));
+    }
+
+    Ok(job)
+}
@@ -1,17 +1,29 @@
-use axum::{Json, extract::State};
-use ic_agent::{Identity, identity::DelegatedIdentity};
+use std::collections::HashMap;
+
+use axum::extract::State;
+use candid::Principal;
 use serde::{Deserialize, Serialize};
 use utoipa::{IntoParams, ToSchema};
 use yral_canisters_client::{
     ic::{USER_INFO_SERVICE_ID, USER_POST_SERVICE_ID},
-    user_post_service::{self, PostStatus, Result2},
+    user_post_service::{self, Post, PostStatus, Result2},
 };
 
 use crate::{
+    api::update_video_metadata::{CREATION_CONTEXT_KEY, MENTIONS_KEY},
     app_state::AppState,
     utils::{
-        notification_client::{self, NotificationType},
-        types::{ApiResponse, AppError, DelegatedIdentityWire, EmptyResp},
+        audit_log::{AuditAction, AuditEvent},
+        circuit_breaker::CircuitBreaker,
+        json_body::AppJson,
+        mentions::{self, ResolvedMention},
+        notification_client::NotificationType,
+        outbox::{Outbox, OutboxMessage},
+        publish_scheduler::PublishScheduler,
+        request_auth::Caller,
+        request_id::RequestId,
+        storj_interface::StorjInterface,
+        types::{ApiResponse, AppError, CreationContext, DelegatedIdentityWire, EmptyResp},
     },
 };
 
@@ -19,7 +31,9 @@ use crate::{
 pub struct MarkPostAsPublishedRequest {
     #[schema(example = "post-id-string")]
     pub post_id: String,
-    pub delegated_identity_wire: DelegatedIdentityWire,
+    /// Identifies the caller of a request that is not signed
+    #[serde(default)]
+    pub delegated_identity_wire: Option<DelegatedIdentityWire>,
 }
 
 /// Mark a post as published
@@ -33,87 +47,248 @@ pub struct MarkPostAsPublishedRequest {
 )]
 pub async fn mark_post_as_published(
     State(app_state): State<AppState>,
-    Json(payload): Json<MarkPostAsPublishedRequest>,
+    caller: Caller,
+    request_id: RequestId,
+    AppJson(payload): AppJson<MarkPostAsPublishedRequest>,
 ) -> ApiResponse<()> {
+    let post_id = payload.post_id.clone();
+    let mut status_before = None;
     let mark_post_as_published_res = mark_post_as_published_impl(
         &app_state.ic_admin_agent,
-        &app_state.notification_client,
-        &app_state.events_service,
+        &app_state.circuit_breakers.ic_agent,
+        &app_state.storj_client,
+        &app_state.outbox,
+        &app_state.publish_scheduler,
+        &caller,
         payload,
+        &mut status_before,
     )
     .await;
 
+    app_state.audit_log.record(
+        AuditEvent::new(
+            AuditAction::Publish,
+            Some(post_id),
+            caller.resolved_principal(),
+            Some(request_id.0),
+        )
+        .with_transition_from(status_before, "published")
+        .with_error(mark_post_as_published_res.as_ref().err()),
+    );
+
     ApiResponse::from(mark_post_as_published_res)
 }
 
+#[allow(clippy::too_many_arguments)]
 async fn mark_post_as_published_impl(
     ic_admin_agent: &ic_agent::Agent,
-    notification_client: &notification_client::NotificationClient,
-    event_service: &crate::utils::events_interface::EventService,
+    ic_agent_breaker: &CircuitBreaker,
+    storj_client: &StorjInterface,
+    outbox: &Outbox,
+    publish_scheduler: &PublishScheduler,
+    caller: &Caller,
     payload: MarkPostAsPublishedRequest,
+    status_before: &mut Option<String>,
 ) -> Result<(), AppError> {
-    let identity = DelegatedIdentity::try_from(payload.delegated_identity_wire)
-        .map_err(|e| AppError::InvalidDelegatedIdentity(e.to_string()))?;
+    let sender = caller.principal(payload.delegated_identity_wire.as_ref())?;
 
-    let user_post_service =
-        user_post_service::UserPostService(USER_POST_SERVICE_ID, ic_admin_agent);
+    let post_details =
+        fetch_post_details(ic_admin_agent, ic_agent_breaker, &payload.post_id).await?;
+    *status_before = Some(post_status_label(&post_details.status));
 
-    let post_details_res = user_post_service
-        .get_individual_post_details_by_id(payload.post_id.clone())
-        .await?;
+    ensure_post_creator(sender, &post_details)?;
 
-    let post_details = match post_details_res {
-        Result2::Ok(post) => post,
-        Result2::Err(user_post_service_error) => {
-            return Err(AppError::PostNotFound(format!(
-                "Error from user post service while fetching post details for post id {}: {:?}",
-                payload.post_id, user_post_service_error
-            )));
-        }
-    };
+    publish_post(
+        ic_admin_agent,
+        ic_agent_breaker,
+        storj_client,
+        outbox,
+        post_details,
+    )
+    .await?;
 
-    if identity
-        .sender()
-        .map_err(|e| AppError::InvalidDelegatedIdentity(e))?
-        != post_details.creator_principal
-    {
+    // a manual publish supersedes any pending schedule for the post
+    publish_scheduler.cancel_superseded(&payload.post_id);
+
+    Ok(())
+}
+
+pub(crate) fn ensure_post_creator(sender: Principal, post_details: &Post) -> Result<(), AppError> {
+    if sender != post_details.creator_principal {
         return Err(AppError::Unauthorized(format!(
             "The sender of the delegated identity is not the creator of the post. Sender: {:?}, Post Creator: {:?}",
-            identity
-                .sender()
-                .map_err(|e| AppError::InvalidDelegatedIdentity(e))?,
-            post_details.creator_principal
+            sender, post_details.creator_principal
         )));
     }
 
-    user_post_service
-        .update_post_status(payload.post_id.clone(), PostStatus::Uploaded)
+    Ok(())
+}
+
+/// Status of the post as recorded in the audit log, e.g. `draft`
+pub(crate) fn post_status_label(status: &PostStatus) -> String {
+    format!("{:?}", status).to_lowercase()
+}
+
+pub(crate) async fn fetch_post_details(
+    ic_admin_agent: &ic_agent::Agent,
+    ic_agent_breaker: &CircuitBreaker,
+    post_id: &str,
+) -> Result<Post, AppError> {
+    let user_post_service =
+        user_post_service::UserPostService(USER_POST_SERVICE_ID, ic_admin_agent);
+
+    let post_details_res = ic_agent_breaker
+        .call(async {
+            user_post_service
+                .get_individual_post_details_by_id(post_id.to_string())
+                .await
+                .map_err(AppError::from)
+        })
         .await?;
 
-    let _ = event_service
-        .send_video_upload_successful_event(
-            post_details.video_uid,
-            post_details.hashtags.len(),
-            false,
-            true,
-            post_details.id.clone(),
-            post_details.creator_principal,
-            USER_INFO_SERVICE_ID,
-            String::new(),
-            None,
-        )
-        .await
-        .inspect_err(|e| log::error!("error sending video upload successful event {e}"));
-
-    notification_client
-        .send_notification(
-            NotificationType::VideoPublished {
-                user_principal: post_details.creator_principal,
-                post_id: payload.post_id.clone(),
-            },
-            post_details.creator_principal,
-        )
-        .await;
+    match post_details_res {
+        Result2::Ok(post) => Ok(post),
+        Result2::Err(user_post_service_error) => Err(AppError::from_user_post_service(
+            user_post_service_error,
+            post_id,
+        )),
+    }
+}
+
+/// Moves the post out of drafts and announces it: published event, creator notification and
+/// notifications for every user mentioned in the description.
+pub(crate) async fn publish_post(
+    ic_admin_agent: &ic_agent::Agent,
+    ic_agent_breaker: &CircuitBreaker,
+    storj_client: &StorjInterface,
+    outbox: &Outbox,
+    post_details: Post,
+) -> Result<(), AppError> {
+    let user_post_service =
+        user_post_service::UserPostService(USER_POST_SERVICE_ID, ic_admin_agent);
+
+    ic_agent_breaker
+        .call(async {
+            user_post_service
+                .update_post_status(post_details.id.clone(), PostStatus::Uploaded)
+                .await
+                .map_err(AppError::from)
+        })
+        .await?;
+
+    let stored = stored_upload_details(
+        storj_client,
+        &post_details.id,
+        post_details.creator_principal,
+    )
+    .await;
+
+    outbox.enqueue(OutboxMessage::VideoUploadSuccessful {
+        video_uid: post_details.video_uid,
+        hashtags_len: post_details.hashtags.len(),
+        is_nsfw: false,
+        post_id: post_details.id.clone(),
+        user_principal: post_details.creator_principal,
+        canister_id: USER_INFO_SERVICE_ID,
+        user_name: String::new(),
+        creation_context: stored.creation_context,
+    });
+
+    outbox.enqueue_notification(
+        NotificationType::VideoPublished {
+            user_principal: post_details.creator_principal,
+            post_id: post_details.id.clone(),
+        },
+        post_details.creator_principal,
+    );
+
+    // mentions were resolved when the post was uploaded, as its description was then
+    mentions::notify_mentioned_users(
+        outbox,
+        &stored.mentions,
+        post_details.creator_principal,
+        &post_details.id,
+    );
 
     Ok(())
 }
+
+/// What the upload recorded in the Storj metadata for the publish to announce
+#[derive(Default)]
+struct StoredUploadDetails {
+    creation_context: CreationContext,
+    mentions: Vec<ResolvedMention>,
+}
+
+impl StoredUploadDetails {
+    /// Missing or unreadable entries fall back to their defaults
+    fn from_metadata(metadata: &HashMap<String, String>) -> Self {
+        Self {
+            creation_context: metadata
+                .get(CREATION_CONTEXT_KEY)
+                .and_then(|creation_context| serde_json::from_str(creation_context).ok())
+                .unwrap_or_default(),
+            mentions: metadata
+                .get(MENTIONS_KEY)
+                .and_then(|mentions| serde_json::from_str(mentions).ok())
+                .unwrap_or_default(),
+        }
+    }
+}
+
+async fn stored_upload_details(
+    storj_client: &StorjInterface,
+    post_id: &str,
+    creator_principal: Principal,
+) -> StoredUploadDetails {
+    match storj_client
+        .get_metadata(post_id, &creator_principal.to_text(), false)
+        .await
+    {
+        Ok(metadata) => StoredUploadDetails::from_metadata(&metadata),
+        Err(e) => {
+            log::warn!("Failed to fetch Storj metadata for post {}: {}", post_id, e);
+            StoredUploadDetails::default()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::types::CaptureSource;
+
+    #[test]
+    fn test_stored_upload_details_are_read_from_metadata() {
+        let mention = ResolvedMention {
+            username: "alice".to_string(),
+            principal: Principal::self_authenticating(b"alice"),
+        };
+        let metadata = HashMap::from([
+            (
+                CREATION_CONTEXT_KEY.to_string(),
+                r#"{"capture_source":"camera"}"#.to_string(),
+            ),
+            (
+                MENTIONS_KEY.to_string(),
+                serde_json::to_string(&[&mention]).unwrap(),
+            ),
+        ]);
+
+        let stored = StoredUploadDetails::from_metadata(&metadata);
+        assert_eq!(
+            stored.creation_context.capture_source,
+            Some(CaptureSource::Camera)
+        );
+        assert_eq!(stored.mentions, vec![mention]);
+    }
+
+    #[test]
+    fn test_missing_or_unreadable_details_fall_back_to_defaults() {
+        let metadata = HashMap::from([(MENTIONS_KEY.to_string(), "not json".to_string())]);
+
+        let stored = StoredUploadDetails::from_metadata(&metadata);
+        assert_eq!(stored.creation_context.capture_source, None);
+        assert!(stored.mentions.is_empty());
+    }
+}
@@ -1,4 +1,12 @@
+pub mod audit_log;
+pub mod blocklist;
+pub mod dead_letters;
+pub mod drafts;
 pub mod get_upload_url;
+pub mod jobs;
 pub mod mark_post_as_published;
+pub mod reconciliation;
+pub mod scheduled_posts;
 pub mod update_video_metadata;
+pub mod upload_status;
 pub use update_video_metadata::update_video_metadata;
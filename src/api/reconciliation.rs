@@ -0,0 +1,40 @@
+use axum::extract::State;
+
+use crate::{
+    app_state::AppState,
+    utils::{reconciler::ReconciliationReport, types::ApiResponse},
+};
+
+/// Reconcile recent upload sessions now. Waits for a run already in progress to finish first.
+#[utoipa::path(
+    post,
+    path = "/admin/reconcile",
+    responses(
+        (status = 200, description = "Report of the run", body = ApiResponse<ReconciliationReport>),
+        (status = 401, description = "Missing or invalid admin credentials"),
+        (status = 403, description = "Missing the replay admin scope")
+    )
+)]
+pub async fn run_reconciliation(
+    State(app_state): State<AppState>,
+) -> ApiResponse<ReconciliationReport> {
+    let result = app_state.reconciler.run(&app_state).await;
+
+    ApiResponse::from(result)
+}
+
+/// Get the report of the last reconciliation, `null` if none ran yet
+#[utoipa::path(
+    get,
+    path = "/admin/reconcile/latest",
+    responses(
+        (status = 200, description = "Report of the last run", body = ApiResponse<ReconciliationReport>),
+        (status = 401, description = "Missing or invalid admin credentials"),
+        (status = 403, description = "Missing the read admin scope")
+    )
+)]
+pub async fn latest_reconciliation_report(
+    State(app_state): State<AppState>,
+) -> ApiResponse<Option<ReconciliationReport>> {
+    ApiResponse::from(app_state.reconciler.latest_report())
+}
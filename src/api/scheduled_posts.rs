@@ -0,0 +1,183 @@
+use axum::extract::State;
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+use crate::{
+    app_state::AppState,
+    utils::{
+        audit_log::{AuditAction, AuditEvent},
+        json_body::AppJson,
+        publish_scheduler::{PublishScheduler, ScheduledPost},
+        request_auth::Caller,
+        request_id::RequestId,
+        types::{ApiResponse, AppError, DelegatedIdentityWire, EmptyResp},
+    },
+};
+
+#[derive(Serialize, Deserialize, Clone, Debug, ToSchema)]
+pub struct ListScheduledPostsRequest {
+    /// Identifies the caller of a request that is not signed
+    #[serde(default)]
+    pub delegated_identity_wire: Option<DelegatedIdentityWire>,
+}
+
+#[derive(Serialize, Deserialize, ToSchema)]
+pub struct ListScheduledPostsResp {
+    pub scheduled_posts: Vec<ScheduledPost>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, ToSchema)]
+pub struct ReschedulePostRequest {
+    #[schema(example = "post-id-string")]
+    pub post_id: String,
+    /// New unix timestamp in seconds at which the post is published
+    pub publish_at: u64,
+    /// Identifies the caller of a request that is not signed
+    #[serde(default)]
+    pub delegated_identity_wire: Option<DelegatedIdentityWire>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, ToSchema)]
+pub struct CancelScheduledPostRequest {
+    #[schema(example = "post-id-string")]
+    pub post_id: String,
+    /// Identifies the caller of a request that is not signed
+    #[serde(default)]
+    pub delegated_identity_wire: Option<DelegatedIdentityWire>,
+}
+
+/// List the caller's scheduled posts
+#[utoipa::path(
+    post,
+    path = "/list-scheduled-posts",
+    request_body = ListScheduledPostsRequest,
+    responses(
+        (status = 200, description = "Scheduled posts of the caller", body = ApiResponse<ListScheduledPostsResp>)
+    )
+)]
+pub async fn list_scheduled_posts(
+    State(app_state): State<AppState>,
+    caller: Caller,
+    AppJson(payload): AppJson<ListScheduledPostsRequest>,
+) -> ApiResponse<ListScheduledPostsResp> {
+    let result = caller
+        .principal(payload.delegated_identity_wire.as_ref())
+        .and_then(|creator_principal| {
+            app_state
+                .publish_scheduler
+                .list_for_creator(creator_principal)
+        })
+        .map(|scheduled_posts| ListScheduledPostsResp { scheduled_posts });
+
+    ApiResponse::from(result)
+}
+
+/// Move a scheduled post to a new publish time
+#[utoipa::path(
+    post,
+    path = "/reschedule-post",
+    request_body = ReschedulePostRequest,
+    responses(
+        (status = 200, description = "Post rescheduled", body = ApiResponse<ScheduledPost>)
+    )
+)]
+pub async fn reschedule_post(
+    State(app_state): State<AppState>,
+    caller: Caller,
+    request_id: RequestId,
+    AppJson(payload): AppJson<ReschedulePostRequest>,
+) -> ApiResponse<ScheduledPost> {
+    let scheduled_post = authorize_scheduled_post(
+        &app_state.publish_scheduler,
+        &caller,
+        payload.delegated_identity_wire.as_ref(),
+        &payload.post_id,
+    );
+    let publish_at_before = scheduled_post
+        .as_ref()
+        .ok()
+        .map(|scheduled_post| scheduled_post.publish_at);
+    let result = scheduled_post.and_then(|_| {
+        app_state
+            .publish_scheduler
+            .reschedule(&payload.post_id, payload.publish_at)
+    });
+
+    let mut event = AuditEvent::new(
+        AuditAction::Reschedule,
+        Some(payload.post_id),
+        caller.resolved_principal(),
+        Some(request_id.0),
+    )
+    .with_error(result.as_ref().err());
+    // a post that is not scheduled, or not the caller's, has no publish time to report
+    if let Some(publish_at_before) = publish_at_before {
+        event = event.with_transition(
+            format!("scheduled:{}", publish_at_before),
+            format!("scheduled:{}", payload.publish_at),
+        );
+    }
+    app_state.audit_log.record(event);
+
+    ApiResponse::from(result)
+}
+
+/// Cancel a scheduled publish, the post stays in drafts
+#[utoipa::path(
+    post,
+    path = "/cancel-scheduled-post",
+    request_body = CancelScheduledPostRequest,
+    responses(
+        (status = 200, description = "Scheduled publish cancelled", body = ApiResponse<EmptyResp>)
+    )
+)]
+pub async fn cancel_scheduled_post(
+    State(app_state): State<AppState>,
+    caller: Caller,
+    request_id: RequestId,
+    AppJson(payload): AppJson<CancelScheduledPostRequest>,
+) -> ApiResponse<()> {
+    let result = authorize_scheduled_post(
+        &app_state.publish_scheduler,
+        &caller,
+        payload.delegated_identity_wire.as_ref(),
+        &payload.post_id,
+    )
+    .and_then(|_| app_state.publish_scheduler.cancel(&payload.post_id))
+    .map(|_| ());
+
+    app_state.audit_log.record(
+        AuditEvent::new(
+            AuditAction::CancelScheduledPublish,
+            Some(payload.post_id),
+            caller.resolved_principal(),
+            Some(request_id.0),
+        )
+        .with_transition("scheduled", "draft")
+        .with_error(result.as_ref().err()),
+    );
+
+    ApiResponse::from(result)
+}
+
+fn authorize_scheduled_post(
+    publish_scheduler: &PublishScheduler,
+    caller: &Caller,
+    delegated_identity_wire: Option<&DelegatedIdentityWire>,
+    post_id: &str,
+) -> Result<ScheduledPost, AppError> {
+    let sender = caller.principal(delegated_identity_wire)?;
+
+    let scheduled_post = publish_scheduler.get(post_id)?.ok_or_else(|| {
+        AppError::PostNotFound(format!("No scheduled publish for post id {}", post_id))
+    })?;
+
+    if scheduled_post.creator_principal != sender {
+        return Err(AppError::Unauthorized(format!(
+            "The sender of the delegated identity is not the creator of the post. Sender: {:?}, Post Creator: {:?}",
+            sender, scheduled_post.creator_principal
+        )));
+    }
+
+    Ok(scheduled_post)
+}
@@ -1,8 +1,10 @@
 use std::collections::HashMap;
 
-use axum::{Json, extract::State};
-use ic_agent::{Identity, identity::DelegatedIdentity};
-use serde::Deserialize;
+use axum::{
+    extract::State,
+    response::{IntoResponse, Response},
+};
+use serde::{Deserialize, Serialize};
 use utoipa::{
     PartialSchema, ToSchema,
     openapi::{ArrayBuilder, ObjectBuilder},
@@ -15,16 +17,52 @@ use yral_canisters_client::{
 };
 
 use crate::{
+    api::mark_post_as_published::fetch_post_details,
     app_state::AppState,
     utils::{
-        events_interface::EventService,
-        notification_client::{NotificationClient, NotificationType},
-        storj_interface::StorjInterface,
-        types::{ApiResponse, AppError, DelegatedIdentityWire, EmptyResp, RequestPostDetails},
+        audit_log::{AuditAction, AuditEvent},
+        circuit_breaker::CircuitBreaker,
+        content_filter::ContentFilter,
+        dead_letters::{DeadLetterOperation, DeadLetters},
+        finalize_jobs::{JobStep, StepStatus},
+        json_body::AppJson,
+        mentions::{self, ResolvedMention},
+        notification_client::NotificationType,
+        outbox::{Outbox, OutboxMessage},
+        publish_scheduler::PublishScheduler,
+        request_auth::Caller,
+        request_id::RequestId,
+        time::now_unix_secs,
+        types::{
+            ApiResponse, AppError, CreationContext, DelegatedIdentityWire, EmptyResp,
+            RequestPostDetails,
+        },
+        upload_saga::{
+            ORPHANED_KEY, ORPHANED_REASON_KEY, UploadSagaState, UploadSagas, write_may_have_landed,
+        },
     },
 };
 
 pub static POST_DETAILS_KEY: &str = "post_details";
+pub static CONTENT_FILTER_MATCHES_KEY: &str = "content_filter_matches";
+pub static FLAGGED_FOR_REVIEW_KEY: &str = "flagged_for_review";
+pub static MENTIONS_KEY: &str = "mentions";
+pub static CREATION_CONTEXT_KEY: &str = "creation_context";
+
+/// Metadata keys written by the server, which clients must not set themselves
+const RESERVED_META_KEYS: [&str; 7] = [
+    POST_DETAILS_KEY,
+    CONTENT_FILTER_MATCHES_KEY,
+    FLAGGED_FOR_REVIEW_KEY,
+    MENTIONS_KEY,
+    CREATION_CONTEXT_KEY,
+    ORPHANED_KEY,
+    ORPHANED_REASON_KEY,
+];
+
+const MAX_META_KEYS: usize = 32;
+const MAX_META_KEY_LEN: usize = 64;
+const MAX_META_VALUE_LEN: usize = 4096;
 
 #[utoipa::path(
     post,
@@ -32,31 +70,137 @@ pub static POST_DETAILS_KEY: &str = "post_details";
     request_body = UpdateMetadataRequest,
     responses(
         (status = 200, description = "Metadata updated successfully", body = ApiResponse<EmptyResp>),
+        (status = 202, description = "Finalize job queued, only when `asynchronous` is set", body = ApiResponse<FinalizeJobAccepted>),
         (status = 401, description = "Unauthorized"),
         (status = 500, description = "Internal server error")
     )
 )]
 pub async fn update_video_metadata(
     State(app_state): State<AppState>,
-    Json(req): Json<UpdateMetadataRequest>,
-) -> ApiResponse<()> {
-    let result = update_metadata_impl(
-        &app_state.ic_admin_agent,
-        &app_state.storj_client,
-        &app_state.events_service,
-        &app_state.notification_client,
-        req,
-    )
-    .await;
+    caller: Caller,
+    request_id: RequestId,
+    AppJson(req): AppJson<UpdateMetadataRequest>,
+) -> Response {
+    if req.asynchronous {
+        let result = prepare_upload(&app_state, &caller, &request_id, req)
+            .await
+            .and_then(|upload| app_state.finalize_jobs.enqueue(upload))
+            .map(|job_id| FinalizeJobAccepted { job_id });
+
+        let mut response = ApiResponse::from(result);
+        if response.success {
+            response.status_code = 202;
+        }
+
+        return response.into_response();
+    }
+
+    let result = update_metadata_impl(&app_state, &caller, &request_id, req).await;
+
+    ApiResponse::from(result).into_response()
+}
+
+#[derive(Serialize, Deserialize, ToSchema)]
+pub struct FinalizeJobAccepted {
+    /// Progress is reported by a signed `GET /jobs/{job_id}` of the creator
+    #[schema(example = "job-id-string")]
+    pub job_id: String,
+}
+
+/// Upload whose request passed validation, ready for the Storj finalize and canister write
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct PreparedUpload {
+    pub publisher_user_id: String,
+    pub post_details: RequestPostDetails,
+    pub is_published: bool,
+    pub meta: HashMap<String, String>,
+    pub mentions: Vec<ResolvedMention>,
+    pub creation_context: CreationContext,
+    pub publish_at: Option<u64>,
+    /// `X-Request-Id` of the request that submitted the upload
+    #[serde(default)]
+    pub request_id: Option<String>,
+}
+
+impl PreparedUpload {
+    fn audit_event(&self, action: AuditAction) -> AuditEvent {
+        AuditEvent::new(
+            action,
+            Some(self.post_details.id.clone()),
+            Some(self.post_details.creator_principal.to_text()),
+            self.request_id.clone(),
+        )
+    }
+
+    fn canister_post_details(&self) -> PostDetailsFromFrontendV1 {
+        let mut post_details = PostDetailsFromFrontendV1::from(self.post_details.clone());
+        if self.is_published {
+            post_details.status = PostStatusFromFrontend::Published;
+        }
 
-    ApiResponse::from(result)
+        post_details
+    }
 }
 
 #[derive(Debug, Clone, Deserialize)]
 pub struct UpdateMetadataRequest {
-    pub delegated_identity_wire: DelegatedIdentityWire,
+    /// Identifies the caller of a request that is not signed
+    #[serde(default)]
+    pub delegated_identity_wire: Option<DelegatedIdentityWire>,
     pub meta: HashMap<String, String>,
     pub post_details: PostDetailsFromFrontendV1,
+    /// Unix timestamp in seconds at which the post should be published. The post is kept in
+    /// drafts until then.
+    #[serde(default)]
+    pub publish_at: Option<u64>,
+    #[serde(default)]
+    pub creation_context: Option<CreationContext>,
+    /// Queue the finalize as a job and answer with 202 and its id instead of waiting for it
+    #[serde(default)]
+    pub asynchronous: bool,
+}
+
+/// Bounds the client supplied `meta`, which ends up in the Storj object metadata
+fn validate_meta(meta: &HashMap<String, String>) -> Result<(), AppError> {
+    if meta.len() > MAX_META_KEYS {
+        return Err(AppError::InvalidRequest(format!(
+            "meta has {} keys, at most {} are accepted",
+            meta.len(),
+            MAX_META_KEYS
+        )));
+    }
+
+    if let Some(key) = meta
+        .keys()
+        .find(|key| key.is_empty() || key.len() > MAX_META_KEY_LEN)
+    {
+        return Err(AppError::InvalidRequest(format!(
+            "meta key {:?} must be between 1 and {} bytes",
+            key, MAX_META_KEY_LEN
+        )));
+    }
+
+    if let Some(key) = meta
+        .keys()
+        .find(|key| RESERVED_META_KEYS.contains(&key.as_str()))
+    {
+        return Err(AppError::InvalidRequest(format!(
+            "meta key {:?} is reserved for the server",
+            key
+        )));
+    }
+
+    if let Some(key) = meta
+        .iter()
+        .find_map(|(key, value)| (value.len() > MAX_META_VALUE_LEN).then_some(key))
+    {
+        return Err(AppError::InvalidRequest(format!(
+            "meta value of {:?} exceeds {} bytes",
+            key, MAX_META_VALUE_LEN
+        )));
+    }
+
+    Ok(())
 }
 
 impl ToSchema for UpdateMetadataRequest {
@@ -71,7 +215,13 @@ impl PartialSchema for UpdateMetadataRequest {
             .property("delegated_identity_wire", DelegatedIdentityWire::schema())
             .property(
                 "meta",
-                ObjectBuilder::new().schema_type(utoipa::openapi::schema::Type::Object),
+                ObjectBuilder::new()
+                    .schema_type(utoipa::openapi::schema::Type::Object)
+                    .description(
+                        "At most 32 keys of up to 64 bytes, each value up to 4096 bytes. Keys the \
+                         server writes, such as `post_details` or `mentions`, are rejected"
+                            .into(),
+                    ),
             )
             .property(
                 "post_details",
@@ -101,134 +251,605 @@ impl PartialSchema for UpdateMetadataRequest {
                         ArrayBuilder::new().schema_type(utoipa::openapi::schema::Type::String),
                     ),
             )
+            .property(
+                "publish_at",
+                ObjectBuilder::new()
+                    .schema_type(utoipa::openapi::schema::Type::Integer)
+                    .description(
+                        "Optional unix timestamp in seconds at which the post is published".into(),
+                    ),
+            )
+            .property("creation_context", CreationContext::schema())
+            .property(
+                "asynchronous",
+                ObjectBuilder::new()
+                    .schema_type(utoipa::openapi::schema::Type::Boolean)
+                    .description(
+                        "Queue the finalize as a job and answer with 202 and its id".into(),
+                    ),
+            )
             .into()
     }
 }
 
 async fn update_metadata_impl(
-    ic_admin_agent: &ic_agent::Agent,
-    storj_interface: &StorjInterface,
-    events_service: &EventService,
-    notification_client: &NotificationClient,
-    mut req_data: UpdateMetadataRequest,
+    app_state: &AppState,
+    caller: &Caller,
+    request_id: &RequestId,
+    req_data: UpdateMetadataRequest,
 ) -> Result<(), AppError> {
-    let delegated_identity = DelegatedIdentity::try_from(req_data.delegated_identity_wire.clone())
-        .map_err(|e| AppError::InvalidDelegatedIdentity(e.to_string()))?;
+    let upload = prepare_upload(app_state, caller, request_id, req_data).await?;
 
-    //TODO: we not using delegated identity for storj upload or canister upload we could get away with a signature that is signed by this Delegated Identity.
+    // detached from the request, so that the request deadline or a client disconnect cannot
+    // drop the pipeline between the Storj finalize and the canister write, skipping compensation
+    let app_state = app_state.clone();
+    tokio::spawn(async move { run_upload_pipeline(&app_state, &upload, |_, _, _| {}).await })
+        .await
+        .map_err(|e| AppError::InternalError(format!("Upload pipeline task failed: {}", e)))?
+}
 
-    let publisher_user_id = delegated_identity
-        .sender()
-        .map_err(|e| AppError::InvalidDelegatedIdentity(e))?
+/// Authorizes and validates the request and builds the Storj metadata of the upload.
+async fn prepare_upload(
+    app_state: &AppState,
+    caller: &Caller,
+    request_id: &RequestId,
+    mut req_data: UpdateMetadataRequest,
+) -> Result<PreparedUpload, AppError> {
+    let publisher_user_id = caller
+        .principal(req_data.delegated_identity_wire.as_ref())?
         .to_text();
 
+    validate_meta(&req_data.meta)?;
+
     if !publisher_user_id.eq(&req_data.post_details.creator_principal.to_text()) {
         return Err(AppError::Unauthorized(
             "Publisher user id does not match creator principal in post details".to_string(),
         ));
     }
 
+    if let Some(publish_at) = req_data.publish_at {
+        PublishScheduler::validate_publish_at(publish_at)?;
+        req_data.post_details.status = PostStatusFromFrontend::Draft;
+    }
+
+    let creation_context = req_data.creation_context.clone().unwrap_or_default();
+    creation_context.validate()?;
+    req_data.meta.insert(
+        CREATION_CONTEXT_KEY.to_string(),
+        serde_json::to_string(&creation_context)?,
+    );
+
+    screen_post_content(
+        &app_state.content_filter,
+        &app_state.outbox,
+        &mut req_data.post_details,
+        &mut req_data.meta,
+        &creation_context,
+    )
+    .await?;
+
+    let mentions = mentions::resolve_mentions(
+        &app_state.ic_admin_agent,
+        &app_state.circuit_breakers.ic_agent,
+        mentions::parse_mentions(&req_data.post_details.description),
+    )
+    .await;
+
+    if !mentions.is_empty() {
+        req_data
+            .meta
+            .insert(MENTIONS_KEY.to_string(), serde_json::to_string(&mentions)?);
+    }
+
+    let post_details = RequestPostDetails::from(req_data.post_details.clone());
     req_data.meta.insert(
         POST_DETAILS_KEY.to_string(),
-        serde_json::to_string(&Into::<RequestPostDetails>::into(
-            req_data.post_details.clone(),
-        ))?,
+        serde_json::to_string(&post_details)?,
     );
 
-    // Finalize Storj upload with metadata (without delegated-identity)
-    storj_interface
+    Ok(PreparedUpload {
+        publisher_user_id,
+        post_details,
+        is_published: matches!(
+            req_data.post_details.status,
+            PostStatusFromFrontend::Published
+        ),
+        meta: req_data.meta,
+        mentions,
+        creation_context,
+        publish_at: req_data.publish_at,
+        request_id: Some(request_id.0.clone()),
+    })
+}
+
+/// Finalizes the Storj upload, writes the post to the canister and schedules its publish,
+/// reporting the outcome of every step to `report_step`.
+pub(crate) async fn run_upload_pipeline(
+    app_state: &AppState,
+    upload: &PreparedUpload,
+    report_step: impl FnMut(JobStep, StepStatus, Option<&AppError>),
+) -> Result<(), AppError> {
+    app_state.upload_sagas.begin(
+        &upload.post_details.id,
+        upload.post_details.creator_principal,
+    )?;
+
+    continue_upload_pipeline(
+        app_state,
+        upload,
+        UploadSagaState::FinalizingStorj,
+        report_step,
+    )
+    .await
+}
+
+/// Picks the pipeline of an upload interrupted by a restart up again from the step its saga
+/// reached. A canister write that landed before the restart is found by the duplicate check of
+/// the compensation.
+pub(crate) async fn resume_upload_pipeline(
+    app_state: &AppState,
+    upload: &PreparedUpload,
+    report_step: impl FnMut(JobStep, StepStatus, Option<&AppError>),
+) -> Result<(), AppError> {
+    let post_id = &upload.post_details.id;
+
+    match app_state.upload_sagas.get(post_id)?.map(|saga| saga.state) {
+        None | Some(UploadSagaState::Failed) => {
+            run_upload_pipeline(app_state, upload, report_step).await
+        }
+        Some(
+            state @ (UploadSagaState::FinalizingStorj
+            | UploadSagaState::StorjFinalized
+            | UploadSagaState::Completed),
+        ) => continue_upload_pipeline(app_state, upload, state, report_step).await,
+        Some(state) => Err(AppError::InvalidRequest(format!(
+            "The upload of post {} already ended in {:?}",
+            post_id, state
+        ))),
+    }
+}
+
+/// Runs the steps of the pipeline from the saga state `from` on
+async fn continue_upload_pipeline(
+    app_state: &AppState,
+    upload: &PreparedUpload,
+    from: UploadSagaState,
+    mut report_step: impl FnMut(JobStep, StepStatus, Option<&AppError>),
+) -> Result<(), AppError> {
+    if from == UploadSagaState::FinalizingStorj {
+        finalize_storj_step(app_state, upload, &mut report_step).await?;
+    } else {
+        report_step(JobStep::FinalizeStorj, StepStatus::Succeeded, None);
+    }
+
+    if from == UploadSagaState::Completed {
+        report_step(JobStep::WriteCanister, StepStatus::Succeeded, None);
+    } else {
+        write_canister_step(app_state, upload, &mut report_step).await?;
+    }
+
+    match upload.publish_at {
+        Some(publish_at) => {
+            if let Err(e) = app_state.publish_scheduler.schedule(
+                upload.post_details.id.clone(),
+                upload.post_details.creator_principal,
+                publish_at,
+            ) {
+                report_step(JobStep::SchedulePublish, StepStatus::Failed, Some(&e));
+                return Err(e);
+            }
+            report_step(JobStep::SchedulePublish, StepStatus::Succeeded, None);
+        }
+        None => report_step(JobStep::SchedulePublish, StepStatus::Skipped, None),
+    }
+
+    Ok(())
+}
+
+/// Finalizes the Storj upload with the metadata, without the delegated identity
+async fn finalize_storj_step(
+    app_state: &AppState,
+    upload: &PreparedUpload,
+    report_step: &mut impl FnMut(JobStep, StepStatus, Option<&AppError>),
+) -> Result<(), AppError> {
+    let upload_sagas = &app_state.upload_sagas;
+    let post_id = upload.post_details.id.clone();
+
+    report_step(JobStep::FinalizeStorj, StepStatus::Running, None);
+    if let Err(e) = app_state
+        .storj_client
         .finalize_upload(
-            &req_data.post_details.id,
-            &publisher_user_id,
+            &post_id,
+            &upload.publisher_user_id,
             false,
-            req_data.meta.clone(),
+            upload.meta.clone(),
         )
         .await
-        .map_err(|e| AppError::StorageError(e.to_string()))?;
+    {
+        let error = AppError::StorageError(e.to_string());
+        upload_sagas.advance(&post_id, UploadSagaState::Failed, Some(&error));
+        app_state.audit_log.record(
+            upload
+                .audit_event(AuditAction::FinalizeUpload)
+                .with_transition("finalizing_storj", "storj_finalized")
+                .with_error(Some(&error)),
+        );
+        report_step(JobStep::FinalizeStorj, StepStatus::Failed, Some(&error));
+        return Err(error);
+    }
+    upload_sagas.advance(&post_id, UploadSagaState::StorjFinalized, None);
+    app_state.audit_log.record(
+        upload
+            .audit_event(AuditAction::FinalizeUpload)
+            .with_transition("finalizing_storj", "storj_finalized"),
+    );
+    report_step(JobStep::FinalizeStorj, StepStatus::Succeeded, None);
+
+    Ok(())
+}
+
+/// Writes the post to the canister, compensating the Storj finalize when that fails for good
+async fn write_canister_step(
+    app_state: &AppState,
+    upload: &PreparedUpload,
+    report_step: &mut impl FnMut(JobStep, StepStatus, Option<&AppError>),
+) -> Result<(), AppError> {
+    let upload_sagas = &app_state.upload_sagas;
+    let post_id = upload.post_details.id.clone();
 
-    upload_video_canister(
-        ic_admin_agent,
-        events_service,
-        notification_client,
-        req_data.post_details.clone(),
+    report_step(JobStep::WriteCanister, StepStatus::Running, None);
+    let post_details = upload.canister_post_details();
+    if let Err(e) = upload_video_canister(
+        &app_state.ic_admin_agent,
+        &app_state.circuit_breakers.ic_agent,
+        upload_sagas,
+        post_details.clone(),
     )
-    .await?;
+    .await
+    {
+        let written = upload_sagas
+            .compensate(
+                &app_state.storj_client,
+                &post_id,
+                &upload.publisher_user_id,
+                upload.meta.clone(),
+                &e,
+                || post_exists(app_state, &post_id),
+            )
+            .await;
+
+        if !written {
+            app_state.audit_log.record(
+                upload
+                    .audit_event(AuditAction::WriteCanister)
+                    .with_transition("storj_finalized", "completed")
+                    .with_error(Some(&e)),
+            );
+            announce_failed_upload(
+                &app_state.outbox,
+                &post_details,
+                &upload.creation_context,
+                &e,
+            );
+
+            if DeadLetters::should_record(&e) {
+                let attempts = upload_sagas
+                    .get(&post_id)
+                    .ok()
+                    .flatten()
+                    .map_or(1, |saga| saga.canister_attempts);
+                app_state.dead_letters.record(
+                    DeadLetterOperation::CanisterWrite {
+                        upload: upload.clone(),
+                    },
+                    &e.to_string(),
+                    attempts,
+                );
+            }
+
+            report_step(JobStep::WriteCanister, StepStatus::Failed, Some(&e));
+            return Err(e);
+        }
+    }
+    upload_sagas.advance(&post_id, UploadSagaState::Completed, None);
+    app_state.audit_log.record(
+        upload
+            .audit_event(AuditAction::WriteCanister)
+            .with_transition("storj_finalized", "completed"),
+    );
+    announce_upload(
+        &app_state.outbox,
+        &post_details,
+        &upload.mentions,
+        &upload.creation_context,
+    );
+    report_step(JobStep::WriteCanister, StepStatus::Succeeded, None);
 
     Ok(())
 }
 
-async fn upload_video_canister(
+/// Writes the post of a dead-lettered upload to the canister again and restores the Storj
+/// metadata its compensation marked as orphaned.
+pub(crate) async fn replay_canister_write(
+    app_state: &AppState,
+    upload: &PreparedUpload,
+) -> Result<(), AppError> {
+    let upload_sagas = &app_state.upload_sagas;
+    let post_id = upload.post_details.id.clone();
+
+    if upload_sagas
+        .get(&post_id)?
+        .is_some_and(|saga| saga.state == UploadSagaState::Deleted)
+    {
+        return Err(AppError::InvalidRequest(format!(
+            "The video of post {} was already deleted from Storj",
+            post_id
+        )));
+    }
+
+    let post_details = upload.canister_post_details();
+    let write_result = match upload_video_canister(
+        &app_state.ic_admin_agent,
+        &app_state.circuit_breakers.ic_agent,
+        upload_sagas,
+        post_details.clone(),
+    )
+    .await
+    {
+        Err(e) if write_may_have_landed(&e) => {
+            if post_exists(app_state, &post_id).await? {
+                log::warn!(
+                    "Replayed canister write for post {} failed with \"{}\" but the post exists",
+                    post_id,
+                    e
+                );
+                Ok(())
+            } else {
+                Err(e)
+            }
+        }
+        result => result,
+    };
+    app_state.audit_log.record(
+        upload
+            .audit_event(AuditAction::WriteCanister)
+            .with_error(write_result.as_ref().err()),
+    );
+    write_result?;
+    upload_sagas.advance(&post_id, UploadSagaState::Completed, None);
+    announce_upload(
+        &app_state.outbox,
+        &post_details,
+        &upload.mentions,
+        &upload.creation_context,
+    );
+
+    if let Err(e) = app_state
+        .storj_client
+        .set_metadata(
+            &post_id,
+            &upload.publisher_user_id,
+            false,
+            upload.meta.clone(),
+        )
+        .await
+    {
+        log::error!(
+            "Failed to restore Storj metadata of replayed post {}: {}",
+            post_id,
+            e
+        );
+    }
+
+    if let Some(publish_at) = upload.publish_at {
+        // a publish time that passed while the upload was dead-lettered publishes on the next poll
+        app_state.publish_scheduler.schedule(
+            post_id,
+            upload.post_details.creator_principal,
+            publish_at.max(now_unix_secs() + 1),
+        )?;
+    }
+
+    Ok(())
+}
+
+/// Runs the blocked-terms filter over the post text. Masked terms are rewritten in place and
+/// every matched rule is recorded in `meta` so it ends up in the Storj object metadata.
+pub(crate) async fn screen_post_content(
+    content_filter: &ContentFilter,
+    outbox: &Outbox,
+    post_details: &mut PostDetailsFromFrontendV1,
+    meta: &mut HashMap<String, String>,
+    creation_context: &CreationContext,
+) -> Result<(), AppError> {
+    let filter_outcome = content_filter.apply(&post_details.description, &post_details.hashtags);
+
+    if let Some(rejection) = filter_outcome.rejected_by() {
+        outbox.enqueue(OutboxMessage::VideoUploadUnsuccessful {
+            error: format!("Content rejected by filter rule {}", rejection.rule_id),
+            hashtags_len: post_details.hashtags.len(),
+            is_nsfw: false,
+            enable_hot_or_not: creation_context.enable_hot_or_not(),
+            user_principal: post_details.creator_principal,
+            user_name: String::new(),
+            user_canister: USER_INFO_SERVICE_ID,
+            matched_filter_rule: Some(rejection.rule_id.clone()),
+        });
+
+        return Err(AppError::ContentRejected(rejection.rule_id.clone()));
+    }
+
+    if !filter_outcome.matches.is_empty() {
+        meta.insert(
+            CONTENT_FILTER_MATCHES_KEY.to_string(),
+            serde_json::to_string(&filter_outcome.matches)?,
+        );
+    }
+
+    if filter_outcome.is_flagged() {
+        meta.insert(FLAGGED_FOR_REVIEW_KEY.to_string(), true.to_string());
+    }
+
+    post_details.description = filter_outcome.description;
+    post_details.hashtags = filter_outcome.hashtags;
+
+    Ok(())
+}
+
+pub(crate) async fn upload_video_canister(
     ic_admin_agent: &ic_agent::Agent,
-    events_service: &EventService,
-    notification_client: &NotificationClient,
+    ic_agent_breaker: &CircuitBreaker,
+    upload_sagas: &UploadSagas,
     post_details: PostDetailsFromFrontendV1,
 ) -> Result<(), AppError> {
     let user_post_service_canister = UserPostService(USER_POST_SERVICE_ID, ic_admin_agent);
 
-    let post_is_published = matches!(post_details.status, PostStatusFromFrontend::Published);
-
-    let upload_to_canister_res = user_post_service_canister
-        .add_post_v_1(post_details.clone())
+    let upload_to_canister_res = upload_sagas
+        .write_to_canister(&post_details.id, || {
+            ic_agent_breaker.call(async {
+                user_post_service_canister
+                    .add_post_v_1(post_details.clone())
+                    .await
+                    .map_err(AppError::from)
+            })
+        })
         .await?;
 
     match upload_to_canister_res {
-        Result_::Ok => {
-            if post_is_published {
-                let _ = events_service
-                    .send_video_upload_successful_event(
-                        post_details.video_uid,
-                        post_details.hashtags.len(),
-                        false,
-                        true,
-                        post_details.id.clone(),
-                        post_details.creator_principal,
-                        USER_INFO_SERVICE_ID.into(),
-                        String::new(),
-                        None,
-                    )
-                    .await
-                    .inspect_err(|e| {
-                        log::error!("Failed to send video_upload_successful event: {}", e);
-                    });
-            }
+        Result_::Ok => Ok(()),
+        Result_::Err(user_post_service_error) => Err(AppError::from_user_post_service(
+            user_post_service_error,
+            &post_details.id,
+        )),
+    }
+}
 
-            let notification_payload = if post_is_published {
-                NotificationType::VideoPublished {
-                    user_principal: post_details.creator_principal,
-                    post_id: post_details.id.clone(),
-                }
-            } else {
-                NotificationType::VideoUploadedToDraft {
-                    user_principal: post_details.creator_principal,
-                    post_id: post_details.id.clone(),
-                }
-            };
+/// Whether the post is in the canister, used to tell if a failed write landed after all
+pub(crate) async fn post_exists(app_state: &AppState, post_id: &str) -> Result<bool, AppError> {
+    match fetch_post_details(
+        &app_state.ic_admin_agent,
+        &app_state.circuit_breakers.ic_agent,
+        post_id,
+    )
+    .await
+    {
+        Ok(_) => Ok(true),
+        Err(AppError::PostNotFound(_)) => Ok(false),
+        Err(e) => Err(e),
+    }
+}
 
-            notification_client
-                .send_notification(notification_payload, post_details.creator_principal)
-                .await;
+/// Enqueues the upload event and the notifications of a post written to the canister
+fn announce_upload(
+    outbox: &Outbox,
+    post_details: &PostDetailsFromFrontendV1,
+    mentions: &[ResolvedMention],
+    creation_context: &CreationContext,
+) {
+    let post_is_published = matches!(post_details.status, PostStatusFromFrontend::Published);
 
-            Ok(())
+    if post_is_published {
+        outbox.enqueue(OutboxMessage::VideoUploadSuccessful {
+            video_uid: post_details.video_uid.clone(),
+            hashtags_len: post_details.hashtags.len(),
+            is_nsfw: false,
+            post_id: post_details.id.clone(),
+            user_principal: post_details.creator_principal,
+            canister_id: USER_INFO_SERVICE_ID,
+            user_name: String::new(),
+            creation_context: creation_context.clone(),
+        });
+    }
+
+    let notification_payload = if post_is_published {
+        NotificationType::VideoPublished {
+            user_principal: post_details.creator_principal,
+            post_id: post_details.id.clone(),
+        }
+    } else {
+        NotificationType::VideoUploadedToDraft {
+            user_principal: post_details.creator_principal,
+            post_id: post_details.id.clone(),
         }
-        Result_::Err(user_post_service_error) => {
-            let error = format!("{:?}", user_post_service_error);
-
-            let _ = events_service
-                .send_video_event_unsuccessful(
-                    error.clone(),
-                    post_details.hashtags.len(),
-                    false,
-                    true,
-                    post_details.creator_principal,
-                    String::new(),
-                    USER_INFO_SERVICE_ID.into(),
-                )
-                .await
-                .inspect_err(|e| {
-                    log::error!("Failed to send video_event_unsuccessful event: {}", e)
-                });
-
-            Err(AppError::CanisterError(error))
+    };
+
+    outbox.enqueue_notification(notification_payload, post_details.creator_principal);
+
+    if post_is_published {
+        mentions::notify_mentioned_users(
+            outbox,
+            mentions,
+            post_details.creator_principal,
+            &post_details.id,
+        );
+    }
+}
+
+fn announce_failed_upload(
+    outbox: &Outbox,
+    post_details: &PostDetailsFromFrontendV1,
+    creation_context: &CreationContext,
+    error: &AppError,
+) {
+    outbox.enqueue(OutboxMessage::VideoUploadUnsuccessful {
+        error: error.to_string(),
+        hashtags_len: post_details.hashtags.len(),
+        is_nsfw: false,
+        enable_hot_or_not: creation_context.enable_hot_or_not(),
+        user_principal: post_details.creator_principal,
+        user_name: String::new(),
+        user_canister: USER_INFO_SERVICE_ID,
+        matched_filter_rule: None,
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn meta(entries: impl IntoIterator<Item = (String, String)>) -> HashMap<String, String> {
+        entries.into_iter().collect()
+    }
+
+    #[test]
+    fn test_meta_bounds() {
+        let cases = [
+            (meta([]), true),
+            (
+                meta((0..MAX_META_KEYS).map(|i| (i.to_string(), String::new()))),
+                true,
+            ),
+            (
+                meta((0..=MAX_META_KEYS).map(|i| (i.to_string(), String::new()))),
+                false,
+            ),
+            (meta([(String::new(), "value".to_string())]), false),
+            (meta([("k".repeat(MAX_META_KEY_LEN), String::new())]), true),
+            (
+                meta([("k".repeat(MAX_META_KEY_LEN + 1), String::new())]),
+                false,
+            ),
+            (
+                meta([("key".to_string(), "v".repeat(MAX_META_VALUE_LEN))]),
+                true,
+            ),
+            (
+                meta([("key".to_string(), "v".repeat(MAX_META_VALUE_LEN + 1))]),
+                false,
+            ),
+            (meta([(MENTIONS_KEY.to_string(), "[]".to_string())]), false),
+            (
+                meta([(ORPHANED_KEY.to_string(), "false".to_string())]),
+                false,
+            ),
+        ];
+
+        for (meta, valid) in cases {
+            let result = validate_meta(&meta);
+            assert_eq!(result.is_ok(), valid, "{} keys: {:?}", meta.len(), result);
+            if !valid {
+                assert!(matches!(result, Err(AppError::InvalidRequest(_))));
+            }
         }
     }
 }
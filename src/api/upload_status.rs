@@ -0,0 +1,65 @@
+use axum::extract::State;
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+use crate::{
+    app_state::AppState,
+    utils::{
+        json_body::AppJson,
+        request_auth::Caller,
+        types::{ApiResponse, AppError, DelegatedIdentityWire},
+        upload_saga::{UploadSaga, UploadSagas},
+    },
+};
+
+#[derive(Serialize, Deserialize, Clone, Debug, ToSchema)]
+pub struct UploadStatusRequest {
+    #[schema(example = "post-id-string")]
+    pub post_id: String,
+    /// Identifies the caller of a request that is not signed
+    #[serde(default)]
+    pub delegated_identity_wire: Option<DelegatedIdentityWire>,
+}
+
+/// Get the state of a post's upload, including any compensation of a failed canister write
+#[utoipa::path(
+    post,
+    path = "/upload-status",
+    request_body = UploadStatusRequest,
+    responses(
+        (status = 200, description = "Upload state of the post", body = ApiResponse<UploadSaga>)
+    )
+)]
+pub async fn upload_status(
+    State(app_state): State<AppState>,
+    caller: Caller,
+    AppJson(payload): AppJson<UploadStatusRequest>,
+) -> ApiResponse<UploadSaga> {
+    let result = upload_status_impl(&app_state.upload_sagas, &caller, payload);
+
+    ApiResponse::from(result)
+}
+
+fn upload_status_impl(
+    upload_sagas: &UploadSagas,
+    caller: &Caller,
+    payload: UploadStatusRequest,
+) -> Result<UploadSaga, AppError> {
+    let sender = caller.principal(payload.delegated_identity_wire.as_ref())?;
+
+    let upload_saga = upload_sagas.get(&payload.post_id)?.ok_or_else(|| {
+        AppError::PostNotFound(format!(
+            "No upload recorded for post id {}",
+            payload.post_id
+        ))
+    })?;
+
+    if upload_saga.creator_principal != sender {
+        return Err(AppError::Unauthorized(format!(
+            "The sender of the delegated identity is not the creator of the post. Sender: {:?}, Post Creator: {:?}",
+            sender, upload_saga.creator_principal
+        )));
+    }
+
+    Ok(upload_saga)
+}
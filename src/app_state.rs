@@ -3,8 +3,12 @@ use std::sync::Arc;
 use ic_agent::Agent;
 
 use crate::utils::{
-    events_interface::EventService, notification_client::NotificationClient,
-    storj_interface::StorjInterface,
+    audit_log::AuditLog, blocklist::Blocklist, circuit_breaker::CircuitBreakers,
+    content_filter::ContentFilter, dead_letters::DeadLetters, delegation::DelegationPolicy,
+    events_interface::EventService, finalize_jobs::FinalizeJobs, idempotency::IdempotencyStore,
+    notification_client::NotificationClient, outbox::Outbox, publish_scheduler::PublishScheduler,
+    reconciler::Reconciler, request_auth::IdentityWirePolicy, storj_interface::StorjInterface,
+    upload_saga::UploadSagas, upload_sessions::UploadSessions,
 };
 
 #[derive(Clone)]
@@ -13,4 +17,18 @@ pub struct AppState {
     pub ic_admin_agent: Agent,
     pub events_service: EventService,
     pub notification_client: NotificationClient,
+    pub content_filter: ContentFilter,
+    pub publish_scheduler: PublishScheduler,
+    pub outbox: Outbox,
+    pub upload_sagas: UploadSagas,
+    pub idempotency_store: IdempotencyStore,
+    pub circuit_breakers: CircuitBreakers,
+    pub finalize_jobs: FinalizeJobs,
+    pub upload_sessions: UploadSessions,
+    pub reconciler: Reconciler,
+    pub dead_letters: DeadLetters,
+    pub delegation_policy: Arc<DelegationPolicy>,
+    pub identity_wire_policy: IdentityWirePolicy,
+    pub blocklist: Blocklist,
+    pub audit_log: AuditLog,
 }
@@ -0,0 +1,421 @@
+use std::{collections::HashSet, path::PathBuf, time::Duration};
+
+use candid::Principal;
+use yral_canisters_client::ic::{USER_INFO_SERVICE_ID, USER_POST_SERVICE_ID};
+
+use crate::utils::{
+    admin_auth::{AdminAuthConfig, AdminScope, AdminToken},
+    bulkhead::BulkheadConfig,
+    circuit_breaker::BreakerConfig,
+    cors::CorsConfig,
+    delegation::DelegationPolicy,
+    request_auth::IdentityWirePolicy,
+    retry::RetryPolicy,
+    storj_interface::LinkshareConfig,
+    upload_saga::CompensationAction,
+};
+
+/// Timeouts applied to every call to one downstream service
+#[derive(Clone, Copy, Debug)]
+pub struct TimeoutConfig {
+    pub connect: Duration,
+    /// Upper bound on a whole call, from connecting until the response is read
+    pub total: Duration,
+}
+
+impl TimeoutConfig {
+    pub fn client_builder(&self) -> reqwest::ClientBuilder {
+        reqwest::Client::builder()
+            .connect_timeout(self.connect)
+            .timeout(self.total)
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct AppConfig {
+    /// Directory of the embedded store holding the service's local state
+    pub data_dir: PathBuf,
+    /// JSON file holding the blocked-terms rules, `None` disables the filter
+    pub content_filter_rules_path: Option<PathBuf>,
+    /// How often the rules file is checked for changes
+    pub content_filter_reload_interval: Duration,
+    /// How often the scheduler looks for posts that are due to be published
+    pub scheduled_publish_poll_interval: Duration,
+    /// Upper bound on how long a retried outbox entry waits for the dispatcher
+    pub outbox_poll_interval: Duration,
+    /// Delivery attempts before an outbox entry is moved to dead letter
+    pub outbox_max_attempts: u32,
+    /// Retry policy for calls to Storj and the Cloudflare video download
+    pub storj_retry_policy: RetryPolicy,
+    /// Retry policy for writing a post to the canister after its video was finalized in Storj
+    pub canister_write_retry_policy: RetryPolicy,
+    /// What happens to the Storj object when that canister write fails for good
+    pub upload_compensation_action: CompensationAction,
+    /// How long the saga of a settled upload is kept, it should outlast the reconcile lookback
+    pub upload_saga_ttl: Duration,
+    /// How long the response to a request with an `Idempotency-Key` is replayed
+    pub idempotency_key_ttl: Duration,
+    pub events_breaker: BreakerConfig,
+    pub notifications_breaker: BreakerConfig,
+    pub storj_breaker: BreakerConfig,
+    pub ic_agent_breaker: BreakerConfig,
+    pub events_bulkhead: BulkheadConfig,
+    pub notifications_bulkhead: BulkheadConfig,
+    pub storj_bulkhead: BulkheadConfig,
+    pub ic_agent_bulkhead: BulkheadConfig,
+    pub events_timeouts: TimeoutConfig,
+    pub notifications_timeouts: TimeoutConfig,
+    /// Covers a whole video upload or download, so it is much longer than the others
+    pub storj_timeouts: TimeoutConfig,
+    /// Public read URLs of the Storj buckets, videos are downloaded from them to be copied
+    pub storj_linkshare: LinkshareConfig,
+    pub ic_agent_timeouts: TimeoutConfig,
+    /// Overall deadline of a request, after which it fails with a 504. The upload pipeline of
+    /// update-video-metadata carries on past it, its outcome is reported by upload-status.
+    pub request_deadline: Duration,
+    /// Workers executing asynchronous finalize jobs
+    pub finalize_workers: usize,
+    /// Finalize jobs waiting for a worker, further jobs are refused with a 503
+    pub finalize_queue_capacity: usize,
+    /// How long a finished finalize job can still be looked up
+    pub finalize_job_ttl: Duration,
+    /// How often upload sessions are reconciled against the canister and Storj
+    pub reconcile_interval: Duration,
+    /// Upload sessions older than this are no longer reconciled
+    pub reconcile_lookback: Duration,
+    /// Upload sessions younger than this are left alone as they may still be in progress
+    pub reconcile_grace_period: Duration,
+    /// Credentials of the `/admin` endpoints, none disables them
+    pub admin_auth: AdminAuthConfig,
+    /// Checks a client's delegation chain has to pass before its principal is trusted
+    pub delegation_policy: DelegationPolicy,
+    /// How far the timestamp of a signed request may be from the server time, its nonce is
+    /// remembered for as long
+    pub signed_request_clock_skew: Duration,
+    /// Whether and until when unsigned requests may carry a `delegated_identity_wire`
+    pub identity_wire_policy: IdentityWirePolicy,
+    /// Browser origins allowed to call the service
+    pub cors: CorsConfig,
+    /// Largest request body accepted on any route
+    pub max_request_body_bytes: usize,
+    /// Largest body of an update-video-metadata request
+    pub update_metadata_max_body_bytes: usize,
+    /// Largest body of the other user routes, which only carry ids and a delegated identity
+    pub user_request_max_body_bytes: usize,
+}
+
+impl AppConfig {
+    pub fn from_env() -> Self {
+        Self {
+            data_dir: PathBuf::from(env_or("DATA_DIR", "data".to_string())),
+            content_filter_rules_path: std::env::var("CONTENT_FILTER_RULES_PATH")
+                .ok()
+                .map(PathBuf::from),
+            content_filter_reload_interval: Duration::from_secs(env_or(
+                "CONTENT_FILTER_RELOAD_INTERVAL_SECS",
+                30,
+            )),
+            scheduled_publish_poll_interval: Duration::from_secs(env_or(
+                "SCHEDULED_PUBLISH_POLL_INTERVAL_SECS",
+                15,
+            )),
+            outbox_poll_interval: Duration::from_secs(env_or("OUTBOX_POLL_INTERVAL_SECS", 5)),
+            outbox_max_attempts: env_or("OUTBOX_MAX_ATTEMPTS", 8),
+            storj_retry_policy: RetryPolicy {
+                max_attempts: env_or("STORJ_RETRY_MAX_ATTEMPTS", 3).max(1),
+                base_delay: Duration::from_millis(env_or("STORJ_RETRY_BASE_DELAY_MS", 200)),
+                max_delay: Duration::from_millis(env_or("STORJ_RETRY_MAX_DELAY_MS", 5000)),
+            },
+            canister_write_retry_policy: RetryPolicy {
+                max_attempts: env_or("CANISTER_WRITE_MAX_ATTEMPTS", 3).max(1),
+                ..RetryPolicy::default()
+            },
+            upload_compensation_action: env_or(
+                "UPLOAD_COMPENSATION_ACTION",
+                CompensationAction::MarkOrphaned,
+            ),
+            upload_saga_ttl: Duration::from_secs(env_or("UPLOAD_SAGA_TTL_SECS", 30 * 24 * 60 * 60)),
+            idempotency_key_ttl: Duration::from_secs(env_or(
+                "IDEMPOTENCY_KEY_TTL_SECS",
+                24 * 60 * 60,
+            )),
+            events_breaker: breaker_config("EVENTS"),
+            notifications_breaker: breaker_config("NOTIFICATIONS"),
+            storj_breaker: breaker_config("STORJ"),
+            ic_agent_breaker: breaker_config("IC_AGENT"),
+            events_bulkhead: bulkhead_config("EVENTS", 16),
+            notifications_bulkhead: bulkhead_config("NOTIFICATIONS", 16),
+            storj_bulkhead: bulkhead_config("STORJ", 32),
+            ic_agent_bulkhead: bulkhead_config("IC_AGENT", 64),
+            events_timeouts: timeout_config("EVENTS", 2_000, 5_000),
+            notifications_timeouts: timeout_config("NOTIFICATIONS", 2_000, 5_000),
+            storj_timeouts: timeout_config("STORJ", 5_000, 120_000),
+            storj_linkshare: LinkshareConfig {
+                sfw_base: env_or(
+                    "STORJ_SFW_LINKSHARE_BASE",
+                    "https://link.storjshare.io/raw/jx6vm3ebgb4gt3gfkmcrw62bl7rq/yral-videos"
+                        .to_string(),
+                ),
+                nsfw_base: env_or(
+                    "STORJ_NSFW_LINKSHARE_BASE",
+                    "https://link.storjshare.io/raw/jwait7tp3civp6cbaot4zzjbheqq/yral-nsfw-videos"
+                        .to_string(),
+                ),
+            },
+            ic_agent_timeouts: timeout_config("IC_AGENT", 5_000, 30_000),
+            request_deadline: Duration::from_secs(env_or("REQUEST_DEADLINE_SECS", 180)),
+            finalize_workers: env_or("FINALIZE_WORKERS", 4),
+            finalize_queue_capacity: env_or("FINALIZE_QUEUE_CAPACITY", 256),
+            finalize_job_ttl: Duration::from_secs(env_or(
+                "FINALIZE_JOB_TTL_SECS",
+                7 * 24 * 60 * 60,
+            )),
+            reconcile_interval: Duration::from_secs(env_or("RECONCILE_INTERVAL_SECS", 60 * 60)),
+            reconcile_lookback: Duration::from_secs(env_or(
+                "RECONCILE_LOOKBACK_SECS",
+                7 * 24 * 60 * 60,
+            )),
+            reconcile_grace_period: Duration::from_secs(env_or("RECONCILE_GRACE_SECS", 60 * 60)),
+            admin_auth: admin_auth_config(),
+            delegation_policy: DelegationPolicy {
+                max_chain_length: env_or("DELEGATION_MAX_CHAIN_LENGTH", 4),
+                max_clock_skew: Duration::from_secs(env_or("DELEGATION_MAX_CLOCK_SKEW_SECS", 300)),
+                accepted_targets: std::env::var("DELEGATION_ACCEPTED_TARGETS")
+                    .ok()
+                    .map(|targets| {
+                        targets
+                            .split(',')
+                            .map(|target| {
+                                Principal::from_text(target.trim())
+                                    .expect("DELEGATION_ACCEPTED_TARGETS must list principals")
+                            })
+                            .collect()
+                    })
+                    .unwrap_or_else(|| vec![USER_POST_SERVICE_ID, USER_INFO_SERVICE_ID]),
+            },
+            signed_request_clock_skew: Duration::from_secs(env_or(
+                "SIGNED_REQUEST_CLOCK_SKEW_SECS",
+                300,
+            )),
+            identity_wire_policy: IdentityWirePolicy {
+                enabled: env_or("DELEGATED_IDENTITY_WIRE_ENABLED", true),
+                sunset_at: std::env::var("DELEGATED_IDENTITY_WIRE_SUNSET_AT")
+                    .ok()
+                    .filter(|sunset_at| !sunset_at.is_empty())
+                    .map(|sunset_at| {
+                        sunset_at
+                            .parse()
+                            .expect("DELEGATED_IDENTITY_WIRE_SUNSET_AT must be a unix timestamp")
+                    }),
+            },
+            cors: cors_config(),
+            max_request_body_bytes: env_or("MAX_REQUEST_BODY_BYTES", 1024 * 1024),
+            update_metadata_max_body_bytes: env_or("UPDATE_METADATA_MAX_BODY_BYTES", 256 * 1024),
+            user_request_max_body_bytes: env_or("USER_REQUEST_MAX_BODY_BYTES", 16 * 1024),
+        }
+    }
+}
+
+/// Reads `<PREFIX>_BREAKER_FAILURE_THRESHOLD` and `<PREFIX>_BREAKER_OPEN_SECS`
+fn breaker_config(prefix: &str) -> BreakerConfig {
+    BreakerConfig {
+        failure_threshold: env_or(&format!("{}_BREAKER_FAILURE_THRESHOLD", prefix), 5).max(1),
+        open_duration: Duration::from_secs(env_or(&format!("{}_BREAKER_OPEN_SECS", prefix), 30)),
+    }
+}
+
+/// Reads `<PREFIX>_MAX_CONCURRENT_CALLS` and `<PREFIX>_QUEUE_TIMEOUT_MS`
+fn bulkhead_config(prefix: &str, default_max_concurrent_calls: usize) -> BulkheadConfig {
+    BulkheadConfig {
+        max_concurrent_calls: env_or(
+            &format!("{}_MAX_CONCURRENT_CALLS", prefix),
+            default_max_concurrent_calls,
+        )
+        .max(1),
+        queue_timeout: Duration::from_millis(env_or(
+            &format!("{}_QUEUE_TIMEOUT_MS", prefix),
+            5_000,
+        )),
+    }
+}
+
+/// Reads `<PREFIX>_CONNECT_TIMEOUT_MS` and `<PREFIX>_TIMEOUT_MS`
+fn timeout_config(prefix: &str, default_connect_ms: u64, default_total_ms: u64) -> TimeoutConfig {
+    TimeoutConfig {
+        connect: Duration::from_millis(env_or(
+            &format!("{}_CONNECT_TIMEOUT_MS", prefix),
+            default_connect_ms,
+        )),
+        total: Duration::from_millis(env_or(&format!("{}_TIMEOUT_MS", prefix), default_total_ms)),
+    }
+}
+
+/// Reads the comma separated `CORS_ALLOWED_ORIGINS` (`*` for any origin, the web app when unset or
+/// empty),
+/// `CORS_ALLOWED_METHODS`, `CORS_ALLOWED_HEADERS`, `CORS_ALLOW_CREDENTIALS` and `CORS_MAX_AGE_SECS`
+fn cors_config() -> CorsConfig {
+    let list = |key: &str| -> Option<Vec<String>> {
+        std::env::var(key).ok().map(|values| {
+            values
+                .split(',')
+                .map(str::trim)
+                .filter(|value| !value.is_empty())
+                .map(str::to_string)
+                .collect()
+        })
+    };
+
+    let allowed_origins = match list("CORS_ALLOWED_ORIGINS") {
+        Some(origins) if origins.iter().any(|origin| origin == "*") => None,
+        Some(origins) if !origins.is_empty() => Some(
+            origins
+                .into_iter()
+                .map(|origin| {
+                    origin
+                        .parse()
+                        .expect("CORS_ALLOWED_ORIGINS must list valid origins")
+                })
+                .collect(),
+        ),
+        _ => Some(CorsConfig::default_allowed_origins()),
+    };
+    let allow_credentials = env_or("CORS_ALLOW_CREDENTIALS", false);
+    assert!(
+        !(allow_credentials && allowed_origins.is_none()),
+        "CORS_ALLOW_CREDENTIALS cannot be combined with any origin, list the origins instead"
+    );
+
+    CorsConfig {
+        allowed_origins,
+        allowed_methods: list("CORS_ALLOWED_METHODS").map_or_else(
+            CorsConfig::default_allowed_methods,
+            |methods| {
+                methods
+                    .iter()
+                    .map(|method| {
+                        method
+                            .to_uppercase()
+                            .parse()
+                            .expect("CORS_ALLOWED_METHODS must list HTTP methods")
+                    })
+                    .collect()
+            },
+        ),
+        allowed_headers: list("CORS_ALLOWED_HEADERS").map_or_else(
+            CorsConfig::default_allowed_headers,
+            |headers| {
+                headers
+                    .iter()
+                    .map(|header| {
+                        header
+                            .parse()
+                            .expect("CORS_ALLOWED_HEADERS must list header names")
+                    })
+                    .collect()
+            },
+        ),
+        allow_credentials,
+        max_age: Duration::from_secs(env_or("CORS_MAX_AGE_SECS", 600)),
+    }
+}
+
+/// Reads `ADMIN_API_TOKENS` as `name:token:scope,scope;...`, `ADMIN_PRINCIPALS` as
+/// `principal:scope,scope;...` and `ADMIN_API_TOKEN`, a token holding every scope
+fn admin_auth_config() -> AdminAuthConfig {
+    let entries = |key: &str| -> Vec<String> {
+        std::env::var(key)
+            .unwrap_or_default()
+            .split(';')
+            .map(str::trim)
+            .filter(|entry| !entry.is_empty())
+            .map(str::to_string)
+            .collect()
+    };
+
+    let mut tokens: Vec<AdminToken> = entries("ADMIN_API_TOKENS")
+        .iter()
+        .map(|entry| {
+            parse_admin_token(entry).expect("ADMIN_API_TOKENS entries must be name:token:scopes")
+        })
+        .collect();
+    if let Some(token) = std::env::var("ADMIN_API_TOKEN")
+        .ok()
+        .filter(|token| !token.is_empty())
+    {
+        tokens.push(AdminToken {
+            name: "admin".to_string(),
+            token,
+            scopes: HashSet::from(AdminScope::ALL),
+        });
+    }
+
+    let principals = entries("ADMIN_PRINCIPALS")
+        .iter()
+        .map(|entry| {
+            let (principal, principal_scopes) = entry
+                .split_once(':')
+                .expect("ADMIN_PRINCIPALS entries must be principal:scopes");
+            (
+                Principal::from_text(principal.trim())
+                    .expect("ADMIN_PRINCIPALS must list principals"),
+                parse_admin_scopes(principal_scopes),
+            )
+        })
+        .collect();
+
+    AdminAuthConfig { tokens, principals }
+}
+
+/// Parses a `name:token:scopes` entry. The name and the scopes hold no `:`, so the token is
+/// whatever lies between the first and the last one and may contain `:` itself.
+fn parse_admin_token(entry: &str) -> Option<AdminToken> {
+    let (name, rest) = entry.split_once(':')?;
+    let (token, scopes) = rest.rsplit_once(':')?;
+    if token.trim().is_empty() {
+        return None;
+    }
+
+    Some(AdminToken {
+        name: name.trim().to_string(),
+        token: token.trim().to_string(),
+        scopes: parse_admin_scopes(scopes),
+    })
+}
+
+fn parse_admin_scopes(scopes: &str) -> HashSet<AdminScope> {
+    scopes
+        .split(',')
+        .map(|scope| {
+            scope
+                .trim()
+                .parse()
+                .expect("Admin scopes must be read, replay, moderate or delete")
+        })
+        .collect()
+}
+
+fn env_or<T: std::str::FromStr>(key: &str, default: T) -> T {
+    std::env::var(key)
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(default)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_admin_token_may_contain_colons() {
+        let token = parse_admin_token("ops:abc:def=:read,replay").unwrap();
+
+        assert_eq!(token.name, "ops");
+        assert_eq!(token.token, "abc:def=");
+        assert_eq!(
+            token.scopes,
+            HashSet::from([AdminScope::Read, AdminScope::Replay])
+        );
+        assert!(parse_admin_token("ops:read").is_none());
+        assert!(parse_admin_token("ops::read").is_none());
+    }
+}
@@ -3,8 +3,10 @@ use std::sync::Arc;
 use axum::{
     Json, Router,
     body::Body,
-    http::Request,
-    routing::{get, post},
+    extract::State,
+    http::{Request, StatusCode},
+    middleware,
+    routing::{MethodRouter, get, post},
 };
 use sentry::integrations::tower::{NewSentryLayer, SentryHttpLayer};
 use serde_json::json;
@@ -15,9 +17,30 @@ use utoipa_swagger_ui::SwaggerUi;
 use crate::{
     api::get_upload_url::get_upload_url,
     app_state::AppState,
+    config::AppConfig,
     utils::{
-        events_interface::EventService, notification_client::NotificationClient,
+        admin_auth::{AdminAuth, AdminScope, admin_auth_middleware},
+        audit_log::AuditLog,
+        blocklist::Blocklist,
+        body_limit::body_limit_middleware,
+        bulkhead::Bulkhead,
+        circuit_breaker::{BreakerState, CircuitBreaker, CircuitBreakers},
+        content_filter::ContentFilter,
+        dead_letters::DeadLetters,
+        deadline::deadline_middleware,
+        events_interface::EventService,
+        finalize_jobs::FinalizeJobs,
+        idempotency::{IdempotencyStore, idempotency_middleware},
+        notification_client::NotificationClient,
+        outbox::Outbox,
+        publish_scheduler::PublishScheduler,
+        reconciler::{Reconciler, ReconcilerConfig},
+        request_auth::{RequestVerifier, signed_request_middleware},
+        request_id::request_id_middleware,
+        store::Store,
         storj_interface::StorjInterface,
+        upload_saga::UploadSagas,
+        upload_sessions::UploadSessions,
     },
 };
 #[derive(OpenApi)]
@@ -26,14 +49,77 @@ use crate::{
         api::get_upload_url::get_upload_url,
         api::update_video_metadata::update_video_metadata,
         api::mark_post_as_published::mark_post_as_published,
+        api::scheduled_posts::list_scheduled_posts,
+        api::scheduled_posts::reschedule_post,
+        api::scheduled_posts::cancel_scheduled_post,
+        api::drafts::list_drafts,
+        api::drafts::delete_draft,
+        api::drafts::duplicate_draft,
+        api::upload_status::upload_status,
+        api::jobs::get_job,
+        api::reconciliation::run_reconciliation,
+        api::reconciliation::latest_reconciliation_report,
+        api::dead_letters::list_dead_letters,
+        api::dead_letters::get_dead_letter,
+        api::dead_letters::replay_dead_letter,
+        api::dead_letters::replay_dead_letters,
+        api::dead_letters::discard_dead_letter,
+        api::audit_log::list_audit_log,
+        api::audit_log::verify_audit_log,
+        api::blocklist::list_blocked_principals,
+        api::blocklist::block_principal,
+        api::blocklist::unblock_principal,
     ),
     components(
         schemas(
             api::get_upload_url::GetUploadUrlReq,
             api::get_upload_url::GetUploadUrlResp,
             api::update_video_metadata::UpdateMetadataRequest,
+            api::update_video_metadata::FinalizeJobAccepted,
+            utils::finalize_jobs::JobProgress,
+            utils::finalize_jobs::JobStepProgress,
+            utils::finalize_jobs::JobStep,
+            utils::finalize_jobs::StepStatus,
+            utils::finalize_jobs::JobStatus,
             api::mark_post_as_published::MarkPostAsPublishedRequest,
+            api::scheduled_posts::ListScheduledPostsRequest,
+            api::scheduled_posts::ListScheduledPostsResp,
+            api::scheduled_posts::ReschedulePostRequest,
+            api::scheduled_posts::CancelScheduledPostRequest,
+            utils::publish_scheduler::ScheduledPost,
+            api::drafts::ListDraftsRequest,
+            api::drafts::ListDraftsResp,
+            api::drafts::DraftPost,
+            api::drafts::DraftActionRequest,
+            api::drafts::DuplicateDraftResp,
+            api::upload_status::UploadStatusRequest,
+            utils::upload_saga::UploadSaga,
+            utils::upload_saga::UploadSagaState,
+            utils::reconciler::ReconciliationReport,
+            utils::reconciler::ReconciliationMismatch,
+            utils::reconciler::ReconciliationCheckFailure,
+            utils::reconciler::MismatchKind,
+            utils::reconciler::RepairAction,
+            api::dead_letters::ListDeadLettersResp,
+            api::dead_letters::ReplayDeadLettersRequest,
+            api::dead_letters::ReplayDeadLettersResp,
+            utils::dead_letters::DeadLetter,
+            utils::dead_letters::DeadLetterKind,
+            utils::dead_letters::ReplayOutcome,
+            utils::admin_auth::AdminScope,
+            api::audit_log::ListAuditLogResp,
+            utils::audit_log::AuditEntry,
+            utils::audit_log::AuditEvent,
+            utils::audit_log::AuditAction,
+            utils::audit_log::AuditOutcome,
+            utils::audit_log::AuditVerification,
+            api::blocklist::BlockPrincipalRequest,
+            api::blocklist::ListBlockedPrincipalsResp,
+            utils::blocklist::BlockedPrincipal,
             utils::types::DelegatedIdentityWire,
+            utils::types::CreationContext,
+            utils::types::CaptureSource,
+            utils::types::ErrorCode,
         )
     ),
     tags(
@@ -44,12 +130,315 @@ struct ApiDoc;
 
 pub mod api;
 pub mod app_state;
+pub mod config;
 pub mod utils;
 async fn health_check() -> Json<serde_json::Value> {
     json!({ "status": "ok" }).into()
 }
 
+/// Not ready while the breaker of a dependency uploads cannot do without is open. Once its open
+/// duration elapsed the breaker reports half-open and the pod is ready to take the probe call.
+async fn readiness_check(
+    State(app_state): State<AppState>,
+) -> (StatusCode, Json<serde_json::Value>) {
+    let circuit_breakers = &app_state.circuit_breakers;
+
+    let dependencies: serde_json::Map<_, _> = circuit_breakers
+        .all()
+        .iter()
+        .map(|breaker| (breaker.name().to_string(), json!(breaker.state())))
+        .collect();
+    let is_ready = circuit_breakers
+        .critical()
+        .iter()
+        .all(|breaker| breaker.state() != BreakerState::Open);
+
+    let (status_code, status) = if is_ready {
+        (StatusCode::OK, "ok")
+    } else {
+        (StatusCode::SERVICE_UNAVAILABLE, "unavailable")
+    };
+
+    (
+        status_code,
+        json!({ "status": status, "dependencies": dependencies }).into(),
+    )
+}
+
+/// Services and stores shared by the handlers and the background tasks
+fn build_app_state(
+    config: &AppConfig,
+    store: &Store,
+    ic_admin_agent: ic_agent::Agent,
+    events_api_token: String,
+    notifications_api_token: String,
+) -> AppState {
+    let circuit_breakers = CircuitBreakers {
+        events: CircuitBreaker::new("events", config.events_breaker)
+            .with_bulkhead(Bulkhead::new("events", config.events_bulkhead)),
+        notifications: CircuitBreaker::new("notifications", config.notifications_breaker)
+            .with_bulkhead(Bulkhead::new(
+                "notifications",
+                config.notifications_bulkhead,
+            )),
+        storj: CircuitBreaker::new("storj", config.storj_breaker)
+            .with_bulkhead(Bulkhead::new("storj", config.storj_bulkhead)),
+        ic_agent: CircuitBreaker::new("ic_agent", config.ic_agent_breaker)
+            .with_bulkhead(Bulkhead::new("ic_agent", config.ic_agent_bulkhead)),
+    };
+
+    let content_filter = match &config.content_filter_rules_path {
+        Some(path) => ContentFilter::from_file(path.clone())
+            .expect("CONTENT_FILTER_RULES_PATH must point to a valid rules file"),
+        None => ContentFilter::disabled(),
+    };
+
+    let dead_letters = DeadLetters::new(store).unwrap();
+
+    AppState {
+        storj_client: Arc::new(
+            StorjInterface::new(
+                "https://storj-interface.yral.com".to_string(),
+                config.storj_linkshare.clone(),
+                config.storj_timeouts,
+                config.storj_retry_policy,
+                circuit_breakers.storj.clone(),
+            )
+            .unwrap(),
+        ),
+        events_service: EventService::with_auth_token(
+            events_api_token,
+            config.events_timeouts,
+            circuit_breakers.events.clone(),
+        ),
+        ic_admin_agent,
+        notification_client: NotificationClient::new(
+            notifications_api_token,
+            config.notifications_timeouts,
+            circuit_breakers.notifications.clone(),
+        ),
+        content_filter,
+        publish_scheduler: PublishScheduler::new(store).unwrap(),
+        outbox: Outbox::new(store, dead_letters.clone()).unwrap(),
+        upload_sagas: UploadSagas::new(
+            store,
+            config.canister_write_retry_policy,
+            config.upload_compensation_action,
+            config.upload_saga_ttl,
+        )
+        .unwrap(),
+        idempotency_store: IdempotencyStore::new(store, config.idempotency_key_ttl).unwrap(),
+        circuit_breakers,
+        finalize_jobs: FinalizeJobs::new(
+            store,
+            config.finalize_queue_capacity,
+            config.finalize_job_ttl,
+        )
+        .unwrap(),
+        upload_sessions: UploadSessions::new(store).unwrap(),
+        reconciler: Reconciler::new(
+            store,
+            ReconcilerConfig {
+                lookback: config.reconcile_lookback,
+                grace_period: config.reconcile_grace_period,
+                compensation_action: config.upload_compensation_action,
+            },
+        )
+        .unwrap(),
+        dead_letters,
+        delegation_policy: Arc::new(config.delegation_policy.clone()),
+        identity_wire_policy: config.identity_wire_policy,
+        blocklist: Blocklist::new(store).unwrap(),
+        audit_log: AuditLog::new(store).unwrap(),
+    }
+}
+
+/// Every route of the service with its middleware, as served
+fn build_router(
+    app_state: AppState,
+    config: &AppConfig,
+    request_verifier: RequestVerifier,
+) -> Router {
+    let idempotency_layer =
+        middleware::from_fn_with_state(app_state.clone(), idempotency_middleware);
+
+    let admin_auth = AdminAuth::new(config.admin_auth.clone(), app_state.audit_log.clone());
+    let require =
+        |scope| middleware::from_fn_with_state(admin_auth.require(scope), admin_auth_middleware);
+
+    let admin_router = Router::new()
+        .route(
+            "/reconcile",
+            post(api::reconciliation::run_reconciliation).layer(require(AdminScope::Replay)),
+        )
+        .route(
+            "/reconcile/latest",
+            get(api::reconciliation::latest_reconciliation_report).layer(require(AdminScope::Read)),
+        )
+        .route(
+            "/dead-letters",
+            get(api::dead_letters::list_dead_letters).layer(require(AdminScope::Read)),
+        )
+        .route(
+            "/dead-letters/replay",
+            post(api::dead_letters::replay_dead_letters).layer(require(AdminScope::Replay)),
+        )
+        .route(
+            "/dead-letters/{id}",
+            get(api::dead_letters::get_dead_letter).layer(require(AdminScope::Read)),
+        )
+        .route(
+            "/dead-letters/{id}/replay",
+            post(api::dead_letters::replay_dead_letter).layer(require(AdminScope::Replay)),
+        )
+        .route(
+            "/dead-letters/{id}/discard",
+            post(api::dead_letters::discard_dead_letter).layer(require(AdminScope::Delete)),
+        )
+        .route(
+            "/audit-log",
+            get(api::audit_log::list_audit_log).layer(require(AdminScope::Read)),
+        )
+        .route(
+            "/audit-log/verify",
+            get(api::audit_log::verify_audit_log).layer(require(AdminScope::Read)),
+        )
+        .route(
+            "/blocklist",
+            get(api::blocklist::list_blocked_principals)
+                .layer(require(AdminScope::Read))
+                .merge(post(api::blocklist::block_principal).layer(require(AdminScope::Moderate))),
+        )
+        .route(
+            "/blocklist/{principal}/unblock",
+            post(api::blocklist::unblock_principal).layer(require(AdminScope::Moderate)),
+        )
+        // admins allowlisted by principal authenticate with a signed request
+        .layer(middleware::from_fn_with_state(
+            request_verifier.clone(),
+            signed_request_middleware,
+        ));
+
+    let body_limit = |max_bytes| middleware::from_fn_with_state(max_bytes, body_limit_middleware);
+    let signed_requests =
+        middleware::from_fn_with_state(request_verifier, signed_request_middleware);
+    // the route's body limit applies before the signature check buffers the body
+    let user_route = |route: MethodRouter<AppState>, max_bytes| {
+        route
+            .layer(signed_requests.clone())
+            .layer(body_limit(max_bytes))
+    };
+    let user_max_bytes = config.user_request_max_body_bytes;
+
+    Router::new()
+        .route(
+            "/get-upload-url",
+            user_route(post(get_upload_url), user_max_bytes),
+        )
+        .route(
+            "/update-video-metadata",
+            user_route(
+                post(api::update_video_metadata::update_video_metadata)
+                    .layer(idempotency_layer.clone()),
+                config.update_metadata_max_body_bytes,
+            ),
+        )
+        .route(
+            "/mark-post-as-published",
+            user_route(
+                post(api::mark_post_as_published::mark_post_as_published).layer(idempotency_layer),
+                user_max_bytes,
+            ),
+        )
+        .route(
+            "/list-scheduled-posts",
+            user_route(
+                post(api::scheduled_posts::list_scheduled_posts),
+                user_max_bytes,
+            ),
+        )
+        .route(
+            "/reschedule-post",
+            user_route(post(api::scheduled_posts::reschedule_post), user_max_bytes),
+        )
+        .route(
+            "/cancel-scheduled-post",
+            user_route(
+                post(api::scheduled_posts::cancel_scheduled_post),
+                user_max_bytes,
+            ),
+        )
+        .route(
+            "/list-drafts",
+            user_route(post(api::drafts::list_drafts), user_max_bytes),
+        )
+        .route(
+            "/delete-draft",
+            user_route(post(api::drafts::delete_draft), user_max_bytes),
+        )
+        .route(
+            "/duplicate-draft",
+            user_route(post(api::drafts::duplicate_draft), user_max_bytes),
+        )
+        .route(
+            "/upload-status",
+            user_route(post(api::upload_status::upload_status), user_max_bytes),
+        )
+        .route(
+            "/jobs/{job_id}",
+            user_route(get(api::jobs::get_job), user_max_bytes),
+        )
+        .route("/health", get(health_check))
+        .route("/ready", get(readiness_check))
+        .route("/metrics", get(utils::metrics::metrics_handler))
+        .nest("/admin", admin_router)
+        .merge(SwaggerUi::new("/explore").url("/api-doc/openapi.json", ApiDoc::openapi()))
+        .with_state(app_state)
+        .layer(
+            ServiceBuilder::new()
+                .layer(NewSentryLayer::<Request<Body>>::new_from_top())
+                .layer(SentryHttpLayer::new().enable_transaction())
+                .layer(middleware::from_fn(request_id_middleware))
+                .layer(config.cors.layer())
+                .layer(middleware::from_fn_with_state(
+                    config.max_request_body_bytes,
+                    body_limit_middleware,
+                ))
+                .layer(middleware::from_fn_with_state(
+                    config.request_deadline,
+                    deadline_middleware,
+                )),
+        )
+}
+
+/// `verify-audit-log` command checking the hash chain of the audit log in `DATA_DIR`. The store
+/// is locked by a running service, so it is run against a stopped one or a copy of its data.
+fn verify_audit_log() -> i32 {
+    env_logger::init();
+    let config = AppConfig::from_env();
+
+    let verification = Store::open(&config.data_dir)
+        .and_then(|store| AuditLog::new(&store))
+        .map_err(|e| e.to_string())
+        .and_then(|audit_log| audit_log.verify().map_err(|e| e.to_string()));
+
+    match verification {
+        Ok(verification) => {
+            println!("{}", serde_json::to_string_pretty(&verification).unwrap());
+            if verification.valid { 0 } else { 1 }
+        }
+        Err(e) => {
+            eprintln!("Failed to read the audit log: {}", e);
+            2
+        }
+    }
+}
+
 fn main() {
+    if std::env::args().nth(1).as_deref() == Some("verify-audit-log") {
+        std::process::exit(verify_audit_log());
+    }
+
     #[cfg(not(feature = "local"))]
     let _guard = {
         let app_env = std::env::var("APP_ENV").unwrap_or_else(|_| "production".to_string());
@@ -72,6 +461,8 @@ fn main() {
         .unwrap()
         .block_on(async {
             env_logger::init();
+            let config = AppConfig::from_env();
+
             let ic_admin_identity = {
                 #[cfg(not(feature = "local"))]
                 {
@@ -79,12 +470,10 @@ fn main() {
                     let private_key = std::env::var("IC_ADMIN_PRIVATE_KEY")
                         .expect("IC_ADMIN_PRIVATE_KEY must be set in environment variables");
 
-                    let pem = Secp256k1Identity::from_pem(stringreader::StringReader::new(
+                    Secp256k1Identity::from_pem(stringreader::StringReader::new(
                         private_key.as_str(),
                     ))
-                    .unwrap();
-
-                    pem
+                    .unwrap()
                 }
                 #[cfg(feature = "local")]
                 {
@@ -101,62 +490,85 @@ fn main() {
             let ic_admin_agent = ic_agent::Agent::builder()
                 .with_identity(ic_admin_identity)
                 .with_url("https://ic0.app")
+                .with_http_client(config.ic_agent_timeouts.client_builder().build().unwrap())
+                .with_max_polling_time(config.ic_agent_timeouts.total)
                 .build()
                 .unwrap();
 
-            let event_service = {
+            let (events_api_token, notifications_api_token) = {
                 #[cfg(feature = "local")]
                 {
-                    EventService::with_auth_token("test".to_owned())
+                    ("test".to_string(), "test".to_string())
                 }
                 #[cfg(not(feature = "local"))]
                 {
-                    EventService::with_auth_token(
+                    (
                         std::env::var("OFFCHAIN_EVENTS_API_TOKEN").unwrap(),
+                        std::env::var("YRAL_METADATA_NOTIFICATION_SERVICE_API_TOKEN").unwrap(),
                     )
                 }
             };
 
-            let notification_client = {
+            let store = {
                 #[cfg(feature = "local")]
                 {
-                    NotificationClient::new("test".to_string())
+                    Store::temporary().unwrap()
                 }
                 #[cfg(not(feature = "local"))]
                 {
-                    NotificationClient::new(
-                        std::env::var("YRAL_METADATA_NOTIFICATION_SERVICE_API_TOKEN").unwrap(),
-                    )
+                    Store::open(&config.data_dir).expect("Failed to open data store")
                 }
             };
 
-            let app_state = AppState {
-                storj_client: Arc::new(
-                    StorjInterface::new("https://storj-interface.yral.com".to_string()).unwrap(),
-                ),
-                events_service: event_service,
-                ic_admin_agent: ic_admin_agent,
-                notification_client: notification_client,
-            };
+            let app_state = build_app_state(
+                &config,
+                &store,
+                ic_admin_agent,
+                events_api_token,
+                notifications_api_token,
+            );
+
+            app_state
+                .content_filter
+                .spawn_reloader(config.content_filter_reload_interval);
+
+            app_state.outbox.spawn_dispatcher(
+                app_state.events_service.clone(),
+                app_state.notification_client.clone(),
+                config.outbox_poll_interval,
+                config.outbox_max_attempts,
+            );
+
+            app_state.idempotency_store.spawn_pruner();
+
+            app_state.upload_sagas.spawn_pruner();
+
+            app_state
+                .dead_letters
+                .release_interrupted()
+                .expect("Failed to release interrupted dead letter replays");
+
+            app_state
+                .finalize_jobs
+                .spawn_workers(app_state.clone(), config.finalize_workers);
+
+            app_state
+                .publish_scheduler
+                .spawn(app_state.clone(), config.scheduled_publish_poll_interval);
+
+            app_state
+                .reconciler
+                .spawn(app_state.clone(), config.reconcile_interval);
+
+            let request_verifier = RequestVerifier::new(
+                &store,
+                app_state.delegation_policy.clone(),
+                config.signed_request_clock_skew,
+            )
+            .unwrap();
+            request_verifier.spawn_nonce_pruner();
 
-            let app = Router::new()
-                .route("/get-upload-url", post(get_upload_url))
-                .route(
-                    "/update-video-metadata",
-                    post(api::update_video_metadata::update_video_metadata),
-                )
-                .route(
-                    "/mark-post-as-published",
-                    post(api::mark_post_as_published::mark_post_as_published),
-                )
-                .route("/health", get(health_check))
-                .merge(SwaggerUi::new("/explore").url("/api-doc/openapi.json", ApiDoc::openapi()))
-                .with_state(app_state)
-                .layer(
-                    ServiceBuilder::new()
-                        .layer(NewSentryLayer::<Request<Body>>::new_from_top())
-                        .layer(SentryHttpLayer::new().enable_transaction()),
-                );
+            let app = build_router(app_state, &config, request_verifier);
 
             let listner = tokio::net::TcpListener::bind("0.0.0.0:3000").await.unwrap();
             axum::serve(listner, app).await.unwrap();
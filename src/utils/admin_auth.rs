@@ -0,0 +1,326 @@
+use std::{
+    collections::{HashMap, HashSet},
+    str::FromStr,
+    sync::Arc,
+};
+
+use axum::{
+    extract::{Request, State},
+    http::{HeaderMap, header::AUTHORIZATION},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use candid::Principal;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use utoipa::ToSchema;
+
+use crate::utils::{
+    audit_log::{AuditAction, AuditEvent, AuditLog},
+    request_auth::AuthenticatedPrincipal,
+    request_id::RequestId,
+    types::AppError,
+};
+
+/// What an admin credential is allowed to do
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Hash, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum AdminScope {
+    /// Inspect reports, dead letters and the audit trail
+    Read,
+    /// Re-run failed or inconsistent operations
+    Replay,
+    /// Restrict what users can do
+    Moderate,
+    /// Drop data for good
+    Delete,
+}
+
+impl AdminScope {
+    pub const ALL: [AdminScope; 4] = [
+        AdminScope::Read,
+        AdminScope::Replay,
+        AdminScope::Moderate,
+        AdminScope::Delete,
+    ];
+}
+
+impl FromStr for AdminScope {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "read" => Ok(AdminScope::Read),
+            "replay" => Ok(AdminScope::Replay),
+            "moderate" => Ok(AdminScope::Moderate),
+            "delete" => Ok(AdminScope::Delete),
+            _ => Err(format!("Unknown admin scope: {}", s)),
+        }
+    }
+}
+
+/// A static bearer token of the admin API
+#[derive(Clone, Debug)]
+pub struct AdminToken {
+    /// Name the token's requests are audited under
+    pub name: String,
+    pub token: String,
+    pub scopes: HashSet<AdminScope>,
+}
+
+/// Credentials accepted by the admin API. Both empty disables it.
+#[derive(Clone, Debug, Default)]
+pub struct AdminAuthConfig {
+    pub tokens: Vec<AdminToken>,
+    /// Principals that may call the admin API with a signed request
+    pub principals: HashMap<Principal, HashSet<AdminScope>>,
+}
+
+impl AdminAuthConfig {
+    pub fn is_disabled(&self) -> bool {
+        self.tokens.is_empty() && self.principals.is_empty()
+    }
+
+    /// Works out who a request comes from and checks they hold `scope`. The actor is returned
+    /// alongside a rejection whenever the credentials were recognised.
+    fn authorize(
+        &self,
+        headers: &HeaderMap,
+        authenticated: Option<Principal>,
+        scope: AdminScope,
+    ) -> Result<String, (Option<String>, AppError)> {
+        if self.is_disabled() {
+            return Err((
+                None,
+                AppError::Forbidden("Admin API is disabled".to_string()),
+            ));
+        }
+
+        let bearer_token = headers
+            .get(AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "));
+
+        let (actor, scopes) = match (bearer_token, authenticated) {
+            (Some(bearer_token), _) => {
+                let bearer_digest = Sha256::digest(bearer_token.as_bytes());
+                let token = self
+                    .tokens
+                    .iter()
+                    .find(|token| token_matches(&bearer_digest, &token.token))
+                    .ok_or_else(|| {
+                        (
+                            None,
+                            AppError::Unauthenticated("Invalid admin token".to_string()),
+                        )
+                    })?;
+                (format!("token:{}", token.name), &token.scopes)
+            }
+            (None, Some(principal)) => {
+                let actor = format!("principal:{}", principal);
+                let scopes = self.principals.get(&principal).ok_or_else(|| {
+                    (
+                        Some(actor.clone()),
+                        AppError::Forbidden(format!("{} is not an admin", principal)),
+                    )
+                })?;
+                (actor, scopes)
+            }
+            (None, None) => {
+                return Err((
+                    None,
+                    AppError::Unauthenticated(
+                        "An admin token or a signed request is required".to_string(),
+                    ),
+                ));
+            }
+        };
+
+        if !scopes.contains(&scope) {
+            return Err((
+                Some(actor),
+                AppError::Forbidden(format!("Missing the {:?} admin scope", scope)),
+            ));
+        }
+
+        Ok(actor)
+    }
+}
+
+/// Compares the SHA-256 digests of the tokens without stopping at the first differing byte, so
+/// that response times tell nothing about how close a guessed token was
+fn token_matches(bearer_digest: &[u8], token: &str) -> bool {
+    Sha256::digest(token.as_bytes())
+        .iter()
+        .zip(bearer_digest)
+        .fold(0u8, |difference, (a, b)| difference | (a ^ b))
+        == 0
+}
+
+/// Who an authorized admin request comes from, added to the request extensions
+#[derive(Clone, Debug)]
+pub struct AdminActor(pub String);
+
+/// Authorizes admin requests and records them in the audit log
+#[derive(Clone)]
+pub struct AdminAuth {
+    config: Arc<AdminAuthConfig>,
+    audit_log: AuditLog,
+}
+
+/// State of `admin_auth_middleware` on a route requiring `scope`
+#[derive(Clone)]
+pub struct AdminScopeGuard {
+    auth: AdminAuth,
+    scope: AdminScope,
+}
+
+impl AdminAuth {
+    pub fn new(config: AdminAuthConfig, audit_log: AuditLog) -> Self {
+        Self {
+            config: Arc::new(config),
+            audit_log,
+        }
+    }
+
+    pub fn require(&self, scope: AdminScope) -> AdminScopeGuard {
+        AdminScopeGuard {
+            auth: self.clone(),
+            scope,
+        }
+    }
+}
+
+/// Middleware of an `/admin` route, accepting `Authorization: Bearer <token>` or a request
+/// signed by an allowlisted principal that holds the route's scope. Every request goes to the
+/// audit log, rejected ones included.
+pub async fn admin_auth_middleware(
+    State(guard): State<AdminScopeGuard>,
+    mut request: Request,
+    next: Next,
+) -> Response {
+    let method = request.method().to_string();
+    let path = request.uri().path().to_string();
+    let request_id = request
+        .extensions()
+        .get::<RequestId>()
+        .map(|request_id| request_id.0.clone());
+    let authenticated = request
+        .extensions()
+        .get::<AuthenticatedPrincipal>()
+        .map(|authenticated| authenticated.0);
+
+    let (actor, response) =
+        match guard
+            .auth
+            .config
+            .authorize(request.headers(), authenticated, guard.scope)
+        {
+            Ok(actor) => {
+                request.extensions_mut().insert(AdminActor(actor.clone()));
+                (Some(actor), next.run(request).await)
+            }
+            Err((actor, e)) => {
+                log::warn!("Rejected admin request to {}: {}", path, e);
+                (actor, e.to_api_response::<()>().into_response())
+            }
+        };
+
+    let status = response.status();
+    guard.auth.audit_log.record(
+        AuditEvent::new(
+            AuditAction::Admin,
+            Some(format!("{} {}", method, path)),
+            actor,
+            request_id,
+        )
+        .with_error((!status.is_success()).then(|| format!("Responded with {}", status))),
+    );
+
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::HeaderValue;
+
+    use super::*;
+
+    fn config() -> AdminAuthConfig {
+        AdminAuthConfig {
+            tokens: vec![AdminToken {
+                name: "ops".to_string(),
+                token: "ops-token".to_string(),
+                scopes: HashSet::from([AdminScope::Read, AdminScope::Replay]),
+            }],
+            principals: HashMap::from([(
+                Principal::management_canister(),
+                HashSet::from([AdminScope::Moderate]),
+            )]),
+        }
+    }
+
+    fn bearer(token: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            AUTHORIZATION,
+            HeaderValue::from_str(&format!("Bearer {}", token)).unwrap(),
+        );
+        headers
+    }
+
+    #[test]
+    fn test_token_is_limited_to_its_scopes() {
+        let config = config();
+
+        assert_eq!(
+            config
+                .authorize(&bearer("ops-token"), None, AdminScope::Replay)
+                .unwrap(),
+            "token:ops"
+        );
+        assert!(matches!(
+            config.authorize(&bearer("ops-token"), None, AdminScope::Delete),
+            Err((Some(_), AppError::Forbidden(_)))
+        ));
+        assert!(matches!(
+            config.authorize(&bearer("other-token"), None, AdminScope::Read),
+            Err((None, AppError::Unauthenticated(_)))
+        ));
+    }
+
+    #[test]
+    fn test_only_allowlisted_principals_are_admins() {
+        let config = config();
+
+        assert!(
+            config
+                .authorize(
+                    &HeaderMap::new(),
+                    Some(Principal::management_canister()),
+                    AdminScope::Moderate,
+                )
+                .is_ok()
+        );
+        assert!(matches!(
+            config.authorize(
+                &HeaderMap::new(),
+                Some(Principal::anonymous()),
+                AdminScope::Read,
+            ),
+            Err((Some(_), AppError::Forbidden(_)))
+        ));
+        assert!(matches!(
+            config.authorize(&HeaderMap::new(), None, AdminScope::Read),
+            Err((None, AppError::Unauthenticated(_)))
+        ));
+    }
+
+    #[test]
+    fn test_disabled_without_credentials() {
+        assert!(matches!(
+            AdminAuthConfig::default().authorize(&bearer(""), None, AdminScope::Read),
+            Err((None, AppError::Forbidden(_)))
+        ));
+    }
+}
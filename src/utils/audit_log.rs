@@ -0,0 +1,699 @@
+use std::sync::mpsc;
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use utoipa::ToSchema;
+
+use crate::utils::{
+    store::{Store, StoreError, TypedTree},
+    time::now_unix_secs,
+    types::AppError,
+};
+
+/// `prev_hash` of the first entry
+const GENESIS_HASH: &str = "0000000000000000000000000000000000000000000000000000000000000000";
+
+/// Version of the encoding entries are hashed with, see `AuditEntry::compute_hash`
+const HASH_VERSION: u8 = 1;
+
+const PRINCIPAL_INDEX: &str = "principal";
+const SUBJECT_INDEX: &str = "subject";
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum AuditAction {
+    IssueUploadUrl,
+    /// Finalizing the Storj upload with the post metadata
+    FinalizeUpload,
+    /// Writing the post to the user post service canister
+    WriteCanister,
+    Publish,
+    Reschedule,
+    CancelScheduledPublish,
+    DeleteDraft,
+    DuplicateDraft,
+    /// A request to the admin API, rejected ones included
+    Admin,
+    /// Blocking a principal, with the reason and expiry in the details
+    Block,
+    Unblock,
+    ReplayDeadLetter,
+    DiscardDeadLetter,
+}
+
+impl AuditAction {
+    /// Name of the action in the entry hash, which must not change once entries were written
+    fn hash_name(self) -> &'static str {
+        match self {
+            AuditAction::IssueUploadUrl => "issue_upload_url",
+            AuditAction::FinalizeUpload => "finalize_upload",
+            AuditAction::WriteCanister => "write_canister",
+            AuditAction::Publish => "publish",
+            AuditAction::Reschedule => "reschedule",
+            AuditAction::CancelScheduledPublish => "cancel_scheduled_publish",
+            AuditAction::DeleteDraft => "delete_draft",
+            AuditAction::DuplicateDraft => "duplicate_draft",
+            AuditAction::Admin => "admin",
+            AuditAction::Block => "block",
+            AuditAction::Unblock => "unblock",
+            AuditAction::ReplayDeadLetter => "replay_dead_letter",
+            AuditAction::DiscardDeadLetter => "discard_dead_letter",
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum AuditOutcome {
+    Succeeded,
+    Failed,
+}
+
+impl AuditOutcome {
+    fn hash_name(self) -> &'static str {
+        match self {
+            AuditOutcome::Succeeded => "succeeded",
+            AuditOutcome::Failed => "failed",
+        }
+    }
+}
+
+/// A state-changing action, as recorded in the audit log
+#[derive(Serialize, Deserialize, Clone, Debug, ToSchema)]
+pub struct AuditEvent {
+    pub action: AuditAction,
+    /// Principal the action was taken by or on behalf of, `token:<name>` for admin tokens
+    pub principal: Option<String>,
+    /// `X-Request-Id` of the request, `None` for background jobs
+    pub request_id: Option<String>,
+    /// Post, video, principal or dead letter the action is about, the route for `admin` entries
+    pub subject: Option<String>,
+    /// Status of the subject before the action, e.g. `draft`
+    pub before: Option<String>,
+    /// Status the action moves the subject to, only reached when it succeeded
+    pub after: Option<String>,
+    pub outcome: AuditOutcome,
+    pub error: Option<String>,
+    /// Parameters of the action as JSON, e.g. the reason and expiry of a block
+    pub details: Option<String>,
+}
+
+impl AuditEvent {
+    pub fn new(
+        action: AuditAction,
+        subject: Option<String>,
+        principal: Option<String>,
+        request_id: Option<String>,
+    ) -> Self {
+        Self {
+            action,
+            principal,
+            request_id,
+            subject,
+            before: None,
+            after: None,
+            outcome: AuditOutcome::Succeeded,
+            error: None,
+            details: None,
+        }
+    }
+
+    pub fn with_transition(mut self, before: impl Into<String>, after: impl Into<String>) -> Self {
+        self.before = Some(before.into());
+        self.after = Some(after.into());
+        self
+    }
+
+    /// Like `with_transition` for a subject whose status is only known once it was looked up,
+    /// `None` when the action failed before that
+    pub fn with_transition_from(
+        mut self,
+        before: Option<String>,
+        after: impl Into<String>,
+    ) -> Self {
+        self.before = before;
+        self.after = Some(after.into());
+        self
+    }
+
+    pub fn with_details(mut self, details: serde_json::Value) -> Self {
+        self.details = Some(details.to_string());
+        self
+    }
+
+    /// Marks the event failed with `error`, `None` leaves it succeeded
+    pub fn with_error(mut self, error: Option<impl ToString>) -> Self {
+        if let Some(error) = error {
+            self.outcome = AuditOutcome::Failed;
+            self.error = Some(error.to_string());
+        }
+        self
+    }
+}
+
+/// An event chained to the entry before it. Altering or removing an entry breaks the hash of
+/// every later one.
+#[derive(Serialize, Deserialize, Clone, Debug, ToSchema)]
+pub struct AuditEntry {
+    pub seq: u64,
+    pub at: u64,
+    #[serde(flatten)]
+    pub event: AuditEvent,
+    /// Version of the encoding `hash` was computed over
+    pub hash_version: u8,
+    pub prev_hash: String,
+    /// Hex encoded SHA-256 of `prev_hash`, `seq`, `at` and the event
+    pub hash: String,
+}
+
+impl AuditEntry {
+    /// Hashes a fixed encoding of the entry rather than its JSON, so that adding a field to
+    /// `AuditEvent` or reordering its fields leaves the hashes of earlier entries intact. Every
+    /// string is length prefixed and every optional one flagged, so that no two entries encode
+    /// alike.
+    fn compute_hash(prev_hash: &str, seq: u64, at: u64, event: &AuditEvent) -> String {
+        fn update_str(hasher: &mut Sha256, value: &str) {
+            hasher.update((value.len() as u64).to_be_bytes());
+            hasher.update(value.as_bytes());
+        }
+
+        fn update_opt_str(hasher: &mut Sha256, value: Option<&str>) {
+            match value {
+                Some(value) => {
+                    hasher.update([1]);
+                    update_str(hasher, value);
+                }
+                None => hasher.update([0]),
+            }
+        }
+
+        let mut hasher = Sha256::new();
+        hasher.update([HASH_VERSION]);
+        update_str(&mut hasher, prev_hash);
+        hasher.update(seq.to_be_bytes());
+        hasher.update(at.to_be_bytes());
+        update_str(&mut hasher, event.action.hash_name());
+        update_opt_str(&mut hasher, event.principal.as_deref());
+        update_opt_str(&mut hasher, event.request_id.as_deref());
+        update_opt_str(&mut hasher, event.subject.as_deref());
+        update_opt_str(&mut hasher, event.before.as_deref());
+        update_opt_str(&mut hasher, event.after.as_deref());
+        update_str(&mut hasher, event.outcome.hash_name());
+        update_opt_str(&mut hasher, event.error.as_deref());
+        update_opt_str(&mut hasher, event.details.as_deref());
+        hex::encode(hasher.finalize())
+    }
+}
+
+/// Outcome of checking the hash chain of the audit log
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, ToSchema)]
+pub struct AuditVerification {
+    pub valid: bool,
+    /// Entries checked, up to the first broken one
+    pub entries: u64,
+    /// Hash of the last entry, dropped entries at the end of the log only show against a head
+    /// hash noted earlier
+    pub head_hash: Option<String>,
+    /// Sequence number of the first entry that does not chain up
+    pub broken_at: Option<u64>,
+    pub error: Option<String>,
+}
+
+fn verify_chain(entries: impl IntoIterator<Item = (String, AuditEntry)>) -> AuditVerification {
+    let mut verification = AuditVerification {
+        valid: true,
+        entries: 0,
+        head_hash: None,
+        broken_at: None,
+        error: None,
+    };
+    let mut prev_hash = GENESIS_HASH.to_string();
+
+    for (key, entry) in entries {
+        let expected_seq = verification.entries;
+        let error = if key != entry_key(entry.seq) || entry.seq != expected_seq {
+            Some(format!("expected entry {} but found {}", expected_seq, key))
+        } else if entry.hash_version != HASH_VERSION {
+            Some(format!("unknown hash version {}", entry.hash_version))
+        } else if entry.prev_hash != prev_hash {
+            Some("prev_hash does not match the hash of the entry before".to_string())
+        } else if entry.hash
+            != AuditEntry::compute_hash(&prev_hash, entry.seq, entry.at, &entry.event)
+        {
+            Some("hash does not match the contents of the entry".to_string())
+        } else {
+            None
+        };
+
+        if let Some(error) = error {
+            verification.valid = false;
+            verification.broken_at = Some(expected_seq);
+            verification.error = Some(error);
+            return verification;
+        }
+
+        prev_hash = entry.hash;
+        verification.entries += 1;
+        verification.head_hash = Some(prev_hash.clone());
+    }
+
+    verification
+}
+
+fn entry_key(seq: u64) -> String {
+    format!("{:020}", seq)
+}
+
+/// Keys of the index entries pointing at the entries whose `field` is `value`
+fn index_prefix(field: &str, value: &str) -> String {
+    format!("{}\0{}\0", field, value)
+}
+
+/// Filters of an audit log query
+#[derive(Clone, Debug, Default)]
+pub struct AuditQuery {
+    pub principal: Option<String>,
+    pub subject: Option<String>,
+    pub action: Option<AuditAction>,
+    pub limit: usize,
+}
+
+impl AuditQuery {
+    fn matches(&self, entry: &AuditEntry) -> bool {
+        self.principal
+            .as_ref()
+            .is_none_or(|principal| entry.event.principal.as_ref() == Some(principal))
+            && self
+                .subject
+                .as_ref()
+                .is_none_or(|subject| entry.event.subject.as_ref() == Some(subject))
+            && self
+                .action
+                .is_none_or(|action| entry.event.action == action)
+    }
+}
+
+/// The appending end of the log. Only the writer thread holds it, which keeps appends in order
+/// without a lock.
+struct AuditChain {
+    entries: TypedTree<AuditEntry>,
+    index: TypedTree<u64>,
+    next_seq: u64,
+    head_hash: String,
+}
+
+impl AuditChain {
+    /// Picks the chain up after its last stored entry
+    fn open(entries: TypedTree<AuditEntry>, index: TypedTree<u64>) -> Result<Self, StoreError> {
+        let (next_seq, head_hash) = match entries.last()? {
+            Some(last) => (last.seq + 1, last.hash),
+            None => (0, GENESIS_HASH.to_string()),
+        };
+
+        Ok(Self {
+            entries,
+            index,
+            next_seq,
+            head_hash,
+        })
+    }
+
+    fn append(&mut self, event: AuditEvent) {
+        let at = now_unix_secs();
+        let entry = AuditEntry {
+            seq: self.next_seq,
+            at,
+            hash: AuditEntry::compute_hash(&self.head_hash, self.next_seq, at, &event),
+            hash_version: HASH_VERSION,
+            prev_hash: self.head_hash.clone(),
+            event,
+        };
+
+        let key = entry_key(entry.seq);
+        match self.entries.insert_if_absent(&key, &entry) {
+            Ok(true) => {}
+            Ok(false) => {
+                log::error!(
+                    "Failed to record audit entry {}: the sequence number is taken",
+                    entry.seq
+                );
+                return;
+            }
+            Err(e) => {
+                log::error!("Failed to record audit entry {:?}: {}", entry, e);
+                return;
+            }
+        }
+
+        self.next_seq += 1;
+        self.head_hash = entry.hash.clone();
+
+        let indexed = [
+            (PRINCIPAL_INDEX, entry.event.principal.as_deref()),
+            (SUBJECT_INDEX, entry.event.subject.as_deref()),
+        ];
+        for (field, value) in indexed {
+            let Some(value) = value else { continue };
+            let index_key = format!("{}{}", index_prefix(field, value), key);
+            if let Err(e) = self.index.insert(&index_key, &entry.seq) {
+                log::error!(
+                    "Failed to index audit entry {} by {}: {}",
+                    entry.seq,
+                    field,
+                    e
+                );
+            }
+        }
+    }
+}
+
+/// Append-only, hash-chained record of every state-changing action of the service and every
+/// admin API request
+#[derive(Clone)]
+pub struct AuditLog {
+    entries: TypedTree<AuditEntry>,
+    /// Entry sequence numbers by principal and by subject
+    index: TypedTree<u64>,
+    appender: mpsc::Sender<AuditEvent>,
+}
+
+impl AuditLog {
+    pub fn new(store: &Store) -> Result<Self, StoreError> {
+        let entries: TypedTree<AuditEntry> = store.tree("audit_log")?;
+        let index: TypedTree<u64> = store.tree("audit_log_index")?;
+        let mut chain = AuditChain::open(entries.clone(), index.clone())?;
+
+        // appends block on sled, so they run on a thread of their own rather than in the
+        // request handlers recording the events
+        let (appender, events) = mpsc::channel();
+        std::thread::spawn(move || {
+            for event in events {
+                chain.append(event);
+            }
+        });
+
+        Ok(Self {
+            entries,
+            index,
+            appender,
+        })
+    }
+
+    /// Hands the event to the writer thread and returns right away. The action it describes has
+    /// already happened, so an event that cannot be stored is only logged.
+    pub fn record(&self, event: AuditEvent) {
+        log::info!(
+            "Audit: {:?} of {} by {} -> {:?}",
+            event.action,
+            event.subject.as_deref().unwrap_or("-"),
+            event.principal.as_deref().unwrap_or("-"),
+            event.outcome
+        );
+
+        if let Err(mpsc::SendError(event)) = self.appender.send(event) {
+            log::error!("Audit log writer is gone, dropping {:?}", event);
+        }
+    }
+
+    /// The most recent entries matching the query, newest first. Queries by principal or
+    /// subject only read the entries of that principal or subject.
+    pub fn query(&self, query: &AuditQuery) -> Result<Vec<AuditEntry>, AppError> {
+        let candidates: Box<dyn Iterator<Item = Result<AuditEntry, StoreError>>> =
+            match (&query.principal, &query.subject) {
+                (Some(principal), _) => Box::new(self.indexed(PRINCIPAL_INDEX, principal)),
+                (None, Some(subject)) => Box::new(self.indexed(SUBJECT_INDEX, subject)),
+                (None, None) => Box::new(
+                    self.entries
+                        .entries_rev()
+                        .map(|entry| entry.map(|(_, entry)| entry)),
+                ),
+            };
+
+        let mut found = Vec::new();
+        for entry in candidates {
+            if found.len() >= query.limit {
+                break;
+            }
+
+            let entry = entry?;
+            if query.matches(&entry) {
+                found.push(entry);
+            }
+        }
+
+        Ok(found)
+    }
+
+    /// Entries whose `field` is `value`, newest first
+    fn indexed(
+        &self,
+        field: &str,
+        value: &str,
+    ) -> impl Iterator<Item = Result<AuditEntry, StoreError>> + use<> {
+        let entries = self.entries.clone();
+
+        self.index
+            .prefix_entries_rev(&index_prefix(field, value))
+            .filter_map(move |indexed| {
+                indexed
+                    .and_then(|(_, seq)| entries.get(&entry_key(seq)))
+                    .transpose()
+            })
+    }
+
+    /// Recomputes the hash chain from the first entry
+    pub fn verify(&self) -> Result<AuditVerification, AppError> {
+        Ok(verify_chain(self.entries.entries()?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use axum::{Router, body::Body, http::Request, middleware, routing::post};
+    use tower::ServiceExt;
+
+    use super::*;
+    use crate::utils::request_id::{REQUEST_ID_HEADER, RequestId, request_id_middleware};
+
+    fn open_chain(store: &Store) -> AuditChain {
+        AuditChain::open(
+            store.tree("audit_log").unwrap(),
+            store.tree("audit_log_index").unwrap(),
+        )
+        .unwrap()
+    }
+
+    fn publish_event(post_id: &str, principal: &str) -> AuditEvent {
+        AuditEvent::new(
+            AuditAction::Publish,
+            Some(post_id.to_string()),
+            Some(principal.to_string()),
+            None,
+        )
+    }
+
+    /// Waits for the writer thread to have appended `len` entries
+    fn wait_for_entries(audit_log: &AuditLog, len: usize) -> Vec<AuditEntry> {
+        let query = AuditQuery {
+            limit: usize::MAX,
+            ..AuditQuery::default()
+        };
+        for _ in 0..100 {
+            let entries = audit_log.query(&query).unwrap();
+            if entries.len() >= len {
+                return entries;
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        panic!("the audit log did not reach {} entries", len);
+    }
+
+    fn chain(len: u64) -> Vec<(String, AuditEntry)> {
+        let mut prev_hash = GENESIS_HASH.to_string();
+        (0..len)
+            .map(|seq| {
+                let event = AuditEvent::new(
+                    AuditAction::Publish,
+                    Some(format!("post-{}", seq)),
+                    Some("principal".to_string()),
+                    None,
+                )
+                .with_transition("draft", "published");
+                let hash = AuditEntry::compute_hash(&prev_hash, seq, 1_700_000_000, &event);
+                let entry = AuditEntry {
+                    seq,
+                    at: 1_700_000_000,
+                    event,
+                    hash_version: HASH_VERSION,
+                    prev_hash: std::mem::replace(&mut prev_hash, hash.clone()),
+                    hash,
+                };
+                (entry_key(seq), entry)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_intact_chain_verifies() {
+        let entries = chain(3);
+        let head_hash = entries[2].1.hash.clone();
+
+        assert_eq!(
+            verify_chain(entries),
+            AuditVerification {
+                valid: true,
+                entries: 3,
+                head_hash: Some(head_hash),
+                broken_at: None,
+                error: None,
+            }
+        );
+    }
+
+    #[test]
+    fn test_altered_entry_breaks_the_chain() {
+        let mut entries = chain(3);
+        entries[1].1.event.principal = Some("someone-else".to_string());
+
+        let verification = verify_chain(entries);
+        assert!(!verification.valid);
+        assert_eq!(verification.broken_at, Some(1));
+    }
+
+    #[test]
+    fn test_removed_entry_breaks_the_chain() {
+        let mut entries = chain(3);
+        entries.remove(1);
+
+        let verification = verify_chain(entries);
+        assert!(!verification.valid);
+        assert_eq!(verification.broken_at, Some(1));
+    }
+
+    #[test]
+    fn test_rehashed_entry_breaks_the_next_link() {
+        let mut entries = chain(3);
+        let entry = &mut entries[1].1;
+        entry.event.outcome = AuditOutcome::Failed;
+        entry.hash = AuditEntry::compute_hash(&entry.prev_hash, entry.seq, entry.at, &entry.event);
+
+        let verification = verify_chain(entries);
+        assert!(!verification.valid);
+        assert_eq!(verification.broken_at, Some(2));
+    }
+
+    #[test]
+    fn test_chain_head_is_recovered_after_a_reopen() {
+        let store = Store::temporary().unwrap();
+
+        let mut chain = open_chain(&store);
+        chain.append(publish_event("post-0", "principal"));
+        chain.append(publish_event("post-1", "principal"));
+        let head_hash = chain.head_hash.clone();
+        drop(chain);
+
+        let mut chain = open_chain(&store);
+        assert_eq!(chain.next_seq, 2);
+        assert_eq!(chain.head_hash, head_hash);
+
+        chain.append(publish_event("post-2", "principal"));
+        let verification = verify_chain(store.tree("audit_log").unwrap().entries().unwrap());
+        assert!(verification.valid);
+        assert_eq!(verification.entries, 3);
+    }
+
+    #[test]
+    fn test_query_by_principal_and_subject_uses_the_index() {
+        let store = Store::temporary().unwrap();
+        let mut chain = open_chain(&store);
+        chain.append(publish_event("post-0", "alice"));
+        chain.append(publish_event("post-1", "bob"));
+        chain.append(publish_event("post-2", "alice"));
+
+        let audit_log = AuditLog::new(&store).unwrap();
+        let query = |principal: Option<&str>, subject: Option<&str>, limit| {
+            audit_log
+                .query(&AuditQuery {
+                    principal: principal.map(str::to_string),
+                    subject: subject.map(str::to_string),
+                    action: None,
+                    limit,
+                })
+                .unwrap()
+                .into_iter()
+                .map(|entry| entry.seq)
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(query(Some("alice"), None, 10), vec![2, 0]);
+        assert_eq!(query(Some("alice"), None, 1), vec![2]);
+        assert_eq!(query(None, Some("post-1"), 10), vec![1]);
+        assert_eq!(query(Some("alice"), Some("post-1"), 10), Vec::<u64>::new());
+        assert_eq!(query(None, None, 2), vec![2, 1]);
+    }
+
+    #[test]
+    fn test_hash_tells_optional_fields_apart() {
+        let event = AuditEvent::new(AuditAction::Publish, Some("post".to_string()), None, None);
+        let mut moved = event.clone();
+        moved.subject = None;
+        moved.request_id = Some("post".to_string());
+
+        assert_ne!(
+            AuditEntry::compute_hash(GENESIS_HASH, 0, 0, &event),
+            AuditEntry::compute_hash(GENESIS_HASH, 0, 0, &moved)
+        );
+    }
+
+    #[test]
+    fn test_hash_covers_the_details() {
+        let event = AuditEvent::new(
+            AuditAction::Block,
+            Some("principal".to_string()),
+            None,
+            None,
+        )
+        .with_details(serde_json::json!({ "reason": "spam", "expires_at": null }));
+        let mut altered = event.clone();
+        altered.details =
+            Some(serde_json::json!({ "reason": "spam", "expires_at": 1 }).to_string());
+
+        assert_ne!(
+            AuditEntry::compute_hash(GENESIS_HASH, 0, 0, &event),
+            AuditEntry::compute_hash(GENESIS_HASH, 0, 0, &altered)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_request_id_reaches_the_entry() {
+        let audit_log = AuditLog::new(&Store::temporary().unwrap()).unwrap();
+        let handler_audit_log = audit_log.clone();
+        let app = Router::new()
+            .route(
+                "/publish",
+                post(move |request_id: RequestId| async move {
+                    handler_audit_log.record(AuditEvent::new(
+                        AuditAction::Publish,
+                        Some("post".to_string()),
+                        None,
+                        Some(request_id.0),
+                    ));
+                }),
+            )
+            .layer(middleware::from_fn(request_id_middleware));
+
+        let request = Request::builder()
+            .method("POST")
+            .uri("/publish")
+            .header(REQUEST_ID_HEADER, "request-1")
+            .body(Body::empty())
+            .unwrap();
+        let response = app.oneshot(request).await.unwrap();
+        assert_eq!(response.headers()[REQUEST_ID_HEADER], "request-1");
+
+        let entries = wait_for_entries(&audit_log, 1);
+        assert_eq!(entries[0].event.request_id.as_deref(), Some("request-1"));
+    }
+}
@@ -0,0 +1,217 @@
+use candid::Principal;
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+use crate::utils::{
+    store::{Store, StoreError, TypedTree},
+    time::now_unix_secs,
+    types::AppError,
+};
+
+#[derive(Serialize, Deserialize, Clone, Debug, ToSchema)]
+pub struct BlockedPrincipal {
+    #[schema(value_type = String, example = "principal-id-string")]
+    pub principal: Principal,
+    /// Shown to the user alongside the restriction
+    pub reason: Option<String>,
+    pub blocked_at: u64,
+    /// Unix timestamp in seconds at which the block lifts, `None` blocks until unblocked
+    pub expires_at: Option<u64>,
+    /// Admin that added the block
+    pub blocked_by: String,
+}
+
+impl BlockedPrincipal {
+    fn is_active(&self, now: u64) -> bool {
+        self.expires_at.is_none_or(|expires_at| expires_at > now)
+    }
+}
+
+/// Principals that may not upload, publish or manage their posts
+#[derive(Clone)]
+pub struct Blocklist {
+    blocked: TypedTree<BlockedPrincipal>,
+}
+
+impl Blocklist {
+    pub fn new(store: &Store) -> Result<Self, StoreError> {
+        Ok(Self {
+            blocked: store.tree("blocklist")?,
+        })
+    }
+
+    /// Blocks the principal, replacing an existing block of it
+    pub fn block(
+        &self,
+        principal: Principal,
+        reason: Option<String>,
+        expires_at: Option<u64>,
+        blocked_by: String,
+    ) -> Result<BlockedPrincipal, AppError> {
+        let now = now_unix_secs();
+        if expires_at.is_some_and(|expires_at| expires_at <= now) {
+            return Err(AppError::InvalidRequest(
+                "expires_at must be in the future".to_string(),
+            ));
+        }
+
+        let blocked = BlockedPrincipal {
+            principal,
+            reason,
+            blocked_at: now,
+            expires_at,
+            blocked_by,
+        };
+        self.blocked.insert(&principal.to_text(), &blocked)?;
+
+        Ok(blocked)
+    }
+
+    pub fn unblock(&self, principal: Principal) -> Result<BlockedPrincipal, AppError> {
+        self.blocked.remove(&principal.to_text())?.ok_or_else(|| {
+            AppError::BlockNotFound(format!("Principal {} is not blocked", principal))
+        })
+    }
+
+    /// Blocks in force, expired ones are dropped along the way
+    pub fn list(&self) -> Result<Vec<BlockedPrincipal>, AppError> {
+        let now = now_unix_secs();
+        let (active, expired): (Vec<_>, Vec<_>) = self
+            .blocked
+            .values()?
+            .into_iter()
+            .partition(|blocked| blocked.is_active(now));
+
+        for blocked in expired {
+            self.blocked.remove(&blocked.principal.to_text())?;
+        }
+
+        Ok(active)
+    }
+
+    /// Fails with `AccountRestricted` while the principal is blocked
+    pub fn ensure_not_blocked(&self, principal: Principal) -> Result<(), AppError> {
+        let Some(blocked) = self.blocked.get(&principal.to_text())? else {
+            return Ok(());
+        };
+        if !blocked.is_active(now_unix_secs()) {
+            return Ok(());
+        }
+
+        let until = blocked
+            .expires_at
+            .map_or("further notice".to_string(), |expires_at| {
+                format!("{} (unix time)", expires_at)
+            });
+        Err(AppError::AccountRestricted {
+            message: format!(
+                "{} until {}",
+                blocked.reason.as_deref().unwrap_or("Account is blocked"),
+                until
+            ),
+            expires_at: blocked.expires_at,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn blocklist() -> Blocklist {
+        Blocklist::new(&Store::temporary().unwrap()).unwrap()
+    }
+
+    fn principal() -> Principal {
+        Principal::self_authenticating(b"creator")
+    }
+
+    #[test]
+    fn test_block_is_active_until_it_expires() {
+        let blocked = BlockedPrincipal {
+            principal: principal(),
+            reason: None,
+            blocked_at: 100,
+            expires_at: Some(200),
+            blocked_by: "token:ops".to_string(),
+        };
+
+        assert!(blocked.is_active(199));
+        assert!(!blocked.is_active(200));
+        assert!(
+            BlockedPrincipal {
+                expires_at: None,
+                ..blocked
+            }
+            .is_active(u64::MAX)
+        );
+    }
+
+    #[test]
+    fn test_block_expiring_in_the_past_is_rejected() {
+        let result = blocklist().block(
+            principal(),
+            None,
+            Some(now_unix_secs()),
+            "token:ops".to_string(),
+        );
+
+        assert!(matches!(result, Err(AppError::InvalidRequest(_))));
+    }
+
+    #[test]
+    fn test_blocked_principal_is_restricted_until_unblocked() {
+        let blocklist = blocklist();
+        let expires_at = now_unix_secs() + 60;
+        blocklist
+            .block(
+                principal(),
+                Some("Spam".to_string()),
+                Some(expires_at),
+                "token:ops".to_string(),
+            )
+            .unwrap();
+
+        let error = blocklist.ensure_not_blocked(principal()).unwrap_err();
+        assert!(matches!(
+            error,
+            AppError::AccountRestricted { expires_at: Some(at), .. } if at == expires_at
+        ));
+        assert_eq!(blocklist.list().unwrap().len(), 1);
+
+        blocklist.unblock(principal()).unwrap();
+        assert!(blocklist.ensure_not_blocked(principal()).is_ok());
+        assert!(matches!(
+            blocklist.unblock(principal()),
+            Err(AppError::BlockNotFound(_))
+        ));
+    }
+
+    #[test]
+    fn test_expired_blocks_are_dropped_from_the_list() {
+        let blocklist = blocklist();
+        blocklist
+            .blocked
+            .insert(
+                &principal().to_text(),
+                &BlockedPrincipal {
+                    principal: principal(),
+                    reason: None,
+                    blocked_at: 100,
+                    expires_at: Some(200),
+                    blocked_by: "token:ops".to_string(),
+                },
+            )
+            .unwrap();
+
+        assert!(blocklist.ensure_not_blocked(principal()).is_ok());
+        assert!(blocklist.list().unwrap().is_empty());
+        assert!(
+            blocklist
+                .blocked
+                .get(&principal().to_text())
+                .unwrap()
+                .is_none()
+        );
+    }
+}
@@ -0,0 +1,119 @@
+use axum::{
+    body::{Body, to_bytes},
+    extract::{Request, State},
+    http::header::CONTENT_LENGTH,
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+
+use crate::utils::types::AppError;
+
+/// Middleware failing requests with bodies over `max_bytes` with a 413. A body announcing its
+/// length is rejected up front, one streamed without a length is buffered up to the limit.
+pub async fn body_limit_middleware(
+    State(max_bytes): State<usize>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let content_length = request
+        .headers()
+        .get(CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<usize>().ok());
+
+    match content_length {
+        Some(content_length) if content_length > max_bytes => {
+            too_large(request.uri().path(), max_bytes)
+        }
+        Some(_) => next.run(request).await,
+        None => {
+            let (parts, body) = request.into_parts();
+            match to_bytes(body, max_bytes).await {
+                Ok(body_bytes) => {
+                    next.run(Request::from_parts(parts, Body::from(body_bytes)))
+                        .await
+                }
+                Err(_) => too_large(parts.uri.path(), max_bytes),
+            }
+        }
+    }
+}
+
+fn too_large(path: &str, max_bytes: usize) -> Response {
+    log::warn!(
+        "Rejected request to {} with a body over {} bytes",
+        path,
+        max_bytes
+    );
+
+    AppError::PayloadTooLarge(format!("Request body exceeds {} bytes", max_bytes))
+        .to_api_response::<()>()
+        .into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{
+        Router,
+        http::{Request, StatusCode},
+        middleware,
+        routing::post,
+    };
+    use tower::ServiceExt;
+
+    use super::*;
+
+    const GLOBAL_MAX_BYTES: usize = 16;
+    const ROUTE_MAX_BYTES: usize = 8;
+
+    fn app() -> Router {
+        let body_limit =
+            |max_bytes| middleware::from_fn_with_state(max_bytes, body_limit_middleware);
+
+        Router::new()
+            .route(
+                "/limited",
+                post(|body: String| async move { body }).layer(body_limit(ROUTE_MAX_BYTES)),
+            )
+            .route("/unlimited", post(|body: String| async move { body }))
+            .layer(body_limit(GLOBAL_MAX_BYTES))
+    }
+
+    async fn status(path: &str, body_len: usize, with_length: bool) -> StatusCode {
+        let mut request = Request::builder().method("POST").uri(path);
+        if with_length {
+            request = request.header(CONTENT_LENGTH, body_len);
+        }
+        let request = request.body(Body::from(vec![b'a'; body_len])).unwrap();
+
+        app().oneshot(request).await.unwrap().status()
+    }
+
+    #[tokio::test]
+    async fn test_route_limit_applies_below_the_global_one() {
+        for with_length in [true, false] {
+            assert_eq!(
+                status("/limited", ROUTE_MAX_BYTES, with_length).await,
+                StatusCode::OK
+            );
+            assert_eq!(
+                status("/limited", ROUTE_MAX_BYTES + 1, with_length).await,
+                StatusCode::PAYLOAD_TOO_LARGE
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_global_limit_applies_to_every_route() {
+        for with_length in [true, false] {
+            assert_eq!(
+                status("/unlimited", GLOBAL_MAX_BYTES, with_length).await,
+                StatusCode::OK
+            );
+            assert_eq!(
+                status("/unlimited", GLOBAL_MAX_BYTES + 1, with_length).await,
+                StatusCode::PAYLOAD_TOO_LARGE
+            );
+        }
+    }
+}
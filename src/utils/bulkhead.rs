@@ -0,0 +1,126 @@
+use std::{sync::Arc, time::Duration};
+
+use prometheus::IntGauge;
+use thiserror::Error;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+use crate::utils::metrics::{BULKHEAD_QUEUE_DEPTH, BULKHEAD_REJECTIONS};
+
+#[derive(Error, Debug)]
+#[error("{0} is saturated, no call slot freed up in time")]
+pub struct BulkheadFullError(pub &'static str);
+
+#[derive(Clone, Copy, Debug)]
+pub struct BulkheadConfig {
+    /// Calls to the downstream that may be in flight at once
+    pub max_concurrent_calls: usize,
+    /// How long a call waits for a free slot before it is rejected
+    pub queue_timeout: Duration,
+}
+
+/// Caps the concurrent calls to one downstream so a spike against it cannot exhaust
+/// connections or starve calls to the others.
+#[derive(Clone, Debug)]
+pub struct Bulkhead {
+    name: &'static str,
+    queue_timeout: Duration,
+    semaphore: Arc<Semaphore>,
+}
+
+impl Bulkhead {
+    pub fn new(name: &'static str, config: BulkheadConfig) -> Self {
+        BULKHEAD_QUEUE_DEPTH.with_label_values(&[name]).set(0);
+
+        Self {
+            name,
+            queue_timeout: config.queue_timeout,
+            semaphore: Arc::new(Semaphore::new(config.max_concurrent_calls.max(1))),
+        }
+    }
+
+    /// Waits for a free slot, which is held until the returned permit is dropped.
+    pub async fn acquire(&self) -> Result<OwnedSemaphorePermit, BulkheadFullError> {
+        let queued = QueuedCall::new(self.name);
+        let permit =
+            tokio::time::timeout(self.queue_timeout, self.semaphore.clone().acquire_owned()).await;
+        drop(queued);
+
+        match permit {
+            Ok(Ok(permit)) => Ok(permit),
+            // the semaphore is never closed, so only the timeout ends up here
+            Ok(Err(_)) | Err(_) => {
+                BULKHEAD_REJECTIONS.with_label_values(&[self.name]).inc();
+                Err(BulkheadFullError(self.name))
+            }
+        }
+    }
+}
+
+/// Counts a call in the queue depth while it waits for a slot, including when the waiting
+/// caller is dropped.
+struct QueuedCall(IntGauge);
+
+impl QueuedCall {
+    fn new(name: &str) -> Self {
+        let queue_depth = BULKHEAD_QUEUE_DEPTH.with_label_values(&[name]);
+        queue_depth.inc();
+        Self(queue_depth)
+    }
+}
+
+impl Drop for QueuedCall {
+    fn drop(&mut self) {
+        self.0.dec();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bulkhead(queue_timeout: Duration) -> Bulkhead {
+        Bulkhead::new(
+            "test",
+            BulkheadConfig {
+                max_concurrent_calls: 1,
+                queue_timeout,
+            },
+        )
+    }
+
+    #[tokio::test]
+    async fn test_rejects_once_queue_timeout_elapses() {
+        let bulkhead = bulkhead(Duration::from_millis(10));
+        let _held = bulkhead.acquire().await.unwrap();
+
+        assert!(bulkhead.acquire().await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_cancelled_wait_leaves_the_queue() {
+        let bulkhead = Bulkhead::new(
+            "test_cancelled_wait",
+            BulkheadConfig {
+                max_concurrent_calls: 1,
+                queue_timeout: Duration::from_secs(60),
+            },
+        );
+        let queue_depth = BULKHEAD_QUEUE_DEPTH.with_label_values(&["test_cancelled_wait"]);
+        let _held = bulkhead.acquire().await.unwrap();
+
+        let waiting = tokio::time::timeout(Duration::from_millis(10), bulkhead.acquire()).await;
+
+        assert!(waiting.is_err());
+        assert_eq!(queue_depth.get(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_slot_is_released_with_permit() {
+        let bulkhead = bulkhead(Duration::from_millis(10));
+
+        for _ in 0..3 {
+            let permit = bulkhead.acquire().await;
+            assert!(permit.is_ok());
+        }
+    }
+}
@@ -0,0 +1,373 @@
+use std::{
+    future::Future,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use serde::Serialize;
+use thiserror::Error;
+use tokio::sync::OwnedSemaphorePermit;
+
+use crate::utils::{
+    bulkhead::{Bulkhead, BulkheadFullError},
+    metrics::{CIRCUIT_BREAKER_REJECTIONS, CIRCUIT_BREAKER_STATE},
+};
+
+#[derive(Error, Debug)]
+#[error("{0} is unavailable, circuit breaker is open")]
+pub struct CircuitOpenError(pub &'static str);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BreakerState {
+    Closed,
+    Open,
+    HalfOpen,
+}
+
+impl BreakerState {
+    fn metric_value(self) -> i64 {
+        match self {
+            BreakerState::Closed => 0,
+            BreakerState::HalfOpen => 1,
+            BreakerState::Open => 2,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct BreakerConfig {
+    /// Consecutive failures after which the breaker opens
+    pub failure_threshold: u32,
+    /// How long the breaker stays open before a probe call is let through
+    pub open_duration: Duration,
+}
+
+#[derive(Debug)]
+struct BreakerInner {
+    state: BreakerState,
+    consecutive_failures: u32,
+    /// When the breaker last opened, or when the current half-open probe started
+    since: Instant,
+}
+
+/// Fails calls to a downstream fast once it failed `failure_threshold` times in a row. After
+/// `open_duration` a single probe call is let through, which closes the breaker on success.
+/// Calls that get through also wait for a slot of the downstream's bulkhead, if it has one.
+#[derive(Clone, Debug)]
+pub struct CircuitBreaker {
+    name: &'static str,
+    config: BreakerConfig,
+    inner: Arc<Mutex<BreakerInner>>,
+    bulkhead: Option<Bulkhead>,
+}
+
+impl CircuitBreaker {
+    pub fn new(name: &'static str, config: BreakerConfig) -> Self {
+        CIRCUIT_BREAKER_STATE
+            .with_label_values(&[name])
+            .set(BreakerState::Closed.metric_value());
+
+        Self {
+            name,
+            config,
+            inner: Arc::new(Mutex::new(BreakerInner {
+                state: BreakerState::Closed,
+                consecutive_failures: 0,
+                since: Instant::now(),
+            })),
+            bulkhead: None,
+        }
+    }
+
+    pub fn with_bulkhead(mut self, bulkhead: Bulkhead) -> Self {
+        self.bulkhead = Some(bulkhead);
+        self
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    /// An open breaker whose `open_duration` elapsed is reported half-open, the next call
+    /// through it probes the downstream.
+    pub fn state(&self) -> BreakerState {
+        let inner = self.inner.lock().unwrap();
+
+        match inner.state {
+            BreakerState::Open if inner.since.elapsed() >= self.config.open_duration => {
+                BreakerState::HalfOpen
+            }
+            state => state,
+        }
+    }
+
+    fn set_state(&self, inner: &mut BreakerInner, state: BreakerState) {
+        if inner.state != state {
+            log::warn!(
+                "Circuit breaker for {} moved from {:?} to {:?}",
+                self.name,
+                inner.state,
+                state
+            );
+        }
+
+        inner.state = state;
+        inner.since = Instant::now();
+        CIRCUIT_BREAKER_STATE
+            .with_label_values(&[self.name])
+            .set(state.metric_value());
+    }
+
+    fn try_acquire(&self) -> Result<(), CircuitOpenError> {
+        let mut inner = self.inner.lock().unwrap();
+
+        match inner.state {
+            BreakerState::Closed => Ok(()),
+            // a probe that never reported back does not keep the breaker half-open forever
+            BreakerState::Open | BreakerState::HalfOpen
+                if inner.since.elapsed() >= self.config.open_duration =>
+            {
+                self.set_state(&mut inner, BreakerState::HalfOpen);
+                Ok(())
+            }
+            BreakerState::Open | BreakerState::HalfOpen => {
+                CIRCUIT_BREAKER_REJECTIONS
+                    .with_label_values(&[self.name])
+                    .inc();
+                Err(CircuitOpenError(self.name))
+            }
+        }
+    }
+
+    fn record_success(&self) {
+        let mut inner = self.inner.lock().unwrap();
+
+        inner.consecutive_failures = 0;
+        if inner.state != BreakerState::Closed {
+            self.set_state(&mut inner, BreakerState::Closed);
+        }
+    }
+
+    fn record_failure(&self) {
+        let mut inner = self.inner.lock().unwrap();
+
+        inner.consecutive_failures += 1;
+        if inner.state == BreakerState::HalfOpen
+            || inner.consecutive_failures >= self.config.failure_threshold
+        {
+            self.set_state(&mut inner, BreakerState::Open);
+        }
+    }
+
+    /// Runs `call` unless the breaker is open, recording its outcome. A call rejected by the
+    /// bulkhead never reached the downstream, so it does not count as a failure.
+    pub async fn call<T, E, Fut>(&self, call: Fut) -> Result<T, E>
+    where
+        Fut: Future<Output = Result<T, E>>,
+        E: From<CircuitOpenError> + From<BulkheadFullError>,
+    {
+        self.call_counting(call, |_| true).await
+    }
+
+    /// Like `call`, but only errors `is_failure` accepts count against the downstream. Any other
+    /// error shows the downstream answered, so it counts as a success.
+    pub async fn call_counting<T, E, Fut>(
+        &self,
+        call: Fut,
+        is_failure: impl FnOnce(&E) -> bool,
+    ) -> Result<T, E>
+    where
+        Fut: Future<Output = Result<T, E>>,
+        E: From<CircuitOpenError> + From<BulkheadFullError>,
+    {
+        self.try_acquire()?;
+
+        let _permit = self.acquire_slot().await?;
+
+        let result = call.await;
+        match &result {
+            Err(e) if is_failure(e) => self.record_failure(),
+            _ => self.record_success(),
+        }
+
+        result
+    }
+
+    /// Like `call_counting`, but leaves the bulkhead to `call`, which takes a slot with
+    /// `acquire_slot` for each of its attempts so that it holds none while backing off between
+    /// retries. `is_failure` returns `None` for errors that never reached the downstream, such as
+    /// a full bulkhead, which are not recorded either way.
+    pub async fn call_per_attempt<T, E, Fut>(
+        &self,
+        call: Fut,
+        is_failure: impl FnOnce(&E) -> Option<bool>,
+    ) -> Result<T, E>
+    where
+        Fut: Future<Output = Result<T, E>>,
+        E: From<CircuitOpenError>,
+    {
+        self.try_acquire()?;
+
+        let result = call.await;
+        match result.as_ref().err().map(is_failure) {
+            Some(None) => {}
+            Some(Some(true)) => self.record_failure(),
+            _ => self.record_success(),
+        }
+
+        result
+    }
+
+    /// Waits for a slot of the downstream's bulkhead, if it has one, held until the returned
+    /// permit is dropped
+    pub async fn acquire_slot(&self) -> Result<Option<OwnedSemaphorePermit>, BulkheadFullError> {
+        match &self.bulkhead {
+            Some(bulkhead) => Ok(Some(bulkhead.acquire().await?)),
+            None => Ok(None),
+        }
+    }
+}
+
+/// One breaker per downstream service
+#[derive(Clone)]
+pub struct CircuitBreakers {
+    pub events: CircuitBreaker,
+    pub notifications: CircuitBreaker,
+    pub storj: CircuitBreaker,
+    pub ic_agent: CircuitBreaker,
+}
+
+impl CircuitBreakers {
+    pub fn all(&self) -> [&CircuitBreaker; 4] {
+        [
+            &self.events,
+            &self.notifications,
+            &self.storj,
+            &self.ic_agent,
+        ]
+    }
+
+    /// Breakers whose downstream an upload cannot complete without
+    pub fn critical(&self) -> [&CircuitBreaker; 2] {
+        [&self.storj, &self.ic_agent]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::error::Error;
+
+    use super::*;
+    use crate::utils::bulkhead::BulkheadConfig;
+
+    fn breaker(open_duration: Duration) -> CircuitBreaker {
+        CircuitBreaker::new(
+            "test",
+            BreakerConfig {
+                failure_threshold: 2,
+                open_duration,
+            },
+        )
+    }
+
+    #[test]
+    fn test_opens_after_consecutive_failures() {
+        let breaker = breaker(Duration::from_secs(60));
+
+        breaker.record_failure();
+        breaker.record_success();
+        breaker.record_failure();
+        assert_eq!(breaker.state(), BreakerState::Closed);
+
+        breaker.record_failure();
+        assert_eq!(breaker.state(), BreakerState::Open);
+        assert!(breaker.try_acquire().is_err());
+    }
+
+    #[test]
+    fn test_half_open_probe() {
+        let open_duration = Duration::from_millis(20);
+        let breaker = breaker(open_duration);
+
+        breaker.record_failure();
+        breaker.record_failure();
+        assert_eq!(breaker.state(), BreakerState::Open);
+
+        std::thread::sleep(open_duration);
+        assert_eq!(breaker.state(), BreakerState::HalfOpen);
+        assert!(breaker.try_acquire().is_ok());
+        assert_eq!(breaker.state(), BreakerState::HalfOpen);
+
+        breaker.record_failure();
+        assert_eq!(breaker.state(), BreakerState::Open);
+
+        std::thread::sleep(open_duration);
+        assert!(breaker.try_acquire().is_ok());
+        breaker.record_success();
+        assert_eq!(breaker.state(), BreakerState::Closed);
+    }
+
+    #[tokio::test]
+    async fn test_errors_not_counted_as_failures_keep_the_breaker_closed() {
+        let breaker = breaker(Duration::from_secs(60));
+
+        for _ in 0..3 {
+            let result: Result<(), Box<dyn Error>> = breaker
+                .call_counting(async { Err("404 - not found".into()) }, |_| false)
+                .await;
+            assert!(result.is_err());
+        }
+        assert_eq!(breaker.state(), BreakerState::Closed);
+    }
+
+    #[tokio::test]
+    async fn test_call_per_attempt_holds_no_slot_between_attempts() {
+        let breaker = breaker(Duration::from_secs(60)).with_bulkhead(Bulkhead::new(
+            "test",
+            BulkheadConfig {
+                max_concurrent_calls: 1,
+                queue_timeout: Duration::from_millis(10),
+            },
+        ));
+
+        let result: Result<(), Box<dyn Error>> = breaker
+            .call_per_attempt(
+                async {
+                    for _ in 0..2 {
+                        let _permit = breaker.acquire_slot().await?;
+                    }
+                    Err("503 - unavailable".into())
+                },
+                |_| Some(true),
+            )
+            .await;
+        assert_eq!(result.unwrap_err().to_string(), "503 - unavailable");
+
+        let result: Result<(), Box<dyn Error>> = breaker
+            .call_counting(
+                async {
+                    breaker.acquire_slot().await?;
+                    Ok(())
+                },
+                |_| true,
+            )
+            .await;
+        assert!(result.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_call_per_attempt_does_not_record_calls_that_never_reached_the_downstream() {
+        let breaker = breaker(Duration::from_secs(60));
+
+        breaker.record_failure();
+        let result: Result<(), Box<dyn Error>> = breaker
+            .call_per_attempt(async { Err("bulkhead full".into()) }, |_| None)
+            .await;
+        assert!(result.is_err());
+        assert_eq!(breaker.state(), BreakerState::Closed);
+
+        breaker.record_failure();
+        assert_eq!(breaker.state(), BreakerState::Open);
+    }
+}
@@ -0,0 +1,285 @@
+use std::{
+    error::Error,
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+    time::{Duration, SystemTime},
+};
+
+use regex::{Captures, Regex};
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum FilterAction {
+    /// Refuse the upload altogether
+    Reject,
+    /// Replace the matched text with `*`
+    Mask,
+    /// Publish as is but mark the post for manual review
+    Flag,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+pub struct FilterRuleConfig {
+    pub id: String,
+    /// A plain word (matched case-insensitively on word boundaries) or a regex if `is_regex` is set
+    pub pattern: String,
+    #[serde(default)]
+    pub is_regex: bool,
+    pub action: FilterAction,
+}
+
+#[derive(Deserialize)]
+struct FilterRulesFile {
+    rules: Vec<FilterRuleConfig>,
+}
+
+struct FilterRule {
+    id: String,
+    action: FilterAction,
+    regex: Regex,
+}
+
+impl FilterRule {
+    fn compile(config: FilterRuleConfig) -> Result<Self, regex::Error> {
+        let pattern = if config.is_regex {
+            format!("(?i){}", config.pattern)
+        } else {
+            format!(r"(?i)\b{}\b", regex::escape(&config.pattern))
+        };
+
+        Ok(Self {
+            id: config.id,
+            action: config.action,
+            regex: Regex::new(&pattern)?,
+        })
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct FilterMatch {
+    pub rule_id: String,
+    pub action: FilterAction,
+    pub field: String,
+}
+
+#[derive(Debug, Default)]
+pub struct FilterOutcome {
+    /// Description with masked terms replaced
+    pub description: String,
+    /// Hashtags with masked terms replaced
+    pub hashtags: Vec<String>,
+    pub matches: Vec<FilterMatch>,
+}
+
+impl FilterOutcome {
+    pub fn rejected_by(&self) -> Option<&FilterMatch> {
+        self.matches
+            .iter()
+            .find(|m| m.action == FilterAction::Reject)
+    }
+
+    pub fn is_flagged(&self) -> bool {
+        self.matches.iter().any(|m| m.action == FilterAction::Flag)
+    }
+}
+
+/// Blocked-terms filter applied to creator supplied text.
+///
+/// Rules are loaded from a JSON file of the form
+/// `{ "rules": [{ "id": "...", "pattern": "...", "is_regex": false, "action": "reject" }] }`
+/// and can be swapped at runtime through [`ContentFilter::reload`].
+#[derive(Clone)]
+pub struct ContentFilter {
+    rules: Arc<RwLock<Arc<Vec<FilterRule>>>>,
+    source: Option<PathBuf>,
+}
+
+impl ContentFilter {
+    pub fn disabled() -> Self {
+        Self {
+            rules: Arc::new(RwLock::new(Arc::new(Vec::new()))),
+            source: None,
+        }
+    }
+
+    pub fn from_rules(rules: Vec<FilterRuleConfig>) -> Result<Self, Box<dyn Error>> {
+        let compiled = rules
+            .into_iter()
+            .map(FilterRule::compile)
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(Self {
+            rules: Arc::new(RwLock::new(Arc::new(compiled))),
+            source: None,
+        })
+    }
+
+    pub fn from_file(path: PathBuf) -> Result<Self, Box<dyn Error>> {
+        let rules = Self::load_rules(&path)?;
+
+        Ok(Self {
+            rules: Arc::new(RwLock::new(Arc::new(rules))),
+            source: Some(path),
+        })
+    }
+
+    fn load_rules(path: &Path) -> Result<Vec<FilterRule>, Box<dyn Error>> {
+        let contents = std::fs::read_to_string(path)?;
+        let rules_file: FilterRulesFile = serde_json::from_str(&contents)?;
+
+        rules_file
+            .rules
+            .into_iter()
+            .map(|rule| FilterRule::compile(rule).map_err(|e| e.into()))
+            .collect()
+    }
+
+    /// Re-reads the rules file. The current rules stay in place if the new file is invalid.
+    pub fn reload(&self) -> Result<usize, Box<dyn Error>> {
+        let Some(path) = &self.source else {
+            return Ok(0);
+        };
+
+        let rules = Self::load_rules(path)?;
+        let rules_count = rules.len();
+        *self.rules.write().unwrap() = Arc::new(rules);
+
+        Ok(rules_count)
+    }
+
+    /// Polls the rules file and reloads it whenever its modification time changes.
+    pub fn spawn_reloader(&self, interval: Duration) {
+        let Some(path) = self.source.clone() else {
+            return;
+        };
+
+        let filter = self.clone();
+        tokio::spawn(async move {
+            let mut last_modified = modified_at(&path);
+            let mut ticker = tokio::time::interval(interval);
+
+            loop {
+                ticker.tick().await;
+
+                let modified = modified_at(&path);
+                if modified == last_modified {
+                    continue;
+                }
+                last_modified = modified;
+
+                match filter.reload() {
+                    Ok(rules_count) => {
+                        log::info!("Reloaded {} content filter rules", rules_count)
+                    }
+                    Err(e) => log::error!("Failed to reload content filter rules: {}", e),
+                }
+            }
+        });
+    }
+
+    pub fn apply(&self, description: &str, hashtags: &[String]) -> FilterOutcome {
+        let rules = self.rules.read().unwrap().clone();
+        let mut matches = Vec::new();
+
+        let description = screen_text(&rules, "description", description, &mut matches);
+        let hashtags = hashtags
+            .iter()
+            .map(|hashtag| screen_text(&rules, "hashtags", hashtag, &mut matches))
+            .collect();
+
+        FilterOutcome {
+            description,
+            hashtags,
+            matches,
+        }
+    }
+}
+
+fn modified_at(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+fn screen_text(
+    rules: &[FilterRule],
+    field: &str,
+    text: &str,
+    matches: &mut Vec<FilterMatch>,
+) -> String {
+    let mut screened = text.to_string();
+
+    for rule in rules {
+        if !rule.regex.is_match(&screened) {
+            continue;
+        }
+
+        matches.push(FilterMatch {
+            rule_id: rule.id.clone(),
+            action: rule.action,
+            field: field.to_string(),
+        });
+
+        if rule.action == FilterAction::Mask {
+            screened = rule
+                .regex
+                .replace_all(&screened, |caps: &Captures| {
+                    "*".repeat(caps[0].chars().count())
+                })
+                .into_owned();
+        }
+    }
+
+    screened
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule(id: &str, pattern: &str, is_regex: bool, action: FilterAction) -> FilterRuleConfig {
+        FilterRuleConfig {
+            id: id.to_string(),
+            pattern: pattern.to_string(),
+            is_regex,
+            action,
+        }
+    }
+
+    #[test]
+    fn test_mask_replaces_whole_words_only() {
+        let filter =
+            ContentFilter::from_rules(vec![rule("mask-ass", "ass", false, FilterAction::Mask)])
+                .unwrap();
+
+        let outcome = filter.apply("Kick ASS, not a class act", &["ass".to_string()]);
+
+        assert_eq!(outcome.description, "Kick ***, not a class act");
+        assert_eq!(outcome.hashtags, vec!["***".to_string()]);
+        assert_eq!(outcome.matches.len(), 2);
+        assert!(outcome.rejected_by().is_none());
+        assert!(!outcome.is_flagged());
+    }
+
+    #[test]
+    fn test_reject_and_flag_rules_are_reported() {
+        let filter = ContentFilter::from_rules(vec![
+            rule("scam-link", r"bit\.ly/\w+", true, FilterAction::Reject),
+            rule("review-giveaway", "giveaway", false, FilterAction::Flag),
+        ])
+        .unwrap();
+
+        let outcome = filter.apply("Giveaway at bit.ly/abc", &[]);
+
+        assert_eq!(outcome.rejected_by().unwrap().rule_id, "scam-link");
+        assert!(outcome.is_flagged());
+        assert_eq!(outcome.description, "Giveaway at bit.ly/abc");
+    }
+
+    #[test]
+    fn test_disabled_filter_passes_text_through() {
+        let outcome = ContentFilter::disabled().apply("anything goes", &["tag".to_string()]);
+
+        assert!(outcome.matches.is_empty());
+        assert_eq!(outcome.description, "anything goes");
+    }
+}
@@ -0,0 +1,274 @@
+use std::time::Duration;
+
+use axum::http::{HeaderName, HeaderValue, Method, header};
+use tower_http::cors::{AllowOrigin, CorsLayer};
+
+use crate::utils::{
+    idempotency::IDEMPOTENCY_KEY_HEADER,
+    request_auth::{
+        DELEGATION_CHAIN_HEADER, REQUEST_NONCE_HEADER, REQUEST_SIGNATURE_HEADER,
+        REQUEST_TIMESTAMP_HEADER,
+    },
+    request_id::REQUEST_ID_HEADER,
+};
+
+/// Which browser origins may call the service and how
+#[derive(Clone, Debug)]
+pub struct CorsConfig {
+    /// Origins allowed to call the service, `None` allows any origin
+    pub allowed_origins: Option<Vec<HeaderValue>>,
+    pub allowed_methods: Vec<Method>,
+    pub allowed_headers: Vec<HeaderName>,
+    /// Whether browsers may send cookies and `Authorization` along, needs explicit origins
+    pub allow_credentials: bool,
+    /// How long browsers may cache a preflight response
+    pub max_age: Duration,
+}
+
+impl CorsConfig {
+    /// Origins of the web app, allowed when none are configured
+    pub fn default_allowed_origins() -> Vec<HeaderValue> {
+        vec![
+            HeaderValue::from_static("https://yral.com"),
+            HeaderValue::from_static("https://www.yral.com"),
+        ]
+    }
+
+    /// Methods the routes of the service are served on
+    pub fn default_allowed_methods() -> Vec<Method> {
+        vec![Method::GET, Method::POST]
+    }
+
+    /// Headers a browser client sends: JSON bodies, admin tokens, idempotency keys, request ids
+    /// and signed request envelopes
+    pub fn default_allowed_headers() -> Vec<HeaderName> {
+        let mut headers = vec![header::CONTENT_TYPE, header::AUTHORIZATION];
+        headers.extend(
+            [
+                IDEMPOTENCY_KEY_HEADER,
+                REQUEST_ID_HEADER,
+                DELEGATION_CHAIN_HEADER,
+                REQUEST_TIMESTAMP_HEADER,
+                REQUEST_NONCE_HEADER,
+                REQUEST_SIGNATURE_HEADER,
+            ]
+            .into_iter()
+            .map(|name| HeaderName::from_bytes(name.as_bytes()).unwrap()),
+        );
+        headers
+    }
+
+    /// Layer answering preflight requests and adding the CORS headers to every response
+    pub fn layer(&self) -> CorsLayer {
+        let allow_origin = match &self.allowed_origins {
+            Some(origins) => AllowOrigin::list(origins.iter().cloned()),
+            None => AllowOrigin::any(),
+        };
+
+        CorsLayer::new()
+            .allow_origin(allow_origin)
+            .allow_methods(self.allowed_methods.clone())
+            .allow_headers(self.allowed_headers.clone())
+            .allow_credentials(self.allow_credentials)
+            .expose_headers([HeaderName::from_bytes(REQUEST_ID_HEADER.as_bytes()).unwrap()])
+            .max_age(self.max_age)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{
+        Router,
+        body::Body,
+        http::{Request, StatusCode},
+    };
+    use tower::ServiceExt;
+    use utoipa::OpenApi;
+
+    use super::*;
+    use crate::{
+        ApiDoc, build_app_state, build_router,
+        config::AppConfig,
+        utils::{request_auth::RequestVerifier, store::Store},
+    };
+
+    const ORIGIN: &str = "https://app.example.com";
+
+    fn config() -> CorsConfig {
+        CorsConfig {
+            allowed_origins: Some(vec![HeaderValue::from_static(ORIGIN)]),
+            allowed_methods: CorsConfig::default_allowed_methods(),
+            allowed_headers: CorsConfig::default_allowed_headers(),
+            allow_credentials: true,
+            max_age: Duration::from_secs(600),
+        }
+    }
+
+    /// Every documented route with the methods it is served on, path parameters filled in
+    fn routes() -> Vec<(String, Method)> {
+        ApiDoc::openapi()
+            .paths
+            .paths
+            .into_iter()
+            .flat_map(|(path, item)| {
+                [
+                    (Method::GET, item.get.is_some()),
+                    (Method::POST, item.post.is_some()),
+                    (Method::PUT, item.put.is_some()),
+                    (Method::PATCH, item.patch.is_some()),
+                    (Method::DELETE, item.delete.is_some()),
+                ]
+                .into_iter()
+                .filter(|(_, served)| *served)
+                .map(move |(method, _)| (path.clone(), method))
+            })
+            .map(|(path, method)| {
+                let path = path
+                    .split('/')
+                    .map(|segment| {
+                        if segment.starts_with('{') {
+                            "x"
+                        } else {
+                            segment
+                        }
+                    })
+                    .collect::<Vec<_>>()
+                    .join("/");
+                (path, method)
+            })
+            .collect()
+    }
+
+    /// The router as served, with `cors` as its policy
+    fn app(cors: &CorsConfig) -> Router {
+        let mut config = AppConfig::from_env();
+        config.cors = cors.clone();
+        let store = Store::temporary().unwrap();
+        let ic_admin_agent = ic_agent::Agent::builder()
+            .with_url("http://127.0.0.1:9")
+            .build()
+            .unwrap();
+        let app_state = build_app_state(
+            &config,
+            &store,
+            ic_admin_agent,
+            "test".to_string(),
+            "test".to_string(),
+        );
+        let request_verifier = RequestVerifier::new(
+            &store,
+            app_state.delegation_policy.clone(),
+            config.signed_request_clock_skew,
+        )
+        .unwrap();
+
+        build_router(app_state, &config, request_verifier)
+    }
+
+    fn preflight(path: &str, origin: &str, method: &Method) -> Request<Body> {
+        Request::builder()
+            .method(Method::OPTIONS)
+            .uri(path)
+            .header(header::ORIGIN, origin)
+            .header(header::ACCESS_CONTROL_REQUEST_METHOD, method.as_str())
+            .header(
+                header::ACCESS_CONTROL_REQUEST_HEADERS,
+                format!(
+                    "content-type,authorization,{},{},{},{},{}",
+                    IDEMPOTENCY_KEY_HEADER,
+                    DELEGATION_CHAIN_HEADER,
+                    REQUEST_TIMESTAMP_HEADER,
+                    REQUEST_NONCE_HEADER,
+                    REQUEST_SIGNATURE_HEADER
+                )
+                .to_lowercase(),
+            )
+            .body(Body::empty())
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_preflight_allowed_on_every_route() {
+        let config = config();
+        let routes = routes();
+        assert!(!routes.is_empty());
+
+        let app = app(&config);
+        for (path, method) in routes {
+            let response = app
+                .clone()
+                .oneshot(preflight(&path, ORIGIN, &method))
+                .await
+                .unwrap();
+            let headers = response.headers();
+
+            assert_eq!(response.status(), StatusCode::OK, "{} {}", method, path);
+            assert_eq!(headers[header::ACCESS_CONTROL_ALLOW_ORIGIN], ORIGIN);
+            assert_eq!(headers[header::ACCESS_CONTROL_ALLOW_CREDENTIALS], "true");
+            assert!(
+                headers[header::ACCESS_CONTROL_ALLOW_METHODS]
+                    .to_str()
+                    .unwrap()
+                    .contains(method.as_str()),
+                "{} {}",
+                method,
+                path
+            );
+            let allowed_headers = headers[header::ACCESS_CONTROL_ALLOW_HEADERS]
+                .to_str()
+                .unwrap();
+            for name in CorsConfig::default_allowed_headers() {
+                assert!(allowed_headers.contains(name.as_str()));
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_preflight_from_unknown_origin_is_not_allowed() {
+        let config = config();
+
+        let app = app(&config);
+        for (path, method) in routes() {
+            let response = app
+                .clone()
+                .oneshot(preflight(&path, "https://evil.example.com", &method))
+                .await
+                .unwrap();
+
+            assert!(
+                !response
+                    .headers()
+                    .contains_key(header::ACCESS_CONTROL_ALLOW_ORIGIN),
+                "{} {}",
+                method,
+                path
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_web_app_is_allowed_by_default() {
+        let config = CorsConfig {
+            allowed_origins: Some(CorsConfig::default_allowed_origins()),
+            allow_credentials: false,
+            ..config()
+        };
+
+        let response = app(&config)
+            .oneshot(
+                Request::builder()
+                    .uri("/health")
+                    .header(header::ORIGIN, "https://yral.com")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            response.headers()[header::ACCESS_CONTROL_ALLOW_ORIGIN],
+            "https://yral.com"
+        );
+    }
+}
@@ -0,0 +1,502 @@
+use candid::Principal;
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+use uuid::Uuid;
+use yral_canisters_client::user_post_service::PostStatus;
+
+use crate::{
+    api::{
+        mark_post_as_published::{fetch_post_details, post_status_label, publish_post},
+        update_video_metadata::{PreparedUpload, replay_canister_write},
+    },
+    app_state::AppState,
+    utils::{
+        outbox::OutboxMessage,
+        store::{Store, StoreError, TypedTree},
+        time::now_unix_secs,
+        types::AppError,
+    },
+};
+
+/// A replay started this long ago is assumed to have died with the process that ran it
+const STALE_REPLAY_SECS: u64 = 15 * 60;
+
+/// Most dead letters replayed by one request, so that it finishes well within an HTTP timeout
+pub const MAX_REPLAY_BATCH: usize = 20;
+
+/// A publish pipeline operation that failed for good, with everything needed to replay it
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum DeadLetterOperation {
+    /// An event or notification the outbox gave up delivering
+    OutboxDelivery { message: OutboxMessage },
+    /// A scheduled publish that kept failing
+    ScheduledPublish {
+        post_id: String,
+        creator_principal: Principal,
+    },
+    /// A canister write that failed after the video was finalized in Storj
+    CanisterWrite { upload: PreparedUpload },
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum DeadLetterKind {
+    OutboxDelivery,
+    ScheduledPublish,
+    CanisterWrite,
+}
+
+impl DeadLetterOperation {
+    pub fn kind(&self) -> DeadLetterKind {
+        match self {
+            DeadLetterOperation::OutboxDelivery { .. } => DeadLetterKind::OutboxDelivery,
+            DeadLetterOperation::ScheduledPublish { .. } => DeadLetterKind::ScheduledPublish,
+            DeadLetterOperation::CanisterWrite { .. } => DeadLetterKind::CanisterWrite,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, ToSchema)]
+pub struct DeadLetter {
+    #[schema(example = "dead-letter-id-string")]
+    pub id: String,
+    #[schema(value_type = Object)]
+    pub operation: DeadLetterOperation,
+    /// Error of the last attempt before the operation was given up on
+    pub error: String,
+    /// Attempts made before the operation was given up on
+    pub attempts: u32,
+    pub created_at: u64,
+    #[serde(default)]
+    pub replay_attempts: u32,
+    #[serde(default)]
+    pub last_replay_error: Option<String>,
+    #[serde(default)]
+    pub last_replayed_at: Option<u64>,
+    /// Set while a replay is running so that a concurrent one is refused
+    #[serde(default)]
+    pub replay_started_at: Option<u64>,
+}
+
+/// Outcome of replaying one dead letter
+#[derive(Serialize, Deserialize, Clone, Debug, ToSchema)]
+pub struct ReplayOutcome {
+    pub id: String,
+    pub succeeded: bool,
+    pub error: Option<String>,
+}
+
+/// Failed publish pipeline operations kept for inspection and replay by an operator.
+#[derive(Clone)]
+pub struct DeadLetters {
+    letters: TypedTree<DeadLetter>,
+}
+
+impl DeadLetters {
+    pub fn new(store: &Store) -> Result<Self, StoreError> {
+        Ok(Self {
+            letters: store.tree("dead_letters")?,
+        })
+    }
+
+    /// Whether an operation that failed with `error` is worth keeping for a replay. Client errors
+    /// such as a duplicate post fail the same way however often they are replayed.
+    pub fn should_record(error: &AppError) -> bool {
+        error.status_code() >= 500 || error.is_retryable()
+    }
+
+    /// Captures the failed operation. Callers have given up on it by then, so a letter that
+    /// cannot be stored is only logged.
+    pub fn record(&self, operation: DeadLetterOperation, error: &str, attempts: u32) {
+        let created_at = now_unix_secs();
+        // keys sort by creation time so letters are listed in order
+        let id = format!("{:020}-{}", created_at, Uuid::new_v4());
+
+        let letter = DeadLetter {
+            id: id.clone(),
+            operation,
+            error: error.to_string(),
+            attempts,
+            created_at,
+            replay_attempts: 0,
+            last_replay_error: None,
+            last_replayed_at: None,
+            replay_started_at: None,
+        };
+
+        if let Err(e) = self.letters.insert(&id, &letter) {
+            log::error!("Failed to record dead letter {:?}: {}", letter.operation, e);
+        }
+    }
+
+    /// Dead letters in the order they were recorded, optionally only those of `kind`
+    pub fn list(&self, kind: Option<DeadLetterKind>) -> Result<Vec<DeadLetter>, AppError> {
+        Ok(self
+            .letters
+            .values()?
+            .into_iter()
+            .filter(|letter| kind.is_none_or(|kind| letter.operation.kind() == kind))
+            .collect())
+    }
+
+    pub fn get(&self, id: &str) -> Result<DeadLetter, AppError> {
+        self.letters
+            .get(id)?
+            .ok_or_else(|| AppError::DeadLetterNotFound(id.to_string()))
+    }
+
+    pub fn discard(&self, id: &str) -> Result<(), AppError> {
+        self.letters
+            .remove(id)?
+            .map(|_| ())
+            .ok_or_else(|| AppError::DeadLetterNotFound(id.to_string()))
+    }
+
+    /// Replays the operation. It is removed on success and kept with the replay error otherwise.
+    /// The replay runs in its own task, so a caller that goes away does not leave the letter
+    /// locked.
+    pub async fn replay(&self, app_state: &AppState, id: &str) -> Result<(), AppError> {
+        let letter = self.begin_replay(id, now_unix_secs())?;
+
+        let dead_letters = self.clone();
+        let app_state = app_state.clone();
+        let id = id.to_string();
+        tokio::spawn(async move {
+            let replay_result = replay_operation(&app_state, &letter.operation).await;
+            dead_letters.finish_replay(&id, replay_result.as_ref().err())?;
+
+            replay_result
+        })
+        .await
+        .map_err(|e| AppError::InternalError(format!("Replay task failed: {}", e)))?
+    }
+
+    /// Releases the letters whose replay died with the previous process. Called at startup,
+    /// before anything is replayed.
+    pub fn release_interrupted(&self) -> Result<(), StoreError> {
+        for (id, letter) in self.letters.entries()? {
+            if letter.replay_started_at.is_none() {
+                continue;
+            }
+
+            log::warn!("Releasing dead letter {} of an interrupted replay", id);
+            self.letters.fetch_and_update(&id, |letter| {
+                let mut letter = letter?;
+                letter.replay_started_at = None;
+                Some(letter)
+            })?;
+        }
+
+        Ok(())
+    }
+
+    /// Marks the letter as being replayed, refusing while another replay of it is running
+    fn begin_replay(&self, id: &str, now: u64) -> Result<DeadLetter, AppError> {
+        let mut in_progress = false;
+        let letter = self.letters.fetch_and_update(id, |letter| {
+            let mut letter = letter?;
+            in_progress = letter
+                .replay_started_at
+                .is_some_and(|started_at| now.saturating_sub(started_at) < STALE_REPLAY_SECS);
+            if !in_progress {
+                letter.replay_started_at = Some(now);
+            }
+            Some(letter)
+        })?;
+
+        let letter = letter.ok_or_else(|| AppError::DeadLetterNotFound(id.to_string()))?;
+        if in_progress {
+            return Err(AppError::ReplayInProgress(id.to_string()));
+        }
+
+        Ok(letter)
+    }
+
+    /// Removes the letter after a successful replay, or records the error and releases it for
+    /// the next replay. A letter discarded meanwhile stays gone.
+    fn finish_replay(&self, id: &str, error: Option<&AppError>) -> Result<(), AppError> {
+        let Some(error) = error else {
+            log::info!("Replayed dead letter {}", id);
+            self.letters.remove(id)?;
+            return Ok(());
+        };
+
+        log::warn!("Replay of dead letter {} failed: {}", id, error);
+        self.letters.fetch_and_update(id, |letter| {
+            let mut letter = letter?;
+            letter.replay_attempts += 1;
+            letter.last_replay_error = Some(error.to_string());
+            letter.last_replayed_at = Some(now_unix_secs());
+            letter.replay_started_at = None;
+            Some(letter)
+        })?;
+
+        Ok(())
+    }
+
+    /// Replays the given dead letters one after the other, or a batch of those of `kind` when
+    /// `ids` is empty. Returns the outcomes along with the number of letters of `kind` left for a
+    /// later batch.
+    pub async fn replay_many(
+        &self,
+        app_state: &AppState,
+        ids: Vec<String>,
+        kind: Option<DeadLetterKind>,
+    ) -> Result<(Vec<ReplayOutcome>, usize), AppError> {
+        if ids.len() > MAX_REPLAY_BATCH {
+            return Err(AppError::InvalidRequest(format!(
+                "At most {} dead letters can be replayed at once",
+                MAX_REPLAY_BATCH
+            )));
+        }
+
+        let (ids, remaining) = if ids.is_empty() {
+            self.replay_batch(kind)?
+        } else {
+            (ids, 0)
+        };
+
+        let mut outcomes = Vec::with_capacity(ids.len());
+        for id in ids {
+            let error = self.replay(app_state, &id).await.err();
+            outcomes.push(ReplayOutcome {
+                id,
+                succeeded: error.is_none(),
+                error: error.map(|e| e.to_string()),
+            });
+        }
+
+        Ok((outcomes, remaining))
+    }
+
+    /// Ids of the next letters of `kind` to replay and how many are left over. Letters never
+    /// replayed come first, then the ones replayed longest ago, so that letters failing every
+    /// replay do not keep the others from being picked.
+    fn replay_batch(&self, kind: Option<DeadLetterKind>) -> Result<(Vec<String>, usize), AppError> {
+        let mut letters = self.list(kind)?;
+        letters.sort_by_key(|letter| letter.last_replayed_at.unwrap_or(0));
+
+        let remaining = letters.len().saturating_sub(MAX_REPLAY_BATCH);
+        let ids = letters
+            .into_iter()
+            .take(MAX_REPLAY_BATCH)
+            .map(|letter| letter.id)
+            .collect();
+
+        Ok((ids, remaining))
+    }
+}
+
+async fn replay_operation(
+    app_state: &AppState,
+    operation: &DeadLetterOperation,
+) -> Result<(), AppError> {
+    match operation {
+        // back through the outbox so delivery gets its usual retries
+        DeadLetterOperation::OutboxDelivery { message } => {
+            app_state.outbox.enqueue(message.clone());
+            Ok(())
+        }
+        DeadLetterOperation::ScheduledPublish {
+            post_id,
+            creator_principal,
+        } => {
+            // a blocked creator's letter is kept until the block lifts
+            app_state.blocklist.ensure_not_blocked(*creator_principal)?;
+
+            let post_details = fetch_post_details(
+                &app_state.ic_admin_agent,
+                &app_state.circuit_breakers.ic_agent,
+                post_id,
+            )
+            .await?;
+
+            // published or taken down since, there is nothing left to replay
+            if !matches!(post_details.status, PostStatus::Draft) {
+                log::info!(
+                    "Skipping replay of scheduled publish of post {}, it is {}",
+                    post_id,
+                    post_status_label(&post_details.status)
+                );
+                return Ok(());
+            }
+
+            publish_post(
+                &app_state.ic_admin_agent,
+                &app_state.circuit_breakers.ic_agent,
+                &app_state.storj_client,
+                &app_state.outbox,
+                post_details,
+            )
+            .await
+        }
+        DeadLetterOperation::CanisterWrite { upload } => {
+            app_state
+                .blocklist
+                .ensure_not_blocked(upload.post_details.creator_principal)?;
+
+            replay_canister_write(app_state, upload).await
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dead_letters() -> DeadLetters {
+        DeadLetters::new(&Store::temporary().unwrap()).unwrap()
+    }
+
+    fn scheduled_publish(post_id: &str) -> DeadLetterOperation {
+        DeadLetterOperation::ScheduledPublish {
+            post_id: post_id.to_string(),
+            creator_principal: Principal::anonymous(),
+        }
+    }
+
+    fn recorded_id(dead_letters: &DeadLetters) -> String {
+        dead_letters.list(None).unwrap().remove(0).id
+    }
+
+    #[test]
+    fn test_list_filters_by_kind() {
+        let dead_letters = dead_letters();
+        dead_letters.record(scheduled_publish("post-1"), "canister unavailable", 5);
+
+        assert_eq!(dead_letters.list(None).unwrap().len(), 1);
+        assert_eq!(
+            dead_letters
+                .list(Some(DeadLetterKind::ScheduledPublish))
+                .unwrap()
+                .len(),
+            1
+        );
+        assert!(
+            dead_letters
+                .list(Some(DeadLetterKind::CanisterWrite))
+                .unwrap()
+                .is_empty()
+        );
+    }
+
+    #[test]
+    fn test_discard_removes_the_letter() {
+        let dead_letters = dead_letters();
+        dead_letters.record(scheduled_publish("post-1"), "canister unavailable", 5);
+        let id = recorded_id(&dead_letters);
+
+        dead_letters.discard(&id).unwrap();
+
+        assert!(matches!(
+            dead_letters.get(&id),
+            Err(AppError::DeadLetterNotFound(_))
+        ));
+        assert!(matches!(
+            dead_letters.discard(&id),
+            Err(AppError::DeadLetterNotFound(_))
+        ));
+    }
+
+    #[test]
+    fn test_concurrent_replay_is_refused() {
+        let dead_letters = dead_letters();
+        dead_letters.record(scheduled_publish("post-1"), "canister unavailable", 5);
+        let id = recorded_id(&dead_letters);
+        let now = now_unix_secs();
+
+        dead_letters.begin_replay(&id, now).unwrap();
+
+        assert!(matches!(
+            dead_letters.begin_replay(&id, now),
+            Err(AppError::ReplayInProgress(_))
+        ));
+        // a replay that never finished does not hold the letter forever
+        assert!(
+            dead_letters
+                .begin_replay(&id, now + STALE_REPLAY_SECS)
+                .is_ok()
+        );
+    }
+
+    #[test]
+    fn test_failed_replay_keeps_the_letter_and_releases_it() {
+        let dead_letters = dead_letters();
+        dead_letters.record(scheduled_publish("post-1"), "canister unavailable", 5);
+        let id = recorded_id(&dead_letters);
+        let now = now_unix_secs();
+
+        dead_letters.begin_replay(&id, now).unwrap();
+        dead_letters
+            .finish_replay(&id, Some(&AppError::AgentError("timeout".to_string())))
+            .unwrap();
+
+        let letter = dead_letters.get(&id).unwrap();
+        assert_eq!(letter.replay_attempts, 1);
+        assert!(letter.last_replay_error.is_some());
+        assert!(letter.replay_started_at.is_none());
+        assert!(dead_letters.begin_replay(&id, now).is_ok());
+    }
+
+    #[test]
+    fn test_successful_replay_removes_the_letter() {
+        let dead_letters = dead_letters();
+        dead_letters.record(scheduled_publish("post-1"), "canister unavailable", 5);
+        let id = recorded_id(&dead_letters);
+
+        dead_letters.begin_replay(&id, now_unix_secs()).unwrap();
+        dead_letters.finish_replay(&id, None).unwrap();
+
+        assert!(dead_letters.list(None).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_release_interrupted_unlocks_letters() {
+        let dead_letters = dead_letters();
+        dead_letters.record(scheduled_publish("post-1"), "canister unavailable", 5);
+        let id = recorded_id(&dead_letters);
+        let now = now_unix_secs();
+
+        dead_letters.begin_replay(&id, now).unwrap();
+        dead_letters.release_interrupted().unwrap();
+
+        assert!(dead_letters.get(&id).unwrap().replay_started_at.is_none());
+        assert!(dead_letters.begin_replay(&id, now).is_ok());
+    }
+
+    #[test]
+    fn test_replay_batch_is_bounded_and_prefers_letters_not_replayed_lately() {
+        let dead_letters = dead_letters();
+        for i in 0..MAX_REPLAY_BATCH + 2 {
+            dead_letters.record(scheduled_publish(&format!("post-{}", i)), "unavailable", 5);
+        }
+        let first = recorded_id(&dead_letters);
+        dead_letters.begin_replay(&first, now_unix_secs()).unwrap();
+        dead_letters
+            .finish_replay(&first, Some(&AppError::AgentError("timeout".to_string())))
+            .unwrap();
+
+        let (ids, remaining) = dead_letters.replay_batch(None).unwrap();
+
+        assert_eq!(ids.len(), MAX_REPLAY_BATCH);
+        assert_eq!(remaining, 2);
+        assert!(!ids.contains(&first));
+    }
+
+    #[test]
+    fn test_client_errors_are_not_recorded() {
+        let cases = [
+            (AppError::DuplicatePost("post-1".to_string()), false),
+            (AppError::InvalidRequest("bad".to_string()), false),
+            (AppError::PostNotFound("post-1".to_string()), false),
+            (AppError::CanisterError("rejected".to_string()), true),
+            (AppError::AgentError("timeout".to_string()), true),
+            (AppError::DeadlineExceeded("late".to_string()), true),
+        ];
+
+        for (error, recorded) in cases {
+            assert_eq!(DeadLetters::should_record(&error), recorded, "{}", error);
+        }
+    }
+}
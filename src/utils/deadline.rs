@@ -0,0 +1,28 @@
+use std::time::Duration;
+
+use axum::{
+    extract::{Request, State},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+
+use crate::utils::types::AppError;
+
+/// Middleware failing a request with a 504 once it runs longer than the configured deadline
+pub async fn deadline_middleware(
+    State(deadline): State<Duration>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let path = request.uri().path().to_string();
+
+    match tokio::time::timeout(deadline, next.run(request)).await {
+        Ok(response) => response,
+        Err(_) => {
+            log::warn!("Request to {} exceeded its {:?} deadline", path, deadline);
+            AppError::DeadlineExceeded(format!("{} did not complete within {:?}", path, deadline))
+                .to_api_response::<()>()
+                .into_response()
+        }
+    }
+}
@@ -0,0 +1,253 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use candid::Principal;
+use ic_agent::{
+    Identity,
+    identity::{DelegatedIdentity, SignedDelegation},
+};
+
+use crate::utils::types::AppError;
+
+/// What a delegation chain has to satisfy before its sender is trusted
+#[derive(Clone, Debug)]
+pub struct DelegationPolicy {
+    /// Delegations allowed between the root key and the key signing on its behalf
+    pub max_chain_length: usize,
+    /// How long past its expiration a delegation is still accepted, covering clock drift
+    pub max_clock_skew: Duration,
+    /// A delegation restricted to targets has to include at least one of these canisters
+    pub accepted_targets: Vec<Principal>,
+}
+
+/// Verifies that `delegation_chain` delegates from `from_key` to the key of `to_identity`: the
+/// chain is not too long, it ends at that key, no delegation is expired or targeted only at
+/// canisters we do not accept, and every delegation is signed by the key before it.
+pub fn verify_delegation_chain(
+    from_key: Vec<u8>,
+    delegation_chain: Vec<SignedDelegation>,
+    to_identity: Box<dyn Identity>,
+    policy: &DelegationPolicy,
+    now: SystemTime,
+) -> Result<DelegatedIdentity, AppError> {
+    if delegation_chain.len() > policy.max_chain_length {
+        return Err(AppError::DelegationChainTooLong(format!(
+            "{} delegations, at most {} are accepted",
+            delegation_chain.len(),
+            policy.max_chain_length
+        )));
+    }
+
+    let to_key = to_identity.public_key().ok_or_else(|| {
+        AppError::DelegationKeyMismatch("The signing identity has no public key".to_string())
+    })?;
+    let chain_end_key = delegation_chain
+        .last()
+        .map_or(&from_key, |signed| &signed.delegation.pubkey);
+    if *chain_end_key != to_key {
+        return Err(AppError::DelegationKeyMismatch(
+            "The delegation chain does not end at the signing key".to_string(),
+        ));
+    }
+
+    // delegation expirations are nanoseconds since the epoch
+    let now_nanos = now
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos();
+    let skew_nanos = policy.max_clock_skew.as_nanos();
+
+    for (index, signed) in delegation_chain.iter().enumerate() {
+        let delegation = &signed.delegation;
+
+        if u128::from(delegation.expiration) + skew_nanos < now_nanos {
+            return Err(AppError::DelegationExpired(format!(
+                "Delegation {} expired at {}ns",
+                index, delegation.expiration
+            )));
+        }
+
+        let targets_accepted = delegation.targets.as_ref().is_none_or(|targets| {
+            targets
+                .iter()
+                .any(|target| policy.accepted_targets.contains(target))
+        });
+        if !targets_accepted {
+            return Err(AppError::DelegationTargetNotAccepted(format!(
+                "Delegation {} is restricted to {:?}",
+                index, delegation.targets
+            )));
+        }
+    }
+
+    DelegatedIdentity::new(from_key, to_identity, delegation_chain)
+        .map_err(|e| AppError::InvalidDelegationSignature(e.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use ic_agent::identity::{Delegation, Secp256k1Identity};
+    use k256::{SecretKey, elliptic_curve::rand_core::OsRng};
+
+    use super::*;
+
+    fn policy() -> DelegationPolicy {
+        DelegationPolicy {
+            max_chain_length: 2,
+            max_clock_skew: Duration::from_secs(60),
+            accepted_targets: vec![Principal::management_canister()],
+        }
+    }
+
+    fn identity(secret_key: &SecretKey) -> Secp256k1Identity {
+        Secp256k1Identity::from_private_key(secret_key.clone())
+    }
+
+    /// Delegation from `from` to `to` expiring `expires_in` after now
+    fn sign(
+        from: &impl Identity,
+        to: &SecretKey,
+        expires_in: Duration,
+        targets: Option<Vec<Principal>>,
+    ) -> SignedDelegation {
+        let expiration = (SystemTime::now() + expires_in)
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_nanos() as u64;
+        let delegation = Delegation {
+            pubkey: identity(to).public_key().unwrap(),
+            expiration,
+            targets,
+        };
+        let signature = from.sign_delegation(&delegation).unwrap();
+
+        SignedDelegation {
+            delegation,
+            signature: signature.signature.unwrap(),
+        }
+    }
+
+    fn verify(
+        root: &SecretKey,
+        chain: Vec<SignedDelegation>,
+        to: &SecretKey,
+    ) -> Result<DelegatedIdentity, AppError> {
+        verify_delegation_chain(
+            identity(root).public_key().unwrap(),
+            chain,
+            Box::new(identity(to)),
+            &policy(),
+            SystemTime::now(),
+        )
+    }
+
+    #[test]
+    fn test_accepts_valid_chain() {
+        let root = SecretKey::random(&mut OsRng);
+        let session = SecretKey::random(&mut OsRng);
+        let chain = vec![sign(
+            &identity(&root),
+            &session,
+            Duration::from_secs(3600),
+            Some(vec![Principal::management_canister()]),
+        )];
+
+        let delegated = verify(&root, chain, &session).unwrap();
+        assert_eq!(
+            delegated.sender().unwrap(),
+            identity(&root).sender().unwrap()
+        );
+    }
+
+    #[test]
+    fn test_rejects_expired_delegation() {
+        let root = SecretKey::random(&mut OsRng);
+        let session = SecretKey::random(&mut OsRng);
+        let mut chain = vec![sign(
+            &identity(&root),
+            &session,
+            Duration::from_secs(3600),
+            None,
+        )];
+        chain[0].delegation.expiration = 0;
+
+        assert!(matches!(
+            verify(&root, chain, &session),
+            Err(AppError::DelegationExpired(_))
+        ));
+    }
+
+    #[test]
+    fn test_rejects_unaccepted_targets() {
+        let root = SecretKey::random(&mut OsRng);
+        let session = SecretKey::random(&mut OsRng);
+        let chain = vec![sign(
+            &identity(&root),
+            &session,
+            Duration::from_secs(3600),
+            Some(vec![Principal::anonymous()]),
+        )];
+
+        assert!(matches!(
+            verify(&root, chain, &session),
+            Err(AppError::DelegationTargetNotAccepted(_))
+        ));
+    }
+
+    #[test]
+    fn test_rejects_chain_not_ending_at_signing_key() {
+        let root = SecretKey::random(&mut OsRng);
+        let session = SecretKey::random(&mut OsRng);
+        let other = SecretKey::random(&mut OsRng);
+        let chain = vec![sign(
+            &identity(&root),
+            &session,
+            Duration::from_secs(3600),
+            None,
+        )];
+
+        assert!(matches!(
+            verify(&root, chain, &other),
+            Err(AppError::DelegationKeyMismatch(_))
+        ));
+    }
+
+    #[test]
+    fn test_rejects_forged_signature() {
+        let root = SecretKey::random(&mut OsRng);
+        let attacker = SecretKey::random(&mut OsRng);
+        let session = SecretKey::random(&mut OsRng);
+        let chain = vec![sign(
+            &identity(&attacker),
+            &session,
+            Duration::from_secs(3600),
+            None,
+        )];
+
+        assert!(matches!(
+            verify(&root, chain, &session),
+            Err(AppError::InvalidDelegationSignature(_))
+        ));
+    }
+
+    #[test]
+    fn test_rejects_long_chain() {
+        let root = SecretKey::random(&mut OsRng);
+        let keys: Vec<_> = (0..3).map(|_| SecretKey::random(&mut OsRng)).collect();
+        let mut chain = Vec::new();
+        let mut signer = root.clone();
+        for key in &keys {
+            chain.push(sign(
+                &identity(&signer),
+                key,
+                Duration::from_secs(3600),
+                None,
+            ));
+            signer = key.clone();
+        }
+
+        assert!(matches!(
+            verify(&root, chain, &keys[2]),
+            Err(AppError::DelegationChainTooLong(_))
+        ));
+    }
+}
@@ -1,18 +1,26 @@
-use std::error::Error;
-
 use axum::http::{HeaderMap, HeaderValue};
 use candid::Principal;
-use reqwest::{Client, ClientBuilder, Url, header};
+use reqwest::{Client, Url, header};
 use serde_json::json;
 
+use crate::{
+    config::TimeoutConfig,
+    utils::{circuit_breaker::CircuitBreaker, retry::HttpCallError, types::CreationContext},
+};
+
 #[derive(Clone)]
 pub struct EventService {
     base_url: Url,
     reqwest_client: Client,
+    breaker: CircuitBreaker,
 }
 
 impl EventService {
-    pub fn with_auth_token(auth_token: String) -> Self {
+    pub fn with_auth_token(
+        auth_token: String,
+        timeouts: TimeoutConfig,
+        breaker: CircuitBreaker,
+    ) -> Self {
         let base_url = "https://offchain.yral.com/";
         let mut headers = HeaderMap::new();
         headers.insert(
@@ -20,11 +28,13 @@ impl EventService {
             HeaderValue::from_str(format!("Bearer {}", &auth_token).as_ref()).unwrap(),
         );
         Self {
-            reqwest_client: ClientBuilder::new()
+            reqwest_client: timeouts
+                .client_builder()
                 .default_headers(headers)
                 .build()
                 .expect("Invalid event service client config"),
             base_url: Url::parse(base_url).unwrap(),
+            breaker,
         }
     }
 
@@ -34,13 +44,12 @@ impl EventService {
         video_uid: String,
         hashtags_len: usize,
         is_nsfw: bool,
-        enable_hot_or_not: bool,
         post_id: String,
         user_principal: Principal,
         canister_id: Principal,
         user_name: String,
-        country: Option<String>,
-    ) -> Result<(), Box<dyn Error>> {
+        creation_context: &CreationContext,
+    ) -> Result<(), HttpCallError> {
         let params = json!({
             "user_id": user_principal,
             "publisher_user_id": user_principal,
@@ -49,36 +58,18 @@ impl EventService {
             "creator_category": "NA",
             "hashtag_count": hashtags_len,
             "is_nsfw": is_nsfw,
-            "is_hot_or_not": enable_hot_or_not,
-            "is_filter_used": false,
+            "is_hot_or_not": creation_context.enable_hot_or_not(),
+            "is_filter_used": creation_context.is_filter_used(),
+            "filters_used": creation_context.filters_used,
+            "capture_source": creation_context.capture_source,
+            "ai_tool": creation_context.ai_tool,
             "video_id": video_uid,
             "post_id": post_id,
-            "country": country,
+            "country": creation_context.country,
         })
         .to_string();
 
-        let path = "api/v2/events";
-
-        let response = self
-            .reqwest_client
-            .post(self.base_url.join(path).unwrap())
-            .json(&json!({
-                "event": "video_upload_successful".to_owned(),
-                "params": params
-            }))
-            .send()
-            .await?;
-
-        if response.status().is_success() {
-            Ok(())
-        } else {
-            let status = response.status();
-            let error = response.text().await?;
-            Err(
-                format!("error sending video_upload_successful event. Error {status} {error}",)
-                    .into(),
-            )
-        }
+        self.send_event("video_upload_successful", params).await
     }
 
     #[allow(clippy::too_many_arguments)]
@@ -91,7 +82,8 @@ impl EventService {
         user_principal: Principal,
         user_name: String,
         user_canister: Principal,
-    ) -> Result<(), Box<dyn Error>> {
+        matched_filter_rule: Option<String>,
+    ) -> Result<(), HttpCallError> {
         let params = json!({
             "user_id": user_principal,
             "display_name": user_name,
@@ -101,16 +93,31 @@ impl EventService {
             "is_NSFW": is_nsfw,
             "is_hotorNot": enable_hot_or_not,
             "fail_reason": error,
+            "matched_filter_rule": matched_filter_rule,
         })
         .to_string();
 
+        self.send_event("video_upload_unsuccessful", params).await
+    }
+
+    async fn send_event(&self, event: &str, params: String) -> Result<(), HttpCallError> {
+        // a rejected event shows the service is up, only its failures open the breaker
+        self.breaker
+            .call_counting(
+                self.post_event(event, params),
+                HttpCallError::is_downstream_failure,
+            )
+            .await
+    }
+
+    async fn post_event(&self, event: &str, params: String) -> Result<(), HttpCallError> {
         let path = "api/v2/events";
 
         let response = self
             .reqwest_client
             .post(self.base_url.join(path).unwrap())
             .json(&json!({
-                "event": "video_upload_unsuccessful".to_owned(),
+                "event": event,
                 "params": params
             }))
             .send()
@@ -119,12 +126,9 @@ impl EventService {
         if response.status().is_success() {
             Ok(())
         } else {
-            let status = response.status();
-            let error = response.text().await?;
-            Err(
-                format!("error sending video_upload_successful event. Error {status} {error}",)
-                    .into(),
-            )
+            let error = HttpCallError::from_response(response).await;
+            log::warn!("Error sending {} event: {}", event, error);
+            Err(error)
         }
     }
 }
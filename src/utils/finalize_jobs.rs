@@ -0,0 +1,472 @@
+use std::{collections::HashSet, sync::Arc, time::Duration};
+
+use candid::Principal;
+use serde::{Deserialize, Serialize};
+use tokio::sync::{Mutex, mpsc};
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+use crate::{
+    api::update_video_metadata::{PreparedUpload, resume_upload_pipeline, run_upload_pipeline},
+    app_state::AppState,
+    utils::{
+        store::{Store, StoreError, TypedTree},
+        time::now_unix_secs,
+        types::AppError,
+    },
+};
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum JobStep {
+    FinalizeStorj,
+    WriteCanister,
+    SchedulePublish,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum StepStatus {
+    Pending,
+    Running,
+    Succeeded,
+    Failed,
+    Skipped,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum JobStatus {
+    Queued,
+    Running,
+    Succeeded,
+    Failed,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, ToSchema)]
+pub struct JobStepProgress {
+    pub step: JobStep,
+    pub status: StepStatus,
+    pub error: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, ToSchema)]
+pub struct JobProgress {
+    #[schema(example = "job-id-string")]
+    pub job_id: String,
+    #[schema(example = "post-id-string")]
+    pub post_id: String,
+    #[schema(value_type = String, example = "principal-id-string")]
+    pub creator_principal: Principal,
+    pub status: JobStatus,
+    pub steps: Vec<JobStepProgress>,
+    pub error: Option<String>,
+    pub created_at: u64,
+    pub updated_at: u64,
+}
+
+const PRUNE_INTERVAL: Duration = Duration::from_secs(10 * 60);
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+struct FinalizeJob {
+    progress: JobProgress,
+    upload: PreparedUpload,
+    /// Set when a restart interrupted the job, it resumes from the step its upload saga reached
+    #[serde(default)]
+    interrupted: bool,
+}
+
+/// Persisted finalize jobs of asynchronous metadata updates, executed by a pool of workers.
+#[derive(Clone)]
+pub struct FinalizeJobs {
+    jobs: TypedTree<FinalizeJob>,
+    queue_tx: mpsc::Sender<String>,
+    queue_rx: Arc<Mutex<mpsc::Receiver<String>>>,
+    /// How long a finished job can still be looked up
+    job_ttl: Duration,
+}
+
+impl FinalizeJobs {
+    pub fn new(
+        store: &Store,
+        queue_capacity: usize,
+        job_ttl: Duration,
+    ) -> Result<Self, StoreError> {
+        let (queue_tx, queue_rx) = mpsc::channel(queue_capacity.max(1));
+
+        Ok(Self {
+            jobs: store.tree("finalize_jobs")?,
+            queue_tx,
+            queue_rx: Arc::new(Mutex::new(queue_rx)),
+            job_ttl,
+        })
+    }
+
+    /// Persists a job for the upload and queues it. Returns the job id.
+    pub fn enqueue(&self, upload: PreparedUpload) -> Result<String, AppError> {
+        let job_id = Uuid::new_v4().to_string();
+        let now = now_unix_secs();
+
+        let steps = [
+            JobStep::FinalizeStorj,
+            JobStep::WriteCanister,
+            JobStep::SchedulePublish,
+        ]
+        .into_iter()
+        .map(|step| JobStepProgress {
+            step,
+            status: StepStatus::Pending,
+            error: None,
+        })
+        .collect();
+
+        let job = FinalizeJob {
+            progress: JobProgress {
+                job_id: job_id.clone(),
+                post_id: upload.post_details.id.clone(),
+                creator_principal: upload.post_details.creator_principal,
+                status: JobStatus::Queued,
+                steps,
+                error: None,
+                created_at: now,
+                updated_at: now,
+            },
+            upload,
+            interrupted: false,
+        };
+        self.jobs.insert(&job_id, &job)?;
+
+        if let Err(e) = self.queue_tx.try_send(job_id.clone()) {
+            self.jobs.remove(&job_id)?;
+            return Err(match e {
+                mpsc::error::TrySendError::Full(_) => {
+                    AppError::DependencyUnavailable("The finalize job queue is full".to_string())
+                }
+                mpsc::error::TrySendError::Closed(_) => {
+                    AppError::InternalError("The finalize job queue is closed".to_string())
+                }
+            });
+        }
+
+        Ok(job_id)
+    }
+
+    pub fn get(&self, job_id: &str) -> Result<Option<JobProgress>, AppError> {
+        Ok(self.jobs.get(job_id)?.map(|job| job.progress))
+    }
+
+    /// Posts of the jobs that are queued or running
+    pub fn unfinished_post_ids(&self) -> Result<HashSet<String>, StoreError> {
+        Ok(self
+            .jobs
+            .values()?
+            .into_iter()
+            .filter(|job| matches!(job.progress.status, JobStatus::Queued | JobStatus::Running))
+            .map(|job| job.progress.post_id)
+            .collect())
+    }
+
+    /// Updates the stored progress. A progress write that fails only leaves the status clients
+    /// poll behind, so it is logged and the job runs on.
+    fn update(&self, job_id: &str, apply: impl FnOnce(&mut JobProgress)) {
+        let result = self.jobs.get(job_id).and_then(|job| match job {
+            Some(mut job) => {
+                apply(&mut job.progress);
+                job.progress.updated_at = now_unix_secs();
+                self.jobs.insert(job_id, &job)
+            }
+            None => Ok(()),
+        });
+
+        if let Err(e) = result {
+            log::error!("Failed to update finalize job {}: {}", job_id, e);
+        }
+    }
+
+    /// Starts `worker_count` workers and the pruning of finished jobs. Jobs queued before a
+    /// restart are picked up again, jobs that were running resume from the step their upload
+    /// saga reached.
+    pub fn spawn_workers(&self, app_state: AppState, worker_count: usize) {
+        // read before the workers start, so a job they pick up is not taken for an interrupted one
+        let unfinished = self.reset_unfinished();
+
+        for _ in 0..worker_count.max(1) {
+            let finalize_jobs = self.clone();
+            let app_state = app_state.clone();
+
+            tokio::spawn(async move {
+                loop {
+                    let job_id = finalize_jobs.queue_rx.lock().await.recv().await;
+                    let Some(job_id) = job_id else {
+                        break;
+                    };
+
+                    finalize_jobs.execute(&app_state, &job_id).await;
+                }
+            });
+        }
+
+        let finalize_jobs = self.clone();
+        tokio::spawn(async move {
+            for job_id in unfinished {
+                // waits for the workers when there are more jobs than the queue holds
+                if finalize_jobs.queue_tx.send(job_id).await.is_err() {
+                    break;
+                }
+            }
+
+            loop {
+                if let Err(e) = finalize_jobs.prune_finished() {
+                    log::error!("Failed to prune finalize jobs: {}", e);
+                }
+
+                tokio::time::sleep(PRUNE_INTERVAL).await;
+            }
+        });
+    }
+
+    /// Queues the jobs a restart interrupted again and returns the ids of every unfinished job
+    fn reset_unfinished(&self) -> Vec<String> {
+        let jobs = match self.jobs.entries() {
+            Ok(jobs) => jobs,
+            Err(e) => {
+                log::error!("Failed to load finalize jobs: {}", e);
+                return Vec::new();
+            }
+        };
+
+        let mut unfinished = Vec::new();
+        for (job_id, job) in jobs {
+            match job.progress.status {
+                JobStatus::Queued => {}
+                JobStatus::Running => {
+                    let reset = self.jobs.fetch_and_update(&job_id, |job| {
+                        job.map(|mut job| {
+                            if job.progress.status == JobStatus::Running {
+                                job.interrupted = true;
+                                job.progress.status = JobStatus::Queued;
+                            }
+                            job
+                        })
+                    });
+                    if let Err(e) = reset {
+                        log::error!(
+                            "Failed to requeue interrupted finalize job {}: {}",
+                            job_id,
+                            e
+                        );
+                        continue;
+                    }
+                }
+                JobStatus::Succeeded | JobStatus::Failed => continue,
+            }
+
+            unfinished.push(job_id);
+        }
+
+        unfinished
+    }
+
+    /// Moves the job from queued to running and returns it, `None` when it is not queued. A job
+    /// can be sent to the queue twice, by its request and by the requeue of a restart, and only
+    /// the first worker to claim it runs it.
+    fn claim(&self, job_id: &str) -> Result<Option<FinalizeJob>, StoreError> {
+        let now = now_unix_secs();
+        let mut claimed = None;
+
+        self.jobs.fetch_and_update(job_id, |job| {
+            claimed = None;
+            job.map(|mut job| {
+                if job.progress.status == JobStatus::Queued {
+                    job.progress.status = JobStatus::Running;
+                    job.progress.updated_at = now;
+                    claimed = Some(job.clone());
+                }
+                job
+            })
+        })?;
+
+        Ok(claimed)
+    }
+
+    fn prune_finished(&self) -> Result<(), StoreError> {
+        let now = now_unix_secs();
+
+        for (job_id, job) in self.jobs.entries()? {
+            let finished = matches!(
+                job.progress.status,
+                JobStatus::Succeeded | JobStatus::Failed
+            );
+            if finished && job.progress.updated_at + self.job_ttl.as_secs() <= now {
+                self.jobs.remove(&job_id)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn execute(&self, app_state: &AppState, job_id: &str) {
+        let job = match self.claim(job_id) {
+            Ok(Some(job)) => job,
+            Ok(None) => return,
+            Err(e) => {
+                log::error!("Failed to claim finalize job {}: {}", job_id, e);
+                return;
+            }
+        };
+
+        // the creator may have been blocked while the job was queued
+        if let Err(e) = app_state
+            .blocklist
+            .ensure_not_blocked(job.progress.creator_principal)
+        {
+            log::warn!("Finalize job {} refused: {}", job_id, e);
+            self.update(job_id, |progress| {
+                progress.status = JobStatus::Failed;
+                progress.error = Some(e.to_string());
+            });
+            return;
+        }
+
+        let report_step = |step, status, error: Option<&AppError>| {
+            self.update(job_id, |progress| {
+                if let Some(step_progress) = progress.steps.iter_mut().find(|s| s.step == step) {
+                    step_progress.status = status;
+                    step_progress.error = error.map(|e| e.to_string());
+                }
+            });
+        };
+        let result = if job.interrupted {
+            resume_upload_pipeline(app_state, &job.upload, report_step).await
+        } else {
+            run_upload_pipeline(app_state, &job.upload, report_step).await
+        };
+
+        self.update(job_id, |progress| match result {
+            Ok(()) => progress.status = JobStatus::Succeeded,
+            Err(e) => {
+                log::warn!("Finalize job {} failed: {}", job_id, e);
+                progress.status = JobStatus::Failed;
+                progress.error = Some(e.to_string());
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+    use crate::utils::types::{CreationContext, RequestPostDetails};
+
+    fn finalize_jobs(queue_capacity: usize) -> FinalizeJobs {
+        FinalizeJobs::new(
+            &Store::temporary().unwrap(),
+            queue_capacity,
+            Duration::from_secs(60),
+        )
+        .unwrap()
+    }
+
+    fn upload(post_id: &str) -> PreparedUpload {
+        PreparedUpload {
+            publisher_user_id: Principal::anonymous().to_text(),
+            post_details: RequestPostDetails {
+                video_uid: post_id.to_string(),
+                description: String::new(),
+                hashtags: Vec::new(),
+                creator_principal: Principal::anonymous(),
+                id: post_id.to_string(),
+            },
+            is_published: true,
+            meta: HashMap::new(),
+            mentions: Vec::new(),
+            creation_context: CreationContext::default(),
+            publish_at: None,
+            request_id: None,
+        }
+    }
+
+    fn set_status(finalize_jobs: &FinalizeJobs, job_id: &str, status: JobStatus) {
+        finalize_jobs.update(job_id, |progress| progress.status = status);
+    }
+
+    fn status(finalize_jobs: &FinalizeJobs, job_id: &str) -> JobStatus {
+        finalize_jobs.get(job_id).unwrap().unwrap().status
+    }
+
+    #[test]
+    fn test_full_queue_rolls_the_job_back() {
+        let finalize_jobs = finalize_jobs(1);
+
+        let job_id = finalize_jobs.enqueue(upload("first")).unwrap();
+        assert_eq!(status(&finalize_jobs, &job_id), JobStatus::Queued);
+        assert_eq!(
+            finalize_jobs.get(&job_id).unwrap().unwrap().post_id,
+            "first"
+        );
+
+        assert!(matches!(
+            finalize_jobs.enqueue(upload("second")),
+            Err(AppError::DependencyUnavailable(_))
+        ));
+        assert_eq!(finalize_jobs.jobs.entries().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_job_is_claimed_once() {
+        let finalize_jobs = finalize_jobs(4);
+        let job_id = finalize_jobs.enqueue(upload("post")).unwrap();
+
+        assert!(finalize_jobs.claim(&job_id).unwrap().is_some());
+        assert_eq!(status(&finalize_jobs, &job_id), JobStatus::Running);
+        assert!(finalize_jobs.claim(&job_id).unwrap().is_none());
+
+        set_status(&finalize_jobs, &job_id, JobStatus::Succeeded);
+        assert!(finalize_jobs.claim(&job_id).unwrap().is_none());
+        assert_eq!(status(&finalize_jobs, &job_id), JobStatus::Succeeded);
+        assert!(finalize_jobs.claim("missing").unwrap().is_none());
+    }
+
+    #[test]
+    fn test_interrupted_jobs_resume_after_a_restart() {
+        let finalize_jobs = finalize_jobs(4);
+        let queued = finalize_jobs.enqueue(upload("queued")).unwrap();
+        let running = finalize_jobs.enqueue(upload("running")).unwrap();
+        let succeeded = finalize_jobs.enqueue(upload("succeeded")).unwrap();
+        finalize_jobs.claim(&running).unwrap();
+        set_status(&finalize_jobs, &succeeded, JobStatus::Succeeded);
+
+        let mut unfinished = finalize_jobs.reset_unfinished();
+        unfinished.sort();
+        let mut expected = vec![queued.clone(), running.clone()];
+        expected.sort();
+        assert_eq!(unfinished, expected);
+
+        let job = finalize_jobs.claim(&running).unwrap().unwrap();
+        assert!(job.interrupted);
+        assert!(!finalize_jobs.claim(&queued).unwrap().unwrap().interrupted);
+    }
+
+    #[test]
+    fn test_only_finished_jobs_past_their_ttl_are_pruned() {
+        let finalize_jobs = finalize_jobs(4);
+        let queued = finalize_jobs.enqueue(upload("queued")).unwrap();
+        let recent = finalize_jobs.enqueue(upload("recent")).unwrap();
+        let expired = finalize_jobs.enqueue(upload("expired")).unwrap();
+        set_status(&finalize_jobs, &recent, JobStatus::Failed);
+        set_status(&finalize_jobs, &expired, JobStatus::Succeeded);
+        for job_id in [&queued, &expired] {
+            let mut job = finalize_jobs.jobs.get(job_id).unwrap().unwrap();
+            job.progress.updated_at -= 120;
+            finalize_jobs.jobs.insert(job_id, &job).unwrap();
+        }
+
+        finalize_jobs.prune_finished().unwrap();
+
+        assert!(finalize_jobs.get(&queued).unwrap().is_some());
+        assert!(finalize_jobs.get(&recent).unwrap().is_some());
+        assert!(finalize_jobs.get(&expired).unwrap().is_none());
+    }
+}
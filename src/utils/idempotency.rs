@@ -0,0 +1,460 @@
+use std::time::Duration;
+
+use axum::{
+    body::{Body, to_bytes},
+    extract::{Request, State},
+    http::{HeaderMap, StatusCode, header::CONTENT_TYPE, request::Parts},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::{
+    app_state::AppState,
+    utils::{
+        delegation::DelegationPolicy,
+        request_auth::AuthenticatedPrincipal,
+        store::{Store, StoreError, TypedTree},
+        time::now_unix_secs,
+        types::{AppError, DelegatedIdentityWire},
+    },
+};
+
+pub static IDEMPOTENCY_KEY_HEADER: &str = "Idempotency-Key";
+
+const MAX_IDEMPOTENCY_KEY_LEN: usize = 255;
+/// Request and response bodies larger than this are not buffered for idempotency
+const MAX_BUFFERED_BODY_BYTES: usize = 2 * 1024 * 1024;
+const PRUNE_INTERVAL: Duration = Duration::from_secs(10 * 60);
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(tag = "state", rename_all = "snake_case")]
+enum IdempotencyOutcome {
+    InProgress,
+    Completed { status_code: u16, body: String },
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+struct IdempotencyRecord {
+    request_hash: String,
+    outcome: IdempotencyOutcome,
+    created_at: u64,
+}
+
+/// Remembers the response to every request sent with an `Idempotency-Key` header for `ttl`, so
+/// that a retried request gets the original response instead of being executed again.
+#[derive(Clone)]
+pub struct IdempotencyStore {
+    records: TypedTree<IdempotencyRecord>,
+    ttl: Duration,
+}
+
+enum Reservation<'a> {
+    Reserved(ReservationGuard<'a>),
+    Replay { status_code: u16, body: String },
+}
+
+/// An in-progress key, released when dropped before completing. This covers the request future
+/// being dropped on a client disconnect or by the request deadline, which would otherwise keep
+/// the key in progress until it expires.
+struct ReservationGuard<'a> {
+    idempotency_store: &'a IdempotencyStore,
+    key: String,
+    request_hash: String,
+    completed: bool,
+}
+
+impl ReservationGuard<'_> {
+    fn complete(mut self, status_code: u16, body: String) {
+        self.idempotency_store
+            .complete(&self.key, &self.request_hash, status_code, body);
+        self.completed = true;
+    }
+}
+
+impl Drop for ReservationGuard<'_> {
+    fn drop(&mut self) {
+        if !self.completed {
+            self.idempotency_store.release(&self.key);
+        }
+    }
+}
+
+impl IdempotencyStore {
+    pub fn new(store: &Store, ttl: Duration) -> Result<Self, StoreError> {
+        Ok(Self {
+            records: store.tree("idempotency_keys")?,
+            ttl,
+        })
+    }
+
+    fn is_expired(&self, record: &IdempotencyRecord, now: u64) -> bool {
+        record.created_at + self.ttl.as_secs() <= now
+    }
+
+    fn reserve(&self, key: &str, request_hash: &str) -> Result<Reservation<'_>, AppError> {
+        let now = now_unix_secs();
+
+        if let Some(record) = self.records.get(key)? {
+            if self.is_expired(&record, now) {
+                self.records.remove(key)?;
+            } else if record.request_hash != request_hash {
+                return Err(AppError::IdempotencyKeyReused(key.to_string()));
+            } else {
+                return match record.outcome {
+                    IdempotencyOutcome::InProgress => {
+                        Err(AppError::IdempotencyKeyInProgress(key.to_string()))
+                    }
+                    IdempotencyOutcome::Completed { status_code, body } => {
+                        Ok(Reservation::Replay { status_code, body })
+                    }
+                };
+            }
+        }
+
+        let reserved = self.records.insert_if_absent(
+            key,
+            &IdempotencyRecord {
+                request_hash: request_hash.to_string(),
+                outcome: IdempotencyOutcome::InProgress,
+                created_at: now,
+            },
+        )?;
+
+        // another request with the same key got in first
+        if !reserved {
+            return Err(AppError::IdempotencyKeyInProgress(key.to_string()));
+        }
+
+        Ok(Reservation::Reserved(ReservationGuard {
+            idempotency_store: self,
+            key: key.to_string(),
+            request_hash: request_hash.to_string(),
+            completed: false,
+        }))
+    }
+
+    fn complete(&self, key: &str, request_hash: &str, status_code: u16, body: String) {
+        let record = IdempotencyRecord {
+            request_hash: request_hash.to_string(),
+            outcome: IdempotencyOutcome::Completed { status_code, body },
+            created_at: now_unix_secs(),
+        };
+
+        if let Err(e) = self.records.insert(key, &record) {
+            log::error!(
+                "Failed to store response for idempotency key {}: {}",
+                key,
+                e
+            );
+        }
+    }
+
+    fn release(&self, key: &str) {
+        if let Err(e) = self.records.remove(key) {
+            log::error!("Failed to release idempotency key {}: {}", key, e);
+        }
+    }
+
+    fn prune_expired(&self) -> Result<(), StoreError> {
+        let now = now_unix_secs();
+
+        for (key, record) in self.records.entries()? {
+            if self.is_expired(&record, now) {
+                self.records.remove(&key)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Starts the background task dropping expired keys.
+    pub fn spawn_pruner(&self) {
+        let idempotency_store = self.clone();
+
+        tokio::spawn(async move {
+            loop {
+                if let Err(e) = idempotency_store.prune_expired() {
+                    log::error!("Failed to prune idempotency keys: {}", e);
+                }
+
+                tokio::time::sleep(PRUNE_INTERVAL).await;
+            }
+        });
+    }
+}
+
+fn idempotency_key(headers: &HeaderMap) -> Result<Option<String>, AppError> {
+    let Some(value) = headers.get(IDEMPOTENCY_KEY_HEADER) else {
+        return Ok(None);
+    };
+
+    let key = value
+        .to_str()
+        .ok()
+        .map(str::trim)
+        .filter(|key| !key.is_empty() && key.len() <= MAX_IDEMPOTENCY_KEY_LEN)
+        .ok_or_else(|| {
+            AppError::InvalidRequest(format!(
+                "{} must be a non-empty ASCII string of at most {} characters",
+                IDEMPOTENCY_KEY_HEADER, MAX_IDEMPOTENCY_KEY_LEN
+            ))
+        })?;
+
+    Ok(Some(key.to_string()))
+}
+
+#[derive(Deserialize)]
+struct UnsignedBody {
+    delegated_identity_wire: Option<DelegatedIdentityWire>,
+}
+
+/// The caller a key belongs to, so that two callers picking the same key do not share a record.
+/// Requests without a verified caller share one scope, the handler rejects them anyway.
+fn caller_scope(parts: &Parts, body: &[u8], delegation_policy: &DelegationPolicy) -> String {
+    if let Some(AuthenticatedPrincipal(principal)) = parts.extensions.get() {
+        return principal.to_text();
+    }
+
+    serde_json::from_slice::<UnsignedBody>(body)
+        .ok()
+        .and_then(|body| body.delegated_identity_wire)
+        .and_then(|wire| wire.sender(delegation_policy).ok())
+        .map_or_else(|| "unauthenticated".to_string(), |sender| sender.to_text())
+}
+
+/// Successes and errors the client should not retry. Retrying after any other error must run
+/// the request again rather than replay the error.
+fn is_final_outcome(status: StatusCode, body: &[u8]) -> bool {
+    if status.is_success() {
+        return true;
+    }
+
+    #[derive(Deserialize)]
+    struct Retryable {
+        #[serde(default)]
+        retryable: bool,
+    }
+
+    !status.is_server_error()
+        && serde_json::from_slice::<Retryable>(body).is_ok_and(|body| !body.retryable)
+}
+
+/// Middleware replaying the stored response for a repeated `Idempotency-Key` of the same
+/// caller. Reusing a key with a different body is rejected with 422. Only final outcomes are
+/// stored, so the client can retry server errors and errors marked `retryable`.
+pub async fn idempotency_middleware(
+    State(app_state): State<AppState>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let key = match idempotency_key(request.headers()) {
+        Ok(Some(key)) => key,
+        Ok(None) => return next.run(request).await,
+        Err(e) => return e.to_api_response::<()>().into_response(),
+    };
+
+    let (parts, body) = request.into_parts();
+    let body_bytes = match to_bytes(body, MAX_BUFFERED_BODY_BYTES).await {
+        Ok(body_bytes) => body_bytes,
+        Err(e) => {
+            return AppError::InvalidRequest(format!("Failed to read request body: {}", e))
+                .to_api_response::<()>()
+                .into_response();
+        }
+    };
+
+    // keys are scoped to the caller and the route they were used on
+    let store_key = format!(
+        "{} {} {}",
+        caller_scope(&parts, &body_bytes, &app_state.delegation_policy),
+        parts.uri.path(),
+        key
+    );
+    let request_hash = hex::encode(Sha256::digest(&body_bytes));
+    let idempotency_store = &app_state.idempotency_store;
+
+    let reservation = match idempotency_store.reserve(&store_key, &request_hash) {
+        Ok(Reservation::Reserved(reservation)) => reservation,
+        Ok(Reservation::Replay { status_code, body }) => {
+            return Response::builder()
+                .header(CONTENT_TYPE, "application/json")
+                .status(status_code)
+                .body(Body::from(body))
+                .unwrap();
+        }
+        Err(e) => return e.to_api_response::<()>().into_response(),
+    };
+
+    let response = next
+        .run(Request::from_parts(parts, Body::from(body_bytes)))
+        .await;
+
+    let (parts, body) = response.into_parts();
+    let body_bytes = match to_bytes(body, MAX_BUFFERED_BODY_BYTES).await {
+        Ok(body_bytes) => body_bytes,
+        Err(e) => {
+            log::error!(
+                "Failed to buffer response for idempotency key {}: {}",
+                key,
+                e
+            );
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        }
+    };
+
+    // dropping the reservation of an outcome that is not final releases the key
+    if is_final_outcome(parts.status, &body_bytes) {
+        reservation.complete(
+            parts.status.as_u16(),
+            String::from_utf8_lossy(&body_bytes).into_owned(),
+        );
+    }
+
+    Response::from_parts(parts, Body::from(body_bytes))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn idempotency_store(ttl: Duration) -> IdempotencyStore {
+        IdempotencyStore::new(&Store::temporary().unwrap(), ttl).unwrap()
+    }
+
+    #[test]
+    fn test_completed_response_is_replayed() {
+        let idempotency_store = idempotency_store(Duration::from_secs(60));
+
+        let Ok(Reservation::Reserved(reservation)) = idempotency_store.reserve("key", "hash")
+        else {
+            panic!("expected the key to be reserved");
+        };
+        reservation.complete(200, "{}".to_string());
+
+        assert!(matches!(
+            idempotency_store.reserve("key", "hash"),
+            Ok(Reservation::Replay { status_code: 200, body }) if body == "{}"
+        ));
+    }
+
+    #[test]
+    fn test_key_in_progress_is_rejected() {
+        let idempotency_store = idempotency_store(Duration::from_secs(60));
+
+        let _reservation = idempotency_store.reserve("key", "hash").unwrap();
+
+        assert!(matches!(
+            idempotency_store.reserve("key", "hash"),
+            Err(AppError::IdempotencyKeyInProgress(_))
+        ));
+    }
+
+    #[test]
+    fn test_key_reused_with_another_request_is_rejected() {
+        let idempotency_store = idempotency_store(Duration::from_secs(60));
+
+        let Ok(Reservation::Reserved(reservation)) = idempotency_store.reserve("key", "hash")
+        else {
+            panic!("expected the key to be reserved");
+        };
+        reservation.complete(200, "{}".to_string());
+
+        assert!(matches!(
+            idempotency_store.reserve("key", "other-hash"),
+            Err(AppError::IdempotencyKeyReused(_))
+        ));
+    }
+
+    #[test]
+    fn test_dropped_reservation_releases_the_key() {
+        let idempotency_store = idempotency_store(Duration::from_secs(60));
+
+        drop(idempotency_store.reserve("key", "hash").unwrap());
+
+        assert!(matches!(
+            idempotency_store.reserve("key", "hash"),
+            Ok(Reservation::Reserved(_))
+        ));
+    }
+
+    #[test]
+    fn test_only_final_outcomes_are_stored() {
+        let cases = [
+            (StatusCode::OK, r#"{"success":true}"#, true),
+            (StatusCode::ACCEPTED, "", true),
+            (
+                StatusCode::NOT_FOUND,
+                r#"{"success":false,"retryable":false}"#,
+                true,
+            ),
+            (
+                StatusCode::CONFLICT,
+                r#"{"success":false,"retryable":true}"#,
+                false,
+            ),
+            (StatusCode::BAD_REQUEST, "not json", false),
+            (
+                StatusCode::SERVICE_UNAVAILABLE,
+                r#"{"success":false,"retryable":false}"#,
+                false,
+            ),
+        ];
+
+        for (status, body, is_final) in cases {
+            assert_eq!(
+                is_final_outcome(status, body.as_bytes()),
+                is_final,
+                "{} {}",
+                status,
+                body
+            );
+        }
+    }
+
+    #[test]
+    fn test_keys_are_scoped_to_the_caller() {
+        let delegation_policy = DelegationPolicy {
+            max_chain_length: 4,
+            max_clock_skew: Duration::from_secs(60),
+            accepted_targets: Vec::new(),
+        };
+        let (mut parts, _) = Request::new(Body::empty()).into_parts();
+        // the delegation chain does not verify
+        let body =
+            br#"{"delegated_identity_wire":{"from_key":[1],"to_secret":{},"delegation_chain":[]}}"#;
+
+        assert_eq!(
+            caller_scope(&parts, body, &delegation_policy),
+            "unauthenticated"
+        );
+        assert_eq!(
+            caller_scope(&parts, b"{}", &delegation_policy),
+            "unauthenticated"
+        );
+
+        let principal = candid::Principal::self_authenticating(b"signer");
+        parts.extensions.insert(AuthenticatedPrincipal(principal));
+        assert_eq!(
+            caller_scope(&parts, body, &delegation_policy),
+            principal.to_text()
+        );
+    }
+
+    #[test]
+    fn test_expired_key_is_reserved_again() {
+        let idempotency_store = idempotency_store(Duration::ZERO);
+
+        let Ok(Reservation::Reserved(reservation)) = idempotency_store.reserve("key", "hash")
+        else {
+            panic!("expected the key to be reserved");
+        };
+        reservation.complete(200, "{}".to_string());
+
+        assert!(matches!(
+            idempotency_store.reserve("key", "other-hash"),
+            Ok(Reservation::Reserved(_))
+        ));
+    }
+}
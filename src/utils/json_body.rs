@@ -0,0 +1,195 @@
+use axum::{
+    body::Bytes,
+    extract::{FromRequest, Request},
+    http::{HeaderMap, StatusCode, header::CONTENT_TYPE},
+};
+use serde::de::DeserializeOwned;
+use serde_json::error::Category;
+
+use crate::utils::types::{ApiResponse, AppError, ErrorCode};
+
+/// JSON body extractor rejecting with an `ApiResponse` naming the failing field, in place of
+/// axum's plain text rejections.
+pub struct AppJson<T>(pub T);
+
+impl<T, S> FromRequest<S> for AppJson<T>
+where
+    T: DeserializeOwned,
+    S: Send + Sync,
+{
+    type Rejection = ApiResponse<()>;
+
+    async fn from_request(request: Request, state: &S) -> Result<Self, Self::Rejection> {
+        parse_json_body(request, state)
+            .await
+            .map(AppJson)
+            .map_err(|e| e.to_api_response())
+    }
+}
+
+async fn parse_json_body<T: DeserializeOwned, S: Send + Sync>(
+    request: Request,
+    state: &S,
+) -> Result<T, AppError> {
+    if !is_json_content_type(request.headers()) {
+        return Err(AppError::InvalidBody {
+            code: ErrorCode::UnsupportedContentType,
+            field: None,
+            message: "Expected a request with Content-Type: application/json".to_string(),
+        });
+    }
+
+    let body = Bytes::from_request(request, state)
+        .await
+        .map_err(|rejection| {
+            if rejection.status() == StatusCode::PAYLOAD_TOO_LARGE {
+                AppError::PayloadTooLarge(rejection.body_text())
+            } else {
+                AppError::InvalidRequest(rejection.body_text())
+            }
+        })?;
+
+    let deserializer = &mut serde_json::Deserializer::from_slice(&body);
+    serde_path_to_error::deserialize(deserializer).map_err(|e| {
+        let path = e.path().to_string();
+        let parent = (path != ".").then_some(path);
+        let error = e.into_inner();
+        let message = error.to_string();
+
+        // serde reports a missing field at the struct it is missing from
+        if let Some(name) = missing_field_name(&message) {
+            return AppError::InvalidBody {
+                code: ErrorCode::MissingField,
+                field: Some(match parent {
+                    Some(parent) => format!("{}.{}", parent, name),
+                    None => name.to_string(),
+                }),
+                message,
+            };
+        }
+
+        let code = match error.classify() {
+            Category::Data => ErrorCode::InvalidField,
+            Category::Syntax | Category::Eof | Category::Io => ErrorCode::MalformedJson,
+        };
+
+        AppError::InvalidBody {
+            code,
+            field: parent,
+            message,
+        }
+    })
+}
+
+/// Name of the field in a serde "missing field `name`" message
+fn missing_field_name(message: &str) -> Option<&str> {
+    let rest = message.strip_prefix("missing field `")?;
+    rest.split_once('`').map(|(name, _)| name)
+}
+
+fn is_json_content_type(headers: &HeaderMap) -> bool {
+    headers
+        .get(CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.split(';').next())
+        .map(|mime| mime.trim().to_ascii_lowercase())
+        .is_some_and(|mime| {
+            mime == "application/json"
+                || (mime.starts_with("application/") && mime.ends_with("+json"))
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::body::Body;
+    use serde::Deserialize;
+
+    use super::*;
+
+    #[derive(Deserialize, Debug)]
+    #[allow(dead_code)]
+    struct Payload {
+        post_id: String,
+        details: Details,
+    }
+
+    #[derive(Deserialize, Debug)]
+    #[allow(dead_code)]
+    struct Details {
+        title: String,
+        publish_at: Option<u64>,
+    }
+
+    async fn parse(content_type: Option<&str>, body: &str) -> Result<Payload, AppError> {
+        let mut request = Request::builder().method("POST").uri("/");
+        if let Some(content_type) = content_type {
+            request = request.header(CONTENT_TYPE, content_type);
+        }
+        let request = request.body(Body::from(body.to_string())).unwrap();
+
+        parse_json_body(request, &()).await
+    }
+
+    async fn rejection(content_type: Option<&str>, body: &str) -> (ErrorCode, Option<String>) {
+        match parse(content_type, body).await {
+            Err(AppError::InvalidBody { code, field, .. }) => (code, field),
+            other => panic!("unexpected result {:?}", other.map(|_| ())),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_accepts_valid_body() {
+        let payload = parse(
+            Some("application/json; charset=utf-8"),
+            r#"{"post_id":"post","details":{"title":"title"}}"#,
+        )
+        .await
+        .unwrap();
+        assert_eq!(payload.details.title, "title");
+    }
+
+    #[tokio::test]
+    async fn test_rejects_missing_content_type() {
+        assert_eq!(
+            rejection(None, r#"{"post_id":"post"}"#).await,
+            (ErrorCode::UnsupportedContentType, None)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_rejects_syntax_error() {
+        let (code, _) = rejection(Some("application/json"), r#"{"post_id":"#).await;
+        assert_eq!(code, ErrorCode::MalformedJson);
+    }
+
+    #[tokio::test]
+    async fn test_reports_path_of_missing_field() {
+        assert_eq!(
+            rejection(
+                Some("application/json"),
+                r#"{"post_id":"post","details":{}}"#
+            )
+            .await,
+            (ErrorCode::MissingField, Some("details.title".to_string()))
+        );
+        assert_eq!(
+            rejection(Some("application/json"), r#"{"details":{"title":"t"}}"#).await,
+            (ErrorCode::MissingField, Some("post_id".to_string()))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_reports_path_of_invalid_field() {
+        assert_eq!(
+            rejection(
+                Some("application/json"),
+                r#"{"post_id":"post","details":{"title":"t","publish_at":"soon"}}"#
+            )
+            .await,
+            (
+                ErrorCode::InvalidField,
+                Some("details.publish_at".to_string())
+            )
+        );
+    }
+}
@@ -0,0 +1,214 @@
+use std::{
+    collections::HashSet,
+    sync::{Arc, LazyLock},
+};
+
+use candid::Principal;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use tokio::{sync::Semaphore, task::JoinSet};
+use yral_canisters_client::{
+    ic::USER_INFO_SERVICE_ID,
+    user_info_service::{Result7 as UsernameLookupResult, UserInfoService},
+};
+
+use crate::utils::{
+    circuit_breaker::CircuitBreaker, notification_client::NotificationType, outbox::Outbox,
+    types::AppError,
+};
+
+/// Upper bound on mentions resolved per post, anything beyond is ignored
+pub const MAX_MENTIONS_PER_POST: usize = 20;
+
+/// Lookups of one post run at most this many at a time
+const MAX_CONCURRENT_LOOKUPS: usize = 5;
+
+static MENTION_REGEX: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"(?:^|[^\w@])@([A-Za-z0-9_.]{3,30})").unwrap());
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct ResolvedMention {
+    pub username: String,
+    pub principal: Principal,
+}
+
+/// Extracts unique `@username` mentions in order of first appearance.
+pub fn parse_mentions(text: &str) -> Vec<String> {
+    let mut seen = HashSet::new();
+
+    MENTION_REGEX
+        .captures_iter(text)
+        .map(|caps| caps[1].trim_end_matches('.').to_string())
+        .filter(|username| seen.insert(username.to_lowercase()))
+        .take(MAX_MENTIONS_PER_POST)
+        .collect()
+}
+
+/// Resolves mentioned usernames to principals. Unknown usernames and failed lookups are skipped,
+/// so one failing lookup does not cost the post its other mentions.
+pub async fn resolve_mentions(
+    ic_admin_agent: &ic_agent::Agent,
+    ic_agent_breaker: &CircuitBreaker,
+    usernames: Vec<String>,
+) -> Vec<ResolvedMention> {
+    let ic_admin_agent = ic_admin_agent.clone();
+    let ic_agent_breaker = ic_agent_breaker.clone();
+
+    resolve_with(usernames, move |username| {
+        let ic_admin_agent = ic_admin_agent.clone();
+        let ic_agent_breaker = ic_agent_breaker.clone();
+        async move {
+            let lookup_result = ic_agent_breaker
+                .call(async {
+                    UserInfoService(USER_INFO_SERVICE_ID, &ic_admin_agent)
+                        .get_user_principal_by_username(username.clone())
+                        .await
+                        .map_err(AppError::from)
+                })
+                .await?;
+
+            match lookup_result {
+                UsernameLookupResult::Ok(principal) => Ok(Some(principal)),
+                UsernameLookupResult::Err(e) => {
+                    log::warn!("Skipping unresolved mention @{}: {}", username, e);
+                    Ok(None)
+                }
+            }
+        }
+    })
+    .await
+}
+
+/// Runs `lookup` for every username, `MAX_CONCURRENT_LOOKUPS` at a time, and keeps the found
+/// principals in the order the usernames were mentioned
+async fn resolve_with<F, Fut>(usernames: Vec<String>, lookup: F) -> Vec<ResolvedMention>
+where
+    F: Fn(String) -> Fut,
+    Fut: Future<Output = Result<Option<Principal>, AppError>> + Send + 'static,
+{
+    let permits = Arc::new(Semaphore::new(MAX_CONCURRENT_LOOKUPS));
+    let mut lookups = JoinSet::new();
+
+    for (index, username) in usernames.into_iter().enumerate() {
+        let permits = permits.clone();
+        let lookup = lookup(username.clone());
+        lookups.spawn(async move {
+            let _permit = permits.acquire_owned().await;
+            (index, username, lookup.await)
+        });
+    }
+
+    let mut resolved = Vec::new();
+    while let Some(joined) = lookups.join_next().await {
+        match joined {
+            Ok((index, username, Ok(Some(principal)))) => resolved.push((
+                index,
+                ResolvedMention {
+                    username,
+                    principal,
+                },
+            )),
+            Ok((_, _, Ok(None))) => {}
+            Ok((_, username, Err(e))) => {
+                log::error!("Failed to resolve mention @{}: {}", username, e);
+            }
+            Err(e) => log::error!("Mention lookup task failed: {}", e),
+        }
+    }
+
+    resolved.sort_by_key(|(index, _)| *index);
+    resolved.into_iter().map(|(_, mention)| mention).collect()
+}
+
+/// Notifies every mentioned user, except the creator mentioning themselves.
+pub fn notify_mentioned_users(
+    outbox: &Outbox,
+    mentions: &[ResolvedMention],
+    creator_principal: Principal,
+    post_id: &str,
+) {
+    for mention in mentions
+        .iter()
+        .filter(|mention| mention.principal != creator_principal)
+    {
+        outbox.enqueue_notification(
+            NotificationType::MentionedInPost {
+                user_principal: mention.principal,
+                mentioned_by: creator_principal,
+                post_id: post_id.to_string(),
+            },
+            mention.principal,
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::atomic::{AtomicUsize, Ordering},
+        time::Duration,
+    };
+
+    use super::*;
+
+    #[test]
+    fn test_parse_mentions() {
+        let mentions = parse_mentions(
+            "@alice check this out with @bob_1 and @Alice. mail me at me@example.com @xy",
+        );
+
+        assert_eq!(mentions, vec!["alice".to_string(), "bob_1".to_string()]);
+    }
+
+    #[tokio::test]
+    async fn test_failed_lookups_are_skipped() {
+        let mentions = resolve_with(
+            vec![
+                "alice".to_string(),
+                "ghost".to_string(),
+                "bob".to_string(),
+                "carol".to_string(),
+            ],
+            |username| async move {
+                match username.as_str() {
+                    "ghost" => Ok(None),
+                    "bob" => Err(AppError::AgentError("timed out".to_string())),
+                    _ => Ok(Some(Principal::self_authenticating(username.as_bytes()))),
+                }
+            },
+        )
+        .await;
+
+        let usernames: Vec<_> = mentions.iter().map(|m| m.username.as_str()).collect();
+        assert_eq!(usernames, vec!["alice", "carol"]);
+        assert_eq!(
+            mentions[1].principal,
+            Principal::self_authenticating(b"carol")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_lookups_run_concurrently_within_the_bound() {
+        let in_flight = Arc::new(AtomicUsize::new(0));
+        let max_in_flight = Arc::new(AtomicUsize::new(0));
+
+        let usernames = (0..MAX_MENTIONS_PER_POST)
+            .map(|i| format!("user{}", i))
+            .collect();
+        let mentions = resolve_with(usernames, |_| {
+            let in_flight = in_flight.clone();
+            let max_in_flight = max_in_flight.clone();
+            async move {
+                let current = in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+                max_in_flight.fetch_max(current, Ordering::SeqCst);
+                tokio::time::sleep(Duration::from_millis(10)).await;
+                in_flight.fetch_sub(1, Ordering::SeqCst);
+                Ok(Some(Principal::anonymous()))
+            }
+        })
+        .await;
+
+        assert_eq!(mentions.len(), MAX_MENTIONS_PER_POST);
+        assert_eq!(max_in_flight.load(Ordering::SeqCst), MAX_CONCURRENT_LOOKUPS);
+    }
+}
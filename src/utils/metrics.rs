@@ -0,0 +1,65 @@
+use std::sync::LazyLock;
+
+use axum::{http::header::CONTENT_TYPE, response::IntoResponse};
+use prometheus::{
+    IntCounterVec, IntGaugeVec, TextEncoder, register_int_counter_vec, register_int_gauge_vec,
+};
+
+pub static REQUEST_RETRIES: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "downstream_request_retries_total",
+        "Downstream requests retried after a transient failure",
+        &["operation"]
+    )
+    .unwrap()
+});
+
+/// 0 closed, 1 half-open, 2 open
+pub static CIRCUIT_BREAKER_STATE: LazyLock<IntGaugeVec> = LazyLock::new(|| {
+    register_int_gauge_vec!(
+        "circuit_breaker_state",
+        "State of the circuit breaker of a downstream service (0 closed, 1 half-open, 2 open)",
+        &["dependency"]
+    )
+    .unwrap()
+});
+
+pub static CIRCUIT_BREAKER_REJECTIONS: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "circuit_breaker_rejections_total",
+        "Calls failed fast because the downstream's circuit breaker was open",
+        &["dependency"]
+    )
+    .unwrap()
+});
+
+pub static BULKHEAD_QUEUE_DEPTH: LazyLock<IntGaugeVec> = LazyLock::new(|| {
+    register_int_gauge_vec!(
+        "bulkhead_queue_depth",
+        "Calls waiting for a free slot of the downstream's bulkhead",
+        &["dependency"]
+    )
+    .unwrap()
+});
+
+pub static BULKHEAD_REJECTIONS: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "bulkhead_rejections_total",
+        "Calls failed because no slot of the downstream's bulkhead freed up in time",
+        &["dependency"]
+    )
+    .unwrap()
+});
+
+/// Renders every registered metric in the Prometheus text format
+pub async fn metrics_handler() -> impl IntoResponse {
+    let encoder = TextEncoder::new();
+    let body = encoder
+        .encode_to_string(&prometheus::gather())
+        .unwrap_or_else(|e| {
+            log::error!("Failed to encode metrics: {}", e);
+            String::new()
+        });
+
+    ([(CONTENT_TYPE, prometheus::TEXT_FORMAT)], body)
+}
@@ -1,4 +1,31 @@
+pub mod admin_auth;
+pub mod audit_log;
+pub mod blocklist;
+pub mod body_limit;
+pub mod bulkhead;
+pub mod circuit_breaker;
+pub mod content_filter;
+pub mod cors;
+pub mod dead_letters;
+pub mod deadline;
+pub mod delegation;
 pub mod events_interface;
+pub mod finalize_jobs;
+pub mod idempotency;
+pub mod json_body;
+pub mod mentions;
+pub mod metrics;
 pub mod notification_client;
+pub mod outbox;
+pub mod publish_scheduler;
+pub mod query;
+pub mod reconciler;
+pub mod request_auth;
+pub mod request_id;
+pub mod retry;
+pub mod store;
 pub mod storj_interface;
+pub mod time;
 pub mod types;
+pub mod upload_saga;
+pub mod upload_sessions;
@@ -3,20 +3,50 @@ use std::fmt::Display;
 use candid::Principal;
 use serde::{Deserialize, Serialize};
 
+use crate::{
+    config::TimeoutConfig,
+    utils::{circuit_breaker::CircuitBreaker, retry::HttpCallError},
+};
+
 const METADATA_SERVER_URL: &str = "https://metadata.yral.com";
 
 #[derive(Clone, Debug)]
 pub struct NotificationClient {
     api_key: String,
+    client: reqwest::Client,
+    breaker: CircuitBreaker,
 }
 
 impl NotificationClient {
-    pub fn new(api_key: String) -> Self {
-        Self { api_key }
+    pub fn new(api_key: String, timeouts: TimeoutConfig, breaker: CircuitBreaker) -> Self {
+        Self {
+            api_key,
+            client: timeouts
+                .client_builder()
+                .build()
+                .expect("Invalid notification client config"),
+            breaker,
+        }
+    }
+
+    pub async fn send_notification(
+        &self,
+        data: NotificationType,
+        user_principal: Principal,
+    ) -> Result<(), HttpCallError> {
+        self.breaker
+            .call_counting(
+                self.post_notification(data, user_principal),
+                HttpCallError::is_downstream_failure,
+            )
+            .await
     }
 
-    pub async fn send_notification(&self, data: NotificationType, user_principal: Principal) {
-        let client = reqwest::Client::new();
+    async fn post_notification(
+        &self,
+        data: NotificationType,
+        user_principal: Principal,
+    ) -> Result<(), HttpCallError> {
         let url = format!(
             "{}/notifications/{}/send",
             METADATA_SERVER_URL,
@@ -26,45 +56,34 @@ impl NotificationClient {
         let title = data.to_string();
         let notification = Notification {
             notification: NotificationInfo {
-                title: title,
+                title,
                 body: String::new(),
             },
             data,
         };
 
-        let res = client
+        let response = self
+            .client
             .post(&url)
             .bearer_auth(&self.api_key)
             .json(&notification)
             .send()
-            .await;
+            .await?;
 
-        match res {
-            Ok(response) => {
-                if response.status().is_success() {
-                    log::info!(
-                        "Notification sent successfully to user {}",
-                        user_principal.to_text()
-                    );
-                } else if let Ok(body) = response.text().await {
-                    let msg = format!(
-                        "Failed to send notification to user {}: {}",
-                        user_principal.to_text(),
-                        body
-                    );
-                    log::error!("{}", msg);
-                    sentry::capture_message(&msg, sentry::Level::Error);
-                }
-            }
-            Err(req_err) => {
-                let msg = format!(
-                    "Error sending notification request to user {}: {}",
-                    user_principal.to_text(),
-                    req_err
-                );
-                log::error!("{}", msg);
-                sentry::capture_message(&msg, sentry::Level::Error);
-            }
+        if response.status().is_success() {
+            log::info!(
+                "Notification sent successfully to user {}",
+                user_principal.to_text()
+            );
+            Ok(())
+        } else {
+            let error = HttpCallError::from_response(response).await;
+            log::warn!(
+                "Failed to send notification to user {}: {}",
+                user_principal.to_text(),
+                error
+            );
+            Err(error)
         }
     }
 }
@@ -81,7 +100,7 @@ pub struct Notification {
     pub data: NotificationType,
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Clone, Debug)]
 #[serde(tag = "type")]
 pub enum NotificationType {
     VideoUploadedToDraft {
@@ -92,6 +111,11 @@ pub enum NotificationType {
         user_principal: Principal,
         post_id: String,
     },
+    MentionedInPost {
+        user_principal: Principal,
+        mentioned_by: Principal,
+        post_id: String,
+    },
 }
 
 impl Display for NotificationType {
@@ -112,6 +136,13 @@ impl Display for NotificationType {
             } => {
                 write!(f, "Your video has been published successfully")
             }
+            NotificationType::MentionedInPost {
+                user_principal: _user_principal,
+                mentioned_by: _mentioned_by,
+                post_id: _post_id,
+            } => {
+                write!(f, "You were mentioned in a video")
+            }
         }
     }
 }
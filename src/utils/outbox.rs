@@ -0,0 +1,432 @@
+use std::{sync::Arc, time::Duration};
+
+use candid::Principal;
+use serde::{Deserialize, Serialize};
+use tokio::sync::Notify;
+
+use crate::utils::{
+    dead_letters::{DeadLetterOperation, DeadLetters},
+    events_interface::EventService,
+    notification_client::{NotificationClient, NotificationType},
+    retry::HttpCallError,
+    store::{Store, StoreError, TypedTree},
+    time::now_unix_secs,
+    types::CreationContext,
+};
+
+const BASE_BACKOFF_SECS: u64 = 2;
+const MAX_BACKOFF_SECS: u64 = 10 * 60;
+
+/// A side effect that is delivered after the request that produced it has returned.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum OutboxMessage {
+    VideoUploadSuccessful {
+        video_uid: String,
+        hashtags_len: usize,
+        is_nsfw: bool,
+        post_id: String,
+        user_principal: Principal,
+        canister_id: Principal,
+        user_name: String,
+        creation_context: CreationContext,
+    },
+    VideoUploadUnsuccessful {
+        error: String,
+        hashtags_len: usize,
+        is_nsfw: bool,
+        enable_hot_or_not: bool,
+        user_principal: Principal,
+        user_name: String,
+        user_canister: Principal,
+        matched_filter_rule: Option<String>,
+    },
+    Notification {
+        data: NotificationType,
+        user_principal: Principal,
+    },
+}
+
+impl OutboxMessage {
+    async fn deliver(
+        &self,
+        events_service: &EventService,
+        notification_client: &NotificationClient,
+    ) -> Result<(), HttpCallError> {
+        match self.clone() {
+            OutboxMessage::VideoUploadSuccessful {
+                video_uid,
+                hashtags_len,
+                is_nsfw,
+                post_id,
+                user_principal,
+                canister_id,
+                user_name,
+                creation_context,
+            } => {
+                events_service
+                    .send_video_upload_successful_event(
+                        video_uid,
+                        hashtags_len,
+                        is_nsfw,
+                        post_id,
+                        user_principal,
+                        canister_id,
+                        user_name,
+                        &creation_context,
+                    )
+                    .await
+            }
+            OutboxMessage::VideoUploadUnsuccessful {
+                error,
+                hashtags_len,
+                is_nsfw,
+                enable_hot_or_not,
+                user_principal,
+                user_name,
+                user_canister,
+                matched_filter_rule,
+            } => {
+                events_service
+                    .send_video_event_unsuccessful(
+                        error,
+                        hashtags_len,
+                        is_nsfw,
+                        enable_hot_or_not,
+                        user_principal,
+                        user_name,
+                        user_canister,
+                        matched_filter_rule,
+                    )
+                    .await
+            }
+            OutboxMessage::Notification {
+                data,
+                user_principal,
+            } => {
+                notification_client
+                    .send_notification(data, user_principal)
+                    .await
+            }
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct OutboxEntry {
+    pub id: String,
+    pub message: OutboxMessage,
+    pub attempts: u32,
+    /// Unix timestamp in seconds before which the entry is not retried
+    pub next_attempt_at: u64,
+    pub last_error: Option<String>,
+    pub created_at: u64,
+}
+
+/// Durable queue of events and notifications, drained by a background dispatcher. Entries are
+/// keyed by when they are due, so the dispatcher only reads the ones it delivers.
+#[derive(Clone)]
+pub struct Outbox {
+    store: Store,
+    entries: TypedTree<OutboxEntry>,
+    dead_letters: DeadLetters,
+    wake_dispatcher: Arc<Notify>,
+}
+
+impl Outbox {
+    pub fn new(store: &Store, dead_letters: DeadLetters) -> Result<Self, StoreError> {
+        Ok(Self {
+            store: store.clone(),
+            entries: store.tree("outbox")?,
+            dead_letters,
+            wake_dispatcher: Arc::new(Notify::new()),
+        })
+    }
+
+    /// Persists the message for delivery. Messages are side effects of a write that already
+    /// happened, so one that cannot be stored is logged and reported to Sentry instead of failing
+    /// the request that produced it.
+    pub fn enqueue(&self, message: OutboxMessage) {
+        let created_at = now_unix_secs();
+        // entries due at the same time are delivered in the order they were created
+        let result = self.store.generate_id().and_then(|seq| {
+            let entry = OutboxEntry {
+                id: format!("{:020}-{:020}", created_at, seq),
+                message: message.clone(),
+                attempts: 0,
+                next_attempt_at: created_at,
+                last_error: None,
+                created_at,
+            };
+
+            self.entries.insert(&due_key(&entry), &entry)
+        });
+
+        if let Err(e) = result {
+            let msg = format!("Failed to enqueue outbox message {:?}: {}", message, e);
+            log::error!("{}", msg);
+            sentry::capture_message(&msg, sentry::Level::Error);
+            return;
+        }
+
+        self.wake_dispatcher.notify_one();
+    }
+
+    pub fn enqueue_notification(&self, data: NotificationType, user_principal: Principal) {
+        self.enqueue(OutboxMessage::Notification {
+            data,
+            user_principal,
+        });
+    }
+
+    fn due_entries(&self, now: u64) -> Result<Vec<OutboxEntry>, StoreError> {
+        Ok(self
+            .entries
+            .range("", &format!("{:020}", now.saturating_add(1)))?
+            .into_iter()
+            .map(|(_, entry)| entry)
+            .collect())
+    }
+
+    /// Starts the background task delivering due entries. It wakes up on every enqueue and at
+    /// least once per `poll_interval` to pick up retries.
+    pub fn spawn_dispatcher(
+        &self,
+        events_service: EventService,
+        notification_client: NotificationClient,
+        poll_interval: Duration,
+        max_attempts: u32,
+    ) {
+        let outbox = self.clone();
+
+        tokio::spawn(async move {
+            loop {
+                let due_entries = match outbox.due_entries(now_unix_secs()) {
+                    Ok(due_entries) => due_entries,
+                    Err(e) => {
+                        log::error!("Failed to load due outbox entries: {}", e);
+                        Vec::new()
+                    }
+                };
+
+                for entry in due_entries {
+                    outbox
+                        .dispatch(entry, &events_service, &notification_client, max_attempts)
+                        .await;
+                }
+
+                let _ =
+                    tokio::time::timeout(poll_interval, outbox.wake_dispatcher.notified()).await;
+            }
+        });
+    }
+
+    async fn dispatch(
+        &self,
+        entry: OutboxEntry,
+        events_service: &EventService,
+        notification_client: &NotificationClient,
+        max_attempts: u32,
+    ) {
+        let delivery_error = entry
+            .message
+            .deliver(events_service, notification_client)
+            .await
+            .err()
+            .map(|e| e.to_string());
+
+        self.settle(entry, delivery_error, max_attempts, now_unix_secs());
+    }
+
+    /// Removes a delivered entry, or reschedules a failed one under its new due time until it
+    /// runs out of attempts and moves to the dead letters
+    fn settle(
+        &self,
+        mut entry: OutboxEntry,
+        delivery_error: Option<String>,
+        max_attempts: u32,
+        now: u64,
+    ) {
+        let key = due_key(&entry);
+
+        let store_result = match delivery_error {
+            None => self.entries.remove(&key).map(|_| ()),
+            Some(error) => {
+                entry.attempts += 1;
+                entry.last_error = Some(error.clone());
+
+                if entry.attempts >= max_attempts {
+                    let msg = format!(
+                        "Outbox entry {} moved to dead letter after {} attempts: {}",
+                        entry.id, entry.attempts, error
+                    );
+                    log::error!("{}", msg);
+                    sentry::capture_message(&msg, sentry::Level::Error);
+
+                    self.dead_letters.record(
+                        DeadLetterOperation::OutboxDelivery {
+                            message: entry.message.clone(),
+                        },
+                        &error,
+                        entry.attempts,
+                    );
+                    self.entries.remove(&key).map(|_| ())
+                } else {
+                    entry.next_attempt_at = now + backoff_secs(entry.attempts);
+                    log::warn!(
+                        "Outbox entry {} failed (attempt {}), retrying at {}: {}",
+                        entry.id,
+                        entry.attempts,
+                        entry.next_attempt_at,
+                        error
+                    );
+
+                    // a crash in between delivers the entry twice rather than never
+                    self.entries
+                        .insert(&due_key(&entry), &entry)
+                        .and_then(|_| self.entries.remove(&key).map(|_| ()))
+                }
+            }
+        };
+
+        if let Err(e) = store_result {
+            log::error!("Failed to update outbox entry {}: {}", entry.id, e);
+        }
+    }
+}
+
+/// Key of an entry, sorting by when it is due
+fn due_key(entry: &OutboxEntry) -> String {
+    format!("{:020}-{}", entry.next_attempt_at, entry.id)
+}
+
+/// Exponential backoff with up to 50% random jitter
+fn backoff_secs(attempts: u32) -> u64 {
+    let backoff = BASE_BACKOFF_SECS
+        .saturating_mul(2u64.saturating_pow(attempts.saturating_sub(1)))
+        .min(MAX_BACKOFF_SECS);
+
+    backoff + rand::random_range(0..=backoff / 2)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::dead_letters::DeadLetterKind;
+
+    fn outbox() -> (Outbox, DeadLetters) {
+        let store = Store::temporary().unwrap();
+        let dead_letters = DeadLetters::new(&store).unwrap();
+        (
+            Outbox::new(&store, dead_letters.clone()).unwrap(),
+            dead_letters,
+        )
+    }
+
+    fn message(error: &str) -> OutboxMessage {
+        OutboxMessage::VideoUploadUnsuccessful {
+            error: error.to_string(),
+            hashtags_len: 0,
+            is_nsfw: false,
+            enable_hot_or_not: false,
+            user_principal: Principal::anonymous(),
+            user_name: "user".to_string(),
+            user_canister: Principal::anonymous(),
+            matched_filter_rule: None,
+        }
+    }
+
+    fn error_of(entry: &OutboxEntry) -> &str {
+        match &entry.message {
+            OutboxMessage::VideoUploadUnsuccessful { error, .. } => error,
+            other => panic!("unexpected message {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_enqueued_entries_are_due_in_order() {
+        let (outbox, _) = outbox();
+        outbox.enqueue(message("first"));
+        outbox.enqueue(message("second"));
+
+        let now = now_unix_secs();
+        assert!(outbox.due_entries(now - 1).unwrap().is_empty());
+
+        let due = outbox.due_entries(now).unwrap();
+        assert_eq!(
+            due.iter().map(error_of).collect::<Vec<_>>(),
+            ["first", "second"]
+        );
+        assert!(due.iter().all(|entry| entry.attempts == 0));
+    }
+
+    #[test]
+    fn test_delivered_entry_is_removed() {
+        let (outbox, _) = outbox();
+        outbox.enqueue(message("delivered"));
+        let now = now_unix_secs();
+
+        let entry = outbox.due_entries(now).unwrap().remove(0);
+        outbox.settle(entry, None, 3, now);
+
+        assert!(outbox.entries.entries().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_failed_delivery_is_retried_after_backoff() {
+        let (outbox, dead_letters) = outbox();
+        outbox.enqueue(message("retried"));
+        let now = now_unix_secs();
+
+        let entry = outbox.due_entries(now).unwrap().remove(0);
+        outbox.settle(entry, Some("503 - unavailable".to_string()), 3, now);
+
+        assert!(outbox.due_entries(now).unwrap().is_empty());
+        let due = outbox
+            .due_entries(now + BASE_BACKOFF_SECS + BASE_BACKOFF_SECS / 2)
+            .unwrap();
+        assert_eq!(due.len(), 1);
+        assert_eq!(due[0].attempts, 1);
+        assert_eq!(due[0].last_error.as_deref(), Some("503 - unavailable"));
+        assert_eq!(outbox.entries.entries().unwrap().len(), 1);
+        assert!(dead_letters.list(None).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_entry_out_of_attempts_moves_to_dead_letters() {
+        let (outbox, dead_letters) = outbox();
+        outbox.enqueue(message("dead"));
+        let mut now = now_unix_secs();
+
+        for _ in 0..2 {
+            let entry = outbox.due_entries(now).unwrap().remove(0);
+            outbox.settle(entry, Some("503 - unavailable".to_string()), 2, now);
+            now += MAX_BACKOFF_SECS * 2;
+        }
+
+        assert!(outbox.entries.entries().unwrap().is_empty());
+        let letters = dead_letters
+            .list(Some(DeadLetterKind::OutboxDelivery))
+            .unwrap();
+        assert_eq!(letters.len(), 1);
+        assert_eq!(letters[0].attempts, 2);
+        assert_eq!(letters[0].error, "503 - unavailable");
+    }
+
+    #[test]
+    fn test_backoff_grows_within_jitter_bounds() {
+        for attempts in 1..=12 {
+            let base = (BASE_BACKOFF_SECS << (attempts - 1)).min(MAX_BACKOFF_SECS);
+
+            for _ in 0..20 {
+                let backoff = backoff_secs(attempts);
+                assert!(
+                    (base..=base + base / 2).contains(&backoff),
+                    "{} after {} attempts",
+                    backoff,
+                    attempts
+                );
+            }
+        }
+    }
+}
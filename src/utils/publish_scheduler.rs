@@ -0,0 +1,561 @@
+use std::time::Duration;
+
+use candid::Principal;
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+use crate::{
+    api::mark_post_as_published::{fetch_post_details, post_status_label, publish_post},
+    app_state::AppState,
+    utils::{
+        audit_log::{AuditAction, AuditEvent},
+        dead_letters::{DeadLetterOperation, DeadLetters},
+        store::{Store, StoreError, TypedTree},
+        time::now_unix_secs,
+        types::AppError,
+    },
+};
+
+/// Posts cannot be scheduled further out than this
+pub const MAX_SCHEDULE_HORIZON_SECS: u64 = 90 * 24 * 60 * 60;
+
+/// Failed publishes are retried on every poll until this many attempts were made
+const MAX_PUBLISH_ATTEMPTS: u32 = 5;
+
+#[derive(Serialize, Deserialize, Clone, Debug, ToSchema)]
+pub struct ScheduledPost {
+    #[schema(example = "post-id-string")]
+    pub post_id: String,
+    #[schema(value_type = String, example = "principal-id-string")]
+    pub creator_principal: Principal,
+    /// Unix timestamp in seconds at which the post is published
+    pub publish_at: u64,
+    pub created_at: u64,
+    #[serde(default)]
+    pub attempts: u32,
+    #[serde(default)]
+    pub last_error: Option<String>,
+    /// Set once a due publish was held back because the creator is blocked, so that the hold is
+    /// logged once rather than on every poll
+    #[serde(default)]
+    pub held: bool,
+}
+
+fn due_key(publish_at: u64, post_id: &str) -> String {
+    format!("{:020}\0{}", publish_at, post_id)
+}
+
+/// Persists scheduled publishes and publishes them once they are due.
+#[derive(Clone)]
+pub struct PublishScheduler {
+    posts: TypedTree<ScheduledPost>,
+    /// `publish_at\0post_id` to the post id, so that a poll reads only the due posts. An entry
+    /// whose post was cancelled or rescheduled since is dropped once it is due.
+    due: TypedTree<String>,
+}
+
+impl PublishScheduler {
+    pub fn new(store: &Store) -> Result<Self, StoreError> {
+        Ok(Self {
+            posts: store.tree("scheduled_posts")?,
+            due: store.tree("scheduled_posts_by_due")?,
+        })
+    }
+
+    pub fn validate_publish_at(publish_at: u64) -> Result<(), AppError> {
+        let now = now_unix_secs();
+
+        if publish_at <= now {
+            return Err(AppError::InvalidRequest(format!(
+                "publish_at {} is not in the future",
+                publish_at
+            )));
+        }
+
+        if publish_at > now + MAX_SCHEDULE_HORIZON_SECS {
+            return Err(AppError::InvalidRequest(format!(
+                "publish_at {} is more than {} seconds in the future",
+                publish_at, MAX_SCHEDULE_HORIZON_SECS
+            )));
+        }
+
+        Ok(())
+    }
+
+    pub fn schedule(
+        &self,
+        post_id: String,
+        creator_principal: Principal,
+        publish_at: u64,
+    ) -> Result<ScheduledPost, AppError> {
+        Self::validate_publish_at(publish_at)?;
+
+        let scheduled_post = ScheduledPost {
+            post_id,
+            creator_principal,
+            publish_at,
+            created_at: now_unix_secs(),
+            attempts: 0,
+            last_error: None,
+            held: false,
+        };
+        self.posts
+            .insert(&scheduled_post.post_id, &scheduled_post)?;
+        self.due.insert(
+            &due_key(publish_at, &scheduled_post.post_id),
+            &scheduled_post.post_id,
+        )?;
+
+        Ok(scheduled_post)
+    }
+
+    pub fn get(&self, post_id: &str) -> Result<Option<ScheduledPost>, AppError> {
+        Ok(self.posts.get(post_id)?)
+    }
+
+    pub fn list_for_creator(
+        &self,
+        creator_principal: Principal,
+    ) -> Result<Vec<ScheduledPost>, AppError> {
+        let mut scheduled_posts: Vec<_> = self
+            .posts
+            .values()?
+            .into_iter()
+            .filter(|post| post.creator_principal == creator_principal)
+            .collect();
+        scheduled_posts.sort_by_key(|post| post.publish_at);
+
+        Ok(scheduled_posts)
+    }
+
+    pub fn reschedule(&self, post_id: &str, publish_at: u64) -> Result<ScheduledPost, AppError> {
+        Self::validate_publish_at(publish_at)?;
+
+        let rescheduled = self.posts.fetch_and_update(post_id, |post| {
+            post.map(|mut post| {
+                post.publish_at = publish_at;
+                post.attempts = 0;
+                post.last_error = None;
+                post
+            })
+        })?;
+        let Some(mut scheduled_post) = rescheduled else {
+            return Err(AppError::PostNotFound(format!(
+                "No scheduled publish for post id {}",
+                post_id
+            )));
+        };
+        self.due
+            .insert(&due_key(publish_at, post_id), &post_id.to_string())?;
+
+        scheduled_post.publish_at = publish_at;
+        scheduled_post.attempts = 0;
+        scheduled_post.last_error = None;
+
+        Ok(scheduled_post)
+    }
+
+    pub fn cancel(&self, post_id: &str) -> Result<Option<ScheduledPost>, AppError> {
+        Ok(self.posts.remove(post_id)?)
+    }
+
+    /// Drops the schedule of a post that was just published or deleted on the canister. The
+    /// canister write cannot be taken back, so a schedule that fails to go away is reported to
+    /// Sentry instead of turning the request into an error.
+    pub fn cancel_superseded(&self, post_id: &str) {
+        if let Err(e) = self.cancel(post_id) {
+            let msg = format!(
+                "Failed to cancel the schedule of post {} after it changed on the canister: {}",
+                post_id, e
+            );
+            log::error!("{}", msg);
+            sentry::capture_message(&msg, sentry::Level::Error);
+        }
+    }
+
+    fn due_posts(&self, now: u64) -> Result<Vec<ScheduledPost>, StoreError> {
+        let mut due_posts = Vec::new();
+
+        for (key, post_id) in self
+            .due
+            .range("", &format!("{:020}", now.saturating_add(1)))?
+        {
+            match self.posts.get(&post_id)? {
+                Some(post) if due_key(post.publish_at, &post_id) == key => due_posts.push(post),
+                // cancelled, or rescheduled under another key
+                _ => {
+                    self.due.remove(&key)?;
+                }
+            }
+        }
+
+        Ok(due_posts)
+    }
+
+    /// Replaces the post with `update(post)`, `None` dropping it, unless it was cancelled or
+    /// rescheduled while `scheduled_post` was being published
+    fn settle(
+        &self,
+        scheduled_post: &ScheduledPost,
+        update: impl Fn(ScheduledPost) -> Option<ScheduledPost>,
+    ) -> Result<(), StoreError> {
+        let post_id = &scheduled_post.post_id;
+        let mut dropped = false;
+        self.posts.fetch_and_update(post_id, |post| {
+            dropped = false;
+            match post {
+                Some(post) if post.publish_at == scheduled_post.publish_at => {
+                    let updated = update(post);
+                    dropped = updated.is_none();
+                    updated
+                }
+                post => post,
+            }
+        })?;
+
+        if dropped {
+            self.due
+                .remove(&due_key(scheduled_post.publish_at, post_id))?;
+        }
+
+        Ok(())
+    }
+
+    /// Starts the background task publishing due posts every `poll_interval`.
+    pub fn spawn(&self, app_state: AppState, poll_interval: Duration) {
+        let scheduler = self.clone();
+
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(poll_interval);
+
+            loop {
+                ticker.tick().await;
+
+                let due_posts = match scheduler.due_posts(now_unix_secs()) {
+                    Ok(due_posts) => due_posts,
+                    Err(e) => {
+                        log::error!("Failed to load due scheduled posts: {}", e);
+                        continue;
+                    }
+                };
+
+                for scheduled_post in due_posts {
+                    scheduler.publish_due_post(&app_state, scheduled_post).await;
+                }
+            }
+        });
+    }
+
+    async fn publish_due_post(&self, app_state: &AppState, scheduled_post: ScheduledPost) {
+        // the post stays scheduled and goes out once the block lifts
+        if let Err(e) = app_state
+            .blocklist
+            .ensure_not_blocked(scheduled_post.creator_principal)
+        {
+            if !scheduled_post.held {
+                log::info!(
+                    "Holding scheduled publish of post {}: {}",
+                    scheduled_post.post_id,
+                    e
+                );
+                self.mark_held(&scheduled_post.post_id);
+            }
+            return;
+        }
+
+        let mut status_before = None;
+        let publish_result = async {
+            let post_details = fetch_post_details(
+                &app_state.ic_admin_agent,
+                &app_state.circuit_breakers.ic_agent,
+                &scheduled_post.post_id,
+            )
+            .await?;
+            status_before = Some(post_status_label(&post_details.status));
+
+            publish_post(
+                &app_state.ic_admin_agent,
+                &app_state.circuit_breakers.ic_agent,
+                &app_state.storj_client,
+                &app_state.outbox,
+                post_details,
+            )
+            .await
+        }
+        .await;
+
+        app_state.audit_log.record(
+            AuditEvent::new(
+                AuditAction::Publish,
+                Some(scheduled_post.post_id.clone()),
+                Some(scheduled_post.creator_principal.to_text()),
+                None,
+            )
+            .with_transition_from(status_before, "published")
+            .with_error(publish_result.as_ref().err()),
+        );
+
+        let store_result = match publish_result {
+            Ok(()) => {
+                log::info!("Published scheduled post {}", scheduled_post.post_id);
+                self.settle(&scheduled_post, |_| None)
+            }
+            Err(e) => {
+                let attempts = scheduled_post.attempts + 1;
+
+                // retrying or replaying a client error such as a duplicate post cannot help
+                let worth_retrying = DeadLetters::should_record(&e);
+
+                if !worth_retrying || attempts >= MAX_PUBLISH_ATTEMPTS {
+                    let msg = format!(
+                        "Giving up on scheduled publish of post {} after {} attempts: {}",
+                        scheduled_post.post_id, attempts, e
+                    );
+                    log::error!("{}", msg);
+                    sentry::capture_message(&msg, sentry::Level::Error);
+
+                    if worth_retrying {
+                        app_state.dead_letters.record(
+                            DeadLetterOperation::ScheduledPublish {
+                                post_id: scheduled_post.post_id.clone(),
+                                creator_principal: scheduled_post.creator_principal,
+                            },
+                            &e.to_string(),
+                            attempts,
+                        );
+                    }
+                    self.settle(&scheduled_post, |_| None)
+                } else {
+                    log::warn!(
+                        "Scheduled publish of post {} failed (attempt {}): {}",
+                        scheduled_post.post_id,
+                        attempts,
+                        e
+                    );
+                    self.settle(&scheduled_post, |mut post| {
+                        post.attempts = attempts;
+                        post.last_error = Some(e.to_string());
+                        Some(post)
+                    })
+                }
+            }
+        };
+
+        if let Err(e) = store_result {
+            log::error!(
+                "Failed to update scheduled post {}: {}",
+                scheduled_post.post_id,
+                e
+            );
+        }
+    }
+
+    /// Flags a post still scheduled as held, leaving it alone when it was cancelled meanwhile
+    fn mark_held(&self, post_id: &str) {
+        let result = self.posts.fetch_and_update(post_id, |post| {
+            post.map(|mut post| {
+                post.held = true;
+                post
+            })
+        });
+
+        if let Err(e) = result {
+            log::error!("Failed to mark scheduled post {} as held: {}", post_id, e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn scheduler() -> PublishScheduler {
+        PublishScheduler::new(&Store::temporary().unwrap()).unwrap()
+    }
+
+    fn creator(name: &str) -> Principal {
+        Principal::self_authenticating(name.as_bytes())
+    }
+
+    #[test]
+    fn test_publish_at_must_be_within_the_horizon() {
+        let now = now_unix_secs();
+        let cases = [
+            (now - 1, false),
+            (now, false),
+            (now + 60, true),
+            (now + MAX_SCHEDULE_HORIZON_SECS - 60, true),
+            (now + MAX_SCHEDULE_HORIZON_SECS + 60, false),
+        ];
+
+        for (publish_at, valid) in cases {
+            assert_eq!(
+                PublishScheduler::validate_publish_at(publish_at).is_ok(),
+                valid,
+                "{}",
+                publish_at
+            );
+        }
+    }
+
+    #[test]
+    fn test_lists_the_creators_posts_by_publish_time() {
+        let scheduler = scheduler();
+        let now = now_unix_secs();
+        scheduler
+            .schedule("late".to_string(), creator("alice"), now + 600)
+            .unwrap();
+        scheduler
+            .schedule("early".to_string(), creator("alice"), now + 60)
+            .unwrap();
+        scheduler
+            .schedule("other".to_string(), creator("bob"), now + 60)
+            .unwrap();
+
+        let post_ids: Vec<_> = scheduler
+            .list_for_creator(creator("alice"))
+            .unwrap()
+            .into_iter()
+            .map(|post| post.post_id)
+            .collect();
+        assert_eq!(post_ids, ["early", "late"]);
+    }
+
+    #[test]
+    fn test_reschedule_resets_failed_attempts() {
+        let scheduler = scheduler();
+        let now = now_unix_secs();
+        let mut post = scheduler
+            .schedule("post".to_string(), creator("alice"), now + 60)
+            .unwrap();
+        post.attempts = 3;
+        post.last_error = Some("timed out".to_string());
+        scheduler.posts.insert("post", &post).unwrap();
+
+        let post = scheduler.reschedule("post", now + 600).unwrap();
+        assert_eq!(post.publish_at, now + 600);
+        assert_eq!(post.attempts, 0);
+        assert_eq!(post.last_error, None);
+
+        assert!(matches!(
+            scheduler.reschedule("missing", now + 600),
+            Err(AppError::PostNotFound(_))
+        ));
+    }
+
+    #[test]
+    fn test_only_due_posts_are_published() {
+        let scheduler = scheduler();
+        let now = now_unix_secs();
+        scheduler
+            .schedule("soon".to_string(), creator("alice"), now + 60)
+            .unwrap();
+        scheduler
+            .schedule("later".to_string(), creator("alice"), now + 600)
+            .unwrap();
+
+        assert!(scheduler.due_posts(now).unwrap().is_empty());
+        let due: Vec<_> = scheduler
+            .due_posts(now + 60)
+            .unwrap()
+            .into_iter()
+            .map(|post| post.post_id)
+            .collect();
+        assert_eq!(due, ["soon"]);
+    }
+
+    #[test]
+    fn test_rescheduled_post_is_due_at_its_new_time_only() {
+        let scheduler = scheduler();
+        let now = now_unix_secs();
+        scheduler
+            .schedule("post".to_string(), creator("alice"), now + 60)
+            .unwrap();
+        scheduler.reschedule("post", now + 600).unwrap();
+
+        assert!(scheduler.due_posts(now + 60).unwrap().is_empty());
+        // the entry of the old time is dropped once it is due
+        assert_eq!(scheduler.due.entries().unwrap().len(), 1);
+        assert_eq!(scheduler.due_posts(now + 600).unwrap().len(), 1);
+
+        scheduler.cancel("post").unwrap();
+        assert!(scheduler.due_posts(now + 600).unwrap().is_empty());
+        assert!(scheduler.due.entries().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_settling_leaves_a_post_changed_during_the_attempt_alone() {
+        let scheduler = scheduler();
+        let now = now_unix_secs();
+        let attempted = scheduler
+            .schedule("post".to_string(), creator("alice"), now + 60)
+            .unwrap();
+
+        // rescheduled while the publish was running
+        scheduler.reschedule("post", now + 600).unwrap();
+        scheduler.settle(&attempted, |_| None).unwrap();
+        assert_eq!(
+            scheduler.get("post").unwrap().unwrap().publish_at,
+            now + 600
+        );
+
+        // cancelled while the publish was running
+        scheduler.cancel("post").unwrap();
+        scheduler
+            .settle(&attempted, |mut post| {
+                post.attempts += 1;
+                Some(post)
+            })
+            .unwrap();
+        assert!(scheduler.get("post").unwrap().is_none());
+    }
+
+    #[test]
+    fn test_settling_an_unchanged_post() {
+        let scheduler = scheduler();
+        let now = now_unix_secs();
+        let attempted = scheduler
+            .schedule("post".to_string(), creator("alice"), now + 60)
+            .unwrap();
+
+        scheduler
+            .settle(&attempted, |mut post| {
+                post.attempts = 1;
+                Some(post)
+            })
+            .unwrap();
+        assert_eq!(scheduler.get("post").unwrap().unwrap().attempts, 1);
+
+        scheduler.settle(&attempted, |_| None).unwrap();
+        assert!(scheduler.get("post").unwrap().is_none());
+        assert!(scheduler.due.entries().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_superseded_schedule_is_cancelled() {
+        let scheduler = scheduler();
+        scheduler
+            .schedule("post".to_string(), creator("alice"), now_unix_secs() + 60)
+            .unwrap();
+
+        scheduler.cancel_superseded("post");
+        scheduler.cancel_superseded("never-scheduled");
+
+        assert!(scheduler.get("post").unwrap().is_none());
+    }
+
+    #[test]
+    fn test_hold_leaves_a_cancelled_post_alone() {
+        let scheduler = scheduler();
+        scheduler
+            .schedule("post".to_string(), creator("alice"), now_unix_secs() + 60)
+            .unwrap();
+
+        scheduler.mark_held("post");
+        assert!(scheduler.get("post").unwrap().unwrap().held);
+
+        scheduler.cancel("post").unwrap();
+        scheduler.mark_held("post");
+        assert!(scheduler.get("post").unwrap().is_none());
+    }
+}
@@ -0,0 +1,80 @@
+use axum::{
+    extract::{FromRequestParts, Query},
+    http::request::Parts,
+};
+use serde::de::DeserializeOwned;
+
+use crate::utils::types::{ApiResponse, AppError};
+
+/// Query string extractor rejecting with an `ApiResponse`, in place of axum's plain text
+/// rejections.
+pub struct AppQuery<T>(pub T);
+
+impl<T, S> FromRequestParts<S> for AppQuery<T>
+where
+    T: DeserializeOwned,
+    S: Send + Sync,
+{
+    type Rejection = ApiResponse<()>;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        Query::from_request_parts(parts, state)
+            .await
+            .map(|Query(query)| AppQuery(query))
+            .map_err(|rejection| AppError::InvalidRequest(rejection.body_text()).to_api_response())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{
+        Router,
+        body::{Body, Bytes, to_bytes},
+        http::{Request, StatusCode},
+        routing::get,
+    };
+    use serde::Deserialize;
+    use tower::ServiceExt;
+
+    use super::*;
+    use crate::utils::types::ErrorCode;
+
+    #[derive(Deserialize)]
+    struct Params {
+        limit: Option<usize>,
+    }
+
+    async fn send(uri: &str) -> (StatusCode, Bytes) {
+        let app = Router::new().route(
+            "/",
+            get(|AppQuery(params): AppQuery<Params>| async move {
+                params.limit.unwrap_or_default().to_string()
+            }),
+        );
+
+        let response = app
+            .oneshot(Request::builder().uri(uri).body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        let status = response.status();
+        (
+            status,
+            to_bytes(response.into_body(), usize::MAX).await.unwrap(),
+        )
+    }
+
+    #[tokio::test]
+    async fn test_accepts_valid_query() {
+        assert_eq!(send("/?limit=5").await, (StatusCode::OK, Bytes::from("5")));
+    }
+
+    #[tokio::test]
+    async fn test_invalid_query_is_an_api_response() {
+        let (status, body) = send("/?limit=many").await;
+        let response: ApiResponse<()> = serde_json::from_slice(&body).unwrap();
+
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(response.error_code, Some(ErrorCode::InvalidRequest));
+        assert!(!response.success);
+    }
+}
@@ -0,0 +1,468 @@
+use std::{collections::HashSet, sync::Arc, time::Duration};
+
+use candid::Principal;
+use serde::{Deserialize, Serialize};
+use tokio::sync::Mutex;
+use utoipa::ToSchema;
+
+use crate::{
+    api::mark_post_as_published::fetch_post_details,
+    app_state::AppState,
+    utils::{
+        store::{Store, StoreError, TypedTree},
+        time::now_unix_secs,
+        types::AppError,
+        upload_saga::{CompensationAction, ORPHANED_KEY, ORPHANED_REASON_KEY, UploadSaga},
+        upload_sessions::UploadSession,
+    },
+};
+
+const LATEST_REPORT_KEY: &str = "latest";
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum MismatchKind {
+    /// The canister has a post but its video was never finalized in Storj
+    PostWithoutVideo,
+    /// A video was finalized in Storj but no post references it
+    VideoWithoutPost,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum RepairAction {
+    /// Left for an operator to look at
+    Reported,
+    /// Marked orphaned, the mark is lifted if a later run within the lookback finds the post
+    MarkedOrphaned,
+    /// Marked orphaned and deleted if it still has no post on the next run
+    Quarantined,
+    Deleted,
+    RepairFailed,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, ToSchema)]
+pub struct ReconciliationMismatch {
+    pub video_id: String,
+    #[schema(value_type = String, example = "principal-id-string")]
+    pub publisher_principal: Principal,
+    pub kind: MismatchKind,
+    pub action: RepairAction,
+    pub error: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, ToSchema)]
+pub struct ReconciliationCheckFailure {
+    pub video_id: String,
+    pub error: String,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, ToSchema)]
+pub struct ReconciliationReport {
+    pub started_at: u64,
+    pub finished_at: u64,
+    pub sessions_checked: usize,
+    pub consistent: usize,
+    /// Sessions whose video was never finalized and never got a post
+    pub abandoned: usize,
+    /// Sessions whose upload saga or finalize job is still running, checked on the next run
+    #[serde(default)]
+    pub in_flight: usize,
+    pub mismatches: Vec<ReconciliationMismatch>,
+    /// Sessions that could not be checked, they are retried on the next run
+    pub check_failures: Vec<ReconciliationCheckFailure>,
+}
+
+enum SessionOutcome {
+    InFlight,
+    Consistent,
+    Abandoned,
+    Mismatch(ReconciliationMismatch),
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct ReconcilerConfig {
+    /// Sessions older than this are no longer checked and are dropped
+    pub lookback: Duration,
+    /// Sessions younger than this may still be uploading and are not checked yet
+    pub grace_period: Duration,
+    pub compensation_action: CompensationAction,
+}
+
+/// Compares recent upload sessions against the canister and Storj, repairing videos without a
+/// post and reporting posts without a video.
+#[derive(Clone)]
+pub struct Reconciler {
+    reports: TypedTree<ReconciliationReport>,
+    config: ReconcilerConfig,
+    run_lock: Arc<Mutex<()>>,
+}
+
+impl Reconciler {
+    pub fn new(store: &Store, config: ReconcilerConfig) -> Result<Self, StoreError> {
+        Ok(Self {
+            reports: store.tree("reconciliation_reports")?,
+            config,
+            run_lock: Arc::new(Mutex::new(())),
+        })
+    }
+
+    pub fn latest_report(&self) -> Result<Option<ReconciliationReport>, AppError> {
+        Ok(self.reports.get(LATEST_REPORT_KEY)?)
+    }
+
+    /// Starts the background task reconciling every `interval`.
+    pub fn spawn(&self, app_state: AppState, interval: Duration) {
+        let reconciler = self.clone();
+
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+
+            loop {
+                ticker.tick().await;
+
+                if let Err(e) = reconciler.run(&app_state).await {
+                    log::error!("Reconciliation failed: {}", e);
+                }
+            }
+        });
+    }
+
+    /// Runs a reconciliation, waiting for one already in progress to finish first.
+    pub async fn run(&self, app_state: &AppState) -> Result<ReconciliationReport, AppError> {
+        let _guard = self.run_lock.lock().await;
+
+        let started_at = now_unix_secs();
+        let oldest = started_at.saturating_sub(self.config.lookback.as_secs());
+        let newest = started_at.saturating_sub(self.config.grace_period.as_secs());
+
+        let upload_sessions = &app_state.upload_sessions;
+        upload_sessions.prune_before(oldest)?;
+        let sessions = upload_sessions.unreconciled_between(oldest, newest)?;
+        let queued_posts = app_state.finalize_jobs.unfinished_post_ids()?;
+
+        let mut report = ReconciliationReport {
+            started_at,
+            finished_at: started_at,
+            sessions_checked: sessions.len(),
+            consistent: 0,
+            abandoned: 0,
+            in_flight: 0,
+            mismatches: Vec::new(),
+            check_failures: Vec::new(),
+        };
+
+        for session in sessions {
+            let outcome = match self
+                .reconcile_session(app_state, &queued_posts, &session)
+                .await
+            {
+                Ok(outcome) => outcome,
+                Err(e) => {
+                    report.check_failures.push(ReconciliationCheckFailure {
+                        video_id: session.video_id,
+                        error: e.to_string(),
+                    });
+                    continue;
+                }
+            };
+
+            let settled = match outcome {
+                SessionOutcome::InFlight => {
+                    report.in_flight += 1;
+                    false
+                }
+                SessionOutcome::Consistent => {
+                    report.consistent += 1;
+                    true
+                }
+                SessionOutcome::Abandoned => {
+                    report.abandoned += 1;
+                    true
+                }
+                SessionOutcome::Mismatch(mismatch) => {
+                    // an orphaned video stays checked so its mark is lifted if the post lands
+                    let settled = !matches!(
+                        mismatch.action,
+                        RepairAction::RepairFailed
+                            | RepairAction::Quarantined
+                            | RepairAction::MarkedOrphaned
+                    );
+                    report.mismatches.push(mismatch);
+                    settled
+                }
+            };
+
+            if settled {
+                upload_sessions.mark_reconciled(&session)?;
+            }
+        }
+
+        report.finished_at = now_unix_secs();
+        self.reports.insert(LATEST_REPORT_KEY, &report)?;
+
+        if !report.mismatches.is_empty() {
+            let msg = format!(
+                "Reconciliation found {} mismatch(es): {}",
+                report.mismatches.len(),
+                serde_json::to_string(&report.mismatches)?
+            );
+            log::warn!("{}", msg);
+            sentry::capture_message(&msg, sentry::Level::Warning);
+        }
+
+        Ok(report)
+    }
+
+    async fn reconcile_session(
+        &self,
+        app_state: &AppState,
+        queued_posts: &HashSet<String>,
+        session: &UploadSession,
+    ) -> Result<SessionOutcome, AppError> {
+        let saga = app_state.upload_sagas.get(&session.video_id)?;
+        if is_in_flight(saga.as_ref(), queued_posts, session, now_unix_secs()) {
+            return Ok(SessionOutcome::InFlight);
+        }
+
+        let publisher_user_id = session.publisher_principal.to_text();
+
+        let post_exists = match fetch_post_details(
+            &app_state.ic_admin_agent,
+            &app_state.circuit_breakers.ic_agent,
+            &session.video_id,
+        )
+        .await
+        {
+            Ok(_) => true,
+            Err(AppError::PostNotFound(_)) => false,
+            Err(e) => return Err(e),
+        };
+
+        let video_exists = app_state
+            .storj_client
+            .video_exists(&session.video_id, &publisher_user_id, false)
+            .await
+            .map_err(|e| AppError::StorageError(e.to_string()))?;
+
+        let kind = match (post_exists, video_exists) {
+            (true, true) => {
+                // the post was written after the video was marked orphaned
+                if session.quarantined_at.is_some() {
+                    self.lift_quarantine(app_state, session).await?;
+                }
+                return Ok(SessionOutcome::Consistent);
+            }
+            (false, false) => return Ok(SessionOutcome::Abandoned),
+            (true, false) => MismatchKind::PostWithoutVideo,
+            (false, true) => MismatchKind::VideoWithoutPost,
+        };
+
+        let (action, error) = match kind {
+            MismatchKind::PostWithoutVideo => (RepairAction::Reported, None),
+            MismatchKind::VideoWithoutPost => {
+                match self.repair_video_without_post(app_state, session).await {
+                    Ok(action) => (action, None),
+                    Err(e) => (RepairAction::RepairFailed, Some(e.to_string())),
+                }
+            }
+        };
+
+        Ok(SessionOutcome::Mismatch(ReconciliationMismatch {
+            video_id: session.video_id.clone(),
+            publisher_principal: session.publisher_principal,
+            kind,
+            action,
+            error,
+        }))
+    }
+
+    async fn repair_video_without_post(
+        &self,
+        app_state: &AppState,
+        session: &UploadSession,
+    ) -> Result<RepairAction, AppError> {
+        let storj_client = &app_state.storj_client;
+        let publisher_user_id = session.publisher_principal.to_text();
+
+        match video_without_post_repair(self.config.compensation_action, session) {
+            RepairAction::Deleted => {
+                storj_client
+                    .delete_video(&session.video_id, &publisher_user_id, false)
+                    .await
+                    .map_err(|e| AppError::StorageError(e.to_string()))?;
+
+                Ok(RepairAction::Deleted)
+            }
+            action => {
+                let mut metadata = storj_client
+                    .get_metadata(&session.video_id, &publisher_user_id, false)
+                    .await
+                    .map_err(|e| AppError::StorageError(e.to_string()))?;
+
+                if !metadata.contains_key(ORPHANED_KEY) {
+                    metadata.insert(ORPHANED_KEY.to_string(), true.to_string());
+                    metadata.insert(
+                        ORPHANED_REASON_KEY.to_string(),
+                        "No post found by reconciliation".to_string(),
+                    );
+                    storj_client
+                        .set_metadata(&session.video_id, &publisher_user_id, false, metadata)
+                        .await
+                        .map_err(|e| AppError::StorageError(e.to_string()))?;
+                }
+
+                if session.quarantined_at.is_none() {
+                    app_state
+                        .upload_sessions
+                        .set_quarantined(session, Some(now_unix_secs()))?;
+                }
+
+                Ok(action)
+            }
+        }
+    }
+
+    /// Clears the orphaned mark the quarantine put on a video whose post showed up since
+    async fn lift_quarantine(
+        &self,
+        app_state: &AppState,
+        session: &UploadSession,
+    ) -> Result<(), AppError> {
+        let storj_client = &app_state.storj_client;
+        let publisher_user_id = session.publisher_principal.to_text();
+
+        let mut metadata = storj_client
+            .get_metadata(&session.video_id, &publisher_user_id, false)
+            .await
+            .map_err(|e| AppError::StorageError(e.to_string()))?;
+
+        if metadata.remove(ORPHANED_KEY).is_some() {
+            metadata.remove(ORPHANED_REASON_KEY);
+            storj_client
+                .set_metadata(&session.video_id, &publisher_user_id, false, metadata)
+                .await
+                .map_err(|e| AppError::StorageError(e.to_string()))?;
+        }
+
+        app_state.upload_sessions.set_quarantined(session, None)?;
+        log::info!(
+            "Lifted the quarantine of video {}, its post exists",
+            session.video_id
+        );
+
+        Ok(())
+    }
+}
+
+/// Whether the upload of the session is still under way, so a missing post is not a mismatch yet
+fn is_in_flight(
+    saga: Option<&UploadSaga>,
+    queued_posts: &HashSet<String>,
+    session: &UploadSession,
+    now: u64,
+) -> bool {
+    queued_posts.contains(&session.video_id) || saga.is_some_and(|saga| saga.is_live(now))
+}
+
+/// How a video without a post is repaired. Deleting cannot be undone, so a video is first
+/// quarantined and only deleted when a later run still finds no post for it.
+fn video_without_post_repair(
+    compensation_action: CompensationAction,
+    session: &UploadSession,
+) -> RepairAction {
+    match (compensation_action, session.quarantined_at) {
+        (CompensationAction::MarkOrphaned, _) => RepairAction::MarkedOrphaned,
+        (CompensationAction::Delete, None) => RepairAction::Quarantined,
+        (CompensationAction::Delete, Some(_)) => RepairAction::Deleted,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::upload_saga::UploadSagaState;
+
+    fn session(quarantined_at: Option<u64>) -> UploadSession {
+        UploadSession {
+            video_id: "video".to_string(),
+            publisher_principal: Principal::anonymous(),
+            created_at: 100,
+            reconciled: false,
+            quarantined_at,
+        }
+    }
+
+    #[test]
+    fn test_uploads_under_way_are_in_flight() {
+        let now = 1_000_000;
+        let saga = |state, updated_at| UploadSaga {
+            post_id: "video".to_string(),
+            creator_principal: Principal::anonymous(),
+            state,
+            canister_attempts: 1,
+            last_error: None,
+            created_at: 100,
+            updated_at,
+        };
+        let queued = HashSet::from(["video".to_string()]);
+        let none_queued = HashSet::new();
+
+        let cases = [
+            (None, &queued, true),
+            (None, &none_queued, false),
+            (
+                Some(saga(UploadSagaState::StorjFinalized, now - 10)),
+                &none_queued,
+                true,
+            ),
+            (
+                Some(saga(UploadSagaState::StorjFinalized, 100)),
+                &none_queued,
+                false,
+            ),
+            (
+                Some(saga(UploadSagaState::MarkedOrphaned, now - 10)),
+                &none_queued,
+                false,
+            ),
+        ];
+
+        for (saga, queued_posts, expected) in cases {
+            assert_eq!(
+                is_in_flight(saga.as_ref(), queued_posts, &session(None), now),
+                expected,
+                "{:?}",
+                saga.map(|saga| saga.state)
+            );
+        }
+    }
+
+    #[test]
+    fn test_video_without_post_repair() {
+        let cases = [
+            (
+                CompensationAction::MarkOrphaned,
+                None,
+                RepairAction::MarkedOrphaned,
+            ),
+            (
+                CompensationAction::MarkOrphaned,
+                Some(150),
+                RepairAction::MarkedOrphaned,
+            ),
+            (CompensationAction::Delete, None, RepairAction::Quarantined),
+            (CompensationAction::Delete, Some(150), RepairAction::Deleted),
+        ];
+
+        for (compensation_action, quarantined_at, expected) in cases {
+            assert_eq!(
+                video_without_post_repair(compensation_action, &session(quarantined_at)),
+                expected,
+                "{:?} with quarantined_at {:?}",
+                compensation_action,
+                quarantined_at
+            );
+        }
+    }
+}
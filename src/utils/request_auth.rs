@@ -0,0 +1,923 @@
+use std::{
+    convert::Infallible,
+    sync::{Arc, OnceLock},
+    time::{Duration, SystemTime},
+};
+
+use axum::{
+    body::{Body, to_bytes},
+    extract::{FromRequestParts, Request, State},
+    http::{HeaderMap, request::Parts},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use candid::Principal;
+use ic_agent::{
+    Identity,
+    agent::EnvelopeContent,
+    identity::{Signature, SignedDelegation},
+};
+use k256::{
+    PublicKey,
+    ecdsa::{self, VerifyingKey, signature::Verifier},
+    pkcs8::DecodePublicKey,
+};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::{
+    app_state::AppState,
+    utils::{
+        blocklist::Blocklist,
+        delegation::{DelegationPolicy, verify_delegation_chain},
+        store::{Store, StoreError, TypedTree},
+        time::now_unix_secs,
+        types::{AppError, DelegatedIdentityWire},
+    },
+};
+
+/// Hex encoded JSON `DelegationChainWire`
+pub static DELEGATION_CHAIN_HEADER: &str = "X-Delegation-Chain";
+/// Unix timestamp in seconds at which the request was signed
+pub static REQUEST_TIMESTAMP_HEADER: &str = "X-Request-Timestamp";
+/// Client chosen value that is unique among the requests it signs
+pub static REQUEST_NONCE_HEADER: &str = "X-Request-Nonce";
+/// Hex encoded secp256k1 signature of the request's canonical message by the delegated key
+pub static REQUEST_SIGNATURE_HEADER: &str = "X-Request-Signature";
+
+/// Signed request bodies larger than this are rejected
+const MAX_SIGNED_BODY_BYTES: usize = 2 * 1024 * 1024;
+/// Longer nonces are rejected so the nonce cache stays small
+const MAX_NONCE_LEN: usize = 128;
+const NONCE_PRUNE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// The public part of a delegated identity, sent instead of `DelegatedIdentityWire`
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct DelegationChainWire {
+    /// raw bytes of the delegating identity's public key
+    pub from_key: Vec<u8>,
+    /// delegations from `from_key` to the key signing the request
+    pub delegation_chain: Vec<SignedDelegation>,
+}
+
+/// Principal authenticated by `signed_request_middleware`, added to the request extensions
+#[derive(Clone, Copy, Debug)]
+pub struct AuthenticatedPrincipal(pub Principal);
+
+/// Public key of the delegated identity, only used to verify the delegation chain ending at it
+struct PublicKeyIdentity(Vec<u8>);
+
+impl Identity for PublicKeyIdentity {
+    fn sender(&self) -> Result<Principal, String> {
+        Ok(Principal::self_authenticating(&self.0))
+    }
+
+    fn public_key(&self) -> Option<Vec<u8>> {
+        Some(self.0.clone())
+    }
+
+    fn sign(&self, _content: &EnvelopeContent) -> Result<Signature, String> {
+        Err("A public key identity cannot sign".to_string())
+    }
+}
+
+/// Message a client signs: method, path, query string, timestamp, nonce and the hex SHA-256 of
+/// the body, one per line. The query string is signed as sent, without its `?`, and is empty
+/// when the URI has none.
+pub fn canonical_request_message(
+    method: &str,
+    path: &str,
+    query: &str,
+    timestamp: u64,
+    nonce: &str,
+    body: &[u8],
+) -> String {
+    format!(
+        "{}\n{}\n{}\n{}\n{}\n{}",
+        method.to_uppercase(),
+        path,
+        query,
+        timestamp,
+        nonce,
+        hex::encode(Sha256::digest(body))
+    )
+}
+
+/// Whether unsigned requests may still identify their caller with the `delegated_identity_wire`
+/// of the body, which ships the client's delegated secret key to the service
+#[derive(Clone, Copy, Debug)]
+pub struct IdentityWirePolicy {
+    pub enabled: bool,
+    /// Unix timestamp in seconds from which the wire is refused even while enabled
+    pub sunset_at: Option<u64>,
+}
+
+impl IdentityWirePolicy {
+    fn ensure_accepted(&self, now_secs: u64) -> Result<(), AppError> {
+        if !self.enabled
+            || self
+                .sunset_at
+                .is_some_and(|sunset_at| now_secs >= sunset_at)
+        {
+            return Err(AppError::Unauthenticated(
+                "delegated_identity_wire is no longer accepted, sign the request instead"
+                    .to_string(),
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+/// Nonces of accepted signed requests per principal, persisted so that a restart does not open
+/// a replay window. A nonce is only kept until the timestamp it was signed with falls out of the
+/// clock skew window, from then on that timestamp is rejected anyway, which bounds the cache by
+/// the request rate over the window.
+#[derive(Clone)]
+struct NonceCache {
+    /// `principal\0nonce` to the unix second after which it can be forgotten
+    seen: TypedTree<u64>,
+    /// `expires_at\0principal\0nonce` to the key in `seen`, so that expired nonces are found
+    /// without scanning the ones still live
+    expiry: TypedTree<String>,
+}
+
+impl NonceCache {
+    fn new(store: &Store) -> Result<Self, StoreError> {
+        Ok(Self {
+            seen: store.tree("request_nonces")?,
+            expiry: store.tree("request_nonce_expiry")?,
+        })
+    }
+
+    /// Remembers the nonce until `expires_at`, returns false if it was already used. A nonce
+    /// that expired but was not pruned yet counts as unused.
+    fn insert(
+        &self,
+        principal: Principal,
+        nonce: &str,
+        expires_at: u64,
+        now_secs: u64,
+    ) -> Result<bool, StoreError> {
+        let key = format!("{}\0{}", principal, nonce);
+        let mut inserted = false;
+        self.seen.fetch_and_update(&key, |current| match current {
+            Some(current_expires_at) if current_expires_at >= now_secs => {
+                inserted = false;
+                Some(current_expires_at)
+            }
+            _ => {
+                inserted = true;
+                Some(expires_at)
+            }
+        })?;
+
+        if inserted {
+            self.expiry
+                .insert(&format!("{:020}\0{}", expires_at, key), &key)?;
+        }
+
+        Ok(inserted)
+    }
+
+    /// Forgets the nonces that expired before `now_secs`
+    fn prune_expired(&self, now_secs: u64) -> Result<(), StoreError> {
+        for (expiry_key, key) in self.expiry.range("", &format!("{:020}", now_secs))? {
+            // the nonce may have been used again since, with a later expiry
+            self.seen.fetch_and_update(&key, |current| {
+                current.filter(|expires_at| *expires_at >= now_secs)
+            })?;
+            self.expiry.remove(&expiry_key)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Checks signed requests and works out who they come from
+#[derive(Clone)]
+pub struct RequestVerifier {
+    delegation_policy: Arc<DelegationPolicy>,
+    /// How far the signing timestamp may be from the server time
+    clock_skew: Duration,
+    nonces: NonceCache,
+}
+
+impl RequestVerifier {
+    pub fn new(
+        store: &Store,
+        delegation_policy: Arc<DelegationPolicy>,
+        clock_skew: Duration,
+    ) -> Result<Self, StoreError> {
+        Ok(Self {
+            delegation_policy,
+            clock_skew,
+            nonces: NonceCache::new(store)?,
+        })
+    }
+
+    /// Starts the background task forgetting expired nonces.
+    pub fn spawn_nonce_pruner(&self) {
+        let nonces = self.nonces.clone();
+
+        tokio::spawn(async move {
+            loop {
+                if let Err(e) = nonces.prune_expired(now_unix_secs()) {
+                    log::error!("Failed to prune request nonces: {}", e);
+                }
+
+                tokio::time::sleep(NONCE_PRUNE_INTERVAL).await;
+            }
+        });
+    }
+
+    /// Verifies the signature headers of a request and returns the principal it was signed for
+    pub fn authenticate(
+        &self,
+        method: &str,
+        path: &str,
+        query: &str,
+        headers: &HeaderMap,
+        body: &[u8],
+        now: SystemTime,
+    ) -> Result<Principal, AppError> {
+        let header = |name: &str| {
+            headers
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+                .ok_or_else(|| {
+                    AppError::InvalidRequestSignature(format!("Missing {} header", name))
+                })
+        };
+
+        let chain: DelegationChainWire = hex::decode(header(DELEGATION_CHAIN_HEADER)?)
+            .ok()
+            .and_then(|chain| serde_json::from_slice(&chain).ok())
+            .ok_or_else(|| {
+                AppError::InvalidRequestSignature(format!(
+                    "Malformed {} header",
+                    DELEGATION_CHAIN_HEADER
+                ))
+            })?;
+        let timestamp: u64 = header(REQUEST_TIMESTAMP_HEADER)?.parse().map_err(|_| {
+            AppError::InvalidRequestSignature(format!(
+                "Malformed {} header",
+                REQUEST_TIMESTAMP_HEADER
+            ))
+        })?;
+        let nonce = header(REQUEST_NONCE_HEADER)?;
+        if nonce.is_empty() || nonce.len() > MAX_NONCE_LEN {
+            return Err(AppError::InvalidRequestSignature(format!(
+                "{} must have 1 to {} characters",
+                REQUEST_NONCE_HEADER, MAX_NONCE_LEN
+            )));
+        }
+        let signature = hex::decode(header(REQUEST_SIGNATURE_HEADER)?)
+            .ok()
+            .and_then(|signature| ecdsa::Signature::from_slice(&signature).ok())
+            .ok_or_else(|| {
+                AppError::InvalidRequestSignature(format!(
+                    "Malformed {} header",
+                    REQUEST_SIGNATURE_HEADER
+                ))
+            })?;
+
+        let now_secs = now
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let clock_skew = self.clock_skew.as_secs();
+        if now_secs.abs_diff(timestamp) > clock_skew {
+            return Err(AppError::ReplayedRequest(format!(
+                "Request timestamp {} is more than {}s away from the server time",
+                timestamp, clock_skew
+            )));
+        }
+
+        let signing_key = chain
+            .delegation_chain
+            .last()
+            .map_or(&chain.from_key, |signed| &signed.delegation.pubkey)
+            .clone();
+
+        let verifying_key = PublicKey::from_public_key_der(&signing_key)
+            .map(|public_key| VerifyingKey::from(&public_key))
+            .map_err(|_| {
+                AppError::InvalidRequestSignature(
+                    "Only secp256k1 keys can sign requests".to_string(),
+                )
+            })?;
+        let message = canonical_request_message(method, path, query, timestamp, nonce, body);
+        verifying_key
+            .verify(message.as_bytes(), &signature)
+            .map_err(|_| {
+                AppError::InvalidRequestSignature("Signature does not match".to_string())
+            })?;
+
+        let identity = verify_delegation_chain(
+            chain.from_key,
+            chain.delegation_chain,
+            Box::new(PublicKeyIdentity(signing_key)),
+            &self.delegation_policy,
+            now,
+        )?;
+
+        let principal = identity
+            .sender()
+            .map_err(AppError::InvalidDelegatedIdentity)?;
+
+        // only recorded once the signature is verified, so forged requests cannot burn nonces
+        if !self
+            .nonces
+            .insert(principal, nonce, timestamp + clock_skew, now_secs)?
+        {
+            return Err(AppError::ReplayedRequest(format!(
+                "Nonce {} was already used",
+                nonce
+            )));
+        }
+
+        Ok(principal)
+    }
+}
+
+/// Middleware authenticating requests that carry a signature. The principal they were signed
+/// for is added to the request extensions, requests without a signature pass through untouched.
+pub async fn signed_request_middleware(
+    State(verifier): State<RequestVerifier>,
+    request: Request,
+    next: Next,
+) -> Response {
+    if !request.headers().contains_key(REQUEST_SIGNATURE_HEADER) {
+        return next.run(request).await;
+    }
+
+    let (mut parts, body) = request.into_parts();
+    let body_bytes = match to_bytes(body, MAX_SIGNED_BODY_BYTES).await {
+        Ok(body_bytes) => body_bytes,
+        Err(e) => {
+            return AppError::InvalidRequest(format!("Failed to read request body: {}", e))
+                .to_api_response::<()>()
+                .into_response();
+        }
+    };
+
+    match verifier.authenticate(
+        parts.method.as_str(),
+        parts.uri.path(),
+        parts.uri.query().unwrap_or_default(),
+        &parts.headers,
+        &body_bytes,
+        SystemTime::now(),
+    ) {
+        Ok(principal) => {
+            parts.extensions.insert(AuthenticatedPrincipal(principal));
+        }
+        Err(e) => {
+            log::warn!("Rejected signed request to {}: {}", parts.uri.path(), e);
+            return e.to_api_response::<()>().into_response();
+        }
+    }
+
+    next.run(Request::from_parts(parts, Body::from(body_bytes)))
+        .await
+}
+
+/// The caller of a user endpoint. Handlers resolve it to a principal together with the
+/// `delegated_identity_wire` of the body, which is only needed for unsigned requests.
+pub struct Caller {
+    authenticated: Option<Principal>,
+    delegation_policy: Arc<DelegationPolicy>,
+    identity_wire_policy: IdentityWirePolicy,
+    blocklist: Blocklist,
+    resolved: OnceLock<Principal>,
+}
+
+impl Caller {
+    /// The principal of the caller, failing with `AccountRestricted` while it is blocked
+    pub fn principal(
+        &self,
+        delegated_identity_wire: Option<&DelegatedIdentityWire>,
+    ) -> Result<Principal, AppError> {
+        let principal = match (self.authenticated, delegated_identity_wire) {
+            (Some(principal), _) => principal,
+            (None, Some(delegated_identity_wire)) => {
+                self.identity_wire_policy.ensure_accepted(now_unix_secs())?;
+                delegated_identity_wire.sender(&self.delegation_policy)?
+            }
+            (None, None) => {
+                return Err(AppError::Unauthenticated(
+                    "The request is neither signed nor carries a delegated identity".to_string(),
+                ));
+            }
+        };
+
+        self.blocklist.ensure_not_blocked(principal)?;
+        let _ = self.resolved.set(principal);
+
+        Ok(principal)
+    }
+
+    /// The principal an earlier `principal` call resolved, for the audit log
+    pub fn resolved_principal(&self) -> Option<String> {
+        self.resolved.get().map(Principal::to_text)
+    }
+}
+
+impl FromRequestParts<AppState> for Caller {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        app_state: &AppState,
+    ) -> Result<Self, Self::Rejection> {
+        Ok(Caller {
+            authenticated: parts
+                .extensions
+                .get::<AuthenticatedPrincipal>()
+                .map(|authenticated| authenticated.0),
+            delegation_policy: app_state.delegation_policy.clone(),
+            identity_wire_policy: app_state.identity_wire_policy,
+            blocklist: app_state.blocklist.clone(),
+            resolved: OnceLock::new(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{
+        Router,
+        http::{HeaderValue, StatusCode},
+        middleware,
+        routing::post,
+    };
+    use ic_agent::identity::{Delegation, Secp256k1Identity};
+    use k256::{SecretKey, elliptic_curve::rand_core::OsRng};
+
+    use tower::ServiceExt;
+
+    use super::*;
+
+    struct Client {
+        root: Secp256k1Identity,
+        session: Secp256k1Identity,
+        chain: DelegationChainWire,
+    }
+
+    fn client() -> Client {
+        let root = Secp256k1Identity::from_private_key(SecretKey::random(&mut OsRng));
+        let session = Secp256k1Identity::from_private_key(SecretKey::random(&mut OsRng));
+
+        let delegation = Delegation {
+            pubkey: session.public_key().unwrap(),
+            expiration: (SystemTime::now() + Duration::from_secs(3600))
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .unwrap()
+                .as_nanos() as u64,
+            targets: None,
+        };
+        let signature = root
+            .sign_delegation(&delegation)
+            .unwrap()
+            .signature
+            .unwrap();
+
+        let chain = DelegationChainWire {
+            from_key: root.public_key().unwrap(),
+            delegation_chain: vec![SignedDelegation {
+                delegation,
+                signature,
+            }],
+        };
+
+        Client {
+            root,
+            session,
+            chain,
+        }
+    }
+
+    /// Headers of a POST to `uri` signed by the client's session key
+    fn signed_headers(
+        client: &Client,
+        uri: &str,
+        timestamp: u64,
+        nonce: &str,
+        body: &[u8],
+    ) -> HeaderMap {
+        let (path, query) = uri.split_once('?').unwrap_or((uri, ""));
+        let message = canonical_request_message("POST", path, query, timestamp, nonce, body);
+        let signature = client
+            .session
+            .sign_arbitrary(message.as_bytes())
+            .unwrap()
+            .signature
+            .unwrap();
+
+        let mut headers = HeaderMap::new();
+        let chain = hex::encode(serde_json::to_vec(&client.chain).unwrap());
+        headers.insert(
+            DELEGATION_CHAIN_HEADER,
+            HeaderValue::from_str(&chain).unwrap(),
+        );
+        headers.insert(REQUEST_TIMESTAMP_HEADER, HeaderValue::from(timestamp));
+        headers.insert(REQUEST_NONCE_HEADER, HeaderValue::from_str(nonce).unwrap());
+        headers.insert(
+            REQUEST_SIGNATURE_HEADER,
+            HeaderValue::from_str(&hex::encode(signature)).unwrap(),
+        );
+        headers
+    }
+
+    fn verifier_on(store: &Store) -> RequestVerifier {
+        RequestVerifier::new(
+            store,
+            Arc::new(DelegationPolicy {
+                max_chain_length: 2,
+                max_clock_skew: Duration::from_secs(60),
+                accepted_targets: Vec::new(),
+            }),
+            Duration::from_secs(300),
+        )
+        .unwrap()
+    }
+
+    fn verifier() -> RequestVerifier {
+        verifier_on(&Store::temporary().unwrap())
+    }
+
+    #[test]
+    fn test_authenticates_signed_request() {
+        let client = client();
+        let body = br#"{"post_id":"post"}"#;
+        let headers = signed_headers(
+            &client,
+            "/mark-post-as-published",
+            now_unix_secs(),
+            "nonce",
+            body,
+        );
+
+        let principal = verifier()
+            .authenticate(
+                "POST",
+                "/mark-post-as-published",
+                "",
+                &headers,
+                body,
+                SystemTime::now(),
+            )
+            .unwrap();
+        assert_eq!(principal, client.root.sender().unwrap());
+    }
+
+    #[test]
+    fn test_rejects_tampered_body() {
+        let client = client();
+        let headers = signed_headers(
+            &client,
+            "/mark-post-as-published",
+            now_unix_secs(),
+            "nonce",
+            br#"{"post_id":"post"}"#,
+        );
+
+        let result = verifier().authenticate(
+            "POST",
+            "/mark-post-as-published",
+            "",
+            &headers,
+            br#"{"post_id":"other-post"}"#,
+            SystemTime::now(),
+        );
+        assert!(matches!(result, Err(AppError::InvalidRequestSignature(_))));
+    }
+
+    #[test]
+    fn test_rejects_signature_for_other_path() {
+        let client = client();
+        let body = br#"{"post_id":"post"}"#;
+        let headers = signed_headers(&client, "/delete-draft", now_unix_secs(), "nonce", body);
+
+        let result = verifier().authenticate(
+            "POST",
+            "/mark-post-as-published",
+            "",
+            &headers,
+            body,
+            SystemTime::now(),
+        );
+        assert!(matches!(result, Err(AppError::InvalidRequestSignature(_))));
+    }
+
+    #[test]
+    fn test_rejects_replayed_request() {
+        let client = client();
+        let verifier = verifier();
+        let body = br#"{"post_id":"post"}"#;
+        let headers = signed_headers(
+            &client,
+            "/mark-post-as-published",
+            now_unix_secs(),
+            "nonce",
+            body,
+        );
+        let authenticate = || {
+            verifier.authenticate(
+                "POST",
+                "/mark-post-as-published",
+                "",
+                &headers,
+                body,
+                SystemTime::now(),
+            )
+        };
+
+        assert!(authenticate().is_ok());
+        assert!(matches!(authenticate(), Err(AppError::ReplayedRequest(_))));
+    }
+
+    #[test]
+    fn test_rejects_stale_timestamp() {
+        let client = client();
+        let body = br#"{"post_id":"post"}"#;
+        let headers = signed_headers(
+            &client,
+            "/mark-post-as-published",
+            now_unix_secs() - 301,
+            "nonce",
+            body,
+        );
+
+        let result = verifier().authenticate(
+            "POST",
+            "/mark-post-as-published",
+            "",
+            &headers,
+            body,
+            SystemTime::now(),
+        );
+        assert!(matches!(result, Err(AppError::ReplayedRequest(_))));
+    }
+
+    #[test]
+    fn test_forged_request_does_not_use_up_nonce() {
+        let client = client();
+        let verifier = verifier();
+        let body = br#"{"post_id":"post"}"#;
+        let timestamp = now_unix_secs();
+        let forged = signed_headers(&client, "/delete-draft", timestamp, "nonce", body);
+        let headers = signed_headers(&client, "/mark-post-as-published", timestamp, "nonce", body);
+
+        for (headers, accepted) in [(forged, false), (headers, true)] {
+            let result = verifier.authenticate(
+                "POST",
+                "/mark-post-as-published",
+                "",
+                &headers,
+                body,
+                SystemTime::now(),
+            );
+            assert_eq!(result.is_ok(), accepted);
+        }
+    }
+
+    #[test]
+    fn test_nonce_is_forgotten_after_clock_skew_window() {
+        let client = client();
+        let verifier = verifier();
+        let body = br#"{"post_id":"post"}"#;
+        let signed_at = SystemTime::now();
+
+        for now in [signed_at, signed_at + Duration::from_secs(301)] {
+            let timestamp = now
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .unwrap()
+                .as_secs();
+            let headers =
+                signed_headers(&client, "/mark-post-as-published", timestamp, "nonce", body);
+
+            let result =
+                verifier.authenticate("POST", "/mark-post-as-published", "", &headers, body, now);
+            assert!(result.is_ok());
+        }
+    }
+
+    fn caller(authenticated: Option<Principal>, blocklist: &Blocklist) -> Caller {
+        Caller {
+            authenticated,
+            delegation_policy: Arc::new(DelegationPolicy {
+                max_chain_length: 2,
+                max_clock_skew: Duration::from_secs(60),
+                accepted_targets: Vec::new(),
+            }),
+            identity_wire_policy: IdentityWirePolicy {
+                enabled: true,
+                sunset_at: None,
+            },
+            blocklist: blocklist.clone(),
+            resolved: OnceLock::new(),
+        }
+    }
+
+    #[test]
+    fn test_blocked_caller_is_restricted() {
+        let blocklist = Blocklist::new(&Store::temporary().unwrap()).unwrap();
+        let principal = Principal::self_authenticating(b"creator");
+
+        assert_eq!(
+            caller(Some(principal), &blocklist).principal(None).unwrap(),
+            principal
+        );
+
+        blocklist
+            .block(principal, None, None, "token:ops".to_string())
+            .unwrap();
+        let caller = caller(Some(principal), &blocklist);
+        assert!(matches!(
+            caller.principal(None),
+            Err(AppError::AccountRestricted { .. })
+        ));
+        assert_eq!(caller.resolved_principal(), None);
+    }
+
+    #[test]
+    fn test_unsigned_caller_without_identity_is_unauthenticated() {
+        let blocklist = Blocklist::new(&Store::temporary().unwrap()).unwrap();
+
+        assert!(matches!(
+            caller(None, &blocklist).principal(None),
+            Err(AppError::Unauthenticated(_))
+        ));
+    }
+
+    #[test]
+    fn test_rejects_tampered_query() {
+        let client = client();
+        let body = br#"{"post_id":"post"}"#;
+        let headers = signed_headers(
+            &client,
+            "/mark-post-as-published?dry_run=true",
+            now_unix_secs(),
+            "nonce",
+            body,
+        );
+        let authenticate = |query| {
+            verifier().authenticate(
+                "POST",
+                "/mark-post-as-published",
+                query,
+                &headers,
+                body,
+                SystemTime::now(),
+            )
+        };
+
+        assert!(authenticate("dry_run=true").is_ok());
+        assert!(matches!(
+            authenticate("dry_run=false"),
+            Err(AppError::InvalidRequestSignature(_))
+        ));
+    }
+
+    #[test]
+    fn test_identity_wire_is_refused_once_disabled_or_past_sunset() {
+        let now = now_unix_secs();
+        let cases = [
+            (true, None, true),
+            (true, Some(now + 60), true),
+            (true, Some(now), false),
+            (false, None, false),
+        ];
+
+        for (enabled, sunset_at, accepted) in cases {
+            let policy = IdentityWirePolicy { enabled, sunset_at };
+            assert_eq!(
+                policy.ensure_accepted(now).is_ok(),
+                accepted,
+                "{:?}",
+                policy
+            );
+        }
+    }
+
+    /// Status and authenticated principal of a request through `signed_request_middleware`
+    async fn send_signed(headers: HeaderMap, body: &'static [u8]) -> (StatusCode, String) {
+        let app = Router::new()
+            .route(
+                "/mark-post-as-published",
+                post(|request: Request| async move {
+                    request
+                        .extensions()
+                        .get::<AuthenticatedPrincipal>()
+                        .map_or_else(
+                            || "anonymous".to_string(),
+                            |principal| principal.0.to_text(),
+                        )
+                }),
+            )
+            .layer(middleware::from_fn_with_state(
+                verifier(),
+                signed_request_middleware,
+            ));
+
+        let mut request = Request::builder()
+            .method("POST")
+            .uri("/mark-post-as-published")
+            .body(Body::from(body))
+            .unwrap();
+        *request.headers_mut() = headers;
+
+        let response = app.oneshot(request).await.unwrap();
+        let status = response.status();
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        (status, String::from_utf8_lossy(&body).into_owned())
+    }
+
+    #[tokio::test]
+    async fn test_middleware_authenticates_signed_requests() {
+        let other_client = client();
+        let client = client();
+        let body: &[u8] = br#"{"post_id":"post"}"#;
+        let signed = |client: &Client, timestamp: u64, signed_body: &[u8]| {
+            signed_headers(
+                client,
+                "/mark-post-as-published",
+                timestamp,
+                "nonce",
+                signed_body,
+            )
+        };
+
+        let (status, principal) = send_signed(signed(&client, now_unix_secs(), body), body).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(principal, client.root.sender().unwrap().to_text());
+
+        // signed by a key the delegation chain does not end at
+        let mut bad_signature = signed(&client, now_unix_secs(), body);
+        let other_signature = signed(&other_client, now_unix_secs(), body)
+            .remove(REQUEST_SIGNATURE_HEADER)
+            .unwrap();
+        bad_signature.insert(REQUEST_SIGNATURE_HEADER, other_signature);
+
+        let rejected = [
+            bad_signature,
+            signed(&client, now_unix_secs() - 301, body),
+            signed(&client, now_unix_secs(), br#"{"post_id":"other-post"}"#),
+        ];
+        for headers in rejected {
+            assert_eq!(send_signed(headers, body).await.0, StatusCode::UNAUTHORIZED);
+        }
+
+        let (status, principal) = send_signed(HeaderMap::new(), body).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(principal, "anonymous");
+    }
+
+    #[test]
+    fn test_nonce_survives_a_restart() {
+        let store = Store::temporary().unwrap();
+        let client = client();
+        let body = br#"{"post_id":"post"}"#;
+        let headers = signed_headers(
+            &client,
+            "/mark-post-as-published",
+            now_unix_secs(),
+            "nonce",
+            body,
+        );
+        let authenticate = |verifier: RequestVerifier| {
+            verifier.authenticate(
+                "POST",
+                "/mark-post-as-published",
+                "",
+                &headers,
+                body,
+                SystemTime::now(),
+            )
+        };
+
+        assert!(authenticate(verifier_on(&store)).is_ok());
+        assert!(matches!(
+            authenticate(verifier_on(&store)),
+            Err(AppError::ReplayedRequest(_))
+        ));
+    }
+
+    #[test]
+    fn test_prune_only_forgets_expired_nonces() {
+        let nonces = NonceCache::new(&Store::temporary().unwrap()).unwrap();
+        let principal = Principal::self_authenticating(b"client");
+
+        assert!(nonces.insert(principal, "old", 100, 0).unwrap());
+        assert!(nonces.insert(principal, "new", 200, 0).unwrap());
+
+        nonces.prune_expired(150).unwrap();
+
+        assert_eq!(nonces.seen.entries().unwrap().len(), 1);
+        assert_eq!(nonces.expiry.entries().unwrap().len(), 1);
+        assert!(!nonces.insert(principal, "new", 300, 150).unwrap());
+        assert!(nonces.insert(principal, "old", 300, 150).unwrap());
+    }
+}
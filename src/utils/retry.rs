@@ -0,0 +1,280 @@
+use std::{error::Error, future::Future, time::Duration};
+
+use reqwest::{Response, StatusCode};
+use thiserror::Error;
+
+use crate::utils::{
+    bulkhead::BulkheadFullError, circuit_breaker::CircuitOpenError, metrics::REQUEST_RETRIES,
+};
+
+/// Failure of a single HTTP attempt
+#[derive(Error, Debug)]
+pub enum HttpCallError {
+    #[error("{0}")]
+    Request(#[from] reqwest::Error),
+
+    #[error("{status} - {body}")]
+    Status { status: StatusCode, body: String },
+
+    /// The attempt waited for a slot of the downstream's bulkhead in vain
+    #[error("{0}")]
+    Saturated(#[from] BulkheadFullError),
+
+    /// The downstream's breaker is open, the call was not made
+    #[error("{0}")]
+    CircuitOpen(#[from] CircuitOpenError),
+}
+
+impl HttpCallError {
+    pub async fn from_response(response: Response) -> Self {
+        let status = response.status();
+        let body = response.text().await.unwrap_or_default();
+        HttpCallError::Status { status, body }
+    }
+
+    /// Connect errors, timeouts, 429 and 5xx are worth another attempt
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            HttpCallError::Request(e) => e.is_connect() || e.is_timeout(),
+            HttpCallError::Status { status, .. } => {
+                *status == StatusCode::TOO_MANY_REQUESTS || status.is_server_error()
+            }
+            HttpCallError::Saturated(_) | HttpCallError::CircuitOpen(_) => false,
+        }
+    }
+
+    /// Connect errors, timeouts and 5xx mean the downstream is unhealthy, rather than the
+    /// request being wrong
+    pub fn is_downstream_failure(&self) -> bool {
+        match self {
+            HttpCallError::Request(e) => e.is_connect() || e.is_timeout(),
+            HttpCallError::Status { status, .. } => status.is_server_error(),
+            HttpCallError::Saturated(_) | HttpCallError::CircuitOpen(_) => false,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct RetryPolicy {
+    /// Total attempts including the first one
+    pub max_attempts: u32,
+    pub base_delay: Duration,
+    pub max_delay: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 3,
+            base_delay: Duration::from_millis(200),
+            max_delay: Duration::from_secs(5),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Exponential backoff where the second half of the delay is random jitter
+    pub(crate) fn backoff(&self, attempt: u32) -> Duration {
+        let exponential = self
+            .base_delay
+            .saturating_mul(2u32.saturating_pow(attempt.saturating_sub(1)))
+            .min(self.max_delay);
+        let half = exponential / 2;
+
+        half + half.mul_f64(rand::random::<f64>())
+    }
+
+    /// Runs `call` until it succeeds, fails with a non retryable error or runs out of attempts.
+    /// `operation` names the call in metrics and in the returned error.
+    pub async fn run<T, F, Fut>(&self, operation: &str, mut call: F) -> Result<T, Box<dyn Error>>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T, HttpCallError>>,
+    {
+        let mut attempt = 1;
+
+        loop {
+            let error = match call().await {
+                Ok(value) => return Ok(value),
+                Err(error) => error,
+            };
+
+            if !error.is_retryable() || attempt >= self.max_attempts {
+                return Err(format!(
+                    "Failed to {} after {} attempt(s): {}",
+                    operation, attempt, error
+                )
+                .into());
+            }
+
+            let delay = self.backoff(attempt);
+            log::warn!(
+                "Attempt {} to {} failed, retrying in {:?}: {}",
+                attempt,
+                operation,
+                delay,
+                error
+            );
+            REQUEST_RETRIES.with_label_values(&[operation]).inc();
+
+            tokio::time::sleep(delay).await;
+            attempt += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    use super::*;
+
+    fn status_error(status: StatusCode) -> HttpCallError {
+        HttpCallError::Status {
+            status,
+            body: String::new(),
+        }
+    }
+
+    fn immediate_policy(max_attempts: u32) -> RetryPolicy {
+        RetryPolicy {
+            max_attempts,
+            base_delay: Duration::ZERO,
+            max_delay: Duration::ZERO,
+        }
+    }
+
+    #[test]
+    fn test_backoff_doubles_within_jitter_bounds_and_is_capped() {
+        let policy = RetryPolicy {
+            max_attempts: 10,
+            base_delay: Duration::from_millis(100),
+            max_delay: Duration::from_millis(1000),
+        };
+        let cases = [
+            (1, 100),
+            (2, 200),
+            (3, 400),
+            (4, 800),
+            (5, 1000),
+            (30, 1000),
+        ];
+
+        for (attempt, exponential_ms) in cases {
+            let exponential = Duration::from_millis(exponential_ms);
+            for _ in 0..100 {
+                let delay = policy.backoff(attempt);
+                assert!(
+                    delay >= exponential / 2 && delay <= exponential,
+                    "attempt {}: {:?}",
+                    attempt,
+                    delay
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_status_classification() {
+        let cases = [
+            (StatusCode::BAD_REQUEST, false, false),
+            (StatusCode::NOT_FOUND, false, false),
+            (StatusCode::TOO_MANY_REQUESTS, true, false),
+            (StatusCode::INTERNAL_SERVER_ERROR, true, true),
+            (StatusCode::BAD_GATEWAY, true, true),
+            (StatusCode::SERVICE_UNAVAILABLE, true, true),
+        ];
+
+        for (status, retryable, downstream_failure) in cases {
+            let error = status_error(status);
+            assert_eq!(error.is_retryable(), retryable, "{}", status);
+            assert_eq!(
+                error.is_downstream_failure(),
+                downstream_failure,
+                "{}",
+                status
+            );
+        }
+    }
+
+    #[test]
+    fn test_calls_never_made_are_neither_retried_nor_a_downstream_failure() {
+        let errors = [
+            HttpCallError::from(BulkheadFullError("storj")),
+            HttpCallError::from(CircuitOpenError("storj")),
+        ];
+
+        for error in errors {
+            assert!(!error.is_retryable(), "{:?}", error);
+            assert!(!error.is_downstream_failure(), "{:?}", error);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_request_error_classification() {
+        let client = reqwest::Client::new();
+
+        let refused =
+            HttpCallError::from(client.get("http://127.0.0.1:9").send().await.unwrap_err());
+        assert!(refused.is_retryable());
+        assert!(refused.is_downstream_failure());
+
+        let invalid_url = HttpCallError::from(client.get("not a url").send().await.unwrap_err());
+        assert!(!invalid_url.is_retryable());
+        assert!(!invalid_url.is_downstream_failure());
+    }
+
+    #[tokio::test]
+    async fn test_run_retries_until_success() {
+        let attempts = &AtomicU32::new(0);
+        let result = immediate_policy(3)
+            .run("test call", || async move {
+                match attempts.fetch_add(1, Ordering::SeqCst) {
+                    0 => Err(status_error(StatusCode::SERVICE_UNAVAILABLE)),
+                    _ => Ok("done"),
+                }
+            })
+            .await;
+
+        assert_eq!(result.unwrap(), "done");
+        assert_eq!(attempts.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn test_run_stops_on_non_retryable_error() {
+        let attempts = &AtomicU32::new(0);
+        let result = immediate_policy(3)
+            .run("test call", || async move {
+                attempts.fetch_add(1, Ordering::SeqCst);
+                Err::<(), _>(status_error(StatusCode::BAD_REQUEST))
+            })
+            .await;
+
+        assert!(
+            result
+                .unwrap_err()
+                .to_string()
+                .contains("after 1 attempt(s)")
+        );
+        assert_eq!(attempts.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn test_run_gives_up_after_max_attempts() {
+        let attempts = &AtomicU32::new(0);
+        let result = immediate_policy(3)
+            .run("test call", || async move {
+                attempts.fetch_add(1, Ordering::SeqCst);
+                Err::<(), _>(status_error(StatusCode::BAD_GATEWAY))
+            })
+            .await;
+
+        assert!(
+            result
+                .unwrap_err()
+                .to_string()
+                .contains("after 3 attempt(s)")
+        );
+        assert_eq!(attempts.load(Ordering::SeqCst), 3);
+    }
+}
@@ -0,0 +1,184 @@
+use std::{marker::PhantomData, path::Path};
+
+use serde::{Serialize, de::DeserializeOwned};
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum StoreError {
+    #[error("sled error: {0}")]
+    Sled(#[from] sled::Error),
+
+    #[error("encoding error: {0}")]
+    Encoding(#[from] serde_json::Error),
+}
+
+/// Embedded key-value store backing the service's local state.
+#[derive(Clone)]
+pub struct Store {
+    db: sled::Db,
+}
+
+impl Store {
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, StoreError> {
+        Ok(Self {
+            db: sled::open(path)?,
+        })
+    }
+
+    pub fn temporary() -> Result<Self, StoreError> {
+        Ok(Self {
+            db: sled::Config::new().temporary(true).open()?,
+        })
+    }
+
+    /// Id that is greater than every id generated before, across restarts
+    pub fn generate_id(&self) -> Result<u64, StoreError> {
+        Ok(self.db.generate_id()?)
+    }
+
+    pub fn tree<T>(&self, name: &str) -> Result<TypedTree<T>, StoreError> {
+        Ok(TypedTree {
+            tree: self.db.open_tree(name)?,
+            _value: PhantomData,
+        })
+    }
+}
+
+/// A sled tree holding JSON encoded values of a single type keyed by string.
+pub struct TypedTree<T> {
+    tree: sled::Tree,
+    _value: PhantomData<fn() -> T>,
+}
+
+impl<T> Clone for TypedTree<T> {
+    fn clone(&self) -> Self {
+        Self {
+            tree: self.tree.clone(),
+            _value: PhantomData,
+        }
+    }
+}
+
+impl<T: Serialize + DeserializeOwned> TypedTree<T> {
+    pub fn get(&self, key: &str) -> Result<Option<T>, StoreError> {
+        self.tree
+            .get(key)?
+            .map(|value| serde_json::from_slice(&value).map_err(StoreError::from))
+            .transpose()
+    }
+
+    pub fn insert(&self, key: &str, value: &T) -> Result<(), StoreError> {
+        self.tree.insert(key, serde_json::to_vec(value)?)?;
+        Ok(())
+    }
+
+    /// Inserts `value` unless the key is already present. Returns whether it was inserted.
+    pub fn insert_if_absent(&self, key: &str, value: &T) -> Result<bool, StoreError> {
+        let swap_result = self.tree.compare_and_swap(
+            key,
+            None as Option<&[u8]>,
+            Some(serde_json::to_vec(value)?),
+        )?;
+
+        Ok(swap_result.is_ok())
+    }
+
+    /// Atomically replaces the value at `key` with `update(current)`, `None` removing it, and
+    /// returns the value it replaced. `update` runs again when another write races it.
+    pub fn fetch_and_update(
+        &self,
+        key: &str,
+        mut update: impl FnMut(Option<T>) -> Option<T>,
+    ) -> Result<Option<T>, StoreError> {
+        let mut encoding_error = None;
+        let previous = self.tree.fetch_and_update(key, |current| {
+            encoding_error = None;
+            let updated = current
+                .map(serde_json::from_slice)
+                .transpose()
+                .and_then(|value| {
+                    update(value)
+                        .map(|new| serde_json::to_vec(&new))
+                        .transpose()
+                });
+
+            match updated {
+                Ok(updated) => updated,
+                Err(e) => {
+                    encoding_error = Some(e);
+                    current.map(<[u8]>::to_vec)
+                }
+            }
+        })?;
+
+        if let Some(e) = encoding_error {
+            return Err(e.into());
+        }
+
+        previous
+            .map(|value| serde_json::from_slice(&value).map_err(StoreError::from))
+            .transpose()
+    }
+
+    pub fn remove(&self, key: &str) -> Result<Option<T>, StoreError> {
+        self.tree
+            .remove(key)?
+            .map(|value| serde_json::from_slice(&value).map_err(StoreError::from))
+            .transpose()
+    }
+
+    pub fn contains_key(&self, key: &str) -> Result<bool, StoreError> {
+        Ok(self.tree.contains_key(key)?)
+    }
+
+    /// All key-value pairs in key order
+    pub fn entries(&self) -> Result<Vec<(String, T)>, StoreError> {
+        self.tree.iter().map(decode_entry).collect()
+    }
+
+    /// Key-value pairs with keys from `start` up to but excluding `end`, in key order
+    pub fn range(&self, start: &str, end: &str) -> Result<Vec<(String, T)>, StoreError> {
+        self.tree.range(start..end).map(decode_entry).collect()
+    }
+
+    /// All key-value pairs from the greatest key down, decoded as they are read
+    pub fn entries_rev(&self) -> impl Iterator<Item = Result<(String, T), StoreError>> + use<T> {
+        self.tree.iter().rev().map(decode_entry)
+    }
+
+    /// Key-value pairs with keys starting with `prefix` from the greatest key down, decoded as
+    /// they are read
+    pub fn prefix_entries_rev(
+        &self,
+        prefix: &str,
+    ) -> impl Iterator<Item = Result<(String, T), StoreError>> + use<T> {
+        self.tree.scan_prefix(prefix).rev().map(decode_entry)
+    }
+
+    /// The value with the greatest key
+    pub fn last(&self) -> Result<Option<T>, StoreError> {
+        self.tree
+            .last()?
+            .map(|(_, value)| serde_json::from_slice(&value).map_err(StoreError::from))
+            .transpose()
+    }
+
+    /// All values in key order
+    pub fn values(&self) -> Result<Vec<T>, StoreError> {
+        self.tree
+            .iter()
+            .values()
+            .map(|value| -> Result<T, StoreError> { Ok(serde_json::from_slice(&value?)?) })
+            .collect()
+    }
+}
+
+fn decode_entry<T: DeserializeOwned>(
+    entry: sled::Result<(sled::IVec, sled::IVec)>,
+) -> Result<(String, T), StoreError> {
+    let (key, value) = entry?;
+    Ok((
+        String::from_utf8_lossy(&key).into_owned(),
+        serde_json::from_slice(&value)?,
+    ))
+}
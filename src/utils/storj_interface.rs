@@ -1,12 +1,49 @@
-use reqwest::Client;
+use bytes::Bytes;
+use reqwest::{Client, StatusCode};
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use std::error::Error;
+use std::future::Future;
+use std::sync::atomic::{AtomicBool, Ordering};
 
+use crate::{
+    config::TimeoutConfig,
+    utils::{
+        circuit_breaker::CircuitBreaker,
+        retry::{HttpCallError, RetryPolicy},
+    },
+};
+
+/// LinkShare prefixes serving the objects of the SFW and NSFW buckets
+#[derive(Clone, Debug)]
+pub struct LinkshareConfig {
+    pub sfw_base: String,
+    pub nsfw_base: String,
+}
+
+impl LinkshareConfig {
+    fn video_url(&self, video_id: &str, publisher_user_id: &str, is_nsfw: bool) -> String {
+        let base = if is_nsfw {
+            &self.nsfw_base
+        } else {
+            &self.sfw_base
+        };
+
+        format!("{}/{}/{}.mp4", base, publisher_user_id, video_id)
+    }
+}
+
+/// Client of storj-interface. Uploads go through its `/duplicate_raw/upload` and
+/// `/duplicate_raw/finalize` routes. The `/duplicate_raw/metadata` and `/duplicate_raw/delete`
+/// routes used by drafts, compensation and the reconciler are assumed to follow the same
+/// conventions and have not been checked against a storj-interface deployment yet.
 #[derive(Clone)]
 pub struct StorjInterface {
     base_url: String,
+    linkshare: LinkshareConfig,
     client: Client,
+    retry_policy: RetryPolicy,
+    breaker: CircuitBreaker,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -14,10 +51,70 @@ pub struct FinalizeRequest {
     pub metadata: HashMap<String, String>,
 }
 
+#[derive(Serialize, Deserialize)]
+pub struct MetadataResponse {
+    pub metadata: HashMap<String, String>,
+}
+
 impl StorjInterface {
-    pub fn new(base_url: String) -> Result<Self, Box<dyn Error>> {
-        let client = Client::new();
-        Ok(Self { base_url, client })
+    pub fn new(
+        base_url: String,
+        linkshare: LinkshareConfig,
+        timeouts: TimeoutConfig,
+        retry_policy: RetryPolicy,
+        breaker: CircuitBreaker,
+    ) -> Result<Self, Box<dyn Error>> {
+        let client = timeouts.client_builder().build()?;
+        Ok(Self {
+            base_url,
+            linkshare,
+            client,
+            retry_policy,
+            breaker,
+        })
+    }
+
+    /// Runs `call` with retries, behind the Storj circuit breaker. Each attempt takes its own
+    /// slot of the Storj bulkhead, so none is held through the backoffs. Only a last attempt
+    /// that failed on Storj's side counts against the breaker, a 4xx means Storj is up.
+    async fn run<T, F, Fut>(&self, operation: &str, mut call: F) -> Result<T, Box<dyn Error>>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T, HttpCallError>>,
+    {
+        let downstream_failed = AtomicBool::new(false);
+        let saturated = AtomicBool::new(false);
+
+        self.breaker
+            .call_per_attempt(
+                self.retry_policy.run(operation, || {
+                    let attempt = call();
+                    let downstream_failed = &downstream_failed;
+                    let saturated = &saturated;
+                    async move {
+                        let result = match self.breaker.acquire_slot().await {
+                            Ok(_permit) => attempt.await,
+                            Err(e) => Err(e.into()),
+                        };
+                        saturated.store(
+                            matches!(result, Err(HttpCallError::Saturated(_))),
+                            Ordering::Relaxed,
+                        );
+                        downstream_failed.store(
+                            result
+                                .as_ref()
+                                .is_err_and(HttpCallError::is_downstream_failure),
+                            Ordering::Relaxed,
+                        );
+                        result
+                    }
+                }),
+                |_| {
+                    (!saturated.load(Ordering::Relaxed))
+                        .then(|| downstream_failed.load(Ordering::Relaxed))
+                },
+            )
+            .await
     }
 
     pub fn get_upload_url(&self, video_id: &str, publisher_user_id: &str, is_nsfw: bool) -> String {
@@ -27,23 +124,30 @@ impl StorjInterface {
         )
     }
 
+    async fn get_bytes(&self, url: &str) -> Result<Bytes, HttpCallError> {
+        let response = self.client.get(url).send().await?;
+
+        if !response.status().is_success() {
+            return Err(HttpCallError::from_response(response).await);
+        }
+
+        Ok(response.bytes().await?)
+    }
+
     pub async fn download_video_from_cf(&self, video_id: &str) -> Result<Vec<u8>, Box<dyn Error>> {
         let download_url = format!(
             "https://customer-2p3jflss4r4hmpnz.cloudflarestream.com/{}/downloads/default.mp4",
             video_id
         );
 
-        let response = self.client.get(&download_url).send().await?;
-
-        if !response.status().is_success() {
-            return Err(format!(
-                "Failed to download video from Cloudflare: {}",
-                response.status()
-            )
-            .into());
-        }
+        // Cloudflare is not Storj, so this stays outside the Storj circuit breaker
+        let video_bytes = self
+            .retry_policy
+            .run("download video from Cloudflare", || {
+                self.get_bytes(&download_url)
+            })
+            .await?;
 
-        let video_bytes = response.bytes().await?;
         Ok(video_bytes.to_vec())
     }
 
@@ -58,26 +162,25 @@ impl StorjInterface {
             "{}/duplicate_raw/upload?publisher_user_id={}&video_id={}&is_nsfw={}",
             self.base_url, publisher_user_id, video_id, is_nsfw
         );
+        // cheap to clone for every attempt
+        let video_bytes = Bytes::from(video_bytes);
 
-        let response = self
-            .client
-            .post(&url)
-            .header("Content-Type", "application/octet-stream")
-            .body(video_bytes)
-            .send()
-            .await?;
+        self.run("upload pending video to Storj", || async {
+            let response = self
+                .client
+                .post(&url)
+                .header("Content-Type", "application/octet-stream")
+                .body(video_bytes.clone())
+                .send()
+                .await?;
 
-        if !response.status().is_success() {
-            let status = response.status();
-            let error_body = response.text().await.unwrap_or_default();
-            return Err(format!(
-                "Failed to upload pending video to Storj: {} - {}",
-                status, error_body
-            )
-            .into());
-        }
+            if !response.status().is_success() {
+                return Err(HttpCallError::from_response(response).await);
+            }
 
-        Ok(())
+            Ok(())
+        })
+        .await
     }
 
     pub async fn finalize_upload(
@@ -94,31 +197,28 @@ impl StorjInterface {
 
         let finalize_request = FinalizeRequest { metadata };
 
-        println!("Finalize request URL: {}", url);
-        println!(
+        log::debug!("Finalize request URL: {}", url);
+        log::debug!(
             "Finalize request body: {}",
             serde_json::to_string(&finalize_request).unwrap_or_default()
         );
 
-        let response = self
-            .client
-            .post(&url)
-            .header("Content-Type", "application/json")
-            .json(&finalize_request)
-            .send()
-            .await?;
+        self.run("finalize video upload to Storj", || async {
+            let response = self
+                .client
+                .post(&url)
+                .header("Content-Type", "application/json")
+                .json(&finalize_request)
+                .send()
+                .await?;
 
-        if !response.status().is_success() {
-            let status = response.status();
-            let error_body = response.text().await.unwrap_or_default();
-            return Err(format!(
-                "Failed to finalize video upload to Storj: {} - {}",
-                status, error_body
-            )
-            .into());
-        }
+            if !response.status().is_success() {
+                return Err(HttpCallError::from_response(response).await);
+            }
 
-        Ok(())
+            Ok(())
+        })
+        .await
     }
 
     pub async fn duplicate_video_from_cf_to_storj(
@@ -138,4 +238,177 @@ impl StorjInterface {
 
         Ok(())
     }
+
+    pub async fn download_video(
+        &self,
+        video_id: &str,
+        publisher_user_id: &str,
+        is_nsfw: bool,
+    ) -> Result<Vec<u8>, Box<dyn Error>> {
+        let download_url = self
+            .linkshare
+            .video_url(video_id, publisher_user_id, is_nsfw);
+
+        let video_bytes = self
+            .run("download video from Storj", || {
+                self.get_bytes(&download_url)
+            })
+            .await?;
+
+        Ok(video_bytes.to_vec())
+    }
+
+    pub async fn get_metadata(
+        &self,
+        video_id: &str,
+        publisher_user_id: &str,
+        is_nsfw: bool,
+    ) -> Result<HashMap<String, String>, Box<dyn Error>> {
+        let url = format!(
+            "{}/duplicate_raw/metadata?publisher_user_id={}&video_id={}&is_nsfw={}",
+            self.base_url, publisher_user_id, video_id, is_nsfw
+        );
+
+        let metadata_response: MetadataResponse = self
+            .run("fetch video metadata from Storj", || async {
+                let response = self.client.get(&url).send().await?;
+
+                if !response.status().is_success() {
+                    return Err(HttpCallError::from_response(response).await);
+                }
+
+                Ok(response.json().await?)
+            })
+            .await?;
+
+        Ok(metadata_response.metadata)
+    }
+
+    /// Whether a finalized video object exists, going by its metadata.
+    pub async fn video_exists(
+        &self,
+        video_id: &str,
+        publisher_user_id: &str,
+        is_nsfw: bool,
+    ) -> Result<bool, Box<dyn Error>> {
+        let url = format!(
+            "{}/duplicate_raw/metadata?publisher_user_id={}&video_id={}&is_nsfw={}",
+            self.base_url, publisher_user_id, video_id, is_nsfw
+        );
+
+        self.run("check video existence in Storj", || async {
+            let response = self.client.get(&url).send().await?;
+
+            if response.status() == StatusCode::NOT_FOUND {
+                return Ok(false);
+            }
+            if !response.status().is_success() {
+                return Err(HttpCallError::from_response(response).await);
+            }
+
+            Ok(true)
+        })
+        .await
+    }
+
+    /// Replaces the metadata of an already finalized video object.
+    pub async fn set_metadata(
+        &self,
+        video_id: &str,
+        publisher_user_id: &str,
+        is_nsfw: bool,
+        metadata: HashMap<String, String>,
+    ) -> Result<(), Box<dyn Error>> {
+        let url = format!(
+            "{}/duplicate_raw/metadata?publisher_user_id={}&video_id={}&is_nsfw={}",
+            self.base_url, publisher_user_id, video_id, is_nsfw
+        );
+
+        let metadata_request = FinalizeRequest { metadata };
+
+        self.run("update video metadata in Storj", || async {
+            let response = self
+                .client
+                .post(&url)
+                .header("Content-Type", "application/json")
+                .json(&metadata_request)
+                .send()
+                .await?;
+
+            if !response.status().is_success() {
+                return Err(HttpCallError::from_response(response).await);
+            }
+
+            Ok(())
+        })
+        .await
+    }
+
+    /// Deletes the video object. Deleting an object that does not exist is not an error.
+    pub async fn delete_video(
+        &self,
+        video_id: &str,
+        publisher_user_id: &str,
+        is_nsfw: bool,
+    ) -> Result<(), Box<dyn Error>> {
+        let url = format!(
+            "{}/duplicate_raw/delete?publisher_user_id={}&video_id={}&is_nsfw={}",
+            self.base_url, publisher_user_id, video_id, is_nsfw
+        );
+
+        self.run("delete video from Storj", || async {
+            let response = self.client.delete(&url).send().await?;
+
+            if !response.status().is_success() && response.status() != StatusCode::NOT_FOUND {
+                return Err(HttpCallError::from_response(response).await);
+            }
+
+            Ok(())
+        })
+        .await
+    }
+
+    /// Copies a video object to a new video id, replacing its metadata.
+    pub async fn copy_video(
+        &self,
+        video_id: &str,
+        new_video_id: &str,
+        publisher_user_id: &str,
+        is_nsfw: bool,
+        metadata: HashMap<String, String>,
+    ) -> Result<(), Box<dyn Error>> {
+        let video_bytes = self
+            .download_video(video_id, publisher_user_id, is_nsfw)
+            .await?;
+
+        self.upload_pending(new_video_id, publisher_user_id, is_nsfw, video_bytes)
+            .await?;
+
+        self.finalize_upload(new_video_id, publisher_user_id, is_nsfw, metadata)
+            .await?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_video_url_picks_the_bucket_of_the_video() {
+        let linkshare = LinkshareConfig {
+            sfw_base: "https://link.example.com/raw/sfw".to_string(),
+            nsfw_base: "https://link.example.com/raw/nsfw".to_string(),
+        };
+
+        assert_eq!(
+            linkshare.video_url("video", "publisher", false),
+            "https://link.example.com/raw/sfw/publisher/video.mp4"
+        );
+        assert_eq!(
+            linkshare.video_url("video", "publisher", true),
+            "https://link.example.com/raw/nsfw/publisher/video.mp4"
+        );
+    }
 }
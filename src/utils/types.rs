@@ -1,17 +1,26 @@
 use axum::body::Body;
 use axum::response::{IntoResponse, Response};
 use candid::Principal;
+use ic_agent::Identity;
 use ic_agent::identity::SignedDelegation;
 use ic_agent::identity::{DelegatedIdentity, Secp256k1Identity};
 use k256::elliptic_curve::JwkEcKey;
 use reqwest::header::CONTENT_TYPE;
 use serde::{Deserialize, Serialize};
 use std::error::Error;
+use std::time::SystemTime;
 use thiserror::Error;
 use utoipa::openapi::schema::{self};
 use utoipa::openapi::{ArrayBuilder, Object, ObjectBuilder};
 use utoipa::{PartialSchema, ToSchema};
-use yral_canisters_client::user_post_service::{PostDetailsFromFrontendV1, PostStatusFromFrontend};
+use yral_canisters_client::user_post_service::{
+    PostDetailsFromFrontendV1, PostStatusFromFrontend, UserPostServiceError,
+};
+
+use crate::utils::bulkhead::BulkheadFullError;
+use crate::utils::circuit_breaker::CircuitOpenError;
+use crate::utils::delegation::{DelegationPolicy, verify_delegation_chain};
+use crate::utils::store::StoreError;
 
 #[derive(Error, Debug)]
 pub enum AppError {
@@ -42,11 +51,140 @@ pub enum AppError {
     #[error("Unauthorized: {0}")]
     Unauthorized(String),
 
+    #[error("Forbidden: {0}")]
+    Forbidden(String),
+
     #[error("Canister error: {0}")]
     CanisterError(String),
 
+    #[error("Post {0} already exists")]
+    DuplicatePost(String),
+
     #[error("Serialization error: {0}")]
     SerializationError(String),
+
+    #[error("Content rejected by filter rule: {0}")]
+    ContentRejected(String),
+
+    #[error("Invalid request: {0}")]
+    InvalidRequest(String),
+
+    #[error("Persistence error: {0}")]
+    PersistenceError(String),
+
+    #[error("Idempotency key {0} was already used with a different request")]
+    IdempotencyKeyReused(String),
+
+    #[error("A request with idempotency key {0} is still in progress")]
+    IdempotencyKeyInProgress(String),
+
+    #[error("An upload of post {0} is still in progress")]
+    UploadInProgress(String),
+
+    #[error("Dependency unavailable: {0}")]
+    DependencyUnavailable(String),
+
+    #[error("Deadline exceeded: {0}")]
+    DeadlineExceeded(String),
+
+    #[error("Job not found: {0}")]
+    JobNotFound(String),
+
+    #[error("Dead letter not found: {0}")]
+    DeadLetterNotFound(String),
+
+    #[error("A replay of dead letter {0} is still in progress")]
+    ReplayInProgress(String),
+
+    #[error("Delegation expired: {0}")]
+    DelegationExpired(String),
+
+    #[error("Invalid delegation signature: {0}")]
+    InvalidDelegationSignature(String),
+
+    #[error("Delegation target not accepted: {0}")]
+    DelegationTargetNotAccepted(String),
+
+    #[error("Delegation chain too long: {0}")]
+    DelegationChainTooLong(String),
+
+    #[error("Delegation key mismatch: {0}")]
+    DelegationKeyMismatch(String),
+
+    #[error("Invalid request signature: {0}")]
+    InvalidRequestSignature(String),
+
+    #[error("Unauthenticated: {0}")]
+    Unauthenticated(String),
+
+    #[error("Replayed request: {0}")]
+    ReplayedRequest(String),
+
+    #[error("Account restricted: {message}")]
+    AccountRestricted {
+        message: String,
+        /// Unix timestamp in seconds at which the restriction lifts
+        expires_at: Option<u64>,
+    },
+
+    #[error("Block not found: {0}")]
+    BlockNotFound(String),
+
+    #[error("Payload too large: {0}")]
+    PayloadTooLarge(String),
+
+    #[error("Invalid request body: {message}")]
+    InvalidBody {
+        code: ErrorCode,
+        /// Path of the offending field, e.g. `post_details.title`
+        field: Option<String>,
+        message: String,
+    },
+}
+
+/// Machine-readable reason of a failed request. Codes are stable, clients should branch on them
+/// rather than on `error_message`.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, ToSchema)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum ErrorCode {
+    UnsupportedContentType,
+    MalformedJson,
+    MissingField,
+    InvalidField,
+    PayloadTooLarge,
+    InvalidRequest,
+    InvalidPrincipal,
+    InvalidDelegation,
+    DelegationExpired,
+    DelegationTargetNotAccepted,
+    InvalidRequestSignature,
+    ReplayedRequest,
+    Unauthenticated,
+    /// The caller is not the creator of the post or upload
+    UnauthorizedCreator,
+    /// The admin credentials lack the scope of the route
+    Forbidden,
+    AccountRestricted,
+    ContentRejected,
+    UserNotFound,
+    UserProfileUnavailable,
+    PostNotFound,
+    DuplicatePost,
+    JobNotFound,
+    DeadLetterNotFound,
+    ReplayInProgress,
+    BlockNotFound,
+    IdempotencyKeyReused,
+    IdempotencyKeyInProgress,
+    UploadInProgress,
+    StorageUnavailable,
+    /// A canister could not be reached
+    CanisterUnavailable,
+    /// A canister answered the call with an error
+    CanisterRejected,
+    DependencyUnavailable,
+    DeadlineExceeded,
+    InternalError,
 }
 
 impl From<ic_agent::agent::AgentError> for AppError {
@@ -67,9 +205,18 @@ impl From<candid::types::principal::PrincipalError> for AppError {
     }
 }
 
+/// A boxed error comes out of a downstream client, an open breaker or full bulkhead keeps its
+/// meaning as an unavailable dependency
 impl From<Box<dyn Error>> for AppError {
     fn from(error: Box<dyn Error>) -> Self {
-        AppError::InternalError(error.to_string())
+        if error.is::<CircuitOpenError>() || error.is::<BulkheadFullError>() {
+            return AppError::DependencyUnavailable(error.to_string());
+        }
+
+        match error.downcast::<StoreError>() {
+            Ok(error) => AppError::from(*error),
+            Err(error) => AppError::InternalError(error.to_string()),
+        }
     }
 }
 
@@ -79,11 +226,47 @@ impl From<serde_json::Error> for AppError {
     }
 }
 
+impl From<CircuitOpenError> for AppError {
+    fn from(error: CircuitOpenError) -> Self {
+        AppError::DependencyUnavailable(error.to_string())
+    }
+}
+
+impl From<BulkheadFullError> for AppError {
+    fn from(error: BulkheadFullError) -> Self {
+        AppError::DependencyUnavailable(error.to_string())
+    }
+}
+
+impl From<StoreError> for AppError {
+    fn from(error: StoreError) -> Self {
+        AppError::PersistenceError(error.to_string())
+    }
+}
+
+impl AppError {
+    /// Typed mapping of a `user_post_service` rejection of a call about `post_id`
+    pub fn from_user_post_service(error: UserPostServiceError, post_id: &str) -> Self {
+        match error {
+            UserPostServiceError::PostNotFound => AppError::PostNotFound(post_id.to_string()),
+            UserPostServiceError::DuplicatePostId => AppError::DuplicatePost(post_id.to_string()),
+            UserPostServiceError::Unauthorized => AppError::Unauthorized(format!(
+                "user post service refused the call for post {}",
+                post_id
+            )),
+            UserPostServiceError::CallError(message) => AppError::CanisterError(format!(
+                "user post service failed the call for post {}: {}",
+                post_id, message
+            )),
+        }
+    }
+}
+
 impl AppError {
     pub fn status_code(&self) -> u16 {
         match self {
             AppError::InvalidPrincipal(_) => 400,
-            AppError::UserProfileFetchError(_) => 400,
+            AppError::UserProfileFetchError(_) => 502,
             AppError::UserNotFound => 404,
             AppError::StorageError(_) => 503,
             AppError::InternalError(_) => 500,
@@ -91,8 +274,114 @@ impl AppError {
             AppError::InvalidDelegatedIdentity(_) => 400,
             AppError::PostNotFound(_) => 404,
             AppError::Unauthorized(_) => 403,
+            AppError::Forbidden(_) => 403,
             AppError::CanisterError(_) => 502,
+            AppError::DuplicatePost(_) => 409,
             AppError::SerializationError(_) => 500,
+            AppError::ContentRejected(_) => 422,
+            AppError::InvalidRequest(_) => 400,
+            AppError::PersistenceError(_) => 500,
+            AppError::IdempotencyKeyReused(_) => 422,
+            AppError::IdempotencyKeyInProgress(_) => 409,
+            AppError::UploadInProgress(_) => 409,
+            AppError::DependencyUnavailable(_) => 503,
+            AppError::DeadlineExceeded(_) => 504,
+            AppError::JobNotFound(_) => 404,
+            AppError::DeadLetterNotFound(_) => 404,
+            AppError::ReplayInProgress(_) => 409,
+            AppError::DelegationExpired(_) => 401,
+            AppError::InvalidDelegationSignature(_) => 401,
+            AppError::DelegationTargetNotAccepted(_) => 403,
+            AppError::DelegationChainTooLong(_) => 400,
+            AppError::DelegationKeyMismatch(_) => 401,
+            AppError::InvalidRequestSignature(_) => 401,
+            AppError::Unauthenticated(_) => 401,
+            AppError::ReplayedRequest(_) => 401,
+            AppError::AccountRestricted { .. } => 403,
+            AppError::BlockNotFound(_) => 404,
+            AppError::PayloadTooLarge(_) => 413,
+            AppError::InvalidBody { code, .. } => match code {
+                ErrorCode::UnsupportedContentType => 415,
+                ErrorCode::PayloadTooLarge => 413,
+                ErrorCode::MissingField | ErrorCode::InvalidField => 422,
+                // malformed JSON, the extractor builds no other codes
+                _ => 400,
+            },
+        }
+    }
+
+    pub fn error_code(&self) -> ErrorCode {
+        match self {
+            AppError::InvalidPrincipal(_) => ErrorCode::InvalidPrincipal,
+            AppError::UserProfileFetchError(_) => ErrorCode::UserProfileUnavailable,
+            AppError::UserNotFound => ErrorCode::UserNotFound,
+            AppError::StorageError(_) => ErrorCode::StorageUnavailable,
+            AppError::InternalError(_) => ErrorCode::InternalError,
+            AppError::AgentError(_) => ErrorCode::CanisterUnavailable,
+            AppError::InvalidDelegatedIdentity(_) => ErrorCode::InvalidDelegation,
+            AppError::PostNotFound(_) => ErrorCode::PostNotFound,
+            AppError::Unauthorized(_) => ErrorCode::UnauthorizedCreator,
+            AppError::Forbidden(_) => ErrorCode::Forbidden,
+            AppError::CanisterError(_) => ErrorCode::CanisterRejected,
+            AppError::DuplicatePost(_) => ErrorCode::DuplicatePost,
+            AppError::SerializationError(_) => ErrorCode::InternalError,
+            AppError::ContentRejected(_) => ErrorCode::ContentRejected,
+            AppError::InvalidRequest(_) => ErrorCode::InvalidRequest,
+            AppError::PersistenceError(_) => ErrorCode::InternalError,
+            AppError::IdempotencyKeyReused(_) => ErrorCode::IdempotencyKeyReused,
+            AppError::IdempotencyKeyInProgress(_) => ErrorCode::IdempotencyKeyInProgress,
+            AppError::UploadInProgress(_) => ErrorCode::UploadInProgress,
+            AppError::DependencyUnavailable(_) => ErrorCode::DependencyUnavailable,
+            AppError::DeadlineExceeded(_) => ErrorCode::DeadlineExceeded,
+            AppError::JobNotFound(_) => ErrorCode::JobNotFound,
+            AppError::DeadLetterNotFound(_) => ErrorCode::DeadLetterNotFound,
+            AppError::ReplayInProgress(_) => ErrorCode::ReplayInProgress,
+            AppError::DelegationExpired(_) => ErrorCode::DelegationExpired,
+            AppError::InvalidDelegationSignature(_) => ErrorCode::InvalidDelegation,
+            AppError::DelegationTargetNotAccepted(_) => ErrorCode::DelegationTargetNotAccepted,
+            AppError::DelegationChainTooLong(_) => ErrorCode::InvalidDelegation,
+            AppError::DelegationKeyMismatch(_) => ErrorCode::InvalidDelegation,
+            AppError::InvalidRequestSignature(_) => ErrorCode::InvalidRequestSignature,
+            AppError::Unauthenticated(_) => ErrorCode::Unauthenticated,
+            AppError::ReplayedRequest(_) => ErrorCode::ReplayedRequest,
+            AppError::AccountRestricted { .. } => ErrorCode::AccountRestricted,
+            AppError::BlockNotFound(_) => ErrorCode::BlockNotFound,
+            AppError::PayloadTooLarge(_) => ErrorCode::PayloadTooLarge,
+            AppError::InvalidBody { code, .. } => *code,
+        }
+    }
+
+    /// Whether the same request may succeed when sent again later
+    pub fn is_retryable(&self) -> bool {
+        matches!(
+            self,
+            AppError::UserProfileFetchError(_)
+                | AppError::StorageError(_)
+                | AppError::AgentError(_)
+                | AppError::PersistenceError(_)
+                | AppError::IdempotencyKeyInProgress(_)
+                | AppError::UploadInProgress(_)
+                | AppError::ReplayInProgress(_)
+                | AppError::DependencyUnavailable(_)
+                | AppError::DeadlineExceeded(_)
+        )
+    }
+
+    /// Structured data of the error beyond its message
+    pub fn details(&self) -> Option<serde_json::Value> {
+        match self {
+            AppError::DuplicatePost(post_id) => Some(serde_json::json!({ "post_id": post_id })),
+            AppError::AccountRestricted { expires_at, .. } => {
+                Some(serde_json::json!({ "expires_at": expires_at }))
+            }
+            _ => None,
+        }
+    }
+
+    pub fn field(&self) -> Option<String> {
+        match self {
+            AppError::InvalidBody { field, .. } => field.clone(),
+            _ => None,
         }
     }
 
@@ -101,6 +390,10 @@ impl AppError {
             success: false,
             data: None,
             error_message: Some(self.to_string()),
+            error_code: Some(self.error_code()),
+            field: self.field(),
+            details: self.details(),
+            retryable: self.is_retryable(),
             status_code: self.status_code(),
         }
     }
@@ -113,6 +406,10 @@ impl<T: Serialize> From<Result<T, AppError>> for ApiResponse<T> {
                 success: true,
                 data: Some(data),
                 error_message: None,
+                error_code: None,
+                field: None,
+                details: None,
+                retryable: false,
                 status_code: 200,
             },
             Err(e) => e.to_api_response(),
@@ -128,6 +425,18 @@ pub struct ApiResponse<T> {
     pub success: bool,
     pub data: Option<T>,
     pub error_message: Option<String>,
+    #[serde(default)]
+    pub error_code: Option<ErrorCode>,
+    /// Path of the request field the error is about
+    #[serde(default)]
+    pub field: Option<String>,
+    /// Structured data of the error, its shape depends on `error_code`
+    #[serde(default)]
+    #[schema(value_type = Option<Object>)]
+    pub details: Option<serde_json::Value>,
+    /// Whether sending the same request again later may succeed
+    #[serde(default)]
+    pub retryable: bool,
     #[serde(skip_serializing, default)]
     pub status_code: u16,
 }
@@ -136,32 +445,17 @@ impl<T: Serialize> IntoResponse for ApiResponse<T> {
     fn into_response(self) -> Response {
         //todo we need to think about response status
 
-        let response = Response::builder()
+        Response::builder()
             .header(CONTENT_TYPE, "application/json")
             .status(self.status_code)
             .body(Body::from(serde_json::to_string(&self).unwrap()))
-            .unwrap();
-
-        response
+            .unwrap()
     }
 }
 
 impl<T: Serialize> From<Result<T, Box<dyn Error>>> for ApiResponse<T> {
     fn from(result: Result<T, Box<dyn Error>>) -> Self {
-        match result {
-            Ok(data) => ApiResponse {
-                success: true,
-                data: Some(data),
-                error_message: None,
-                status_code: 200,
-            },
-            Err(e) => ApiResponse {
-                success: false,
-                data: None,
-                error_message: Some(e.to_string()),
-                status_code: 400,
-            },
-        }
+        ApiResponse::from(result.map_err(AppError::from))
     }
 }
 
@@ -177,6 +471,27 @@ pub struct DelegatedIdentityWire {
     pub delegation_chain: Vec<SignedDelegation>,
 }
 
+impl DelegatedIdentityWire {
+    /// Principal on whose behalf the wire's delegation chain signs, once the chain is verified
+    /// against `policy`
+    pub fn sender(&self, policy: &DelegationPolicy) -> Result<Principal, AppError> {
+        let to_secret = k256::SecretKey::from_jwk(&self.to_secret)
+            .map_err(|e| AppError::InvalidDelegatedIdentity(e.to_string()))?;
+
+        let identity = verify_delegation_chain(
+            self.from_key.clone(),
+            self.delegation_chain.clone(),
+            Box::new(Secp256k1Identity::from_private_key(to_secret)),
+            policy,
+            SystemTime::now(),
+        )?;
+
+        identity
+            .sender()
+            .map_err(AppError::InvalidDelegatedIdentity)
+    }
+}
+
 impl ToSchema for DelegatedIdentityWire {
     fn name() -> std::borrow::Cow<'static, str> {
         std::borrow::Cow::Borrowed("DelegatedIdentityWire")
@@ -237,6 +552,83 @@ impl TryFrom<DelegatedIdentityWire> for DelegatedIdentity {
     }
 }
 
+const MAX_FILTERS_USED: usize = 10;
+const MAX_CONTEXT_FIELD_LEN: usize = 64;
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum CaptureSource {
+    Camera,
+    Gallery,
+    ScreenRecording,
+    AiGenerated,
+}
+
+/// How the client produced the video, reported by the app alongside the metadata
+#[derive(Serialize, Deserialize, Clone, Debug, Default, ToSchema)]
+pub struct CreationContext {
+    /// Names of the camera filters or effects applied
+    #[serde(default)]
+    pub filters_used: Vec<String>,
+    pub capture_source: Option<CaptureSource>,
+    /// Tool used to generate the video, only allowed for `ai_generated` captures
+    pub ai_tool: Option<String>,
+    pub hot_or_not_consent: Option<bool>,
+    /// ISO 3166-1 alpha-2 country code
+    #[schema(example = "IN")]
+    pub country: Option<String>,
+}
+
+impl CreationContext {
+    pub fn validate(&self) -> Result<(), AppError> {
+        if self.filters_used.len() > MAX_FILTERS_USED {
+            return Err(AppError::InvalidRequest(format!(
+                "At most {} filters can be reported",
+                MAX_FILTERS_USED
+            )));
+        }
+
+        if self
+            .filters_used
+            .iter()
+            .chain(self.ai_tool.iter())
+            .any(|value| value.is_empty() || value.len() > MAX_CONTEXT_FIELD_LEN)
+        {
+            return Err(AppError::InvalidRequest(format!(
+                "Filter and AI tool names must be between 1 and {} bytes",
+                MAX_CONTEXT_FIELD_LEN
+            )));
+        }
+
+        if self.ai_tool.is_some() && self.capture_source != Some(CaptureSource::AiGenerated) {
+            return Err(AppError::InvalidRequest(
+                "ai_tool is only allowed for ai_generated captures".to_string(),
+            ));
+        }
+
+        let is_valid_country = self.country.as_ref().is_none_or(|country| {
+            country.len() == 2 && country.chars().all(|c| c.is_ascii_uppercase())
+        });
+        if !is_valid_country {
+            return Err(AppError::InvalidRequest(format!(
+                "Invalid ISO 3166-1 alpha-2 country code: {}",
+                self.country.clone().unwrap_or_default()
+            )));
+        }
+
+        Ok(())
+    }
+
+    pub fn is_filter_used(&self) -> bool {
+        !self.filters_used.is_empty()
+    }
+
+    /// Creators are opted in to hot-or-not unless they say otherwise
+    pub fn enable_hot_or_not(&self) -> bool {
+        self.hot_or_not_consent.unwrap_or(true)
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct RequestPostDetails {
     pub video_uid: String,
@@ -293,11 +685,10 @@ mod tests {
                 .unwrap()
                 .as_bytes()
                 .to_vec(),
-            expiration: SystemTime::now()
+            expiration: (SystemTime::now() + std::time::Duration::from_secs(3600))
                 .duration_since(UNIX_EPOCH)
                 .unwrap()
-                .as_secs()
-                + 3600, // valid for 1 hour
+                .as_nanos() as u64, // valid for 1 hour
             targets: None,
         };
 
@@ -321,6 +712,398 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_error_response_is_machine_readable() {
+        let response = serde_json::to_value(
+            AppError::StorageError("timed out".to_string()).to_api_response::<()>(),
+        )
+        .unwrap();
+        assert_eq!(response["error_code"], "STORAGE_UNAVAILABLE");
+        assert_eq!(response["retryable"], true);
+
+        let response = serde_json::to_value(
+            AppError::DuplicatePost("post".to_string()).to_api_response::<()>(),
+        )
+        .unwrap();
+        assert_eq!(response["error_code"], "DUPLICATE_POST");
+        assert_eq!(response["retryable"], false);
+        assert_eq!(response["details"]["post_id"], "post");
+    }
+
+    #[test]
+    fn test_every_error_maps_to_its_code_status_and_retryability() {
+        let m = || "message".to_string();
+        let invalid_body = |code| AppError::InvalidBody {
+            code,
+            field: None,
+            message: m(),
+        };
+        let cases = [
+            (
+                AppError::InvalidPrincipal(m()),
+                ErrorCode::InvalidPrincipal,
+                400,
+                false,
+            ),
+            (
+                AppError::UserProfileFetchError(m()),
+                ErrorCode::UserProfileUnavailable,
+                502,
+                true,
+            ),
+            (AppError::UserNotFound, ErrorCode::UserNotFound, 404, false),
+            (
+                AppError::StorageError(m()),
+                ErrorCode::StorageUnavailable,
+                503,
+                true,
+            ),
+            (
+                AppError::InternalError(m()),
+                ErrorCode::InternalError,
+                500,
+                false,
+            ),
+            (
+                AppError::AgentError(m()),
+                ErrorCode::CanisterUnavailable,
+                502,
+                true,
+            ),
+            (
+                AppError::InvalidDelegatedIdentity(m()),
+                ErrorCode::InvalidDelegation,
+                400,
+                false,
+            ),
+            (
+                AppError::PostNotFound(m()),
+                ErrorCode::PostNotFound,
+                404,
+                false,
+            ),
+            (
+                AppError::Unauthorized(m()),
+                ErrorCode::UnauthorizedCreator,
+                403,
+                false,
+            ),
+            (AppError::Forbidden(m()), ErrorCode::Forbidden, 403, false),
+            (
+                AppError::CanisterError(m()),
+                ErrorCode::CanisterRejected,
+                502,
+                false,
+            ),
+            (
+                AppError::DuplicatePost(m()),
+                ErrorCode::DuplicatePost,
+                409,
+                false,
+            ),
+            (
+                AppError::SerializationError(m()),
+                ErrorCode::InternalError,
+                500,
+                false,
+            ),
+            (
+                AppError::ContentRejected(m()),
+                ErrorCode::ContentRejected,
+                422,
+                false,
+            ),
+            (
+                AppError::InvalidRequest(m()),
+                ErrorCode::InvalidRequest,
+                400,
+                false,
+            ),
+            (
+                AppError::PersistenceError(m()),
+                ErrorCode::InternalError,
+                500,
+                true,
+            ),
+            (
+                AppError::IdempotencyKeyReused(m()),
+                ErrorCode::IdempotencyKeyReused,
+                422,
+                false,
+            ),
+            (
+                AppError::IdempotencyKeyInProgress(m()),
+                ErrorCode::IdempotencyKeyInProgress,
+                409,
+                true,
+            ),
+            (
+                AppError::UploadInProgress(m()),
+                ErrorCode::UploadInProgress,
+                409,
+                true,
+            ),
+            (
+                AppError::DependencyUnavailable(m()),
+                ErrorCode::DependencyUnavailable,
+                503,
+                true,
+            ),
+            (
+                AppError::DeadlineExceeded(m()),
+                ErrorCode::DeadlineExceeded,
+                504,
+                true,
+            ),
+            (
+                AppError::JobNotFound(m()),
+                ErrorCode::JobNotFound,
+                404,
+                false,
+            ),
+            (
+                AppError::DeadLetterNotFound(m()),
+                ErrorCode::DeadLetterNotFound,
+                404,
+                false,
+            ),
+            (
+                AppError::ReplayInProgress(m()),
+                ErrorCode::ReplayInProgress,
+                409,
+                true,
+            ),
+            (
+                AppError::DelegationExpired(m()),
+                ErrorCode::DelegationExpired,
+                401,
+                false,
+            ),
+            (
+                AppError::InvalidDelegationSignature(m()),
+                ErrorCode::InvalidDelegation,
+                401,
+                false,
+            ),
+            (
+                AppError::DelegationTargetNotAccepted(m()),
+                ErrorCode::DelegationTargetNotAccepted,
+                403,
+                false,
+            ),
+            (
+                AppError::DelegationChainTooLong(m()),
+                ErrorCode::InvalidDelegation,
+                400,
+                false,
+            ),
+            (
+                AppError::DelegationKeyMismatch(m()),
+                ErrorCode::InvalidDelegation,
+                401,
+                false,
+            ),
+            (
+                AppError::InvalidRequestSignature(m()),
+                ErrorCode::InvalidRequestSignature,
+                401,
+                false,
+            ),
+            (
+                AppError::Unauthenticated(m()),
+                ErrorCode::Unauthenticated,
+                401,
+                false,
+            ),
+            (
+                AppError::ReplayedRequest(m()),
+                ErrorCode::ReplayedRequest,
+                401,
+                false,
+            ),
+            (
+                AppError::AccountRestricted {
+                    message: m(),
+                    expires_at: None,
+                },
+                ErrorCode::AccountRestricted,
+                403,
+                false,
+            ),
+            (
+                AppError::BlockNotFound(m()),
+                ErrorCode::BlockNotFound,
+                404,
+                false,
+            ),
+            (
+                AppError::PayloadTooLarge(m()),
+                ErrorCode::PayloadTooLarge,
+                413,
+                false,
+            ),
+            (
+                invalid_body(ErrorCode::UnsupportedContentType),
+                ErrorCode::UnsupportedContentType,
+                415,
+                false,
+            ),
+            (
+                invalid_body(ErrorCode::MalformedJson),
+                ErrorCode::MalformedJson,
+                400,
+                false,
+            ),
+            (
+                invalid_body(ErrorCode::MissingField),
+                ErrorCode::MissingField,
+                422,
+                false,
+            ),
+            (
+                invalid_body(ErrorCode::InvalidField),
+                ErrorCode::InvalidField,
+                422,
+                false,
+            ),
+            (
+                invalid_body(ErrorCode::PayloadTooLarge),
+                ErrorCode::PayloadTooLarge,
+                413,
+                false,
+            ),
+        ];
+
+        for (error, code, status_code, retryable) in cases {
+            assert_eq!(error.error_code(), code, "{:?}", error);
+            assert_eq!(error.status_code(), status_code, "{:?}", error);
+            assert_eq!(error.is_retryable(), retryable, "{:?}", error);
+        }
+    }
+
+    #[test]
+    fn test_every_user_post_service_error_is_mapped() {
+        let cases = [
+            (UserPostServiceError::PostNotFound, ErrorCode::PostNotFound),
+            (
+                UserPostServiceError::DuplicatePostId,
+                ErrorCode::DuplicatePost,
+            ),
+            (
+                UserPostServiceError::Unauthorized,
+                ErrorCode::UnauthorizedCreator,
+            ),
+            (
+                UserPostServiceError::CallError("trap".to_string()),
+                ErrorCode::CanisterRejected,
+            ),
+        ];
+
+        for (error, code) in cases {
+            assert_eq!(
+                AppError::from_user_post_service(error, "post").error_code(),
+                code
+            );
+        }
+    }
+
+    #[test]
+    fn test_boxed_errors_are_not_blamed_on_the_request() {
+        let cases: [(Box<dyn Error>, ErrorCode, u16, bool); 3] = [
+            (
+                "503 - unavailable".into(),
+                ErrorCode::InternalError,
+                500,
+                false,
+            ),
+            (
+                Box::new(CircuitOpenError("storj")),
+                ErrorCode::DependencyUnavailable,
+                503,
+                true,
+            ),
+            (
+                Box::new(BulkheadFullError("storj")),
+                ErrorCode::DependencyUnavailable,
+                503,
+                true,
+            ),
+        ];
+
+        for (error, code, status_code, retryable) in cases {
+            let response = ApiResponse::from(Err::<(), _>(error));
+            assert_eq!(response.error_code, Some(code));
+            assert_eq!(response.status_code, status_code);
+            assert_eq!(response.retryable, retryable);
+        }
+    }
+
+    #[test]
+    fn test_creation_context_validation() {
+        let ai_generated = |ai_tool: &str| CreationContext {
+            capture_source: Some(CaptureSource::AiGenerated),
+            ai_tool: Some(ai_tool.to_string()),
+            ..Default::default()
+        };
+        let filters = |count: usize, name: &str| CreationContext {
+            filters_used: vec![name.to_string(); count],
+            ..Default::default()
+        };
+        let country = |country: &str| CreationContext {
+            country: Some(country.to_string()),
+            ..Default::default()
+        };
+        let long_name = "f".repeat(MAX_CONTEXT_FIELD_LEN + 1);
+
+        let cases = [
+            ("empty context", CreationContext::default(), true),
+            ("ai tool on ai capture", ai_generated("veo"), true),
+            (
+                "ai tool on camera capture",
+                CreationContext {
+                    capture_source: Some(CaptureSource::Camera),
+                    ..ai_generated("veo")
+                },
+                false,
+            ),
+            (
+                "ai tool without capture source",
+                CreationContext {
+                    capture_source: None,
+                    ..ai_generated("veo")
+                },
+                false,
+            ),
+            ("empty ai tool", ai_generated(""), false),
+            ("too long ai tool", ai_generated(&long_name), false),
+            ("max filters", filters(MAX_FILTERS_USED, "sepia"), true),
+            (
+                "too many filters",
+                filters(MAX_FILTERS_USED + 1, "sepia"),
+                false,
+            ),
+            ("empty filter", filters(1, ""), false),
+            (
+                "max length filter",
+                filters(1, &"f".repeat(MAX_CONTEXT_FIELD_LEN)),
+                true,
+            ),
+            ("too long filter", filters(1, &long_name), false),
+            ("country code", country("IN"), true),
+            ("lowercase country code", country("in"), false),
+            ("three letter country code", country("IND"), false),
+            ("non letter country code", country("I1"), false),
+        ];
+
+        for (name, context, is_valid) in cases {
+            let result = context.validate();
+            assert_eq!(result.is_ok(), is_valid, "{}: {:?}", name, result);
+            if let Err(error) = result {
+                assert_eq!(error.error_code(), ErrorCode::InvalidRequest, "{}", name);
+            }
+        }
+    }
+
     #[test]
     fn test_valid_delegated_identity_wire() {
         let from_main_secret_key = SecretKey::random(&mut OsRng);
@@ -0,0 +1,612 @@
+use std::{collections::HashMap, future::Future, str::FromStr, time::Duration};
+
+use candid::Principal;
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+use crate::utils::{
+    retry::RetryPolicy,
+    store::{Store, StoreError, TypedTree},
+    storj_interface::StorjInterface,
+    time::now_unix_secs,
+    types::AppError,
+};
+
+pub static ORPHANED_KEY: &str = "orphaned";
+pub static ORPHANED_REASON_KEY: &str = "orphaned_reason";
+
+/// Seconds after which an unfinished saga is taken to belong to an upload that died with the
+/// process, and a new upload of the post may take it over
+const STALE_SAGA_SECS: u64 = 15 * 60;
+
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum UploadSagaState {
+    /// The Storj upload is being finalized
+    FinalizingStorj,
+    /// The video is finalized in Storj and the post is being written to the canister
+    StorjFinalized,
+    /// The post was written to the canister
+    Completed,
+    /// Finalizing the Storj upload failed, nothing had to be undone
+    Failed,
+    /// The canister write failed and the Storj object was marked as orphaned
+    MarkedOrphaned,
+    /// The canister write failed and the Storj object was deleted
+    Deleted,
+    /// The canister write failed and undoing the Storj finalize failed as well
+    CompensationFailed,
+    /// The canister write may have landed but the post could not be looked up, the Storj object
+    /// was left finalized
+    Unverified,
+}
+
+/// What is done with a finalized Storj object whose post could not be written to the canister
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CompensationAction {
+    MarkOrphaned,
+    Delete,
+}
+
+impl FromStr for CompensationAction {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "mark_orphaned" => Ok(CompensationAction::MarkOrphaned),
+            "delete" => Ok(CompensationAction::Delete),
+            _ => Err(format!("Unknown compensation action: {}", s)),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, ToSchema)]
+pub struct UploadSaga {
+    #[schema(example = "post-id-string")]
+    pub post_id: String,
+    #[schema(value_type = String, example = "principal-id-string")]
+    pub creator_principal: Principal,
+    pub state: UploadSagaState,
+    pub canister_attempts: u32,
+    pub last_error: Option<String>,
+    pub created_at: u64,
+    pub updated_at: u64,
+}
+
+impl UploadSaga {
+    /// Whether the upload is still between starting the Storj finalize and the canister write,
+    /// rather than done or died with the process
+    pub fn is_live(&self, now: u64) -> bool {
+        matches!(
+            self.state,
+            UploadSagaState::FinalizingStorj | UploadSagaState::StorjFinalized
+        ) && now.saturating_sub(self.updated_at) < STALE_SAGA_SECS
+    }
+}
+
+/// Records the steps of every metadata update so that a canister write failing after the Storj
+/// finalize can be compensated and its outcome looked up later.
+#[derive(Clone)]
+pub struct UploadSagas {
+    sagas: TypedTree<UploadSaga>,
+    canister_retry_policy: RetryPolicy,
+    compensation_action: CompensationAction,
+    /// How long a saga is kept once its upload is settled
+    ttl: Duration,
+}
+
+impl UploadSagas {
+    pub fn new(
+        store: &Store,
+        canister_retry_policy: RetryPolicy,
+        compensation_action: CompensationAction,
+        ttl: Duration,
+    ) -> Result<Self, StoreError> {
+        Ok(Self {
+            sagas: store.tree("upload_sagas")?,
+            canister_retry_policy,
+            compensation_action,
+            ttl,
+        })
+    }
+
+    pub fn get(&self, post_id: &str) -> Result<Option<UploadSaga>, AppError> {
+        Ok(self.sagas.get(post_id)?)
+    }
+
+    /// Starts the saga of an upload of the post. An existing saga is only restarted when its
+    /// upload failed before anything was finalized or it is stale, otherwise the upload is refused.
+    pub fn begin(&self, post_id: &str, creator_principal: Principal) -> Result<(), AppError> {
+        let now = now_unix_secs();
+        let mut refusal = None;
+
+        self.sagas.fetch_and_update(post_id, |existing| {
+            refusal = existing
+                .as_ref()
+                .and_then(|saga| restart_refusal(saga, now));
+            if refusal.is_some() {
+                return existing;
+            }
+
+            Some(UploadSaga {
+                post_id: post_id.to_string(),
+                creator_principal,
+                state: UploadSagaState::FinalizingStorj,
+                canister_attempts: 0,
+                last_error: None,
+                created_at: now,
+                updated_at: now,
+            })
+        })?;
+
+        refusal.map_or(Ok(()), Err)
+    }
+
+    /// Moves the saga to `state`. The saga records the upload rather than driving it, so a state
+    /// that cannot be stored is logged and the upload carries on.
+    pub fn advance(&self, post_id: &str, state: UploadSagaState, error: Option<&AppError>) {
+        self.update(post_id, |saga| {
+            saga.state = state;
+            if let Some(error) = error {
+                saga.last_error = Some(error.to_string());
+            }
+        });
+    }
+
+    fn update(&self, post_id: &str, apply: impl Fn(&mut UploadSaga)) {
+        let result = self.sagas.fetch_and_update(post_id, |saga| {
+            let mut saga = saga?;
+            apply(&mut saga);
+            saga.updated_at = now_unix_secs();
+            Some(saga)
+        });
+
+        if let Err(e) = result {
+            log::error!("Failed to update upload saga for post {}: {}", post_id, e);
+        }
+    }
+
+    /// Drops the sagas of uploads settled longer than the TTL ago. Sagas that need an operator,
+    /// a failed compensation or an unverified write, are kept until the upload is replayed.
+    fn prune_settled(&self, now: u64) -> Result<(), StoreError> {
+        for (post_id, saga) in self.sagas.entries()? {
+            let settled = matches!(
+                saga.state,
+                UploadSagaState::Completed
+                    | UploadSagaState::Failed
+                    | UploadSagaState::MarkedOrphaned
+                    | UploadSagaState::Deleted
+            );
+            if settled && saga.updated_at + self.ttl.as_secs() <= now {
+                self.sagas.remove(&post_id)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Starts the background task dropping settled sagas past their TTL.
+    pub fn spawn_pruner(&self) {
+        let upload_sagas = self.clone();
+
+        tokio::spawn(async move {
+            loop {
+                if let Err(e) = upload_sagas.prune_settled(now_unix_secs()) {
+                    log::error!("Failed to prune upload sagas: {}", e);
+                }
+
+                tokio::time::sleep(PRUNE_INTERVAL).await;
+            }
+        });
+    }
+
+    /// Runs the canister write, retrying agent errors. Errors returned by the canister itself are
+    /// not retried.
+    pub async fn write_to_canister<T, F, Fut>(
+        &self,
+        post_id: &str,
+        mut call: F,
+    ) -> Result<T, AppError>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T, AppError>>,
+    {
+        let mut attempt = 1;
+
+        loop {
+            let result = call().await;
+            self.update(post_id, |saga| {
+                saga.canister_attempts = attempt;
+                saga.last_error = result.as_ref().err().map(|e| e.to_string());
+            });
+
+            let error = match result {
+                Ok(value) => return Ok(value),
+                Err(error) => error,
+            };
+
+            if !matches!(error, AppError::AgentError(_))
+                || attempt >= self.canister_retry_policy.max_attempts
+            {
+                return Err(error);
+            }
+
+            let delay = self.canister_retry_policy.backoff(attempt);
+            log::warn!(
+                "Canister write for post {} failed (attempt {}), retrying in {:?}: {}",
+                post_id,
+                attempt,
+                delay,
+                error
+            );
+
+            tokio::time::sleep(delay).await;
+            attempt += 1;
+        }
+    }
+
+    /// Undoes the Storj finalize after the canister write failed for good. A write that may have
+    /// landed is looked up with `post_exists` first and the Storj object is only touched when the
+    /// post is absent. Returns whether the post was written after all.
+    pub async fn compensate<Fut>(
+        &self,
+        storj_client: &StorjInterface,
+        post_id: &str,
+        publisher_user_id: &str,
+        mut meta: HashMap<String, String>,
+        canister_error: &AppError,
+        post_exists: impl FnOnce() -> Fut,
+    ) -> bool
+    where
+        Fut: Future<Output = Result<bool, AppError>>,
+    {
+        match check_failed_write(canister_error, post_exists).await {
+            FailedWrite::Landed => {
+                log::warn!(
+                    "Canister write for post {} failed with \"{}\" but the post exists, not compensating",
+                    post_id,
+                    canister_error
+                );
+                self.advance(post_id, UploadSagaState::Completed, None);
+                return true;
+            }
+            FailedWrite::Unverified(lookup_error) => {
+                let msg = format!(
+                    "Canister write for post {} failed with \"{}\" and the post could not be looked up, Storj object left finalized: {}",
+                    post_id, canister_error, lookup_error
+                );
+                log::error!("{}", msg);
+                sentry::capture_message(&msg, sentry::Level::Error);
+                self.advance(post_id, UploadSagaState::Unverified, Some(canister_error));
+                return false;
+            }
+            FailedWrite::Absent => {}
+        }
+
+        let (compensation_result, compensated_state) = match self.compensation_action {
+            CompensationAction::MarkOrphaned => {
+                meta.insert(ORPHANED_KEY.to_string(), true.to_string());
+                meta.insert(ORPHANED_REASON_KEY.to_string(), canister_error.to_string());
+
+                (
+                    storj_client
+                        .set_metadata(post_id, publisher_user_id, false, meta)
+                        .await,
+                    UploadSagaState::MarkedOrphaned,
+                )
+            }
+            CompensationAction::Delete => (
+                storj_client
+                    .delete_video(post_id, publisher_user_id, false)
+                    .await,
+                UploadSagaState::Deleted,
+            ),
+        };
+
+        match compensation_result {
+            Ok(()) => {
+                log::warn!(
+                    "Compensated failed canister write for post {} ({:?}): {}",
+                    post_id,
+                    compensated_state,
+                    canister_error
+                );
+                self.advance(post_id, compensated_state, Some(canister_error));
+            }
+            Err(e) => {
+                let msg = format!(
+                    "Failed to compensate canister write for post {}, Storj object left finalized: {}",
+                    post_id, e
+                );
+                log::error!("{}", msg);
+                sentry::capture_message(&msg, sentry::Level::Error);
+                self.advance(
+                    post_id,
+                    UploadSagaState::CompensationFailed,
+                    Some(&AppError::StorageError(e.to_string())),
+                );
+            }
+        }
+
+        false
+    }
+}
+
+/// Why an upload of a post that already has `saga` must not start, `None` when the saga may be
+/// restarted
+fn restart_refusal(saga: &UploadSaga, now: u64) -> Option<AppError> {
+    match saga.state {
+        UploadSagaState::Failed => None,
+        UploadSagaState::FinalizingStorj | UploadSagaState::StorjFinalized
+            if !saga.is_live(now) =>
+        {
+            None
+        }
+        UploadSagaState::FinalizingStorj | UploadSagaState::StorjFinalized => {
+            Some(AppError::UploadInProgress(saga.post_id.clone()))
+        }
+        UploadSagaState::Completed => Some(AppError::DuplicatePost(saga.post_id.clone())),
+        UploadSagaState::MarkedOrphaned
+        | UploadSagaState::Deleted
+        | UploadSagaState::CompensationFailed
+        | UploadSagaState::Unverified => Some(AppError::InvalidRequest(format!(
+            "The upload of post {} failed after the Storj finalize ({:?}), replay its dead letter instead",
+            saga.post_id, saga.state
+        ))),
+    }
+}
+
+/// Whether a canister write that failed with `error` may have added the post anyway. A retried
+/// write whose first attempt landed is rejected as a duplicate, and an agent error or an expired
+/// deadline can hide a call the canister applied.
+pub fn write_may_have_landed(error: &AppError) -> bool {
+    matches!(
+        error,
+        AppError::DuplicatePost(_) | AppError::AgentError(_) | AppError::DeadlineExceeded(_)
+    )
+}
+
+#[derive(Debug)]
+enum FailedWrite {
+    /// The post is in the canister, the write landed after all
+    Landed,
+    /// The post is not in the canister, or the write cannot have landed
+    Absent,
+    /// The write may have landed but looking the post up failed
+    Unverified(AppError),
+}
+
+async fn check_failed_write<Fut>(
+    canister_error: &AppError,
+    post_exists: impl FnOnce() -> Fut,
+) -> FailedWrite
+where
+    Fut: Future<Output = Result<bool, AppError>>,
+{
+    if !write_may_have_landed(canister_error) {
+        return FailedWrite::Absent;
+    }
+
+    match post_exists().await {
+        Ok(true) => FailedWrite::Landed,
+        Ok(false) => FailedWrite::Absent,
+        Err(e) => FailedWrite::Unverified(e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        config::TimeoutConfig,
+        utils::{
+            circuit_breaker::{BreakerConfig, CircuitBreaker},
+            storj_interface::LinkshareConfig,
+        },
+    };
+
+    fn sagas() -> UploadSagas {
+        UploadSagas::new(
+            &Store::temporary().unwrap(),
+            RetryPolicy::default(),
+            CompensationAction::Delete,
+            Duration::from_secs(60),
+        )
+        .unwrap()
+    }
+
+    /// Client of an address nothing listens on, any call it makes fails
+    fn unreachable_storj() -> StorjInterface {
+        StorjInterface::new(
+            "http://127.0.0.1:9".to_string(),
+            LinkshareConfig {
+                sfw_base: "http://127.0.0.1:9/sfw".to_string(),
+                nsfw_base: "http://127.0.0.1:9/nsfw".to_string(),
+            },
+            TimeoutConfig {
+                connect: Duration::from_millis(100),
+                total: Duration::from_millis(100),
+            },
+            RetryPolicy {
+                max_attempts: 1,
+                ..RetryPolicy::default()
+            },
+            CircuitBreaker::new(
+                "storj",
+                BreakerConfig {
+                    failure_threshold: 100,
+                    open_duration: Duration::from_secs(30),
+                },
+            ),
+        )
+        .unwrap()
+    }
+
+    async fn compensate(
+        sagas: &UploadSagas,
+        error: AppError,
+        post_exists: Result<bool, AppError>,
+    ) -> bool {
+        sagas
+            .compensate(
+                &unreachable_storj(),
+                "post",
+                "publisher",
+                HashMap::new(),
+                &error,
+                || async { post_exists },
+            )
+            .await
+    }
+
+    fn state(sagas: &UploadSagas) -> UploadSagaState {
+        sagas.get("post").unwrap().unwrap().state
+    }
+
+    #[test]
+    fn test_only_settled_sagas_past_their_ttl_are_pruned() {
+        let sagas = sagas();
+        let states = [
+            ("completed", UploadSagaState::Completed),
+            ("orphaned", UploadSagaState::MarkedOrphaned),
+            ("unverified", UploadSagaState::Unverified),
+            ("live", UploadSagaState::StorjFinalized),
+        ];
+        for (post_id, state) in states {
+            sagas.begin(post_id, Principal::anonymous()).unwrap();
+            sagas.advance(post_id, state, None);
+        }
+        let now = now_unix_secs();
+
+        sagas.prune_settled(now).unwrap();
+        assert!(sagas.get("completed").unwrap().is_some());
+
+        sagas.prune_settled(now + 60).unwrap();
+        assert!(sagas.get("completed").unwrap().is_none());
+        assert!(sagas.get("orphaned").unwrap().is_none());
+        assert!(sagas.get("unverified").unwrap().is_some());
+        assert!(sagas.get("live").unwrap().is_some());
+    }
+
+    #[test]
+    fn test_only_ambiguous_failures_may_have_landed() {
+        let cases = [
+            (AppError::DuplicatePost("post".to_string()), true),
+            (AppError::AgentError("timed out".to_string()), true),
+            (
+                AppError::DeadlineExceeded("canister write".to_string()),
+                true,
+            ),
+            (AppError::CanisterError("rejected".to_string()), false),
+            (AppError::PostNotFound("post".to_string()), false),
+        ];
+
+        for (error, expected) in cases {
+            assert_eq!(write_may_have_landed(&error), expected, "{:?}", error);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_duplicate_of_an_existing_post_is_not_compensated() {
+        let sagas = sagas();
+        sagas.begin("post", Principal::anonymous()).unwrap();
+
+        assert!(
+            compensate(
+                &sagas,
+                AppError::DuplicatePost("post".to_string()),
+                Ok(true)
+            )
+            .await
+        );
+        assert_eq!(state(&sagas), UploadSagaState::Completed);
+    }
+
+    #[tokio::test]
+    async fn test_failed_lookup_leaves_the_object_unverified() {
+        let sagas = sagas();
+        sagas.begin("post", Principal::anonymous()).unwrap();
+
+        assert!(
+            !compensate(
+                &sagas,
+                AppError::AgentError("timed out".to_string()),
+                Err(AppError::AgentError("timed out".to_string()))
+            )
+            .await
+        );
+        assert_eq!(state(&sagas), UploadSagaState::Unverified);
+    }
+
+    #[tokio::test]
+    async fn test_absent_post_is_compensated() {
+        let sagas = sagas();
+        sagas.begin("post", Principal::anonymous()).unwrap();
+
+        // the Storj delete cannot reach anything, so the compensation itself fails
+        assert!(
+            !compensate(
+                &sagas,
+                AppError::AgentError("timed out".to_string()),
+                Ok(false)
+            )
+            .await
+        );
+        assert_eq!(state(&sagas), UploadSagaState::CompensationFailed);
+    }
+
+    #[tokio::test]
+    async fn test_rejected_write_is_compensated_without_lookup() {
+        let sagas = sagas();
+        sagas.begin("post", Principal::anonymous()).unwrap();
+
+        assert!(
+            !compensate(
+                &sagas,
+                AppError::CanisterError("rejected".to_string()),
+                Ok(true)
+            )
+            .await
+        );
+        assert_eq!(state(&sagas), UploadSagaState::CompensationFailed);
+    }
+
+    #[test]
+    fn test_begin_refuses_existing_sagas() {
+        let sagas = sagas();
+        sagas.begin("post", Principal::anonymous()).unwrap();
+
+        assert!(matches!(
+            sagas.begin("post", Principal::anonymous()),
+            Err(AppError::UploadInProgress(_))
+        ));
+
+        sagas.advance("post", UploadSagaState::Completed, None);
+        assert!(matches!(
+            sagas.begin("post", Principal::anonymous()),
+            Err(AppError::DuplicatePost(_))
+        ));
+
+        sagas.advance("post", UploadSagaState::MarkedOrphaned, None);
+        assert!(matches!(
+            sagas.begin("post", Principal::anonymous()),
+            Err(AppError::InvalidRequest(_))
+        ));
+    }
+
+    #[test]
+    fn test_begin_restarts_failed_and_stale_sagas() {
+        let sagas = sagas();
+        sagas.begin("post", Principal::anonymous()).unwrap();
+        sagas.advance("post", UploadSagaState::Failed, None);
+
+        assert!(sagas.begin("post", Principal::anonymous()).is_ok());
+        assert_eq!(state(&sagas), UploadSagaState::FinalizingStorj);
+
+        let mut saga = sagas.get("post").unwrap().unwrap();
+        saga.updated_at -= STALE_SAGA_SECS;
+        assert!(restart_refusal(&saga, now_unix_secs()).is_none());
+    }
+}
@@ -0,0 +1,198 @@
+use candid::Principal;
+use serde::{Deserialize, Serialize};
+
+use crate::utils::{
+    store::{Store, StoreError, TypedTree},
+    time::now_unix_secs,
+};
+
+/// An upload URL handed out by `get-upload-url`
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct UploadSession {
+    pub video_id: String,
+    pub publisher_principal: Principal,
+    pub created_at: u64,
+    /// Set once the reconciler found the session consistent or settled its mismatch
+    #[serde(default)]
+    pub reconciled: bool,
+    /// When the reconciler marked the video orphaned. The mark is lifted if a later run finds a
+    /// post, and a video quarantined pending deletion is only deleted if it still has no post.
+    #[serde(default)]
+    pub quarantined_at: Option<u64>,
+}
+
+impl UploadSession {
+    /// Sessions are keyed by creation time so that the reconciler reads only the window it checks
+    fn key(&self) -> String {
+        format!("{}-{}", time_key(self.created_at), self.video_id)
+    }
+}
+
+fn time_key(at: u64) -> String {
+    format!("{:020}", at)
+}
+
+#[derive(Clone)]
+pub struct UploadSessions {
+    sessions: TypedTree<UploadSession>,
+}
+
+impl UploadSessions {
+    pub fn new(store: &Store) -> Result<Self, StoreError> {
+        Ok(Self {
+            sessions: store.tree("upload_sessions")?,
+        })
+    }
+
+    /// Records the session. A session that fails to persist is only missed by the reconciler, so
+    /// handing out the upload URL goes ahead and the error is logged.
+    pub fn record(&self, video_id: &str, publisher_principal: Principal) {
+        let session = UploadSession {
+            video_id: video_id.to_string(),
+            publisher_principal,
+            created_at: now_unix_secs(),
+            reconciled: false,
+            quarantined_at: None,
+        };
+
+        if let Err(e) = self.sessions.insert(&session.key(), &session) {
+            log::error!("Failed to record upload session {}: {}", video_id, e);
+        }
+    }
+
+    pub fn mark_reconciled(&self, session: &UploadSession) -> Result<(), StoreError> {
+        self.update(session, |session| session.reconciled = true)
+    }
+
+    /// Records that the video of the session was quarantined at `at`, `None` lifting it
+    pub fn set_quarantined(
+        &self,
+        session: &UploadSession,
+        at: Option<u64>,
+    ) -> Result<(), StoreError> {
+        self.update(session, |session| session.quarantined_at = at)
+    }
+
+    fn update(
+        &self,
+        session: &UploadSession,
+        apply: impl Fn(&mut UploadSession),
+    ) -> Result<(), StoreError> {
+        self.sessions.fetch_and_update(&session.key(), |stored| {
+            stored.map(|mut stored| {
+                apply(&mut stored);
+                stored
+            })
+        })?;
+
+        Ok(())
+    }
+
+    /// Sessions created between `oldest` and `newest` that still need reconciling
+    pub fn unreconciled_between(
+        &self,
+        oldest: u64,
+        newest: u64,
+    ) -> Result<Vec<UploadSession>, StoreError> {
+        Ok(self
+            .sessions
+            .range(&time_key(oldest), &time_key(newest.saturating_add(1)))?
+            .into_iter()
+            .map(|(_, session)| session)
+            .filter(|session| !session.reconciled)
+            .collect())
+    }
+
+    /// Drops sessions created before `oldest`
+    pub fn prune_before(&self, oldest: u64) -> Result<(), StoreError> {
+        for (key, _) in self.sessions.range("", &time_key(oldest))? {
+            self.sessions.remove(&key)?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn session(video_id: &str, created_at: u64) -> UploadSession {
+        UploadSession {
+            video_id: video_id.to_string(),
+            publisher_principal: Principal::anonymous(),
+            created_at,
+            reconciled: false,
+            quarantined_at: None,
+        }
+    }
+
+    fn upload_sessions(sessions: &[UploadSession]) -> UploadSessions {
+        let upload_sessions = UploadSessions::new(&Store::temporary().unwrap()).unwrap();
+        for session in sessions {
+            upload_sessions
+                .sessions
+                .insert(&session.key(), session)
+                .unwrap();
+        }
+        upload_sessions
+    }
+
+    fn video_ids(sessions: Vec<UploadSession>) -> Vec<String> {
+        sessions
+            .into_iter()
+            .map(|session| session.video_id)
+            .collect()
+    }
+
+    #[test]
+    fn test_only_the_window_is_read() {
+        let upload_sessions = upload_sessions(&[
+            session("too-old", 99),
+            session("oldest", 100),
+            session("newest", 200),
+            session("too-new", 201),
+        ]);
+
+        assert_eq!(
+            video_ids(upload_sessions.unreconciled_between(100, 200).unwrap()),
+            ["oldest", "newest"]
+        );
+    }
+
+    #[test]
+    fn test_reconciled_sessions_are_skipped() {
+        let first = session("first", 100);
+        let upload_sessions = upload_sessions(&[first.clone(), session("second", 150)]);
+
+        upload_sessions.mark_reconciled(&first).unwrap();
+
+        assert_eq!(
+            video_ids(upload_sessions.unreconciled_between(0, 200).unwrap()),
+            ["second"]
+        );
+    }
+
+    #[test]
+    fn test_quarantine_is_kept_across_reads() {
+        let first = session("first", 100);
+        let upload_sessions = upload_sessions(std::slice::from_ref(&first));
+
+        upload_sessions.set_quarantined(&first, Some(150)).unwrap();
+
+        let sessions = upload_sessions.unreconciled_between(0, 200).unwrap();
+        assert_eq!(sessions[0].quarantined_at, Some(150));
+    }
+
+    #[test]
+    fn test_prune_drops_only_older_sessions() {
+        let upload_sessions = upload_sessions(&[session("old", 99), session("kept", 100)]);
+
+        upload_sessions.prune_before(100).unwrap();
+
+        assert_eq!(
+            video_ids(upload_sessions.unreconciled_between(0, u64::MAX).unwrap()),
+            ["kept"]
+        );
+    }
+}